@@ -0,0 +1,55 @@
+//! Measures the cost of sending metrics through a recorder, without any I/O.
+//!
+//! Run with `cargo bench`.
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use cadence::NopMetricSink;
+use metrics::{Key, Label, Level, Metadata, Recorder};
+use metrics_exporter_statsd::StatsdBuilder;
+
+const ITERATIONS: u32 = 1_000_000;
+
+static METADATA: Metadata = Metadata::new(module_path!(), Level::INFO, Some(module_path!()));
+
+fn bench(name: &str, f: impl Fn(u32)) {
+    // warm up, so that the reused buffers are already allocated.
+    for i in 0..ITERATIONS / 10 {
+        f(i);
+    }
+
+    let start = Instant::now();
+    for i in 0..ITERATIONS {
+        f(black_box(i));
+    }
+    let elapsed = start.elapsed();
+    let per_iteration = elapsed.as_nanos() / u128::from(ITERATIONS);
+    println!(
+        "{:<40} {:>6} ns/iter ({:?} total)",
+        name,
+        per_iteration,
+        Duration::from_nanos(elapsed.as_nanos() as u64)
+    );
+}
+
+fn main() {
+    let recorder = StatsdBuilder::from("", 0)
+        .with_sink(NopMetricSink)
+        .build(Some("bench"))
+        .expect("should build a recorder");
+
+    let untagged = recorder.register_counter(&Key::from_name("counter.untagged"), &METADATA);
+    bench("counter without tags", |i| untagged.increment(u64::from(i)));
+
+    let tags = vec![Label::new("t1", "v1"), Label::new("t2", "v2")];
+    let tagged = recorder.register_counter(&Key::from(("counter.tagged", tags)), &METADATA);
+    bench("counter with tags", |i| tagged.increment(u64::from(i)));
+
+    let gauge = recorder.register_gauge(&Key::from_name("gauge.untagged"), &METADATA);
+    bench("gauge without tags", |i| gauge.set(f64::from(i) / 3.0));
+
+    let histogram = recorder.register_histogram(&Key::from_name("histogram.untagged"), &METADATA);
+    bench("histogram without tags", |i| {
+        histogram.record(f64::from(i) / 7.0)
+    });
+}
@@ -0,0 +1,76 @@
+use std::collections::{HashMap, HashSet};
+
+use metrics::Label;
+
+/// The value that replaces the label values that aren't allowed.
+pub(crate) const OTHER: &str = "other";
+
+/// The values allowed for some label keys, see
+/// [`StatsdBuilder::with_allowed_label_values`](crate::StatsdBuilder::with_allowed_label_values).
+/// Labels with any other key are left alone.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct AllowedValues {
+    values: HashMap<String, HashSet<String>>,
+}
+
+impl AllowedValues {
+    pub(crate) fn allow(&mut self, key: String, values: impl IntoIterator<Item = String>) {
+        self.values.entry(key).or_default().extend(values);
+    }
+
+    /// `label`, with its value replaced with [`OTHER`] unless it's allowed.
+    pub(crate) fn apply(&self, label: &Label) -> Label {
+        match self.values.get(label.key()) {
+            Some(values) if !values.contains(label.value()) => {
+                Label::new(label.key().to_string(), OTHER)
+            }
+            _ => label.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics::{Key, Recorder};
+
+    use super::*;
+    use crate::testing::METADATA;
+    use crate::StatsdBuilder;
+
+    #[test]
+    fn replaces_values_not_allowed() {
+        let mut allowed = AllowedValues::default();
+        allowed.allow("status".to_string(), ["200".to_string()]);
+        allowed.allow("status".to_string(), ["404".to_string()]);
+
+        let apply = |key, value| allowed.apply(&Label::new(key, value));
+        assert_eq!(Label::new("status", "200"), apply("status", "200"));
+        assert_eq!(Label::new("status", "404"), apply("status", "404"));
+        assert_eq!(Label::new("status", "other"), apply("status", "500"));
+        assert_eq!(Label::new("endpoint", "/a"), apply("endpoint", "/a"));
+    }
+
+    #[test]
+    fn allowed_label_values() {
+        let sink = crate::testing::FakeSink::new();
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(sink.clone())
+            .with_allowed_label_values("status", ["200", "404"])
+            .build(None)
+            .expect("should build a recorder with custom sink");
+
+        for status in ["200", "503"] {
+            let labels = vec![Label::new("status", status), Label::new("t1", "v1")];
+            let key = Key::from(("counter.name", labels));
+            recorder.register_counter(&key, &METADATA).increment(1);
+        }
+
+        assert_eq!(
+            vec![
+                "counter.name:1|c|#status:200,t1:v1",
+                "counter.name:1|c|#status:other,t1:v1",
+            ],
+            sink.lines()
+        );
+    }
+}
@@ -0,0 +1,199 @@
+use std::cell::RefCell;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use crate::line::ContextTags;
+
+thread_local! {
+    /// The tags of the scopes the current thread is in, outermost first.
+    static TAGS: RefCell<Vec<(String, String)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Run `future` with `tags` added to every metric recorded while it's polled, on top of the tags
+/// of the scopes it runs in, e.g. the ID of the request a task serves, without passing labels
+/// down to every call that records a metric.
+///
+/// The tags follow the future from thread to thread, since they're only in place while it's
+/// polled, which makes them local to the task rather than to the thread. They come after the
+/// default tags, the labels and the tags of
+/// [`StatsdBuilder::with_context_tags`](crate::StatsdBuilder::with_context_tags). A tag replaces
+/// the tag with the same key of an outer scope.
+///
+/// The metrics sent on their own schedule, e.g. the rates of the counters or the histograms
+/// summarized on the client, aren't sent within the scope and don't get the tags.
+///
+/// ```
+/// use metrics_exporter_statsd::{with_tags, StatsdBuilder};
+///
+/// let recorder = StatsdBuilder::from("127.0.0.1", 8125)
+///     .build(None)
+///     .expect("Could not create StatsdRecorder");
+///
+/// async fn serve() {
+///     // emits `requests:1|c|#request_id:42` once the recorder is installed
+///     metrics::counter!("requests").increment(1);
+/// }
+///
+/// let served = with_tags([("request_id", "42")], serve());
+/// ```
+pub fn with_tags<I, K, V, F>(tags: I, future: F) -> WithTags<F>
+where
+    I: IntoIterator<Item = (K, V)>,
+    K: ToString,
+    V: ToString,
+    F: Future,
+{
+    WithTags {
+        tags: collect(tags),
+        future: Box::pin(future),
+    }
+}
+
+/// Tags added to the metrics recorded on the current thread until the guard is dropped, see
+/// [`StatsdRecorder::push_tags`](crate::StatsdRecorder::push_tags).
+#[must_use = "the tags are removed as soon as the guard is dropped"]
+pub struct TagGuard {
+    _scope: Scope,
+    /// The tags are those of the thread that pushed them, the guard stays there.
+    _thread: PhantomData<*const ()>,
+}
+
+pub(crate) fn push_tags<I, K, V>(tags: I) -> TagGuard
+where
+    I: IntoIterator<Item = (K, V)>,
+    K: ToString,
+    V: ToString,
+{
+    TagGuard {
+        _scope: Scope::enter(collect(tags)),
+        _thread: PhantomData,
+    }
+}
+
+fn collect<I, K, V>(tags: I) -> Vec<(String, String)>
+where
+    I: IntoIterator<Item = (K, V)>,
+    K: ToString,
+    V: ToString,
+{
+    tags.into_iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+/// A future with tags added to the metrics it records, see [`with_tags`].
+pub struct WithTags<F> {
+    tags: Vec<(String, String)>,
+    future: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for WithTags<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let _scope = Scope::enter(this.tags.iter().cloned());
+        this.future.as_mut().poll(cx)
+    }
+}
+
+/// The tags of a scope, in place on the current thread until dropped.
+pub(crate) struct Scope {
+    /// How many tags were in place before the scope.
+    outer: usize,
+}
+
+impl Scope {
+    pub(crate) fn enter(tags: impl IntoIterator<Item = (String, String)>) -> Self {
+        TAGS.with(|stack| {
+            let mut stack = stack.borrow_mut();
+            let outer = stack.len();
+            stack.extend(tags);
+            Scope { outer }
+        })
+    }
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        TAGS.with(|stack| stack.borrow_mut().truncate(self.outer));
+    }
+}
+
+/// Add the tags of the scopes the current thread is in, the innermost tag of every key.
+pub(crate) fn add_tags(tags: &mut ContextTags<'_>) {
+    TAGS.with(|stack| {
+        let stack = stack.borrow();
+        for (i, (key, value)) in stack.iter().enumerate() {
+            if !stack[i + 1..].iter().any(|(inner, _)| inner == key) {
+                tags.add(key, value);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics::{Key, Recorder};
+
+    use crate::testing::METADATA;
+    use crate::StatsdBuilder;
+
+    #[test]
+    fn ambient_tags() {
+        let sink = crate::testing::FakeSink::new();
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(sink.clone())
+            .with_default_tag("env", "prod")
+            .build(None)
+            .expect("should build a recorder with custom sink");
+        let requests = recorder.register_counter(&Key::from_name("requests"), &METADATA);
+        let outer = crate::with_tags([("request_id", "42"), ("phase", "outer")], async {
+            requests.increment(1);
+            crate::with_tags([("phase", "inner")], async { requests.increment(2) }).await;
+        });
+        let mut outer = std::pin::pin!(outer);
+        let mut cx = std::task::Context::from_waker(std::task::Waker::noop());
+        assert!(std::future::Future::poll(outer.as_mut(), &mut cx).is_ready());
+        requests.increment(3);
+
+        assert_eq!(
+            vec![
+                "requests:1|c|#env:prod,request_id:42,phase:outer",
+                "requests:2|c|#env:prod,request_id:42,phase:inner",
+                "requests:3|c|#env:prod",
+            ],
+            sink.lines()
+        );
+    }
+
+    #[test]
+    fn pushed_tags() {
+        let sink = crate::testing::FakeSink::new();
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(sink.clone())
+            .build(None)
+            .expect("should build a recorder with custom sink");
+        let requests = recorder.register_counter(&Key::from_name("requests"), &METADATA);
+        {
+            let _startup = recorder.push_tags([("phase", "startup")]);
+            requests.increment(1);
+            let _warmup = recorder
+                .handle()
+                .push_tags([("phase", "warmup"), ("cache", "cold")]);
+            requests.increment(2);
+        }
+        requests.increment(3);
+
+        assert_eq!(
+            vec![
+                "requests:1|c|#phase:startup",
+                "requests:2|c|#phase:warmup,cache:cold",
+                "requests:3|c",
+            ],
+            sink.lines()
+        );
+    }
+}
@@ -0,0 +1,230 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use cadence::{MetricSink, SinkStats};
+
+use crate::sink::SharedSink;
+use crate::upkeep::Upkeep;
+
+static NEXT_SINK_ID: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// The batch of the current thread for each [`BatchingSink`] it has written to.
+    static BATCHES: RefCell<HashMap<usize, Arc<Mutex<String>>>> = RefCell::new(HashMap::new());
+}
+
+/// A [`MetricSink`] wrapper that accumulates lines in a per thread batch and hands whole batches,
+/// newline separated, to the wrapped sink. This way threads only contend on the wrapped sink (the
+/// queue) once per batch instead of once per metric.
+///
+/// A batch is handed over once adding a line would make it larger than `max_bytes`. Batches that
+/// don't fill up are flushed by [`BatchFlusher`], which bounds the extra latency.
+pub(crate) struct BatchingSink {
+    id: usize,
+    inner: SharedSink,
+    max_bytes: usize,
+    /// Every batch of every thread, so that they can be flushed from another thread.
+    batches: Mutex<Vec<Arc<Mutex<String>>>>,
+}
+
+impl BatchingSink {
+    pub(crate) fn new(inner: SharedSink, max_bytes: usize) -> Self {
+        BatchingSink {
+            id: NEXT_SINK_ID.fetch_add(1, Ordering::Relaxed),
+            inner,
+            max_bytes,
+            batches: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn local_batch(&self) -> Arc<Mutex<String>> {
+        BATCHES.with(|batches| {
+            batches
+                .borrow_mut()
+                .entry(self.id)
+                .or_insert_with(|| {
+                    let batch = Arc::new(Mutex::new(String::with_capacity(self.max_bytes)));
+                    self.batches
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .push(batch.clone());
+                    batch
+                })
+                .clone()
+        })
+    }
+
+    fn send(&self, batch: &mut String) {
+        if !batch.is_empty() {
+            // errors are accounted for by the wrapped sink, for every line of the batch.
+            let _ = self.inner.emit(batch);
+            batch.clear();
+        }
+    }
+
+    /// Hand every pending batch to the wrapped sink, and forget the batches of the threads that
+    /// exited.
+    pub(crate) fn flush_batches(&self) {
+        let mut batches = self.batches.lock().unwrap_or_else(|e| e.into_inner());
+        for batch in batches.iter() {
+            self.send(&mut batch.lock().unwrap_or_else(|e| e.into_inner()));
+        }
+        batches.retain(|batch| Arc::strong_count(batch) > 1);
+    }
+}
+
+impl MetricSink for BatchingSink {
+    fn emit(&self, metric: &str) -> io::Result<usize> {
+        if metric.len() >= self.max_bytes {
+            return self.inner.emit(metric);
+        }
+
+        let batch = self.local_batch();
+        let mut batch = batch.lock().unwrap_or_else(|e| e.into_inner());
+        if !batch.is_empty() && batch.len() + 1 + metric.len() > self.max_bytes {
+            let mut full = mem::replace(&mut *batch, String::with_capacity(self.max_bytes));
+            self.send(&mut full);
+        }
+        if !batch.is_empty() {
+            batch.push('\n');
+        }
+        batch.push_str(metric);
+        Ok(metric.len())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.flush_batches();
+        self.inner.flush()
+    }
+
+    fn stats(&self) -> SinkStats {
+        self.inner.stats()
+    }
+}
+
+/// Flushes the batches of a [`BatchingSink`] on an interval, until the sink goes away.
+pub(crate) struct BatchFlusher {
+    sink: Weak<BatchingSink>,
+}
+
+impl BatchFlusher {
+    pub(crate) fn new(sink: &Arc<BatchingSink>) -> Self {
+        BatchFlusher {
+            sink: Arc::downgrade(sink),
+        }
+    }
+
+    pub(crate) fn schedule(self, upkeep: &mut Upkeep, interval: Duration) {
+        upkeep.every(interval, move || match self.sink.upgrade() {
+            Some(sink) => {
+                sink.flush_batches();
+                true
+            }
+            None => false,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics::{Key, Recorder};
+
+    use super::*;
+    use crate::testing::{Environ, METADATA};
+
+    #[derive(Default)]
+    struct LinesSink {
+        lines: Mutex<Vec<String>>,
+    }
+
+    impl MetricSink for LinesSink {
+        fn emit(&self, metric: &str) -> io::Result<usize> {
+            self.lines.lock().unwrap().push(metric.to_string());
+            Ok(metric.len())
+        }
+    }
+
+    #[test]
+    fn batches_until_full() {
+        let lines = Arc::new(LinesSink::default());
+        let sink = BatchingSink::new(lines.clone(), 12);
+
+        sink.emit("a:1|c").unwrap();
+        sink.emit("b:1|c").unwrap();
+        assert!(lines.lines.lock().unwrap().is_empty());
+
+        sink.emit("c:1|c").unwrap();
+        assert_eq!(vec!["a:1|c\nb:1|c"], *lines.lines.lock().unwrap());
+
+        sink.flush().unwrap();
+        assert_eq!(vec!["a:1|c\nb:1|c", "c:1|c"], *lines.lines.lock().unwrap());
+    }
+
+    #[test]
+    fn flushes_batches_of_other_threads() {
+        let lines = Arc::new(LinesSink::default());
+        let sink = Arc::new(BatchingSink::new(lines.clone(), 100));
+
+        let writer = sink.clone();
+        std::thread::spawn(move || writer.emit("a:1|c").unwrap())
+            .join()
+            .unwrap();
+        sink.emit("b:1|c").unwrap();
+
+        sink.flush_batches();
+        let mut sent = lines.lines.lock().unwrap().clone();
+        sent.sort();
+        assert_eq!(vec!["a:1|c", "b:1|c"], sent);
+        assert_eq!(1, sink.batches.lock().unwrap().len());
+    }
+
+    #[test]
+    fn thread_local_batching() {
+        let (server_socket, builder) = Environ::setup();
+        let recorder = builder
+            .with_thread_local_batching(100, Duration::from_millis(10))
+            .build(None)
+            .expect("test env should build a valid recorder");
+        let env = Environ {
+            server_socket,
+            recorder,
+        };
+
+        let counter = env
+            .recorder
+            .register_counter(&Key::from_name("counter.name"), &METADATA);
+        counter.increment(1);
+        counter.increment(2);
+
+        assert_eq!(
+            "counter.name:1|c\ncounter.name:2|c",
+            env.receive_on_server()
+        );
+    }
+
+    #[test]
+    fn shutdown_flushes_batches() {
+        let (server_socket, builder) = Environ::setup();
+        let recorder = builder
+            .with_thread_local_batching(100, Duration::from_secs(3600))
+            .build(None)
+            .expect("test env should build a valid recorder");
+        let env = Environ {
+            server_socket,
+            recorder,
+        };
+
+        let counter = env
+            .recorder
+            .register_counter(&Key::from_name("counter.name"), &METADATA);
+        counter.increment(1);
+        env.recorder.handle().shutdown();
+
+        assert_eq!("counter.name:1|c", env.receive_on_server());
+    }
+}
@@ -1,21 +1,70 @@
-use std::net::UdpSocket;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::ops::RangeInclusive;
 use std::panic::RefUnwindSafe;
+use std::path::PathBuf;
 use std::sync::Arc;
-
-use cadence::{
-    BufferedUdpMetricSink, MetricSink, QueuingMetricSink, StatsdClient, StatsdClientBuilder,
+use std::time::Duration;
+
+use cadence::{BufferedUdpMetricSink, MetricSink, QueuingMetricSink, StatsdClient, UdpMetricSink};
+use metrics::{Key, Label, Level, Recorder, SetRecorderError};
+
+use crate::allowed::AllowedValues;
+use crate::batch::{BatchFlusher, BatchingSink};
+use crate::catalog::CatalogWriter;
+use crate::clock::{Clock, SharedClock, SystemClock};
+use crate::exit;
+use crate::ext;
+use crate::file::{FileSink, Rotation};
+#[cfg(target_os = "linux")]
+use crate::gso::{self, GsoSink};
+use crate::handle::{ContextTagsFn, Scope, Shared};
+use crate::intern::Interner;
+use crate::line::{format_prefix, ContextTags};
+use crate::mapping::LiveMapping;
+use crate::mirror::MirrorSink;
+use crate::origin::{self, ExternalDataSink};
+use crate::packet::{PacketFlusher, PackingSink, PACKET_FLUSH_INTERVAL};
+use crate::pipeline::{Pipeline, PipelineStage};
+use crate::quota::{Limits, Quotas};
+use crate::rates::CounterRates;
+use crate::recorder::{Handle, HandleFlusher, StatsdRecorder};
+use crate::registry::Registries;
+use crate::ring::RingQueue;
+use crate::routing::{RouteMatch, Routes};
+use crate::sampling::{SampleRateSemantics, SampleRates};
+use crate::sink::{
+    CountingSink, InnerSink, Queue, QueueAges, QueueSink, RecentLines, RecentLinesSink, Requeuer,
+    SharedSink, SharedSinkRef, StaleSink, MAX_UDP_PAYLOAD, REQUEUE_INTERVAL,
 };
-use metrics::SetRecorderError;
-
-use crate::recorder::StatsdRecorder;
-use crate::types::HistogramType;
+use crate::sketch::Sketching;
+use crate::smoothing::Smoothing;
+use crate::snapshot::{Activity, LastValues};
+use crate::socks::{self, Socks5Proxy};
+use crate::stats::{DropReason, Stats};
+use crate::stream::{Backoff, StreamAddr, StreamFlusher, StreamSink, StreamTransport};
+use crate::summary::{PercentileNaming, Percentiles};
+use crate::targets::TargetPrefixes;
+use crate::tee::SharedRecorder;
+use crate::telemetry::{
+    ErrorLog, HeartbeatReporter, LogFn, QueueDepthReporter, Telemetry, TopSeriesReporter,
+    UptimeReporter, Watchdog, DEFAULT_ERROR_LOG_INTERVAL, DEFAULT_TELEMETRY_INTERVAL,
+    WATCHDOG_INTERVAL,
+};
+use crate::types::{DualEmit, HistogramType, MetricType};
+use crate::upkeep::Upkeep;
+use crate::values::{ValueBounds, ValuePolicy};
 use thiserror::Error;
 
 const DEFAULT_HOST: &str = "127.0.0.1";
 const DEFAULT_PORT: u16 = 8125;
 const DEFAULT_QUEUE_SIZE: usize = 5000;
 const DEFAULT_BUFFER_SIZE: usize = 256;
+/// The packet size used with GSO when no max packet size is given.
+const DEFAULT_GSO_PACKET_SIZE: usize = 1432;
 const CLIENT_UDP_HOST: &str = "0.0.0.0";
+/// How long [`StatsdBuilder::send_once`] waits for the metric to be sent by default.
+const SEND_ONCE_TIMEOUT: Duration = Duration::from_secs(1);
 
 #[derive(Error, Debug)]
 pub enum StatsdError {
@@ -28,6 +77,71 @@ pub enum StatsdError {
     #[error("Port number must be nonzero")]
     InvalidPortZero,
 
+    /// The caller specified a sample rate that isn't in the `(0, 1]` range.
+    #[error("Sample rate must be greater than 0 and at most 1")]
+    InvalidSampleRate,
+
+    /// A quantile given to [`StatsdBuilder::with_percentiles`] or
+    /// [`StatsdBuilder::with_percentiles_for`] isn't in the `[0, 1]` range.
+    #[error("Quantiles must be between 0 and 1")]
+    InvalidQuantile,
+
+    /// The relative accuracy given to [`StatsdBuilder::with_local_sketches`] isn't in the
+    /// `(0, 1)` range.
+    #[error("Relative accuracy must be greater than 0 and less than 1")]
+    InvalidRelativeAccuracy,
+
+    /// The rate given to [`StatsdBuilder::with_burst_smoothing`] isn't greater than 0, or the
+    /// burst is 0.
+    #[error("Burst smoothing must allow more than 0 values per second and a burst of at least 1")]
+    InvalidBurstSmoothing,
+
+    /// The significant digits given to [`StatsdBuilder::with_hdr_timers`] aren't from 1 to 5.
+    #[error("Significant digits must be from 1 to 5")]
+    InvalidSignificantDigits,
+
+    /// A pattern given to the builder, e.g. to [`StatsdBuilder::with_sample_rate_for`], isn't
+    /// valid.
+    #[error("Invalid pattern `{pattern}`: {reason}")]
+    InvalidPattern { pattern: String, reason: String },
+
+    /// The mapping file given to [`StatsdBuilder::with_mapping_file`] isn't valid.
+    #[error("Invalid mapping on line {line}: {reason}")]
+    InvalidMapping {
+        /// The line of the file the error is on, starting at 1.
+        line: usize,
+        reason: String,
+    },
+
+    /// The host given to the builder couldn't be resolved to an address.
+    #[error("Could not resolve the statsd host {host}: {source}")]
+    DnsResolution {
+        /// The host and port that were resolved, e.g. `statsd.local:8125`.
+        host: String,
+        source: io::Error,
+    },
+
+    /// The local socket the metrics are sent from couldn't be bound, see
+    /// [`StatsdBuilder::with_client_udp_host`].
+    #[error("Could not bind the client socket to {addr}: {source}")]
+    Bind {
+        /// The local address the socket was bound to.
+        addr: String,
+        source: io::Error,
+    },
+
+    /// The socket couldn't be set up to send to the statsd server.
+    #[error("Could not connect to statsd at {addr}: {source}")]
+    Connect {
+        /// The address of the statsd server, as resolved from the host and port.
+        addr: SocketAddr,
+        source: io::Error,
+    },
+
+    /// The file given to [`StatsdBuilder::with_file_sink`] couldn't be opened.
+    #[error("Could not open the metrics file {}: {source}", path.display())]
+    File { path: PathBuf, source: io::Error },
+
     /// MetricError indicates that there was an error reporting metrics to statsd, this is directly
     /// mapped from [`cadence::MetricError`].
     #[error("Metrics reporting error")]
@@ -36,7 +150,23 @@ pub enum StatsdError {
         source: cadence::MetricError,
     },
 
-    /// Any I/O-related errors, e.g. UDP connection/bind errors.
+    /// The metric given to [`StatsdBuilder::send_once`] was still waiting to be sent once
+    /// `timeout` expired.
+    #[error("The metric wasn't sent within {timeout:?}")]
+    SendTimeout {
+        /// How long the metric was waited for, the shutdown timeout.
+        timeout: Duration,
+    },
+
+    /// The metric given to [`StatsdBuilder::send_once`] was dropped rather than sent.
+    #[error("The metric was dropped: {reason}")]
+    Dropped {
+        /// Why the metric was dropped, e.g. [`DropReason::InvalidValue`] for a value out of the
+        /// bounds.
+        reason: DropReason,
+    },
+
+    /// Any other I/O-related errors, e.g. reading the mapping file.
     #[error(transparent)]
     IoError(#[from] std::io::Error),
 
@@ -51,21 +181,114 @@ pub enum StatsdError {
 
 /// Type used as a wrapper for a custom sink.
 ///
-/// The alternative would be `Box<dyn MetricSink + Sync + Send + ...>` but that would incur `dyn`
-/// overhead each time a metric is written, whereas this incurs `dyn` overhead only once (the one
-/// time this closure is called, from `StatsdBuilder::build`).
-type BoxedSinkClosure = Box<dyn FnOnce(&str) -> StatsdClientBuilder>;
+/// The closure defers wrapping the sink until `StatsdBuilder::build`, which is when the recorder's
+/// shared state that the wrapper reports to exists. It's called once per recorder built from the
+/// builder or its clones, which share the sink.
+type SinkClosure = Arc<dyn Fn(Arc<Stats>) -> SharedSink>;
+
+/// Type used for the wrappers of the sink, see [`StatsdBuilder::with_sink_wrapper`].
+type SinkWrapper = Arc<dyn Fn(InnerSink) -> SharedSink>;
+
+/// What the default sink sends metrics over.
+enum Connection {
+    Udp(UdpSocket, SocketAddr),
+    Stream(StreamAddr),
+    File(Arc<FileSink>),
+}
 
 /// [`StatsdBuilder`] is responsible building and configuring a [`StatsdRecorder`].
+///
+/// The builder can be cloned to build several recorders from the same configuration, e.g. one per
+/// destination:
+///
+/// ```
+/// use metrics_exporter_statsd::StatsdBuilder;
+///
+/// let common = StatsdBuilder::from("127.0.0.1", 8125)
+///     .with_queue_size(10_000)
+///     .with_default_tag("service", "checkout");
+/// let primary = common.clone().build(Some("app")).expect("Could not create StatsdRecorder");
+/// let secondary = common
+///     .with_host_and_port("127.0.0.1", 9125)
+///     .build(Some("app"))
+///     .expect("Could not create StatsdRecorder");
+/// ```
+#[derive(Clone)]
 pub struct StatsdBuilder {
     host: String,
     port: u16,
     queue_size: Option<usize>,
+    queue_slot_size: Option<usize>,
     buffer_size: Option<usize>,
     default_histogram: HistogramType,
     client_udp_host: String,
+    client_port_range: Option<RangeInclusive<u16>>,
+    blocking_socket: bool,
+    stream: Option<StreamTransport>,
+    file_sink: Option<PathBuf>,
+    file_rotation: Option<Rotation>,
+    socks5_proxy: Option<(String, u16)>,
+    socks5_auth: Option<(String, String)>,
+    backoff: Backoff,
     default_tags: Vec<(String, String)>,
-    sink: Option<BoxedSinkClosure>,
+    sink: Option<SinkClosure>,
+    sink_wrappers: Vec<SinkWrapper>,
+    telemetry: Option<Duration>,
+    queue_depth_interval: Option<Duration>,
+    heartbeat: Option<Duration>,
+    uptime: Option<(Duration, Vec<(String, String)>)>,
+    recent_lines: Option<usize>,
+    last_values: Option<usize>,
+    batching: Option<(usize, Duration)>,
+    max_packet_size: Option<usize>,
+    udp_gso: bool,
+    queue_workers: Option<usize>,
+    queue_max_fill: [f64; MetricType::ALL.len()],
+    counter_coalescing: bool,
+    max_queue_age: Option<Duration>,
+    sample_rates: SampleRates,
+    sample_rate_semantics: SampleRateSemantics,
+    value_bounds: Option<ValueBounds>,
+    rounding: [f64; MetricType::ALL.len()],
+    negative_values: Option<ValuePolicy>,
+    counter_rates: Option<(CounterRates, Duration)>,
+    summaries: Option<Duration>,
+    percentiles: Percentiles,
+    sketches: Option<Duration>,
+    hdr_timers: Option<(Duration, u8)>,
+    dual_emit: DualEmit,
+    sketching: Sketching,
+    clock: SharedClock,
+    context_tags: Option<ContextTagsFn>,
+    tee: Option<SharedRecorder>,
+    routes: Routes,
+    mirrors: Vec<(StatsdRecorder, f64)>,
+    external_data: Option<String>,
+    unit_suffixes: bool,
+    allowed_values: AllowedValues,
+    max_tags: Option<usize>,
+    tag_priority: Vec<String>,
+    sort_tags: bool,
+    strict: bool,
+    max_name_len: Option<usize>,
+    prefix_env: Option<String>,
+    target_prefixes: Option<Vec<(String, String)>>,
+    quotas: Vec<(String, Limits)>,
+    burst_smoothing: Option<(Duration, f64, u64)>,
+    mapping_file: Option<PathBuf>,
+    descriptions_file: Option<PathBuf>,
+    catalog_file: Option<(PathBuf, Duration)>,
+    mapping_reload: Option<Duration>,
+    stages: Vec<Arc<dyn PipelineStage>>,
+    idle_timeout: Option<Duration>,
+    top_series: Option<(usize, Duration)>,
+    active_keys: bool,
+    top_emitters: Option<usize>,
+    shutdown_timeout: Option<Duration>,
+    exit_hook: Option<Duration>,
+    log: Option<LogFn>,
+    flush_jitter: Duration,
+    error_log_interval: Duration,
 }
 
 impl StatsdBuilder {
@@ -79,13 +302,18 @@ impl StatsdBuilder {
             port,
             queue_size: None,
             buffer_size: None,
-            default_histogram: HistogramType::Histogram,
-            client_udp_host: CLIENT_UDP_HOST.to_string(),
-            default_tags: Vec::new(),
-            sink: None,
+            ..Default::default()
         }
     }
 
+    /// Send the metrics to `host` and `port` rather than to the ones the builder was created with,
+    /// e.g. to point a clone of a builder at another statsd server.
+    pub fn with_host_and_port<S: Into<String>>(mut self, host: S, port: u16) -> Self {
+        self.host = host.into();
+        self.port = port;
+        self
+    }
+
     /// Configure queue size for this builder, the queue size is eventually passed down to the
     /// underlying StatsdClient to control how many elements should be allowed to buffer in a queue.
     /// The default value for the queue size is `5000`, Statsd client will error out and drop the
@@ -95,6 +323,75 @@ impl StatsdBuilder {
         self
     }
 
+    /// Drain the queue with `workers` threads instead of one, for services that emit more metrics
+    /// than a single thread can write to the socket. Each worker has a queue of its own of
+    /// `queue_size` metrics, and metrics are spread over them in turn. Workers share the socket but
+    /// buffer separately, so metrics emitted close together may be sent in a different order.
+    ///
+    /// The default is a single worker. This setting has no effect on a custom sink.
+    pub fn with_queue_workers(mut self, workers: usize) -> Self {
+        self.queue_workers = Some(workers);
+        self
+    }
+
+    /// Queue the metrics in `queue_size` slots of `slot_size` bytes allocated up front rather
+    /// than in cadence's queue, which allocates every metric it holds. Queuing a metric then only
+    /// copies it to a free slot, it allocates only when the metric is larger than any the slot
+    /// held so far, so `slot_size` should fit most metrics. Once every slot is taken, metrics are
+    /// dropped as [`DropReason::QueueFull`] until one is sent.
+    ///
+    /// This has no effect on a custom sink.
+    pub fn with_preallocated_queue(mut self, slot_size: usize) -> Self {
+        self.queue_slot_size = Some(slot_size);
+        self
+    }
+
+    /// Shed the metrics sent as `metric_type` once the queue is more than `max_fill` full, a
+    /// fraction of the queue size, rather than once it's full, so that they're dropped before the
+    /// other metrics under pressure. E.g. histogram samples can be spared more easily than counter
+    /// increments, which can't be made up for. The shed metrics are counted as
+    /// [`DropReason::Shed`].
+    ///
+    /// `max_fill` is clamped to `[0, 1]`, every type may fill the whole queue by default. This
+    /// has no effect on a custom sink.
+    ///
+    /// ```
+    /// use metrics_exporter_statsd::{MetricType, StatsdBuilder};
+    ///
+    /// let recorder = StatsdBuilder::from("127.0.0.1", 8125)
+    ///     .with_queue_priority(MetricType::Histogram, 0.5)
+    ///     .with_queue_priority(MetricType::Distribution, 0.5)
+    ///     .build(None)
+    ///     .expect("Could not create StatsdRecorder");
+    /// ```
+    pub fn with_queue_priority(mut self, metric_type: MetricType, max_fill: f64) -> Self {
+        self.queue_max_fill[metric_type.index()] = max_fill.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Sum the counter increments that don't fit in the queue, rather than dropping them, and queue
+    /// a single increment per counter once there's room again, within 100ms. This keeps counters
+    /// accurate under overload at the cost of some latency. Sums are kept for as many counters as
+    /// the queue holds, the increments of the others are dropped, like the other metrics.
+    ///
+    /// Increments are summed by name and tags, and by sample rate. This has no effect on a custom
+    /// sink.
+    pub fn with_counter_coalescing(mut self) -> Self {
+        self.counter_coalescing = true;
+        self
+    }
+
+    /// Drop the metrics that waited in the queue for longer than `max_age` when they're about to
+    /// be sent, e.g. after the network stalled, rather than sending them late: a burst of stale
+    /// gauges is more misleading than a gap. The dropped metrics are counted as
+    /// [`DropReason::Stale`].
+    ///
+    /// Metrics are sent however long they waited by default. This has no effect on a custom sink.
+    pub fn with_max_queue_age(mut self, max_age: Duration) -> Self {
+        self.max_queue_age = Some(max_age);
+        self
+    }
+
     /// Buffer size controls how much should be buffered in StatsdClient's memory before they are
     /// actually written out over the socket. This value is conservatively set to 256 bytes and
     /// should be adjusted according to the application needs.
@@ -111,6 +408,132 @@ impl StatsdBuilder {
         self
     }
 
+    /// Bind the local udp socket to the first port of `ports` that is available, instead of
+    /// letting the system pick any port, for the environments that only allow some source ports.
+    /// Building fails with [`StatsdError::Bind`] when none of them is.
+    ///
+    /// ```
+    /// use metrics_exporter_statsd::StatsdBuilder;
+    ///
+    /// let recorder = StatsdBuilder::from("127.0.0.1", 8125)
+    ///     .with_client_port_range(40000..=40100)
+    ///     .build(None)
+    ///     .expect("Could not create StatsdRecorder");
+    /// ```
+    pub fn with_client_port_range(mut self, ports: RangeInclusive<u16>) -> Self {
+        self.client_port_range = Some(ports);
+        self
+    }
+
+    /// Make the local udp socket blocking, so that sends wait for room in the kernel buffer rather
+    /// than fail, for the applications that prefer backpressure over losing metrics. Sends happen
+    /// on the queue worker, which then falls behind: metrics pile up in the queue, and are dropped
+    /// once it's full rather than slowing the application down.
+    ///
+    /// The socket is non-blocking by default. This has no effect on the other transports.
+    pub fn with_blocking_socket(mut self) -> Self {
+        self.blocking_socket = true;
+        self
+    }
+
+    /// Send metrics over TCP to the host and port given to [`StatsdBuilder::from`] instead of over
+    /// UDP, newline terminated as statsd servers expect them on streams.
+    ///
+    /// Each queue worker opens its own connection when it sends its first metric. When the
+    /// connection is lost it reconnects with a backoff, see
+    /// [`StatsdBuilder::with_reconnect_backoff`]. Metrics keep piling up in the queue meanwhile,
+    /// and are dropped once it's full. Lines are written once the buffer size is reached, and at
+    /// least every 100ms otherwise. The max packet size has no effect.
+    pub fn with_tcp(mut self) -> Self {
+        self.stream = Some(StreamTransport::Tcp);
+        self
+    }
+
+    /// Send metrics over a Unix stream socket at `path` instead of over UDP, the host and port are
+    /// ignored. This works like [`StatsdBuilder::with_tcp`] otherwise.
+    ///
+    /// On Linux, a `path` that starts with a NUL byte is an address in the abstract namespace, e.g.
+    /// `"\0dogstatsd"`, which needs no file nor permissions on the file system.
+    #[cfg(unix)]
+    pub fn with_unix_stream<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.stream = Some(StreamTransport::Unix(path.into()));
+        self
+    }
+
+    /// Append the metrics to the file at `path` instead of sending them, a line each, e.g. to
+    /// capture them in an air-gapped environment and ingest them later. The host, the port and the
+    /// other transports are ignored. The file is created when it doesn't exist, and `build` fails
+    /// with [`StatsdError::File`] when it can't be opened.
+    ///
+    /// Metrics go through the queue like with the other transports. The file grows without bounds
+    /// unless it's rotated, see [`StatsdBuilder::with_file_rotation`].
+    ///
+    /// ```no_run
+    /// use metrics_exporter_statsd::StatsdBuilder;
+    ///
+    /// let recorder = StatsdBuilder::from("", 0)
+    ///     .with_file_sink("/var/spool/metrics/statsd.log")
+    ///     .with_file_rotation(64 * 1024 * 1024, 4)
+    ///     .build(Some("app"))
+    ///     .expect("Could not create StatsdRecorder");
+    /// ```
+    pub fn with_file_sink<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.file_sink = Some(path.into());
+        self
+    }
+
+    /// Rotate the file given to [`StatsdBuilder::with_file_sink`] before it grows beyond
+    /// `max_bytes`: it's renamed with a `.1` suffix, the previous `.1` to `.2` and so on, keeping
+    /// `max_files` of them. With no file to keep, the file is truncated instead.
+    pub fn with_file_rotation(mut self, max_bytes: u64, max_files: usize) -> Self {
+        self.file_rotation = Some(Rotation {
+            max_bytes,
+            max_files,
+        });
+        self
+    }
+
+    /// Connect to the host and port given to [`StatsdBuilder::from`] through the SOCKS5 proxy at
+    /// `host` and `port` when sending metrics over TCP, see [`StatsdBuilder::with_tcp`]. The proxy
+    /// resolves the host of statsd. Connecting to the proxy and every step of the handshake time
+    /// out after 5s, and the connection is then retried with the reconnect backoff. This has no
+    /// effect on the other transports.
+    ///
+    /// ```
+    /// use metrics_exporter_statsd::StatsdBuilder;
+    ///
+    /// let recorder = StatsdBuilder::from("metrics-relay.internal", 8125)
+    ///     .with_tcp()
+    ///     .with_socks5_proxy("proxy.internal", 1080)
+    ///     .with_socks5_auth("metrics", "secret")
+    ///     .build(None)
+    ///     .expect("Could not create StatsdRecorder");
+    /// ```
+    pub fn with_socks5_proxy<S: Into<String>>(mut self, host: S, port: u16) -> Self {
+        self.socks5_proxy = Some((host.into(), port));
+        self
+    }
+
+    /// Authenticate with `username` and `password` to the proxy given to
+    /// [`StatsdBuilder::with_socks5_proxy`].
+    pub fn with_socks5_auth<U, P>(mut self, username: U, password: P) -> Self
+    where
+        U: Into<String>,
+        P: Into<String>,
+    {
+        self.socks5_auth = Some((username.into(), password.into()));
+        self
+    }
+
+    /// How long to wait before reconnecting a stream transport, see [`StatsdBuilder::with_tcp`].
+    /// The wait starts at `initial` and doubles with every failed attempt up to `max`, and the
+    /// actual wait is picked at random between half and all of that so that clients don't all
+    /// reconnect at once. The defaults are 100ms and 10s.
+    pub fn with_reconnect_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.backoff = Backoff::new(initial, max);
+        self
+    }
+
     /// A hint for the metric emitter to determine how the histogram metrics should be emitted,
     /// all the histogram metrics will be sent as distribution when running in this mode unless
     /// specified otherwise via a label.
@@ -147,6 +570,8 @@ impl StatsdBuilder {
     /// (When this method is not called, the builder creates a default sink using those settings,
     /// [`cadence::QueuingMetricSink`], and [`cadence::UdpMetricSink`].)
     ///
+    /// The recorders built from clones of the builder share the sink.
+    ///
     /// # Examples
     ///
     /// This code replaces the ordinary UDP sink with output to a Unix socket.
@@ -172,200 +597,1976 @@ impl StatsdBuilder {
     where
         T: MetricSink + Sync + Send + RefUnwindSafe + 'static,
     {
-        self.sink = Some(Box::new(move |prefix: &str| {
-            StatsdClient::builder(prefix, sink)
+        let sink: SharedSink = Arc::new(sink);
+        self.sink = Some(Arc::new(move |stats: Arc<Stats>| {
+            Arc::new(CountingSink::new(
+                SharedSinkRef(sink.clone()),
+                stats,
+                DropReason::SendError,
+            ))
         }));
         self
     }
 
-    /// This method is responsible building the StatsdRecorder. It configures the underlying metrics sink for
-    /// the [`StatsdClient`] with the values provided e.g. `queue_size`, `buffer_size` etc.
+    /// Wrap the sink the metrics are sent through with `wrapper`, e.g. to record, route or encrypt
+    /// them, without giving up on the sink this builder makes. This works with a custom sink too.
     ///
-    /// All the metrics emitted from the recorder are prefixed with the prefix that's provided here.
+    /// The wrapper is given the sink once it's built, along with the queue and the drop accounting,
+    /// and every write goes through the sink it returns. Wrappers are applied in the order they're
+    /// added, so the last one sees the writes first. A write may hold several newline separated
+    /// metrics when batching is enabled. The wrapper is called once for every recorder built from
+    /// the builder and its clones.
+    ///
+    /// ```
+    /// use std::io;
+    /// use cadence::MetricSink;
+    /// use metrics_exporter_statsd::{InnerSink, StatsdBuilder};
+    ///
+    /// struct LoggingSink(InnerSink);
+    ///
+    /// impl MetricSink for LoggingSink {
+    ///     fn emit(&self, metric: &str) -> io::Result<usize> {
+    ///         println!("sending {}", metric);
+    ///         self.0.emit(metric)
+    ///     }
+    ///
+    ///     fn flush(&self) -> io::Result<()> {
+    ///         self.0.flush()
+    ///     }
+    /// }
+    ///
+    /// let recorder = StatsdBuilder::from("127.0.0.1", 8125)
+    ///     .with_sink_wrapper(LoggingSink)
+    ///     .build(Some("prefix"))
+    ///     .expect("Could not create StatsdRecorder");
+    /// ```
+    pub fn with_sink_wrapper<F, T>(mut self, wrapper: F) -> Self
+    where
+        F: Fn(InnerSink) -> T + 'static,
+        T: MetricSink + Sync + Send + RefUnwindSafe + 'static,
+    {
+        self.sink_wrappers
+            .push(Arc::new(move |sink| Arc::new(wrapper(sink))));
+        self
+    }
+
+    /// Emit the client telemetry metrics that the official DogStatsD clients report, e.g.
+    /// `datadog.dogstatsd.client.metrics`, `datadog.dogstatsd.client.bytes_sent` and
+    /// `datadog.dogstatsd.client.packets_dropped`. Datadog surfaces these in its client
+    /// troubleshooting views.
+    ///
+    /// Telemetry is reported every 10 seconds from a background thread, it is sent through the
+    /// same sink as every other metric but without the prefix, and is tagged with the default
+    /// tags along with `client`, `client_version` and `client_transport`.
+    pub fn with_telemetry(self) -> Self {
+        self.with_telemetry_interval(DEFAULT_TELEMETRY_INTERVAL)
+    }
+
+    /// Same as [`StatsdBuilder::with_telemetry`], reporting on the given interval instead.
+    pub fn with_telemetry_interval(mut self, interval: Duration) -> Self {
+        self.telemetry = Some(interval);
+        self
+    }
+
+    /// Delay the periodic work of the exporter, e.g. flushing batches and packets or reporting
+    /// telemetry and gauges, by a random duration of up to `jitter` each time, so that the many
+    /// replicas of a service don't send bursts to the agent at the same time. The jitter of a task
+    /// never exceeds its interval, and tasks stay on their interval on average.
+    pub fn with_flush_jitter(mut self, jitter: Duration) -> Self {
+        self.flush_jitter = jitter;
+        self
+    }
+
+    /// Hand a line describing each problem of the exporter to `log`, e.g. a thread that panicked
+    /// and was restarted, typically to forward it to the logger of the application:
     ///
-    /// # Examples
     /// ```
     /// use metrics_exporter_statsd::StatsdBuilder;
-    /// let recorder = StatsdBuilder::from("localhost", 8125)
-    ///                .build(Some("prefix"))
-    ///                .expect("Could not create StatsdRecorder");
     ///
-    /// metrics::set_global_recorder(recorder);
-    /// metrics::counter!("counter.name").increment(10);
+    /// let recorder = StatsdBuilder::from("127.0.0.1", 8125)
+    ///     .with_log(|line| eprintln!("metrics: {}", line))
+    ///     .build(None)
+    ///     .expect("Could not create StatsdRecorder");
     /// ```
-    /// will emit a counter metric name as `prefix.counter.name`
-    pub fn build(self, prefix: Option<&str>) -> Result<StatsdRecorder, StatsdError> {
-        self.is_valid()?;
+    ///
+    /// Threads that panicked are also counted by the `statsd.exporter.worker_restarts` counter and
+    /// by [`StatsdHandle::worker_restarts`](crate::StatsdHandle::worker_restarts).
+    ///
+    /// A name registered as several types, e.g. as a counter and as a gauge, which backends don't
+    /// handle well, is logged once per type it's registered as after the first one, e.g.
+    /// `metric app.requests is registered as |g, it was first registered as |c`. See also
+    /// [`StatsdBuilder::with_strict_validation`] to drop these metrics.
+    ///
+    /// So is a metric described again with another unit or description, e.g. by two call sites of
+    /// `describe_histogram!`, once per metric: the latest description still wins.
+    ///
+    /// Dropped metrics are rolled up rather than logged one by one, so that an agent that is down
+    /// doesn't flood the logs: every 30 seconds, see [`StatsdBuilder::with_error_log_interval`],
+    /// a single line tells how many metrics were dropped and why, along with the last error, e.g.
+    /// `dropped 15000 metrics in the last 30s (send_error: 15000): Connection refused`.
+    pub fn with_log<F>(mut self, log: F) -> Self
+    where
+        F: Fn(&str) + Send + Sync + 'static,
+    {
+        self.log = Some(Arc::new(log));
+        self
+    }
 
-        let prefix = prefix.unwrap_or("");
-        let mut builder = match self.sink {
-            Some(sink_fn) => sink_fn(prefix),
-            None => {
-                // create a local udp socket where the communication needs to happen, the port is set to
-                // 0 so that we can pick any available port on the host. We also want this socket to be
-                // non-blocking
-                let socket = UdpSocket::bind(format!("{}:{}", self.client_udp_host, 0))?;
-                socket.set_nonblocking(true)?;
-                // Initialize the statsd client with metrics sink that will be used to collect and send
-                // the metrics to the remote host.
-                let host = (self.host, self.port);
-
-                // Initialize buffered udp metrics sink with the provided or default capacity, this allows
-                // statsd client (cadence) to buffer metrics upto the configured size in memory before, flushing
-                // to network.
-                let udp_sink = BufferedUdpMetricSink::with_capacity(
-                    host,
-                    socket,
-                    self.buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE),
-                )?;
-                // Initialize a bounded QueuingMetricSink so that we are not buffering unlimited items onto
-                // statsd client's queue, statsd client will error out when the queue is full.
-                let sink = QueuingMetricSink::with_capacity(
-                    udp_sink,
-                    self.queue_size.unwrap_or(DEFAULT_BUFFER_SIZE),
-                );
-                StatsdClient::builder(prefix, sink)
-            }
-        };
+    /// Roll the dropped metrics up into a line logged on `interval`, instead of every 30 seconds,
+    /// see [`StatsdBuilder::with_log`].
+    pub fn with_error_log_interval(mut self, interval: Duration) -> Self {
+        self.error_log_interval = interval;
+        self
+    }
 
-        for (key, value) in self.default_tags {
-            builder = builder.with_tag(key, value);
-        }
+    /// Wait for up to `timeout` for the metrics waiting in the queue to be sent when the recorder
+    /// is dropped or [`StatsdHandle::shutdown`](crate::StatsdHandle::shutdown) is called. By
+    /// default neither waits, and metrics still in the queue when the application exits are lost.
+    ///
+    /// Metrics that are still waiting once the timeout expires are given up on: `shutdown`
+    /// returns how many there are, and they are counted as [`DropReason::Abandoned`] when the
+    /// recorder is dropped. This setting has no effect when a custom sink is used, see
+    /// [`StatsdBuilder::with_sink`].
+    pub fn with_shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = Some(timeout);
+        self
+    }
 
-        Ok(StatsdRecorder {
-            statsd: Arc::new(builder.build()),
-            default_histogram: self.default_histogram,
-        })
+    /// Shut the recorder down when the process exits, as with
+    /// [`StatsdHandle::shutdown`](crate::StatsdHandle::shutdown), for binaries that don't: the
+    /// batches and packets waiting are flushed, then the queue is given up to `timeout` to be
+    /// sent. This registers a hook with `atexit`, which runs when `main` returns or
+    /// [`std::process::exit`] is called, not when the process is killed by a signal or aborts.
+    ///
+    /// This is meant for the recorder installed with [`metrics::set_global_recorder`], which is
+    /// never dropped. A recorder that was dropped before the process exits is skipped, dropping it
+    /// already waited for the queue, see [`StatsdBuilder::with_shutdown_timeout`].
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use metrics_exporter_statsd::StatsdBuilder;
+    ///
+    /// let recorder = StatsdBuilder::from("127.0.0.1", 8125)
+    ///     .with_exit_hook(Duration::from_secs(1))
+    ///     .build(None)
+    ///     .expect("Could not create StatsdRecorder");
+    /// metrics::set_global_recorder(recorder);
+    /// ```
+    pub fn with_exit_hook(mut self, timeout: Duration) -> Self {
+        self.exit_hook = Some(timeout);
+        self
     }
 
-    fn is_valid(&self) -> Result<(), StatsdError> {
-        // Check settings only if we are going to use them.
-        if self.sink.is_none() {
-            if self.host.trim().is_empty() {
-                return Err(StatsdError::InvalidHost);
-            }
-            if self.port == 0 {
-                return Err(StatsdError::InvalidPortZero);
-            }
-        }
-        Ok(())
+    /// Periodically report the number of metrics waiting in the queue as a gauge named
+    /// `statsd.exporter.queue_depth`, so that the queue size can be tuned based on data. The
+    /// gauge is prefixed and tagged like any other metric emitted by the recorder.
+    ///
+    /// The same number is available on demand from [`StatsdHandle::queue_depth`]. This setting has
+    /// no effect when a custom sink is used, see [`StatsdBuilder::with_sink`].
+    ///
+    /// [`StatsdHandle::queue_depth`]: crate::StatsdHandle::queue_depth
+    pub fn with_queue_depth_gauge(mut self, interval: Duration) -> Self {
+        self.queue_depth_interval = Some(interval);
+        self
     }
-}
 
-impl Default for StatsdBuilder {
-    fn default() -> Self {
-        StatsdBuilder {
-            host: DEFAULT_HOST.to_string(),
-            port: DEFAULT_PORT,
-            queue_size: Some(DEFAULT_QUEUE_SIZE),
-            buffer_size: Some(DEFAULT_BUFFER_SIZE),
-            default_histogram: HistogramType::Histogram,
-            client_udp_host: CLIENT_UDP_HOST.to_string(),
-            default_tags: Vec::new(),
-            sink: None,
-        }
+    /// Increment a counter named `statsd.exporter.heartbeat` every `interval`, so that the
+    /// absence of the heartbeat on the backend tells a broken metrics pipeline from a quiet
+    /// application. The counter is prefixed and tagged like any other metric emitted by the
+    /// recorder.
+    pub fn with_heartbeat(mut self, interval: Duration) -> Self {
+        self.heartbeat = Some(interval);
+        self
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::io;
-    use std::net::UdpSocket;
-    use std::sync::{Arc, Mutex};
-    use std::time::Duration;
+    /// Increment a counter named `process.start` once the recorder is built, then report the
+    /// seconds since as a gauge named `process.uptime.seconds` every `interval`, so that deploys
+    /// and restarts show on the backend. Both are tagged with `version_tags`, e.g. the version
+    /// or the commit of the application, on top of the default tags, and prefixed like any other
+    /// metric emitted by the recorder.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use metrics_exporter_statsd::StatsdBuilder;
+    ///
+    /// let recorder = StatsdBuilder::from("127.0.0.1", 8125)
+    ///     .with_uptime(
+    ///         Duration::from_secs(60),
+    ///         [("version", env!("CARGO_PKG_VERSION")), ("commit", "4f2a9c1")],
+    ///     )
+    ///     .build(Some("app"))
+    ///     .expect("Could not create StatsdRecorder");
+    /// ```
+    pub fn with_uptime<I, K, V>(mut self, interval: Duration, version_tags: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: ToString,
+        V: ToString,
+    {
+        let tags = version_tags
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        self.uptime = Some((interval, tags));
+        self
+    }
 
-    use metrics::{Key, Label, Recorder};
+    /// Report the `count` metric names with the most series, i.e. distinct tag sets, as
+    /// `statsd.exporter.top_series` gauges, and the `count` metric names that sent the most bytes
+    /// as `statsd.exporter.top_bytes` gauges, every `interval`. Both are tagged with
+    /// `metric:<name>`, so that the metrics behind the cardinality and the volume can be found
+    /// from the data, and are prefixed and tagged like every other metric.
+    ///
+    /// Series are the metrics registered through the `metrics` macros, bytes are the bytes they
+    /// sent since the previous report.
+    pub fn with_top_series_report(mut self, count: usize, interval: Duration) -> Self {
+        self.top_series = Some((count, interval));
+        self
+    }
 
-    use super::*;
+    /// Count the lines every metric sends and remember when it last sent one, so that the
+    /// registered metrics can be listed along with their activity, e.g. from an admin endpoint,
+    /// with [`StatsdHandle::active_keys`]. Times are told by the clock given to
+    /// [`StatsdBuilder::with_clock`].
+    ///
+    /// Idle metrics evicted with [`StatsdBuilder::with_idle_timeout`] are no longer listed, and
+    /// start over when they're registered again.
+    ///
+    /// [`StatsdHandle::active_keys`]: crate::StatsdHandle::active_keys
+    pub fn with_active_keys(mut self) -> Self {
+        self.active_keys = true;
+        self
+    }
 
-    pub struct Environ {
-        server_socket: UdpSocket,
-        recorder: StatsdRecorder,
+    /// Count the lines every metric sends, so that the `count` metric names that sent the most,
+    /// over all their tag sets, can be found from inside the process with
+    /// [`StatsdHandle::top_emitters`]. Unlike [`StatsdBuilder::with_top_series_report`], nothing
+    /// is sent and the counts add up since the metrics were registered.
+    ///
+    /// A counter is kept per registered metric, idle metrics evicted with
+    /// [`StatsdBuilder::with_idle_timeout`] take their count with them.
+    ///
+    /// [`StatsdHandle::top_emitters`]: crate::StatsdHandle::top_emitters
+    pub fn with_top_emitters(mut self, count: usize) -> Self {
+        self.top_emitters = Some(count);
+        self
     }
 
-    impl Environ {
-        fn setup() -> (UdpSocket, StatsdBuilder) {
-            let server_socket = UdpSocket::bind("127.0.0.1:0")
-                .expect("localhost should always be a valid socket address");
-            server_socket
-                .set_read_timeout(Some(Duration::from_secs(2)))
-                .expect("failed to set the read timeout on our localhost socket");
-            let port = server_socket
-                .local_addr()
-                .expect("socket should have a local addr")
-                .port();
-
-            let builder = StatsdBuilder::from("127.0.0.1", port)
-                .with_queue_size(1)
-                .with_buffer_size(10);
-            (server_socket, builder)
-        }
-
-        pub fn new(prefix: Option<&str>) -> Self {
-            let (server_socket, builder) = Environ::setup();
-            let recorder = builder
-                .build(prefix)
-                .expect("test env should build a valid recorder");
-            Environ {
-                server_socket,
-                recorder,
-            }
-        }
+    /// Keep the last `capacity` lines handed to the sink in memory, they can be retrieved with
+    /// [`StatsdHandle::recent_lines`] to see exactly what was sent without capturing packets.
+    ///
+    /// This is meant for debugging, every emitted line is copied into the buffer.
+    ///
+    /// [`StatsdHandle::recent_lines`]: crate::StatsdHandle::recent_lines
+    pub fn with_recent_lines(mut self, capacity: usize) -> Self {
+        self.recent_lines = Some(capacity);
+        self
+    }
 
-        pub fn new_histogram_is_distribution() -> Self {
-            let (server_socket, builder) = Environ::setup();
-            let recorder = builder
-                .histogram_is_distribution()
-                .build(None)
-                .expect("test env should build a valid recorder");
-            Environ {
-                server_socket,
-                recorder,
-            }
-        }
+    /// Write the descriptions of the metrics as JSON to `path` when
+    /// [`StatsdHandle::shutdown`](crate::StatsdHandle::shutdown) is called, e.g. for a
+    /// documentation pipeline, see
+    /// [`StatsdHandle::descriptions_json`](crate::StatsdHandle::descriptions_json). The file is
+    /// replaced, and failing to write it is logged, see [`StatsdBuilder::with_log`].
+    pub fn with_descriptions_file<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.descriptions_file = Some(path.into());
+        self
+    }
 
-        pub fn new_histogram_is_timer() -> Self {
-            let (server_socket, builder) = Environ::setup();
-            let recorder = builder
-                .histogram_is_timer()
-                .build(None)
-                .expect("test env should build a valid recorder");
-            Environ {
-                server_socket,
-                recorder,
-            }
-        }
+    /// Write the metrics registered so far to `path` every `interval`, as JSON, so that tooling
+    /// can diff what the application actually sends against what dashboards expect: the name of
+    /// every metric, prefix included, its type as sent, e.g. `c` for counters, and the keys of its
+    /// tags, default tags included and sorted, merged over all the tag sets of the metric.
+    ///
+    /// ```json
+    /// [{"name":"app.requests","type":"c","tags":["env","status"]}]
+    /// ```
+    ///
+    /// The file is replaced at once, by renaming a `.tmp` file next to it, so that it's never
+    /// read half written. Failing to write it is logged, see [`StatsdBuilder::with_log`]. Metrics
+    /// dropped by a stage of the pipeline aren't in it, nor are tags added by
+    /// [`StatsdBuilder::with_context_tags`]. Idle metrics evicted with
+    /// [`StatsdBuilder::with_idle_timeout`] leave it too.
+    pub fn with_catalog_file<P: Into<PathBuf>>(mut self, path: P, interval: Duration) -> Self {
+        self.catalog_file = Some((path.into(), interval));
+        self
+    }
+
+    /// Remember the last value of up to `max_keys` metrics so that they can be inspected locally,
+    /// e.g. from an admin endpoint, with [`StatsdHandle::snapshot`]. Counters additionally keep
+    /// track of their total and recent rate.
+    ///
+    /// Metrics registered once `max_keys` are tracked are still sent, they are just not part of the
+    /// snapshot.
+    ///
+    /// [`StatsdHandle::snapshot`]: crate::StatsdHandle::snapshot
+    pub fn with_last_values(mut self, max_keys: usize) -> Self {
+        self.last_values = Some(max_keys);
+        self
+    }
 
-        fn receive_on_server(&self) -> String {
-            let mut buff = [0; 100];
+    /// Accumulate metrics in a batch local to each thread before handing them to the queue, so that
+    /// threads only contend on the queue once per batch instead of once per metric. This helps
+    /// services recording metrics from many threads at a very high rate.
+    ///
+    /// A batch is handed to the queue once it would grow larger than `max_bytes`, keep it at or
+    /// below the buffer size so that a batch fits in a single datagram. Batches that don't fill up
+    /// are flushed every `max_delay`, which bounds the latency this adds.
+    pub fn with_thread_local_batching(mut self, max_bytes: usize, max_delay: Duration) -> Self {
+        self.batching = Some((max_bytes, max_delay));
+        self
+    }
 
-            let size = self
-                .server_socket
-                .recv(&mut buff)
-                .expect("could not receive on server socket");
-            let data = &buff[..size];
-            let request = std::str::from_utf8(data).expect("request is no a valid UTF-8 string");
-            String::from(request)
-        }
+    /// Only send a `rate` fraction of the counter and histogram values, picked at random, and let
+    /// statsd scale them back up using the `|@rate` field of the line. Gauges are always sent.
+    ///
+    /// Whether a value is sent is decided on the recording thread before anything else happens, so
+    /// a rate of `0.01` saves about 99% of the work of formatting and queuing metrics, not only of
+    /// the network traffic. The rate must be greater than 0 and at most 1, otherwise `build` fails
+    /// with [`StatsdError::InvalidSampleRate`].
+    pub fn with_sample_rate(mut self, rate: f64) -> Self {
+        self.sample_rates.default = Some(rate);
+        self
     }
 
-    static METADATA: metrics::Metadata =
-        metrics::Metadata::new(module_path!(), metrics::Level::INFO, Some(module_path!()));
+    /// Sample counters at `rate` instead of the rate given to [`StatsdBuilder::with_sample_rate`],
+    /// e.g. `1.0` to send every counter value while other metrics are sampled.
+    pub fn with_counter_sample_rate(mut self, rate: f64) -> Self {
+        self.sample_rates.counters = Some(rate);
+        self
+    }
 
-    #[test]
-    #[should_panic]
-    fn bad_host_name() {
-        StatsdBuilder::from("", 10)
-            .build(None)
-            .expect("this should panic");
+    /// Sample the values recorded through `histogram!`, whether they're sent as histograms,
+    /// distributions or timers, at `rate` instead of the rate given to
+    /// [`StatsdBuilder::with_sample_rate`].
+    pub fn with_histogram_sample_rate(mut self, rate: f64) -> Self {
+        self.sample_rates.histograms = Some(rate);
+        self
+    }
+
+    /// Sample the counters and histograms registered with `level`, e.g. by
+    /// `counter!(level: Level::DEBUG, "cache.lookups")`, at `rate`. The rate of a level wins over
+    /// the rate of the kind of metric and over the default rate, so that e.g. `DEBUG` metrics can
+    /// be sampled at 1% while everything else is sent. Gauges are always sent.
+    ///
+    /// The level is the one the metric is first registered with, the handle is reused afterwards.
+    ///
+    /// ```
+    /// use metrics::Level;
+    /// use metrics_exporter_statsd::StatsdBuilder;
+    ///
+    /// let recorder = StatsdBuilder::from("127.0.0.1", 8125)
+    ///     .with_level_sample_rate(Level::DEBUG, 0.01)
+    ///     .build(None)
+    ///     .expect("Could not create StatsdRecorder");
+    /// ```
+    pub fn with_level_sample_rate(mut self, level: Level, rate: f64) -> Self {
+        self.sample_rates.set_level(level, rate);
+        self
+    }
+
+    /// How statsd is told that the counters are sampled, with the `|@rate` field by default. Pick
+    /// [`SampleRateSemantics::PreScaled`] for the servers that ignore it, the counter values are
+    /// then scaled up before they're sent.
+    ///
+    /// ```
+    /// use metrics_exporter_statsd::{SampleRateSemantics, StatsdBuilder};
+    ///
+    /// let recorder = StatsdBuilder::from("127.0.0.1", 8125)
+    ///     .with_counter_sample_rate(0.1)
+    ///     .with_sample_rate_semantics(SampleRateSemantics::PreScaled)
+    ///     .build(None)
+    ///     .expect("Could not create StatsdRecorder");
+    /// ```
+    pub fn with_sample_rate_semantics(mut self, semantics: SampleRateSemantics) -> Self {
+        self.sample_rate_semantics = semantics;
+        self
+    }
+
+    /// Only send the gauge, histogram, distribution and timer values whose magnitude is between
+    /// `min` and `max`, or zero, and apply `policy` to the others, e.g. to clamp them. Values are
+    /// always written in fixed-point notation, which gets long for extreme values, e.g. 309 digits
+    /// for `1e308`, and `NaN` or infinite values can't be written at all, many statsd servers
+    /// reject these.
+    ///
+    /// ```
+    /// use metrics_exporter_statsd::{StatsdBuilder, ValuePolicy};
+    ///
+    /// let recorder = StatsdBuilder::from("127.0.0.1", 8125)
+    ///     .with_value_bounds(1e-9, 1e15, ValuePolicy::Clamp)
+    ///     .build(None)
+    ///     .expect("Could not create StatsdRecorder");
+    /// ```
+    pub fn with_value_bounds(mut self, min: f64, max: f64, policy: ValuePolicy) -> Self {
+        self.value_bounds = Some(ValueBounds { min, max, policy });
+        self
+    }
+
+    /// Apply `policy` to the negative values recorded through `histogram!` and
+    /// [`StatsdExt::record_distribution`](crate::StatsdExt::record_distribution), which most statsd
+    /// servers make no sense of: drop them, clamp them to zero, or send them anyway. Otherwise
+    /// negative histograms and distributions are sent while negative timers are dropped, as
+    /// durations can't be negative.
+    ///
+    /// Counters are always positive and gauges are never affected, a gauge can go below zero.
+    pub fn with_negative_values(mut self, policy: ValuePolicy) -> Self {
+        self.negative_values = Some(policy);
+        self
+    }
+
+    /// Round the values sent as `metric_type` to the nearest multiple of `granularity`, e.g.
+    /// `0.1` for timers to send tenths of milliseconds, so that more values are the same, which
+    /// backends billing distributions by points dedupe and pack better. `granularity` is in the
+    /// unit the values are sent in, i.e. milliseconds for timers.
+    ///
+    /// Values are rounded once the value bounds are applied, and aren't rounded by default or
+    /// when `granularity` isn't positive. Counters are always sent as integers.
+    ///
+    /// ```
+    /// use metrics_exporter_statsd::{MetricType, StatsdBuilder};
+    ///
+    /// let recorder = StatsdBuilder::from("127.0.0.1", 8125)
+    ///     .with_rounding(MetricType::Timer, 0.1)
+    ///     .with_rounding(MetricType::Distribution, 0.1)
+    ///     .build(None)
+    ///     .expect("Could not create StatsdRecorder");
+    /// ```
+    pub fn with_rounding(mut self, metric_type: MetricType, granularity: f64) -> Self {
+        self.rounding[metric_type.index()] = granularity;
+        self
+    }
+
+    /// Sample the counters and histograms whose name matches `pattern` at `rate`, so that the
+    /// sampling of specific metrics is configured in one place rather than where they're recorded.
+    /// The rate of a name wins over every other rate, when a name matches several patterns the
+    /// first one given wins.
+    ///
+    /// A pattern is either a name or a glob where `*` matches any part of a single component of a
+    /// name, e.g. `http.*.duration` or `cache.*`, with at most one `*` per component, otherwise
+    /// `build` fails with [`StatsdError::InvalidPattern`]. Patterns match the name once it went
+    /// through the mapping file, without the prefix. Gauges are always sent.
+    ///
+    /// ```
+    /// use metrics_exporter_statsd::StatsdBuilder;
+    ///
+    /// let recorder = StatsdBuilder::from("127.0.0.1", 8125)
+    ///     .with_sample_rate_for("http.request.duration", 0.05)
+    ///     .with_sample_rate_for("cache.*.lookups", 0.01)
+    ///     .build(None)
+    ///     .expect("Could not create StatsdRecorder");
+    /// ```
+    pub fn with_sample_rate_for(mut self, pattern: &str, rate: f64) -> Self {
+        self.sample_rates.set_name(pattern, rate);
+        self
+    }
+
+    /// Also send the histograms whose name matches `pattern` as `metric_type`, e.g. as both
+    /// timers and distributions while the dashboards move from one to the other: every value is
+    /// sent twice, with the same value, sample rate and tags, a line of each type. When a name
+    /// matches several patterns the first one given wins. Patterns are the same as for
+    /// [`StatsdBuilder::with_sample_rate_for`].
+    ///
+    /// Only the histogram types, [`MetricType::Histogram`], [`MetricType::Timer`] and
+    /// [`MetricType::Distribution`], are sent twice, the other types are ignored. The values
+    /// aggregated on the client, e.g. with [`StatsdBuilder::with_local_summaries`], aren't.
+    ///
+    /// ```
+    /// use metrics_exporter_statsd::{MetricType, StatsdBuilder};
+    ///
+    /// let recorder = StatsdBuilder::from("127.0.0.1", 8125)
+    ///     .histogram_is_timer()
+    ///     .with_dual_emit("http.*.duration", MetricType::Distribution)
+    ///     .build(None)
+    ///     .expect("Could not create StatsdRecorder");
+    /// ```
+    pub fn with_dual_emit(mut self, pattern: &str, metric_type: MetricType) -> Self {
+        self.dual_emit.add(pattern, metric_type);
+        self
+    }
+
+    /// Compute the per-second rate of every counter on the client and send it every `interval`, as
+    /// a gauge named after the counter with a `.rate` suffix, e.g. `requests.rate`, for the servers
+    /// that don't turn counts into rates. The counts are sent along with the rates or not at all,
+    /// depending on `rates`.
+    ///
+    /// Rates count every increment, before sampling and before the stages of the pipeline look at
+    /// it, and are sent without the context tags, see [`StatsdBuilder::with_context_tags`].
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use metrics_exporter_statsd::{CounterRates, StatsdBuilder};
+    ///
+    /// let recorder = StatsdBuilder::from("127.0.0.1", 8125)
+    ///     .with_counter_rates(CounterRates::Instead, Duration::from_secs(10))
+    ///     .build(None)
+    ///     .expect("Could not create StatsdRecorder");
+    /// ```
+    pub fn with_counter_rates(mut self, rates: CounterRates, interval: Duration) -> Self {
+        self.counter_rates = Some((rates, interval));
+        self
+    }
+
+    /// Summarize the histograms on the client rather than sending every value: every `interval`,
+    /// the quantiles of the values recorded since the last time are sent as gauges named after
+    /// the histogram and the quantile, e.g. `request.duration.p50` and `request.duration.p99`.
+    /// The quantiles are `0.5`, `0.95` and `0.99` unless configured with
+    /// [`StatsdBuilder::with_percentiles`] and [`StatsdBuilder::with_percentiles_for`].
+    ///
+    /// Only the metrics sent as histograms are summarized, the distributions are left to the
+    /// server and the timers too unless kept in HDR histograms, see
+    /// [`StatsdBuilder::with_hdr_timers`]. Every value counts, whatever the sample rate, and nothing is sent for
+    /// a histogram that recorded no value.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use metrics_exporter_statsd::{PercentileNaming, StatsdBuilder};
+    ///
+    /// let recorder = StatsdBuilder::from("127.0.0.1", 8125)
+    ///     .with_local_summaries(Duration::from_secs(10))
+    ///     .with_percentiles(&[0.5, 0.95, 0.999], PercentileNaming::Long)
+    ///     .with_percentiles_for("db.*.duration", &[0.99])
+    ///     .build(None)
+    ///     .expect("Could not create StatsdRecorder");
+    /// ```
+    pub fn with_local_summaries(mut self, interval: Duration) -> Self {
+        self.summaries = Some(interval);
+        self
+    }
+
+    /// Send `quantiles` of the histograms summarized on the client, see
+    /// [`StatsdBuilder::with_local_summaries`], and of the timers kept in HDR histograms, see
+    /// [`StatsdBuilder::with_hdr_timers`], with the gauges named as per `naming`. Quantiles
+    /// must be in `[0, 1]`, otherwise `build` fails with [`StatsdError::InvalidQuantile`].
+    pub fn with_percentiles(mut self, quantiles: &[f64], naming: PercentileNaming) -> Self {
+        self.percentiles.default = quantiles.to_vec();
+        self.percentiles.naming = naming;
+        self
+    }
+
+    /// Send `quantiles` of the summarized histograms and HDR timers whose name matches `pattern`
+    /// rather than the quantiles of every histogram, when a name matches several patterns the first one given
+    /// wins. Patterns are the same as for [`StatsdBuilder::with_sample_rate_for`].
+    pub fn with_percentiles_for(mut self, pattern: &str, quantiles: &[f64]) -> Self {
+        self.percentiles.set_name(pattern, quantiles);
+        self
+    }
+
+    /// Keep the timers in [HDR histograms] on the client rather than sending every duration,
+    /// when the timer math of the server is too coarse: every `interval`, the quantiles, the min
+    /// and the max of the durations recorded since the last time are sent as gauges in
+    /// milliseconds, with `significant_digits` digits, from 1 to 5, e.g.
+    /// `request.duration.p99:12.345|g`, along with their count as a counter, e.g.
+    /// `request.duration.count:250|c`. The quantiles are picked as for the histograms summarized
+    /// on the client, see [`StatsdBuilder::with_percentiles`] and
+    /// [`StatsdBuilder::with_percentiles_for`]. Otherwise `build` fails with
+    /// [`StatsdError::InvalidSignificantDigits`].
+    ///
+    /// Only the metrics sent as timers are kept in HDR histograms, e.g. the histograms with
+    /// [`StatsdBuilder::histogram_is_timer`]. Every duration counts, whatever the sample rate,
+    /// negative durations passed through, see [`StatsdBuilder::with_negative_values`], are sent
+    /// as they are, and nothing is sent for a timer that recorded no duration.
+    ///
+    /// [HDR histograms]: http://hdrhistogram.org/
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use metrics_exporter_statsd::{PercentileNaming, StatsdBuilder};
+    ///
+    /// let recorder = StatsdBuilder::from("127.0.0.1", 8125)
+    ///     .histogram_is_timer()
+    ///     .with_hdr_timers(Duration::from_secs(10), 3)
+    ///     .with_percentiles(&[0.5, 0.99, 0.999], PercentileNaming::Short)
+    ///     .build(None)
+    ///     .expect("Could not create StatsdRecorder");
+    /// ```
+    pub fn with_hdr_timers(mut self, interval: Duration, significant_digits: u8) -> Self {
+        self.hdr_timers = Some((interval, significant_digits));
+        self
+    }
+
+    /// Smooth the bursts of every counter and gauge with a token bucket of its own, e.g. so that a
+    /// retry storm incrementing a counter a hundred thousand times a second doesn't hammer the
+    /// agent: each metric sends up to `burst` values at once, then `per_second` values a second,
+    /// e.g. `0.5` for a value every two seconds. The values beyond that are deferred and
+    /// coalesced, increments add up and gauges keep the last value, then sent as a single line
+    /// every `interval` once there's a token for them, e.g. `requests:99998|c`. Otherwise `build`
+    /// fails with [`StatsdError::InvalidBurstSmoothing`].
+    ///
+    /// Once values of a metric are deferred, the next ones are too until they're sent, so that
+    /// gauges never go back to an older value. Counters sent with a sample rate keep it, their
+    /// increments add up before the server scales them.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use metrics_exporter_statsd::StatsdBuilder;
+    ///
+    /// let recorder = StatsdBuilder::from("127.0.0.1", 8125)
+    ///     .with_burst_smoothing(Duration::from_secs(1), 10.0, 100)
+    ///     .build(None)
+    ///     .expect("Could not create StatsdRecorder");
+    /// ```
+    pub fn with_burst_smoothing(mut self, interval: Duration, per_second: f64, burst: u64) -> Self {
+        self.burst_smoothing = Some((interval, per_second, burst));
+        self
+    }
+
+    /// Sketch the distributions on the client rather than sending every value, for the hottest
+    /// ones: the values are counted in the bins of a [DDSketch], and every `interval` the value of
+    /// each bin is sent once, with the sample rate telling the server how many values it stands
+    /// for, e.g. `payload.size:100.4|d|@0.001` for a thousand values. The server sees the same
+    /// distribution within `relative_accuracy`, e.g. `0.01` for 1%, in a line per bin rather than
+    /// a line per value. Relative accuracies must be in `(0, 1)`, otherwise `build` fails with
+    /// [`StatsdError::InvalidRelativeAccuracy`].
+    ///
+    /// Every distribution is sketched unless some are picked with
+    /// [`StatsdBuilder::with_sketches_for`]. Only the metrics registered as distributions are
+    /// sketched, e.g. the histograms with [`StatsdBuilder::histogram_is_distribution`], not the
+    /// ones sent with [`StatsdExt::record_distribution`](crate::StatsdExt::record_distribution).
+    /// Every value counts, whatever the sample rate, and nothing is sent for a distribution that
+    /// recorded no value.
+    ///
+    /// [DDSketch]: https://www.vldb.org/pvldb/vol12/p2195-masson.pdf
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use metrics_exporter_statsd::StatsdBuilder;
+    ///
+    /// let recorder = StatsdBuilder::from("127.0.0.1", 8125)
+    ///     .histogram_is_distribution()
+    ///     .with_local_sketches(Duration::from_secs(10), 0.01)
+    ///     .with_sketches_for("http.*.duration")
+    ///     .build(None)
+    ///     .expect("Could not create StatsdRecorder");
+    /// ```
+    pub fn with_local_sketches(mut self, interval: Duration, relative_accuracy: f64) -> Self {
+        self.sketches = Some(interval);
+        self.sketching.relative_accuracy = relative_accuracy;
+        self
+    }
+
+    /// Only sketch the distributions whose name matches `pattern`, and the ones matching the other
+    /// patterns given, see [`StatsdBuilder::with_local_sketches`]. Patterns are the same as for
+    /// [`StatsdBuilder::with_sample_rate_for`].
+    pub fn with_sketches_for(mut self, pattern: &str) -> Self {
+        self.sketching.add_name(pattern);
+        self
+    }
+
+    /// Call `context_tags` every time a metric is recorded, on the recording thread, to add tags
+    /// that depend on the context rather than on the metric, e.g. the tenant or the endpoint being
+    /// served. They come after the default tags and the labels. Metrics can no longer be rendered
+    /// once at registration only, so keep this cheap.
+    ///
+    /// This can be called several times, the tags are added in the same order.
+    ///
+    /// ```
+    /// use std::cell::RefCell;
+    /// use metrics_exporter_statsd::StatsdBuilder;
+    ///
+    /// thread_local! {
+    ///     static TENANT: RefCell<Option<String>> = RefCell::new(None);
+    /// }
+    ///
+    /// let recorder = StatsdBuilder::from("127.0.0.1", 8125)
+    ///     .with_context_tags(|tags| {
+    ///         TENANT.with(|tenant| {
+    ///             if let Some(tenant) = tenant.borrow().as_deref() {
+    ///                 tags.add("tenant", tenant);
+    ///             }
+    ///         })
+    ///     })
+    ///     .build(None)
+    ///     .expect("Could not create StatsdRecorder");
+    /// ```
+    pub fn with_context_tags<F>(mut self, context_tags: F) -> Self
+    where
+        F: Fn(&mut ContextTags<'_>) + Send + Sync + 'static,
+    {
+        self.context_tags = Some(match self.context_tags.take() {
+            Some(previous) => Arc::new(move |tags: &mut ContextTags<'_>| {
+                previous(tags);
+                context_tags(tags);
+            }),
+            None => Arc::new(context_tags),
+        });
+        self
+    }
+
+    /// Call `labels` every time a metric is sent to get labels to add to those of the metric, e.g.
+    /// the name of the current thread or a shard ID kept in a task local. This is a more
+    /// convenient form of [`StatsdBuilder::with_context_tags`], at the cost of collecting the
+    /// labels on every send.
+    ///
+    /// ```
+    /// use metrics::Label;
+    /// use metrics_exporter_statsd::StatsdBuilder;
+    ///
+    /// let recorder = StatsdBuilder::from("127.0.0.1", 8125)
+    ///     .with_dynamic_labels(|| {
+    ///         let thread = std::thread::current();
+    ///         thread.name().map(|name| Label::new("thread", name.to_string()))
+    ///     })
+    ///     .build(None)
+    ///     .expect("Could not create StatsdRecorder");
+    /// ```
+    pub fn with_dynamic_labels<F, I>(self, labels: F) -> Self
+    where
+        F: Fn() -> I + Send + Sync + 'static,
+        I: IntoIterator<Item = Label>,
+    {
+        self.with_context_tags(move |tags| {
+            for label in labels() {
+                tags.add(label.key(), label.value());
+            }
+        })
+    }
+
+    /// Also deliver every metric to `other`, e.g. a Prometheus recorder while migrating away from
+    /// statsd, so that a single recorder can be installed. Calling this again replaces `other`.
+    ///
+    /// `other` sees the metrics the way they were recorded, i.e. without the prefix, the default
+    /// tags or the sampling of statsd, and with the histogram hint label. Metrics recorded through
+    /// [`StatsdExt`](crate::StatsdExt) are specific to statsd and aren't delivered to `other`.
+    ///
+    /// ```
+    /// use metrics::NoopRecorder;
+    /// use metrics_exporter_statsd::StatsdBuilder;
+    ///
+    /// let recorder = StatsdBuilder::from("127.0.0.1", 8125)
+    ///     .tee(NoopRecorder)
+    ///     .build(Some("prefix"))
+    ///     .expect("Could not create StatsdRecorder");
+    ///
+    /// metrics::set_global_recorder(recorder);
+    /// ```
+    pub fn tee<R>(mut self, other: R) -> Self
+    where
+        R: Recorder + Send + Sync + 'static,
+    {
+        self.tee = Some(Arc::new(other));
+        self
+    }
+
+    /// Send the metrics with a label `key` to the recorder given for its value in `routes` rather
+    /// than to the recorder this builder makes, e.g. to send the metrics of every tenant to the
+    /// agent of that tenant. The metrics with another value, or without the label, stay with this
+    /// recorder. Routes can be added over several calls, when a metric matches several of them
+    /// the first one added wins.
+    ///
+    /// A routed metric is entirely up to the recorder it's routed to, e.g. its prefix, its default
+    /// tags and its sampling, and is counted in that recorder's [`StatsdHandle`](crate::StatsdHandle).
+    /// Metrics recorded through [`StatsdExt`](crate::StatsdExt) aren't routed.
+    ///
+    /// ```
+    /// use metrics_exporter_statsd::StatsdBuilder;
+    ///
+    /// let common = StatsdBuilder::from("127.0.0.1", 8125).with_default_tag("service", "checkout");
+    /// let tenant_a = common
+    ///     .clone()
+    ///     .with_host_and_port("10.0.0.1", 8125)
+    ///     .build(Some("app"))
+    ///     .expect("Could not create StatsdRecorder");
+    /// let tenant_b = common
+    ///     .clone()
+    ///     .with_host_and_port("10.0.0.2", 8125)
+    ///     .build(Some("app"))
+    ///     .expect("Could not create StatsdRecorder");
+    /// let recorder = common
+    ///     .with_route_by_tag("tenant", [("a", tenant_a), ("b", tenant_b)])
+    ///     .build(Some("app"))
+    ///     .expect("Could not create StatsdRecorder");
+    /// ```
+    pub fn with_route_by_tag<K, V, I>(mut self, key: K, routes: I) -> Self
+    where
+        K: Into<String>,
+        V: Into<String>,
+        I: IntoIterator<Item = (V, StatsdRecorder)>,
+    {
+        let key = key.into();
+        for (value, recorder) in routes {
+            let route = RouteMatch::Tag {
+                key: key.clone(),
+                value: value.into(),
+            };
+            self.routes.push(route, recorder);
+        }
+        self
+    }
+
+    /// Send the metrics recorded at `level` or at a more severe level to `recorder` rather than to
+    /// the recorder this builder makes, e.g. to send the `Level::ERROR` metrics to a dedicated
+    /// collector. Routes are the same as with [`StatsdBuilder::with_route_by_tag`].
+    ///
+    /// ```
+    /// use metrics::Level;
+    /// use metrics_exporter_statsd::StatsdBuilder;
+    ///
+    /// let collector = StatsdBuilder::from("10.0.0.1", 8125)
+    ///     .build(Some("app"))
+    ///     .expect("Could not create StatsdRecorder");
+    /// let recorder = StatsdBuilder::from("127.0.0.1", 8125)
+    ///     .with_route_by_level(Level::ERROR, collector)
+    ///     .build(Some("app"))
+    ///     .expect("Could not create StatsdRecorder");
+    /// ```
+    pub fn with_route_by_level(mut self, level: Level, recorder: StatsdRecorder) -> Self {
+        self.routes.push(RouteMatch::Level(level), recorder);
+        self
+    }
+
+    /// Send the metrics recorded with `target`, or with a target within it, to `recorder` rather
+    /// than to the recorder this builder makes. The target of a metric is the module it's recorded
+    /// in unless given to the macro, so `app::auth` takes the metrics of `app::auth::session` too.
+    /// Routes are the same as with [`StatsdBuilder::with_route_by_tag`].
+    pub fn with_route_by_target<T: Into<String>>(
+        mut self,
+        target: T,
+        recorder: StatsdRecorder,
+    ) -> Self {
+        self.routes
+            .push(RouteMatch::Target(target.into()), recorder);
+        self
+    }
+
+    /// Also send `percentage` percent of the lines, picked at random, through `recorder`, e.g. to
+    /// copy some of the traffic to a canary agent. The lines are sent as they are, already
+    /// prefixed and tagged, through the queue and the connection of `recorder`, so the primary
+    /// delivery doesn't depend on the mirror. Mirrors can be added several times.
+    ///
+    /// `percentage` is clamped to `[0, 100]`. Only `recorder`'s connection is used, its prefix and
+    /// default tags aren't applied to the mirrored lines.
+    ///
+    /// ```
+    /// use metrics_exporter_statsd::StatsdBuilder;
+    ///
+    /// let canary = StatsdBuilder::from("10.0.0.2", 8125)
+    ///     .build(None)
+    ///     .expect("Could not create StatsdRecorder");
+    /// let recorder = StatsdBuilder::from("127.0.0.1", 8125)
+    ///     .with_mirror(canary, 5.0)
+    ///     .build(Some("app"))
+    ///     .expect("Could not create StatsdRecorder");
+    /// ```
+    pub fn with_mirror(mut self, recorder: StatsdRecorder, percentage: f64) -> Self {
+        self.mirrors
+            .push((recorder, percentage.clamp(0.0, 100.0) / 100.0));
+        self
+    }
+
+    /// Append the DogStatsD external data field, `|e:<external_data>`, to every line, which the
+    /// Datadog agent uses to resolve the origin of the metrics in some Kubernetes setups. `|`
+    /// and newlines are removed from `external_data`, the field is left out when nothing's left.
+    ///
+    /// See [`StatsdBuilder::with_external_data_from_env`] to use the value the Datadog admission
+    /// controller gives the pod.
+    pub fn with_external_data<S: AsRef<str>>(mut self, external_data: S) -> Self {
+        self.external_data = Some(origin::sanitize(external_data.as_ref()))
+            .filter(|external_data| !external_data.is_empty());
+        self
+    }
+
+    /// Same as [`StatsdBuilder::with_external_data`], with the value of the `DD_EXTERNAL_ENV`
+    /// environment variable, if it's set.
+    pub fn with_external_data_from_env(mut self) -> Self {
+        self.external_data = origin::external_data_from_env();
+        self
+    }
+
+    /// Only allow `values` for the labels with the given `key`, any other value is sent as `other`
+    /// instead. This puts a hard limit on the number of series a label can create, e.g. for
+    /// `status_code` or `endpoint` labels that are built from user input. Values can be allowed
+    /// over several calls.
+    ///
+    /// Default tags aren't affected, nor are tags added with
+    /// [`StatsdBuilder::with_context_tags`].
+    ///
+    /// ```
+    /// use metrics_exporter_statsd::StatsdBuilder;
+    ///
+    /// let recorder = StatsdBuilder::from("127.0.0.1", 8125)
+    ///     .with_allowed_label_values("status_code", ["200", "404", "500"])
+    ///     .build(Some("prefix"))
+    ///     .expect("Could not create StatsdRecorder");
+    ///
+    /// metrics::with_local_recorder(&recorder, || {
+    ///     // emits `prefix.requests:1|c|#status_code:other`
+    ///     metrics::counter!("requests", "status_code" => "418").increment(1);
+    /// });
+    /// ```
+    pub fn with_allowed_label_values<K, I, V>(mut self, key: K, values: I) -> Self
+    where
+        K: ToString,
+        I: IntoIterator<Item = V>,
+        V: ToString,
+    {
+        self.allowed_values.allow(
+            key.to_string(),
+            values.into_iter().map(|value| value.to_string()),
+        );
+        self
+    }
+
+    /// Send metrics with at most `max_tags` tags, default tags included, so that a label gone
+    /// wrong can't make lines too long for statsd to parse. The default tags are always kept, the
+    /// labels that don't fit are dropped and counted, see [`StatsdHandle::dropped_tags`].
+    ///
+    /// The labels that are kept are picked in the order given to
+    /// [`StatsdBuilder::with_tag_priority`], then in alphabetical order of their keys, so a metric
+    /// always ends up with the same tags.
+    ///
+    /// [`StatsdHandle::dropped_tags`]: crate::StatsdHandle::dropped_tags
+    pub fn with_max_tags(mut self, max_tags: usize) -> Self {
+        self.max_tags = Some(max_tags);
+        self
+    }
+
+    /// Send the default tags and the labels of every metric sorted by key, then by value, instead
+    /// of the default tags first and then the labels in the order they were given. This way a
+    /// series is always sent as the same line, whichever order its labels were given in. Tags added
+    /// by [`StatsdBuilder::with_context_tags`] still come last.
+    pub fn with_sorted_tags(mut self) -> Self {
+        self.sort_tags = true;
+        self
+    }
+
+    /// Drop the metrics that would make lines a DogStatsD server can't parse, rather than sending
+    /// them: names, prefix included, with `:`, `|`, `@` or a newline, tag keys with `:`, `,`, `|`
+    /// or `#`, tag values with `,` or `|`, and values that are `NaN` or infinite. Names and tags
+    /// are checked once when a metric is registered, values every time they're recorded.
+    ///
+    /// The dropped metrics are counted as [`DropReason::Malformed`] and logged along with the
+    /// others, see [`StatsdBuilder::with_log`]. Tags added by
+    /// [`StatsdBuilder::with_context_tags`] aren't checked.
+    ///
+    /// The metrics whose name, prefix included, was first registered as another type, e.g. a
+    /// gauge named like a counter, are dropped too and counted as [`DropReason::TypeConflict`].
+    /// Such conflicts are always logged, once per name and type, strict or not.
+    pub fn with_strict_validation(mut self) -> Self {
+        self.strict = true;
+        self
+    }
+
+    /// Shorten the names longer than `max_len` bytes, prefix included, for backends that reject
+    /// them, e.g. Datadog beyond 200 characters. A long name is truncated and suffixed with `_`
+    /// and 8 hex digits of a hash of the whole name, so it's shortened the same way every time
+    /// and two long names sharing a beginning stay apart.
+    pub fn with_max_name_len(mut self, max_len: usize) -> Self {
+        self.max_name_len = Some(max_len);
+        self
+    }
+
+    /// Keep the labels with these keys, in this order, before any other when a metric has more
+    /// tags than [`StatsdBuilder::with_max_tags`] allows.
+    pub fn with_tag_priority<I, K>(mut self, keys: I) -> Self
+    where
+        I: IntoIterator<Item = K>,
+        K: ToString,
+    {
+        self.tag_priority = keys.into_iter().map(|key| key.to_string()).collect();
+        self
+    }
+
+    /// Append the unit a metric was described with to its name, e.g. `request.duration.seconds` or
+    /// `payload.size.bytes`, like Prometheus names are. Names that already end with the unit are
+    /// left alone, and so are timers, which statsd always gets in milliseconds.
+    ///
+    /// The name is picked when a metric is first registered, so metrics must be described before
+    /// they are first recorded. Metrics recorded through [`StatsdExt`](crate::StatsdExt) aren't
+    /// suffixed.
+    ///
+    /// ```
+    /// use metrics::Unit;
+    /// use metrics_exporter_statsd::StatsdBuilder;
+    ///
+    /// let recorder = StatsdBuilder::from("127.0.0.1", 8125)
+    ///     .with_unit_suffixes()
+    ///     .build(Some("prefix"))
+    ///     .expect("Could not create StatsdRecorder");
+    ///
+    /// metrics::with_local_recorder(&recorder, || {
+    ///     metrics::describe_histogram!("payload.size", Unit::Bytes, "size of the payloads");
+    ///     // emits `prefix.payload.size.bytes:512|h`
+    ///     metrics::histogram!("payload.size").record(512.0);
+    /// });
+    /// ```
+    pub fn with_unit_suffixes(mut self) -> Self {
+        self.unit_suffixes = true;
+        self
+    }
+
+    /// Rename, relabel, retype or drop metrics by name following the rules in the TOML file at
+    /// `path`, e.g. to adjust the shape of the metrics of a dependency without changing its code.
+    /// The file is read by `build`, which fails with [`StatsdError::InvalidMapping`] when a rule
+    /// isn't valid.
+    ///
+    /// Every rule is a `[[mappings]]` table. The first rule whose `match` pattern matches the name
+    /// of a metric applies to it:
+    ///
+    /// ```toml
+    /// [[mappings]]
+    /// match = "http.*.requests"   # `*` matches any part of a component of the name
+    /// name = "http.requests"      # `$1`, `$2`, ... stand for what every `*` matched
+    /// labels = { method = "$1" }  # added to the labels, replacing those with the same key
+    ///
+    /// [[mappings]]
+    /// match = "db.*.duration"
+    /// type = "timer"              # histogram, distribution or timer, for histograms only
+    ///
+    /// [[mappings]]
+    /// match = "debug.*"
+    /// action = "drop"             # the metric isn't sent at all
+    /// ```
+    ///
+    /// Names are matched before the prefix and the unit suffix are added.
+    pub fn with_mapping_file<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.mapping_file = Some(path.into());
+        self
+    }
+
+    /// Check whether the file given to [`StatsdBuilder::with_mapping_file`] was modified every
+    /// `interval`, and reload it when it was, so that rules can be tuned without restarting the
+    /// application. A file that isn't valid is ignored until it's modified again. See
+    /// [`StatsdHandle::reload_mapping`](crate::StatsdHandle::reload_mapping) to reload on demand
+    /// instead, e.g. on `SIGHUP`.
+    pub fn with_mapping_reload(mut self, interval: Duration) -> Self {
+        self.mapping_reload = Some(interval);
+        self
+    }
+
+    /// Run every metric through `stage` as it's registered, after the mapping file, the sample
+    /// rates and the stages that were added before this one. See [`PipelineStage`].
+    pub fn with_stage<S: PipelineStage>(mut self, stage: S) -> Self {
+        self.stages.push(Arc::new(stage));
+        self
+    }
+
+    /// Forget the metrics that weren't registered for `timeout`, i.e. that the `metrics` macros
+    /// weren't called for, to free what the exporter keeps for them. Otherwise that is kept for as
+    /// long as the recorder, which adds up over time in long running services whose metrics are
+    /// tagged with values that come and go, e.g. the version of a deployment.
+    ///
+    /// Metrics are forgotten between `timeout` and twice `timeout` after they were last
+    /// registered. A metric that is registered again afterwards is set up from scratch, and
+    /// handles that are kept around, e.g. a [`metrics::Counter`] stored in a struct, keep working.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Use `clock` instead of the [`SystemClock`] to schedule the periodic work of the exporter,
+    /// e.g. telemetry and flushes, and to compute the counter rates of
+    /// [`StatsdHandle::snapshot`](crate::StatsdHandle::snapshot). This is meant for tests, see
+    /// [`ManualClock`](crate::testing::ManualClock).
+    pub fn with_clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Pack as many whole metrics as fit in `max_packet_size` bytes into each datagram, instead of
+    /// buffering up to `buffer_size` bytes. A metric is never split across datagrams, and one that
+    /// is larger than a packet is sent in a datagram of its own.
+    ///
+    /// Use the MTU of the path to the statsd server, minus the IP and UDP headers: `1432` suits
+    /// typical networks, `8192` suits jumbo frames and local agents. Packets that don't fill up are
+    /// sent after at most 100ms. This setting replaces `buffer_size` and has no effect on a custom
+    /// sink.
+    pub fn with_max_packet_size(mut self, max_packet_size: usize) -> Self {
+        self.max_packet_size = Some(max_packet_size);
+        self
+    }
+
+    /// Hand the kernel several packets at once over UDP and have it split them into datagrams, with
+    /// the generic segmentation offload (GSO) of Linux, which cuts down on system calls when a lot
+    /// of metrics are sent. Packets are as per [`StatsdBuilder::with_max_packet_size`], `1432`
+    /// bytes unless set, and are sent once 64 of them are waiting or after at most 100ms.
+    ///
+    /// Every datagram but the last of a send must be exactly as large as a packet, so packets are
+    /// padded with newlines, which statsd servers skip. Metrics larger than a packet are dropped.
+    /// When the kernel doesn't support GSO, which takes Linux 4.18, packets are sent one at a time.
+    #[cfg(target_os = "linux")]
+    pub fn with_udp_gso(mut self) -> Self {
+        self.udp_gso = true;
+        self
+    }
+
+    /// Prefix the metrics with the value of the environment variable `var`, e.g. a per-team prefix
+    /// set by the platform, ahead of the prefix given to [`StatsdBuilder::build`], which then
+    /// names the application beneath it. The variable is read by `build`, when it's unset or
+    /// empty only the prefix given to `build` is used.
+    ///
+    /// ```
+    /// use metrics_exporter_statsd::StatsdBuilder;
+    ///
+    /// // with `METRICS_PREFIX=payments`, emits `payments.checkout.orders:1|c`
+    /// let recorder = StatsdBuilder::from("127.0.0.1", 8125)
+    ///     .with_prefix_from_env("METRICS_PREFIX")
+    ///     .build(Some("checkout"))
+    ///     .expect("Could not create StatsdRecorder");
+    /// metrics::with_local_recorder(&recorder, || metrics::counter!("orders").increment(1));
+    /// ```
+    pub fn with_prefix_from_env<S: Into<String>>(mut self, var: S) -> Self {
+        self.prefix_env = Some(var.into());
+        self
+    }
+
+    /// Register at most `max_metrics` metrics at once from `target`, or from a target within it,
+    /// e.g. to keep a misbehaving dependency from flooding the pipeline with tags of unbounded
+    /// cardinality. The metrics registered once the quota is reached are dropped and counted as
+    /// [`DropReason::OverQuota`], the first of them is logged, see [`StatsdBuilder::with_log`].
+    /// The longest matching target wins, and metrics that go away give their share back.
+    ///
+    /// A distinct metric is a name and a set of tags, as registered through the `metrics` macros.
+    /// Metrics a stage of the pipeline drops don't count.
+    ///
+    /// ```
+    /// use metrics_exporter_statsd::StatsdBuilder;
+    ///
+    /// let recorder = StatsdBuilder::from("127.0.0.1", 8125)
+    ///     .with_target_quota("noisy_dep", 100)
+    ///     .with_target_rate_quota("noisy_dep", 1000)
+    ///     .build(Some("my_app"))
+    ///     .expect("Could not create StatsdRecorder");
+    /// ```
+    pub fn with_target_quota<T: Into<String>>(mut self, target: T, max_metrics: usize) -> Self {
+        self.limits(target.into()).max_metrics = Some(max_metrics);
+        self
+    }
+
+    /// Send at most `per_second` values a second from `target`, or from a target within it, the
+    /// values beyond that are dropped and counted as [`DropReason::OverQuota`]. Seconds are told
+    /// by the clock given to [`StatsdBuilder::with_clock`]. See also
+    /// [`StatsdBuilder::with_target_quota`].
+    ///
+    /// Values summarized or sketched on the client aren't limited, nor are the rates of the
+    /// counters.
+    pub fn with_target_rate_quota<T: Into<String>>(mut self, target: T, per_second: u64) -> Self {
+        self.limits(target.into()).per_second = Some(per_second);
+        self
+    }
+
+    fn limits(&mut self, target: String) -> &mut Limits {
+        let index = match self.quotas.iter().position(|(t, _)| *t == target) {
+            Some(index) => index,
+            None => {
+                self.quotas.push((target, Limits::default()));
+                self.quotas.len() - 1
+            }
+        };
+        &mut self.quotas[index].1
+    }
+
+    /// Prefix the metrics with a segment derived from the target they're recorded with, i.e. the
+    /// module they're recorded in unless given to the macro, beneath the prefix of the recorder,
+    /// so that the metrics of libraries are namespaced without them cooperating. The segment is
+    /// the crate of the target, e.g. `hyper.connections` for a metric recorded in
+    /// `hyper::client::pool`, unless mapped with [`StatsdBuilder::with_target_prefix`].
+    ///
+    /// The same key recorded from two crates makes two metrics.
+    pub fn with_target_prefixes(mut self) -> Self {
+        self.target_prefixes.get_or_insert_with(Vec::new);
+        self
+    }
+
+    /// Prefix the metrics recorded with `target`, or with a target within it, with `segment`
+    /// rather than the crate of the target, e.g. `deps.hyper` for `hyper`, or with nothing when
+    /// `segment` is empty, e.g. for the crate of the application. The longest matching target
+    /// wins. This implies [`StatsdBuilder::with_target_prefixes`].
+    ///
+    /// ```
+    /// use metrics_exporter_statsd::StatsdBuilder;
+    ///
+    /// let recorder = StatsdBuilder::from("127.0.0.1", 8125)
+    ///     .with_target_prefix("hyper", "deps.hyper")
+    ///     .with_target_prefix("my_app", "")
+    ///     .build(Some("my_app"))
+    ///     .expect("Could not create StatsdRecorder");
+    /// ```
+    pub fn with_target_prefix<T, S>(mut self, target: T, segment: S) -> Self
+    where
+        T: Into<String>,
+        S: Into<String>,
+    {
+        self.target_prefixes
+            .get_or_insert_with(Vec::new)
+            .push((target.into(), segment.into()));
+        self
+    }
+
+    /// This method is responsible building the StatsdRecorder. It configures the underlying metrics sink for
+    /// the [`StatsdClient`] with the values provided e.g. `queue_size`, `buffer_size` etc.
+    ///
+    /// All the metrics emitted from the recorder are prefixed with the prefix that's provided here.
+    ///
+    /// # Examples
+    /// ```
+    /// use metrics_exporter_statsd::StatsdBuilder;
+    /// let recorder = StatsdBuilder::from("localhost", 8125)
+    ///                .build(Some("prefix"))
+    ///                .expect("Could not create StatsdRecorder");
+    ///
+    /// metrics::set_global_recorder(recorder);
+    /// metrics::counter!("counter.name").increment(10);
+    /// ```
+    /// will emit a counter metric name as `prefix.counter.name`
+    pub fn build(self, prefix: Option<&str>) -> Result<StatsdRecorder, StatsdError> {
+        self.is_valid()?;
+        let mapping = Arc::new(LiveMapping::load(self.mapping_file.clone())?);
+
+        let env_prefix = self.env_prefix(|var| std::env::var(var).ok());
+        let prefix = format!(
+            "{}{}",
+            format_prefix(&env_prefix),
+            format_prefix(prefix.unwrap_or(""))
+        );
+        let stats = Arc::new(Stats::default());
+        let stream = self
+            .stream
+            .as_ref()
+            .map(|transport| transport.addr(&self.host, self.port));
+        let transport = match (&self.sink, &self.file_sink, &stream) {
+            (Some(_), _, _) => "custom",
+            (None, Some(_), _) => "file",
+            (None, None, Some(addr)) => addr.transport(),
+            (None, None, None) => "udp",
+        };
+        let mut queue = None;
+        let mut upkeep = Upkeep::with_jitter(self.flush_jitter);
+        let mut sink: SharedSink = match &self.sink {
+            Some(sink_fn) => sink_fn(stats.clone()),
+            None => {
+                let connection = match (&self.file_sink, &stream) {
+                    (Some(path), _) => Connection::File(Arc::new(
+                        FileSink::open(path.clone(), self.file_rotation).map_err(|source| {
+                            StatsdError::File {
+                                path: path.clone(),
+                                source,
+                            }
+                        })?,
+                    )),
+                    (None, Some(addr)) => Connection::Stream(match &self.socks5_proxy {
+                        Some((host, port)) => addr.clone().with_proxy(Socks5Proxy {
+                            host: host.clone(),
+                            port: *port,
+                            auth: self.socks5_auth.clone(),
+                            timeout: socks::DEFAULT_TIMEOUT,
+                        }),
+                        None => addr.clone(),
+                    }),
+                    (None, None) => {
+                        // create a local udp socket where the communication needs to happen, the port is set to
+                        // 0 so that we can pick any available port on the host. We also want this socket to be
+                        // non-blocking, unless backpressure was asked for
+                        let addr = resolve(&self.host, self.port)?;
+                        let ports = self.client_port_range.clone().unwrap_or(0..=0);
+                        let socket = bind(&self.client_udp_host, ports, self.blocking_socket)?;
+                        Connection::Udp(socket, addr)
+                    }
+                };
+                // Every worker drains its own queue into its own sink, they only share the socket.
+                let capacity = self.queue_size.unwrap_or(DEFAULT_QUEUE_SIZE);
+                let mut queues = Vec::new();
+                let mut queue_ages = Vec::new();
+                for _ in 0..self.queue_workers.unwrap_or(1).max(1) {
+                    let connection_sink: SharedSink = match &connection {
+                        // workers take turns appending to the file.
+                        Connection::File(file) => file.clone(),
+                        // every worker has a connection of its own.
+                        Connection::Stream(addr) => {
+                            let stream = Arc::new(StreamSink::new(
+                                addr.clone(),
+                                self.buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE),
+                                self.backoff,
+                            ));
+                            StreamFlusher::new(&stream)
+                                .schedule(&mut upkeep, PACKET_FLUSH_INTERVAL);
+                            stream
+                        }
+                        Connection::Udp(socket, addr) => {
+                            let addr = *addr;
+                            let connect = |source| StatsdError::Connect { addr, source };
+                            let socket = socket.try_clone().map_err(connect)?;
+                            // Initialize buffered udp metrics sink with the provided or default capacity, this allows
+                            // statsd client (cadence) to buffer metrics upto the configured size in memory before, flushing
+                            // to network.
+                            let max_packet_size = self
+                                .max_packet_size
+                                .or(self.udp_gso.then_some(DEFAULT_GSO_PACKET_SIZE));
+                            match max_packet_size {
+                                Some(max_packet_size) => {
+                                    // the packing sink doesn't report errors, the packets are counted as they
+                                    // are sent instead.
+                                    let udp_sink = packet_sink(
+                                        socket,
+                                        addr,
+                                        max_packet_size,
+                                        self.udp_gso,
+                                        &stats,
+                                    )
+                                    .map_err(connect)?;
+                                    let packing =
+                                        Arc::new(PackingSink::new(udp_sink, max_packet_size));
+                                    PacketFlusher::new(&packing)
+                                        .schedule(&mut upkeep, PACKET_FLUSH_INTERVAL);
+                                    packing
+                                }
+                                None => Arc::new(
+                                    BufferedUdpMetricSink::with_capacity(
+                                        addr,
+                                        socket,
+                                        self.buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE),
+                                    )
+                                    .map_err(|e| connect(io::Error::other(e)))?,
+                                ),
+                            }
+                        }
+                    };
+                    let connection_sink: SharedSink = match self.max_queue_age {
+                        Some(max_age) => {
+                            let ages = Arc::new(QueueAges::new(self.clock.clone(), max_age));
+                            queue_ages.push(ages.clone());
+                            Arc::new(StaleSink::new(
+                                SharedSinkRef(connection_sink),
+                                ages,
+                                stats.clone(),
+                            ))
+                        }
+                        None => connection_sink,
+                    };
+                    // Initialize a bounded QueuingMetricSink so that we are not buffering unlimited items onto
+                    // statsd client's queue, statsd client will error out when the queue is full. Failures
+                    // to write to the socket happen on the queue's thread, so they are counted from there.
+                    queues.push(match self.queue_slot_size {
+                        Some(slot_size) => Queue::Ring(RingQueue::new(
+                            capacity,
+                            slot_size,
+                            connection_sink,
+                            stats.clone(),
+                        )),
+                        None => {
+                            let send_stats = stats.clone();
+                            Queue::Channel(
+                                QueuingMetricSink::builder()
+                                    .with_capacity(capacity)
+                                    .with_error_handler(move |e| {
+                                        send_stats.record_drop(DropReason::SendError);
+                                        send_stats.record_error(&e);
+                                    })
+                                    .build(SharedSinkRef(connection_sink)),
+                            )
+                        }
+                    });
+                }
+                let mut sink = QueueSink::new(queues, self.shutdown_timeout, stats.clone())
+                    .with_max_fill(capacity, self.queue_max_fill);
+                if self.counter_coalescing {
+                    sink = sink.with_coalescing();
+                }
+                if !queue_ages.is_empty() {
+                    sink = sink.with_ages(queue_ages);
+                }
+                if matches!(connection, Connection::File(_)) {
+                    sink = sink.with_shared_sink();
+                }
+                let sink = Arc::new(sink);
+                if self.counter_coalescing {
+                    Requeuer::new(&sink).schedule(&mut upkeep, REQUEUE_INTERVAL);
+                }
+                queue = Some(sink.clone());
+                Arc::new(
+                    CountingSink::new(SharedSinkRef(sink), stats.clone(), DropReason::QueueFull)
+                        .with_max_line_len(MAX_UDP_PAYLOAD),
+                )
+            }
+        };
+
+        if let Some(external_data) = &self.external_data {
+            sink = Arc::new(ExternalDataSink::new(sink, external_data));
+        }
+
+        if !self.mirrors.is_empty() {
+            sink = Arc::new(MirrorSink::new(sink, self.mirrors.clone()));
+        }
+
+        for wrapper in &self.sink_wrappers {
+            sink = wrapper(InnerSink(sink));
+        }
+
+        if let Some((max_bytes, max_delay)) = self.batching {
+            let batching = Arc::new(BatchingSink::new(sink, max_bytes));
+            BatchFlusher::new(&batching).schedule(&mut upkeep, max_delay);
+            sink = batching;
+        }
+
+        let recent = self
+            .recent_lines
+            .map(|capacity| Arc::new(RecentLines::new(capacity)));
+        if let Some(recent) = &recent {
+            sink = Arc::new(RecentLinesSink {
+                inner: sink,
+                recent: recent.clone(),
+            });
+        }
+
+        if let Some(interval) = self.telemetry {
+            Telemetry::new(&sink, stats.clone(), transport, &self.default_tags)
+                .schedule(&mut upkeep, interval);
+        }
+
+        // The prefix and the default tags are rendered along with the rest of the metric by the
+        // recorder, so the client is only used to send fully formatted lines.
+        let statsd = Arc::new(StatsdClient::from_sink("", SharedSinkRef(sink)));
+        if let (Some(interval), Some(queue)) = (self.queue_depth_interval, &queue) {
+            QueueDepthReporter::new(&statsd, queue, &prefix, &self.default_tags)
+                .schedule(&mut upkeep, interval);
+        }
+        if let Some(interval) = self.heartbeat {
+            HeartbeatReporter::new(&statsd, &prefix, &self.default_tags)
+                .schedule(&mut upkeep, interval);
+        }
+        if let Some((interval, version_tags)) = &self.uptime {
+            let tags = [self.default_tags.as_slice(), version_tags].concat();
+            UptimeReporter::new(&statsd, self.clock.clone(), &prefix, &tags)
+                .schedule(&mut upkeep, *interval);
+        }
+        if let (Some(interval), Some(_)) = (self.mapping_reload, &self.mapping_file) {
+            LiveMapping::schedule_reload(Arc::downgrade(&mapping), &mut upkeep, interval);
+        }
+        let registry = Arc::default();
+        let registries = Arc::new(Registries::default());
+        registries.add(&registry);
+        let interner = Arc::new(Interner::default());
+        if let Some(timeout) = self.idle_timeout {
+            let (registries, interner) = (Arc::downgrade(&registries), Arc::downgrade(&interner));
+            upkeep.every(timeout, move || {
+                match (registries.upgrade(), interner.upgrade()) {
+                    (Some(registries), Some(interner)) => {
+                        if registries.evict_idle() > 0 {
+                            interner.evict_unused();
+                        }
+                        true
+                    }
+                    _ => false,
+                }
+            });
+        }
+        if let Some((interval, _, _)) = self.burst_smoothing {
+            HandleFlusher::new(
+                Arc::downgrade(&registries),
+                self.clock.clone(),
+                Handle::send_deferred,
+            )
+            .schedule(&mut upkeep, interval);
+        }
+        if let Some((_, interval)) = self.counter_rates {
+            HandleFlusher::new(
+                Arc::downgrade(&registries),
+                self.clock.clone(),
+                Handle::send_rate,
+            )
+            .schedule(&mut upkeep, interval);
+        }
+        if let Some(interval) = self.summaries {
+            HandleFlusher::new(
+                Arc::downgrade(&registries),
+                self.clock.clone(),
+                Handle::send_summary,
+            )
+            .schedule(&mut upkeep, interval);
+        }
+        if let Some((interval, _)) = self.hdr_timers {
+            HandleFlusher::new(
+                Arc::downgrade(&registries),
+                self.clock.clone(),
+                Handle::send_timer_histogram,
+            )
+            .schedule(&mut upkeep, interval);
+        }
+        if let Some(interval) = self.sketches {
+            HandleFlusher::new(
+                Arc::downgrade(&registries),
+                self.clock.clone(),
+                Handle::send_sketch,
+            )
+            .schedule(&mut upkeep, interval);
+        }
+        if let Some((path, interval)) = &self.catalog_file {
+            CatalogWriter::new(Arc::downgrade(&registries), path.clone(), self.log.clone())
+                .schedule(&mut upkeep, *interval);
+        }
+        if let Some((count, interval)) = self.top_series {
+            TopSeriesReporter::new(&statsd, &registries, count, &prefix, &self.default_tags)
+                .schedule(&mut upkeep, interval);
+        }
+        if let Some(log) = &self.log {
+            ErrorLog::new(&stats, log.clone(), self.error_log_interval)
+                .schedule(&mut upkeep, self.error_log_interval);
+        }
+        if let Some(queue) = &queue {
+            Watchdog::new(
+                &statsd,
+                queue,
+                stats.clone(),
+                self.log.clone(),
+                &prefix,
+                &self.default_tags,
+            )
+            .schedule(&mut upkeep, WATCHDOG_INTERVAL);
+        }
+        let upkeep = upkeep.spawn(self.clock.clone(), stats.clone())?;
+
+        let exit_hook = self.exit_hook;
+        let recorder = StatsdRecorder {
+            statsd,
+            default_histogram: self.default_histogram,
+            shared: Arc::new(Shared {
+                stats,
+                pipeline: Pipeline::new(
+                    vec![mapping.clone(), Arc::new(self.sample_rates)],
+                    self.stages,
+                ),
+                context_tags: self.context_tags,
+                unit_suffixes: self.unit_suffixes,
+                allowed_values: self.allowed_values,
+                max_tags: self.max_tags,
+                tag_priority: self.tag_priority,
+                sort_tags: self.sort_tags,
+                strict: self.strict,
+                log: self.log,
+                descriptions_file: self.descriptions_file,
+                max_name_len: self.max_name_len,
+                count_bytes: self.top_series.is_some(),
+                sample_rate_semantics: self.sample_rate_semantics,
+                value_bounds: self.value_bounds,
+                rounding: self.rounding,
+                negative_values: self.negative_values,
+                counter_rates: self.counter_rates.map(|(rates, _)| rates),
+                hdr_timers: self
+                    .hdr_timers
+                    .map(|(_, digits)| (digits, self.percentiles.clone())),
+                summaries: self.summaries.map(|_| self.percentiles),
+                dual_emit: self.dual_emit,
+                sketches: self.sketches.map(|_| self.sketching),
+                mapping,
+                interner,
+                registries,
+                queue: queue.as_ref().map(Arc::downgrade),
+                recent,
+                upkeep,
+                last_values: self
+                    .last_values
+                    .map(|max_keys| LastValues::new(max_keys, self.clock.clone())),
+                activity: self.active_keys.then(|| Activity::new(self.clock.clone())),
+                quotas: Quotas::new(self.quotas, self.clock.clone()),
+                smoothing: self.burst_smoothing.map(|(_, per_second, burst)| {
+                    Smoothing::new(per_second, burst, self.clock.clone())
+                }),
+                top_emitters: self.top_emitters,
+                count_emits: self.active_keys || self.top_emitters.is_some(),
+                ..Shared::default()
+            }),
+            scope: Arc::new(Scope {
+                prefix,
+                default_tags: self.default_tags,
+            }),
+            registry,
+            tee: self.tee,
+            routes: Arc::new(self.routes),
+            targets: self
+                .target_prefixes
+                .map(|mapped| Arc::new(TargetPrefixes::new(mapped))),
+        };
+        if let Some(timeout) = exit_hook {
+            exit::register(&recorder.shared, timeout);
+        }
+        Ok(recorder)
+    }
+
+    /// Send a single metric and wait for it to be sent, without installing a recorder, e.g. from a
+    /// short script that reports that it ran and exits. The metric is named `name`, prefixed with
+    /// `prefix`, tagged with the default tags and `tags`, and sent as `metric_type`, like with
+    /// [`StatsdExt`](crate::StatsdExt).
+    ///
+    /// This waits up to the shutdown timeout, one second unless set with
+    /// [`StatsdBuilder::with_shutdown_timeout`], and fails with [`StatsdError::SendTimeout`] when
+    /// the metric wasn't sent by then, or with [`StatsdError::Dropped`] when it was dropped. Build a recorder to send more than a few metrics, this sets up a new one every
+    /// time.
+    ///
+    /// ```no_run
+    /// use metrics_exporter_statsd::{MetricType, StatsdBuilder};
+    ///
+    /// # fn main() -> Result<(), metrics_exporter_statsd::StatsdError> {
+    /// StatsdBuilder::from("127.0.0.1", 8125).send_once(
+    ///     Some("backup"),
+    ///     MetricType::Counter,
+    ///     "runs",
+    ///     1.0,
+    ///     &[("status", "success")],
+    /// )?;
+    /// #     Ok(())
+    /// # }
+    /// ```
+    pub fn send_once(
+        self,
+        prefix: Option<&str>,
+        metric_type: MetricType,
+        name: &str,
+        value: f64,
+        tags: &[(&str, &str)],
+    ) -> Result<(), StatsdError> {
+        let timeout = self.shutdown_timeout.unwrap_or(SEND_ONCE_TIMEOUT);
+        let recorder = self.with_shutdown_timeout(timeout).build(prefix)?;
+        let handle = recorder.handle();
+        let labels: Vec<Label> = tags
+            .iter()
+            .map(|(key, value)| Label::new(key.to_string(), value.to_string()))
+            .collect();
+        let key = Key::from_parts(name.to_string(), labels);
+        if let Some(value) = recorder.shared.bound(value) {
+            ext::send(
+                &recorder.statsd,
+                &recorder.shared,
+                &recorder.scope,
+                &key,
+                value,
+                metric_type,
+            );
+        }
+        drop(recorder);
+
+        if handle.shutdown() > 0 {
+            return Err(StatsdError::SendTimeout { timeout });
+        }
+        match handle
+            .dropped_metrics()
+            .iter()
+            .find(|(_, count)| *count > 0)
+        {
+            Some((reason, _)) => Err(StatsdError::Dropped { reason }),
+            None => Ok(()),
+        }
+    }
+
+    /// The value of the variable given to [`StatsdBuilder::with_prefix_from_env`], looked up
+    /// with `lookup`, empty when there is none.
+    fn env_prefix(&self, lookup: impl Fn(&str) -> Option<String>) -> String {
+        self.prefix_env
+            .as_deref()
+            .and_then(lookup)
+            .unwrap_or_default()
+    }
+
+    fn is_valid(&self) -> Result<(), StatsdError> {
+        if !self.sample_rates.is_valid() {
+            return Err(StatsdError::InvalidSampleRate);
+        }
+        if !self.percentiles.is_valid() {
+            return Err(StatsdError::InvalidQuantile);
+        }
+        if !self.sketching.is_valid() {
+            return Err(StatsdError::InvalidRelativeAccuracy);
+        }
+        if self.burst_smoothing.is_some_and(|(_, per_second, burst)| {
+            per_second.is_nan() || per_second <= 0.0 || burst == 0
+        }) {
+            return Err(StatsdError::InvalidBurstSmoothing);
+        }
+        if self
+            .hdr_timers
+            .is_some_and(|(_, digits)| !(1..=5).contains(&digits))
+        {
+            return Err(StatsdError::InvalidSignificantDigits);
+        }
+        if let Some((pattern, reason)) = self
+            .sample_rates
+            .invalid_patterns
+            .iter()
+            .chain(&self.percentiles.invalid_patterns)
+            .chain(&self.sketching.invalid_patterns)
+            .chain(&self.dual_emit.invalid_patterns)
+            .next()
+        {
+            return Err(StatsdError::InvalidPattern {
+                pattern: pattern.clone(),
+                reason: reason.clone(),
+            });
+        }
+        // Check settings only if we are going to use them.
+        let uses_host =
+            self.file_sink.is_none() && self.stream.as_ref().is_none_or(StreamTransport::is_tcp);
+        if self.sink.is_none() && uses_host {
+            if self.host.trim().is_empty() {
+                return Err(StatsdError::InvalidHost);
+            }
+            if self.port == 0 {
+                return Err(StatsdError::InvalidPortZero);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for StatsdBuilder {
+    fn default() -> Self {
+        StatsdBuilder {
+            host: DEFAULT_HOST.to_string(),
+            port: DEFAULT_PORT,
+            queue_size: Some(DEFAULT_QUEUE_SIZE),
+            queue_slot_size: None,
+            buffer_size: Some(DEFAULT_BUFFER_SIZE),
+            default_histogram: HistogramType::Histogram,
+            client_udp_host: CLIENT_UDP_HOST.to_string(),
+            client_port_range: None,
+            blocking_socket: false,
+            stream: None,
+            file_sink: None,
+            file_rotation: None,
+            socks5_proxy: None,
+            socks5_auth: None,
+            backoff: Backoff::default(),
+            default_tags: Vec::new(),
+            sink: None,
+            sink_wrappers: Vec::new(),
+            telemetry: None,
+            queue_depth_interval: None,
+            heartbeat: None,
+            uptime: None,
+            recent_lines: None,
+            last_values: None,
+            batching: None,
+            max_packet_size: None,
+            udp_gso: false,
+            queue_workers: None,
+            queue_max_fill: [1.0; MetricType::ALL.len()],
+            counter_coalescing: false,
+            max_queue_age: None,
+            sample_rates: SampleRates::default(),
+            sample_rate_semantics: SampleRateSemantics::Annotated,
+            value_bounds: None,
+            rounding: [0.0; MetricType::ALL.len()],
+            negative_values: None,
+            counter_rates: None,
+            summaries: None,
+            percentiles: Percentiles::default(),
+            sketches: None,
+            hdr_timers: None,
+            dual_emit: DualEmit::default(),
+            sketching: Sketching::default(),
+            clock: Arc::new(SystemClock),
+            context_tags: None,
+            tee: None,
+            routes: Routes::default(),
+            mirrors: Vec::new(),
+            external_data: None,
+            unit_suffixes: false,
+            allowed_values: AllowedValues::default(),
+            max_tags: None,
+            tag_priority: Vec::new(),
+            sort_tags: false,
+            strict: false,
+            max_name_len: None,
+            prefix_env: None,
+            target_prefixes: None,
+            quotas: Vec::new(),
+            burst_smoothing: None,
+            mapping_file: None,
+            descriptions_file: None,
+            catalog_file: None,
+            mapping_reload: None,
+            stages: Vec::new(),
+            idle_timeout: None,
+            top_series: None,
+            active_keys: false,
+            top_emitters: None,
+            shutdown_timeout: None,
+            exit_hook: None,
+            log: None,
+            flush_jitter: Duration::ZERO,
+            error_log_interval: DEFAULT_ERROR_LOG_INTERVAL,
+        }
+    }
+}
+
+/// A sink sending packets of at most `max_packet_size` bytes to `addr`, several at a time with
+/// `gso` when the kernel supports it.
+#[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+fn packet_sink(
+    socket: UdpSocket,
+    addr: SocketAddr,
+    max_packet_size: usize,
+    gso: bool,
+    stats: &Arc<Stats>,
+) -> io::Result<SharedSink> {
+    #[cfg(target_os = "linux")]
+    if gso && gso::enable(&socket, max_packet_size).is_ok() {
+        return Ok(Arc::new(GsoSink::new(
+            socket,
+            addr,
+            max_packet_size,
+            stats.clone(),
+        )));
+    }
+    let udp_sink = UdpMetricSink::from(addr, socket).map_err(io::Error::other)?;
+    Ok(Arc::new(CountingSink::new(
+        udp_sink,
+        stats.clone(),
+        DropReason::SendError,
+    )))
+}
+
+/// A socket bound to `host` and the first available port of `ports`, non-blocking unless
+/// `blocking`.
+fn bind(host: &str, ports: RangeInclusive<u16>, blocking: bool) -> Result<UdpSocket, StatsdError> {
+    let addr = if ports.start() == ports.end() {
+        format!("{}:{}", host, ports.start())
+    } else {
+        format!("{}:{}-{}", host, ports.start(), ports.end())
+    };
+    let mut error = io::Error::new(io::ErrorKind::InvalidInput, "the port range is empty");
+    for port in ports {
+        match UdpSocket::bind((host, port)) {
+            Ok(socket) => {
+                return match socket.set_nonblocking(!blocking) {
+                    Ok(()) => Ok(socket),
+                    Err(source) => Err(StatsdError::Bind { addr, source }),
+                };
+            }
+            // the port is taken, or reserved, try the next one.
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::AddrInUse | io::ErrorKind::PermissionDenied
+                ) =>
+            {
+                error = e
+            }
+            Err(source) => return Err(StatsdError::Bind { addr, source }),
+        }
+    }
+    Err(StatsdError::Bind {
+        addr,
+        source: error,
+    })
+}
+
+/// The first address `host` resolves to, the one cadence would send to.
+fn resolve(host: &str, port: u16) -> Result<SocketAddr, StatsdError> {
+    let dns = |source| StatsdError::DnsResolution {
+        host: format!("{}:{}", host, port),
+        source,
+    };
+    (host, port)
+        .to_socket_addrs()
+        .map_err(dns)?
+        .next()
+        .ok_or_else(|| dns(io::Error::new(io::ErrorKind::NotFound, "no address found")))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::net::UdpSocket;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use metrics::{Key, Label, Recorder};
+
+    use super::*;
+    use crate::testing::{Environ, METADATA};
+    use crate::{DescribedKind, MetricDescription};
+
+    #[test]
+    #[should_panic]
+    fn bad_host_name() {
+        StatsdBuilder::from("", 10)
+            .build(None)
+            .expect("this should panic");
+    }
+
+    #[test]
+    #[should_panic]
+    fn bad_port() {
+        StatsdBuilder::from("127.0.0.1", 0)
+            .build(None)
+            .expect("this should panic");
+    }
+
+    #[test]
+    fn build_errors_name_the_address() {
+        let result = StatsdBuilder::from("statsd.invalid", 8125).build(None);
+        assert!(matches!(
+            result,
+            Err(StatsdError::DnsResolution { host, .. }) if host == "statsd.invalid:8125"
+        ));
+
+        // not an address of this host.
+        let result = StatsdBuilder::from("127.0.0.1", 8125)
+            .with_client_udp_host("192.0.2.1")
+            .build(None);
+        assert!(matches!(
+            result,
+            Err(StatsdError::Bind { addr, .. }) if addr == "192.0.2.1:0"
+        ));
+    }
+
+    #[test]
+    fn blocking_socket() {
+        for blocking in [false, true] {
+            let socket = bind("127.0.0.1", 0..=0, blocking).expect("should bind a socket");
+            socket
+                .set_read_timeout(Some(Duration::from_millis(50)))
+                .unwrap();
+            let start = std::time::Instant::now();
+            let error = socket.recv(&mut [0; 8]).unwrap_err();
+            assert!(matches!(
+                error.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ));
+            // only a blocking socket waits for the timeout.
+            assert_eq!(blocking, start.elapsed() >= Duration::from_millis(40));
+        }
+
+        let server_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = server_socket.local_addr().unwrap().port();
+        StatsdBuilder::from("127.0.0.1", port)
+            .with_blocking_socket()
+            .send_once(None, MetricType::Counter, "requests", 1.0, &[])
+            .expect("should send over a blocking socket");
+        let mut buf = [0; 64];
+        let len = server_socket.recv(&mut buf).unwrap();
+        assert_eq!(b"requests:1|c\n", &buf[..len]);
     }
 
     #[test]
-    #[should_panic]
-    fn bad_port() {
-        StatsdBuilder::from("127.0.0.1", 0)
+    fn client_port_range() {
+        let taken = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let taken_port = taken.local_addr().unwrap().port();
+        let (_, builder) = Environ::setup();
+        let result = builder
+            .with_client_udp_host("127.0.0.1")
+            .with_client_port_range(taken_port..=taken_port)
+            .build(None);
+        assert!(matches!(
+            result,
+            Err(StatsdError::Bind { addr, .. }) if addr == format!("127.0.0.1:{}", taken_port)
+        ));
+
+        let free_port = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let (server_socket, builder) = Environ::setup();
+        let recorder = builder
+            .with_client_udp_host("127.0.0.1")
+            .with_client_port_range(free_port..=free_port)
             .build(None)
-            .expect("this should panic");
+            .unwrap();
+        recorder
+            .register_counter(&Key::from_name("counter.name"), &METADATA)
+            .increment(1);
+        let mut buf = [0; 100];
+        let (_, from) = server_socket.recv_from(&mut buf).unwrap();
+        assert_eq!(free_port, from.port());
     }
 
     #[test]
@@ -397,6 +2598,18 @@ mod tests {
         assert_eq!("gauge.name:50.25|g", env.receive_on_server());
     }
 
+    #[test]
+    fn integral_gauge() {
+        let env = Environ::new(None);
+        let gauge = env
+            .recorder
+            .register_gauge(&Key::from_name("gauge.name"), &METADATA);
+        gauge.set(50.0);
+        assert_eq!("gauge.name:50|g", env.receive_on_server());
+        gauge.set(-0.0);
+        assert_eq!("gauge.name:0|g", env.receive_on_server());
+    }
+
     #[test]
     fn gauge_with_tags() {
         let env = Environ::new(None);
@@ -526,6 +2739,17 @@ mod tests {
         assert_eq!("histogram.name:100|d|#t1:v1,t2:v2", env.receive_on_server());
     }
 
+    #[test]
+    fn histogram_hint_overrides_default() {
+        let env = Environ::new_histogram_is_timer();
+        let tags = vec![Label::new("histogram", "distribution")];
+        let key = Key::from(("histogram.name", tags));
+
+        let histogram = env.recorder.register_histogram(&key, &METADATA);
+        histogram.record(1.0);
+        assert_eq!("histogram.name:1|d", env.receive_on_server());
+    }
+
     #[test]
     fn default_histogram_to_timer() {
         let env = Environ::new_histogram_is_timer();
@@ -603,4 +2827,677 @@ mod tests {
         let guard = s.lock().unwrap();
         assert_eq!(guard.as_str(), "example_app.counter.name:1|c\n");
     }
+
+    #[test]
+    fn sink_wrapper() {
+        struct TaggingSink(InnerSink, &'static str);
+
+        impl MetricSink for TaggingSink {
+            fn emit(&self, metric: &str) -> io::Result<usize> {
+                self.0.emit(&format!("{}{}", metric, self.1))
+            }
+        }
+
+        let (server_socket, builder) = Environ::setup();
+        let recorder = builder
+            .with_sink_wrapper(|sink| TaggingSink(sink, ",inner:1"))
+            .with_sink_wrapper(|sink| TaggingSink(sink, "|#outer:1"))
+            .build(None)
+            .expect("test env should build a valid recorder");
+        let env = Environ {
+            server_socket,
+            recorder,
+        };
+
+        let counter = env
+            .recorder
+            .register_counter(&Key::from_name("counter.name"), &METADATA);
+        counter.increment(1);
+        assert_eq!("counter.name:1|c|#outer:1,inner:1", env.receive_on_server());
+    }
+
+    #[test]
+    fn clone() {
+        let env = Environ::new(Some("prefix"));
+        let clone = env.recorder.clone();
+
+        let key = Key::from_name("counter.name");
+        let counter = clone.register_counter(&key, &METADATA);
+        counter.increment(1);
+        assert_eq!("prefix.counter.name:1|c", env.receive_on_server());
+
+        metrics::with_local_recorder(&clone, || metrics::counter!("counter.name").increment(2));
+        assert_eq!("prefix.counter.name:2|c", env.receive_on_server());
+        assert!(Arc::ptr_eq(&env.recorder.registry, &clone.registry));
+    }
+
+    #[test]
+    fn unit_suffixes() {
+        let sink = crate::testing::FakeSink::new();
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(sink.clone())
+            .with_unit_suffixes()
+            .build(Some("prefix"))
+            .expect("should build a recorder with custom sink");
+
+        let unit = Some(metrics::Unit::Seconds);
+        recorder.describe_histogram("request.duration".into(), unit, "".into());
+        recorder.describe_histogram("timer.duration".into(), unit, "".into());
+        recorder.describe_gauge("memory.bytes".into(), Some(metrics::Unit::Bytes), "".into());
+        recorder.describe_gauge("counter.name".into(), Some(metrics::Unit::Count), "".into());
+
+        let key = Key::from_name("request.duration");
+        recorder.register_histogram(&key, &METADATA).record(1.5);
+        let key = Key::from(("timer.duration", vec![Label::new("histogram", "timer")]));
+        recorder.register_histogram(&key, &METADATA).record(1.5);
+        let key = Key::from_name("memory.bytes");
+        recorder.register_gauge(&key, &METADATA).set(10.0);
+        // described as a gauge only.
+        let key = Key::from_name("counter.name");
+        recorder.register_counter(&key, &METADATA).increment(1);
+
+        assert_eq!(
+            vec![
+                "prefix.request.duration.seconds:1.5|h",
+                "prefix.timer.duration:1500|ms",
+                "prefix.memory.bytes:10|g",
+                "prefix.counter.name:1|c",
+            ],
+            sink.lines()
+        );
+    }
+
+    #[test]
+    fn scoped() {
+        let sink = crate::testing::FakeSink::new();
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(sink.clone())
+            .with_default_tag("app_name", "test")
+            .build(Some("prefix"))
+            .expect("should build a recorder with custom sink");
+        let db = recorder.scoped("db", [("pool", "primary")]);
+        let replica = db.scoped("replica.", [("pool", "replica")]);
+
+        let key = Key::from(("queries", vec![Label::new("t1", "v1")]));
+        recorder.register_counter(&key, &METADATA).increment(1);
+        db.register_counter(&key, &METADATA).increment(2);
+        replica.register_counter(&key, &METADATA).increment(3);
+
+        assert_eq!(
+            vec![
+                "prefix.queries:1|c|#app_name:test,t1:v1",
+                "prefix.db.queries:2|c|#app_name:test,pool:primary,t1:v1",
+                "prefix.db.replica.queries:3|c|#app_name:test,pool:replica,t1:v1",
+            ],
+            sink.lines()
+        );
+    }
+
+    #[test]
+    fn queue_depth() {
+        let env = Environ::new(None);
+        assert_eq!(Some(0), env.recorder.handle().queue_depth());
+
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(cadence::NopMetricSink)
+            .build(None)
+            .expect("should build a recorder with custom sink");
+        assert_eq!(None, recorder.handle().queue_depth());
+    }
+
+    #[test]
+    fn pause_and_resume() {
+        let sink = crate::testing::FakeSink::new();
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(sink.clone())
+            .build(None)
+            .expect("should build a recorder with custom sink");
+        let handle = recorder.handle();
+        let counter = recorder.register_counter(&Key::from_name("requests"), &METADATA);
+        counter.increment(1);
+
+        handle.pause();
+        assert!(handle.is_paused());
+        counter.increment(2);
+        recorder
+            .scoped("db", [("pool", "primary")])
+            .register_gauge(&Key::from_name("connections"), &METADATA)
+            .set(3.0);
+        crate::StatsdExt::record_set_member(&handle, &Key::from_name("users"), "user-42");
+
+        handle.resume();
+        counter.increment(4);
+        assert_eq!(vec!["requests:1|c", "requests:4|c"], sink.lines());
+    }
+
+    #[test]
+    fn external_data() {
+        let sink = crate::testing::FakeSink::new();
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(sink.clone())
+            .with_external_data("it-false,cn-app,pu-1234")
+            .with_default_tag("env", "prod")
+            .build(Some("app"))
+            .expect("should build a recorder with custom sink");
+        recorder
+            .register_counter(&Key::from_name("requests"), &METADATA)
+            .increment(1);
+        assert_eq!(
+            vec!["app.requests:1|c|#env:prod|e:it-false,cn-app,pu-1234"],
+            sink.lines()
+        );
+
+        let builder = StatsdBuilder::from("", 0).with_external_data("|");
+        assert!(builder.external_data.is_none());
+    }
+
+    #[test]
+    fn prefix_from_env() {
+        let lookup = |var: &str| (var == "METRICS_PREFIX").then(|| "payments".to_string());
+        let builder = StatsdBuilder::from("", 0);
+        assert_eq!("", builder.env_prefix(lookup));
+        let builder = builder.with_prefix_from_env("METRICS_PREFIX");
+        assert_eq!("payments", builder.env_prefix(lookup));
+        assert_eq!("", builder.env_prefix(|_| None));
+
+        // an unset variable leaves the prefix given to `build` alone.
+        let sink = crate::testing::FakeSink::new();
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(sink.clone())
+            .with_prefix_from_env(format!("STATSD_TEST_PREFIX_{}", std::process::id()))
+            .build(Some("checkout"))
+            .expect("should build a recorder with custom sink");
+        recorder
+            .register_counter(&Key::from_name("orders"), &METADATA)
+            .increment(1);
+        assert_eq!(vec!["checkout.orders:1|c"], sink.lines());
+    }
+
+    #[test]
+    fn max_name_len() {
+        let sink = crate::testing::FakeSink::new();
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(sink.clone())
+            .with_max_name_len(20)
+            .build(Some("app"))
+            .expect("should build a recorder with custom sink");
+        for name in [
+            "requests",
+            "requests.by_endpoint.users",
+            "requests.by_endpoint.orders",
+        ] {
+            recorder
+                .register_counter(&Key::from_name(name), &METADATA)
+                .increment(1);
+        }
+
+        let lines = sink.lines();
+        assert_eq!("app.requests:1|c", lines[0]);
+        let names: Vec<&str> = lines[1..]
+            .iter()
+            .map(|line| line.split(':').next().unwrap())
+            .collect();
+        assert!(names.iter().all(|name| name.len() == 20));
+        assert!(names.iter().all(|name| name.starts_with("app.request_")));
+        assert_ne!(names[0], names[1]);
+    }
+
+    #[test]
+    fn rounding() {
+        let sink = crate::testing::FakeSink::new();
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(sink.clone())
+            .with_rounding(MetricType::Timer, 0.1)
+            .with_rounding(MetricType::Distribution, 5.0)
+            .build(None)
+            .expect("should build a recorder with custom sink");
+        let labels = vec![Label::new("histogram", "timer")];
+        let timer = recorder.register_histogram(&Key::from_parts("latency", labels), &METADATA);
+        timer.record(0.00123456);
+        timer.record(0.00126);
+        let labels = vec![Label::new("histogram", "distribution")];
+        recorder
+            .register_histogram(&Key::from_parts("size", labels), &METADATA)
+            .record(512.0);
+        recorder
+            .register_gauge(&Key::from_name("ratio"), &METADATA)
+            .set(0.123);
+        crate::StatsdExt::record_distribution(&recorder, &Key::from_name("payload"), 1023.0);
+
+        assert_eq!(
+            vec![
+                "latency:1.2|ms",
+                "latency:1.3|ms",
+                "size:510|d",
+                "ratio:0.123|g",
+                "payload:1025|d",
+            ],
+            sink.lines()
+        );
+    }
+
+    #[test]
+    fn preregister() {
+        struct Quiet;
+
+        impl PipelineStage for Quiet {
+            fn register(&self, metric: &mut crate::PipelineMetric) -> bool {
+                !metric.name().starts_with("noisy.")
+            }
+        }
+
+        let sink = crate::testing::FakeSink::new();
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(sink.clone())
+            .with_stage(Quiet)
+            .with_strict_validation()
+            .build(Some("app"))
+            .expect("should build a recorder with custom sink");
+        let dropped = recorder.preregister(&[
+            crate::MetricSpec::counter("requests"),
+            crate::MetricSpec::gauge("noisy.gauge"),
+            crate::MetricSpec::histogram("latency|ms"),
+        ]);
+        let dropped: Vec<&str> = dropped.iter().map(|spec| spec.key.name()).collect();
+        assert_eq!(vec!["noisy.gauge", "latency|ms"], dropped);
+        // the handles of the dropped metrics are kept too, so that they're skipped cheaply.
+        assert_eq!(3, recorder.registry.len());
+        // the handles are the ones registered ahead of time.
+        recorder
+            .register_counter(&Key::from_name("requests"), &METADATA)
+            .increment(1);
+        assert_eq!(3, recorder.registry.len());
+        assert_eq!(vec!["app.requests:1|c"], sink.lines());
+    }
+
+    #[test]
+    fn recent_lines() {
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(cadence::NopMetricSink)
+            .with_recent_lines(2)
+            .build(Some("example_app"))
+            .expect("should build a recorder with custom sink");
+        let handle = recorder.handle();
+
+        let counter = recorder.register_counter(&Key::from_name("counter.name"), &METADATA);
+        let gauge = recorder.register_gauge(&Key::from_name("gauge.name"), &METADATA);
+        counter.increment(1);
+        counter.increment(2);
+        gauge.set(3.0);
+
+        assert_eq!(
+            vec!["example_app.counter.name:2|c", "example_app.gauge.name:3|g"],
+            handle.recent_lines()
+        );
+    }
+
+    #[test]
+    fn descriptions() {
+        let env = Environ::new(None);
+        env.recorder.describe_histogram(
+            "request.duration".into(),
+            Some(metrics::Unit::Seconds),
+            "time spent serving a request".into(),
+        );
+        env.recorder
+            .describe_counter("request.count".into(), None, "requests served".into());
+        env.recorder
+            .describe_counter("request.count".into(), None, "number of requests".into());
+
+        let descriptions = env.recorder.handle().descriptions();
+        assert_eq!(
+            vec![
+                MetricDescription {
+                    name: "request.count".to_string(),
+                    kind: DescribedKind::Counter,
+                    unit: None,
+                    description: "number of requests".to_string(),
+                },
+                MetricDescription {
+                    name: "request.duration".to_string(),
+                    kind: DescribedKind::Histogram,
+                    unit: Some(metrics::Unit::Seconds),
+                    description: "time spent serving a request".to_string(),
+                },
+            ],
+            descriptions
+        );
+    }
+
+    #[test]
+    fn description_mismatches() {
+        let logged = Arc::new(Mutex::new(Vec::new()));
+        let log = logged.clone();
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(cadence::NopMetricSink)
+            .with_log(move |line| log.lock().unwrap().push(line.to_string()))
+            .build(None)
+            .expect("should build a recorder with custom sink");
+        let describe = |unit, description: &'static str| {
+            recorder.describe_histogram("request.duration".into(), unit, description.into())
+        };
+        describe(Some(metrics::Unit::Seconds), "time spent");
+        describe(Some(metrics::Unit::Seconds), "time spent");
+        describe(Some(metrics::Unit::Milliseconds), "time spent serving");
+        describe(None, "time spent");
+        recorder.describe_counter("request.duration".into(), None, "requests".into());
+        assert_eq!(
+            vec![
+                "histogram request.duration is described with another unit milliseconds, it was \
+                 seconds and description \"time spent serving\", it was \"time spent\""
+            ],
+            *logged.lock().unwrap()
+        );
+        assert_eq!(None, recorder.descriptions()[1].unit);
+    }
+
+    #[test]
+    fn descriptions_json() {
+        let path = std::env::temp_dir().join(format!("statsd-catalog-{}.json", std::process::id()));
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(cadence::NopMetricSink)
+            .with_descriptions_file(&path)
+            .build(None)
+            .expect("should build a recorder with custom sink");
+        recorder.describe_histogram(
+            "request.duration".into(),
+            Some(metrics::Unit::Seconds),
+            "time spent \"serving\"\na request".into(),
+        );
+        recorder.describe_counter("request.count".into(), None, "requests".into());
+        for labels in [
+            vec![Label::new("status", "ok"), Label::new("method", "GET")],
+            vec![
+                Label::new("status", "error"),
+                Label::new("histogram", "timer"),
+            ],
+        ] {
+            let key = Key::from_parts("request.duration", labels);
+            recorder.register_histogram(&key, &METADATA).record(1.0);
+        }
+
+        let expected = concat!(
+            r#"[{"name":"request.count","type":"counter","unit":null,"description":"requests","#,
+            r#""tags":[]},{"name":"request.duration","type":"histogram","unit":"seconds","#,
+            r#""description":"time spent \"serving\"\na request","tags":["method","status"]}]"#,
+        );
+        assert_eq!(expected, recorder.descriptions_json());
+        recorder.handle().shutdown();
+        assert_eq!(expected, std::fs::read_to_string(&path).unwrap());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn context_tags() {
+        thread_local! {
+            static TENANT: std::cell::Cell<Option<&'static str>> = const { std::cell::Cell::new(None) };
+        }
+
+        let (server_socket, builder) = Environ::setup();
+        let recorder = builder
+            .with_default_tag("app_name", "test")
+            .with_context_tags(|tags| {
+                if let Some(tenant) = TENANT.with(|t| t.get()) {
+                    tags.add("tenant", tenant);
+                }
+            })
+            .build(None)
+            .expect("test env should build a valid recorder");
+        let env = Environ {
+            server_socket,
+            recorder,
+        };
+
+        let counter = env
+            .recorder
+            .register_counter(&Key::from_name("counter.name"), &METADATA);
+        TENANT.with(|t| t.set(Some("acme")));
+        counter.increment(1);
+        assert_eq!(
+            "counter.name:1|c|#app_name:test,tenant:acme",
+            env.receive_on_server()
+        );
+
+        TENANT.with(|t| t.set(None));
+        counter.increment(1);
+        assert_eq!("counter.name:1|c|#app_name:test", env.receive_on_server());
+    }
+
+    #[test]
+    fn dynamic_labels() {
+        let sink = crate::testing::FakeSink::new();
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(sink.clone())
+            .with_context_tags(|tags| tags.add("first", "1"))
+            .with_dynamic_labels(|| {
+                let thread = std::thread::current();
+                thread
+                    .name()
+                    .map(|name| Label::new("thread", name.to_string()))
+            })
+            .build(None)
+            .expect("should build a recorder with custom sink");
+
+        let key = Key::from(("counter.name", vec![Label::new("t1", "v1")]));
+        let counter = recorder.register_counter(&key, &METADATA);
+        std::thread::Builder::new()
+            .name("worker".to_string())
+            .spawn(move || counter.increment(1))
+            .unwrap()
+            .join()
+            .unwrap();
+
+        assert_eq!(
+            vec!["counter.name:1|c|#t1:v1,first:1,thread:worker"],
+            sink.lines()
+        );
+    }
+
+    #[test]
+    fn statsd_type_label() {
+        let sink = crate::testing::FakeSink::new();
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(sink.clone())
+            .build(None)
+            .unwrap();
+        let key = |name, statsd_type| {
+            Key::from((
+                name,
+                vec![
+                    Label::new("statsd_type", statsd_type),
+                    Label::new("histogram", "distribution"),
+                    Label::new("t", "v"),
+                ],
+            ))
+        };
+
+        recorder
+            .register_counter(&key("meter", "m"), &METADATA)
+            .increment(1);
+        recorder
+            .register_gauge(&key("counter", "c"), &METADATA)
+            .set(2.0);
+        recorder
+            .register_histogram(&key("timer", "ms"), &METADATA)
+            .record(0.003);
+        recorder
+            .register_histogram(&key("unknown", "x"), &METADATA)
+            .record(4.0);
+        crate::StatsdExt::record_distribution(&recorder, &key("gauge", "g"), 5.0);
+        assert_eq!(
+            vec![
+                "meter:1|m|#t:v",
+                "counter:2|c|#t:v",
+                "timer:3|ms|#t:v",
+                "unknown:4|d|#t:v",
+                "gauge:5|g|#t:v",
+            ],
+            sink.lines()
+        );
+    }
+
+    #[test]
+    fn cloned_builder() {
+        struct TaggingSink(InnerSink, &'static str);
+
+        impl MetricSink for TaggingSink {
+            fn emit(&self, metric: &str) -> io::Result<usize> {
+                self.0.emit(&format!("{}{}", metric, self.1))
+            }
+        }
+
+        let first = crate::testing::FakeSink::new();
+        let second = crate::testing::FakeSink::new();
+        let common = StatsdBuilder::from("", 0)
+            .with_default_tag("service", "checkout")
+            .with_sink_wrapper(|sink| TaggingSink(sink, ",wrapped:1"));
+        let recorders = [
+            common.clone().with_sink(first.clone()).build(Some("a")),
+            common.with_sink(second.clone()).build(Some("b")),
+        ]
+        .map(|recorder| recorder.expect("should build a recorder with custom sink"));
+        for recorder in &recorders {
+            recorder
+                .register_counter(&Key::from_name("requests"), &METADATA)
+                .increment(1);
+        }
+        assert_eq!(
+            vec!["a.requests:1|c|#service:checkout,wrapped:1"],
+            first.lines()
+        );
+        assert_eq!(
+            vec!["b.requests:1|c|#service:checkout,wrapped:1"],
+            second.lines()
+        );
+    }
+
+    #[test]
+    fn send_once() {
+        let sink = crate::testing::FakeSink::new();
+        StatsdBuilder::from("", 0)
+            .with_sink(sink.clone())
+            .with_default_tag("host", "a")
+            .send_once(
+                Some("backup"),
+                MetricType::Counter,
+                "runs",
+                1.0,
+                &[("status", "success")],
+            )
+            .expect("should send a single metric");
+        assert_eq!(vec!["backup.runs:1|c|#host:a,status:success"], sink.lines());
+
+        let server_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = server_socket.local_addr().unwrap().port();
+        StatsdBuilder::from("127.0.0.1", port)
+            .send_once(None, MetricType::Gauge, "threads", 4.0, &[])
+            .expect("should send a single metric over udp");
+        let mut buf = [0; 64];
+        let len = server_socket.recv(&mut buf).unwrap();
+        assert_eq!(b"threads:4|g\n", &buf[..len]);
+
+        let result = StatsdBuilder::from("", 0)
+            .with_sink(sink)
+            .with_value_bounds(1e-6, 1e12, ValuePolicy::Drop)
+            .send_once(None, MetricType::Gauge, "huge", 1e20, &[]);
+        assert!(matches!(
+            result,
+            Err(StatsdError::Dropped {
+                reason: DropReason::InvalidValue
+            })
+        ));
+    }
+
+    #[test]
+    fn idle_timeout() {
+        let clock = crate::testing::ManualClock::new();
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(cadence::NopMetricSink)
+            .with_clock(clock.clone())
+            .with_idle_timeout(Duration::from_secs(60))
+            .build(None)
+            .expect("should build a recorder with custom sink");
+        let scoped = recorder.scoped("scoped", [("deploy", "v1")]);
+        let key = Key::from_name("requests");
+        recorder.register_counter(&key, &METADATA).increment(1);
+        scoped.register_counter(&key, &METADATA).increment(1);
+        assert_eq!((1, 1), (recorder.registry.len(), scoped.registry.len()));
+
+        // the first sweep finds it was registered since it was created, the second one evicts it.
+        for _ in 0..2 {
+            clock.advance(Duration::from_secs(60));
+            recorder.shared.run_pending();
+        }
+        assert_eq!((0, 0), (recorder.registry.len(), scoped.registry.len()));
+    }
+
+    #[test]
+    fn dual_emit() {
+        let sink = crate::testing::FakeSink::new();
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(sink.clone())
+            .histogram_is_timer()
+            .with_dual_emit("http.*", MetricType::Distribution)
+            .with_dual_emit("db.*", MetricType::Gauge)
+            .build(None)
+            .expect("should build a recorder with custom sink");
+        recorder
+            .register_histogram(&Key::from_name("http.duration"), &METADATA)
+            .record(0.25);
+        recorder
+            .register_histogram(&Key::from_name("db.duration"), &METADATA)
+            .record(0.5);
+        recorder
+            .register_counter(&Key::from_name("http.requests"), &METADATA)
+            .increment(1);
+        assert_eq!(
+            vec![
+                "http.duration:250|ms",
+                "http.duration:250|d",
+                "db.duration:500|ms",
+                "http.requests:1|c",
+            ],
+            sink.lines()
+        );
+    }
+
+    #[test]
+    fn dropped_metrics_from_failing_sink() {
+        struct FailingSink;
+
+        impl MetricSink for FailingSink {
+            fn emit(&self, _metric: &str) -> io::Result<usize> {
+                Err(io::Error::other("nope"))
+            }
+        }
+
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(FailingSink)
+            .build(None)
+            .expect("should build a recorder with custom sink");
+        let handle = recorder.handle();
+
+        let key = Key::from_name("counter.name");
+        let counter = recorder.register_counter(&key, &METADATA);
+        counter.increment(1);
+        counter.increment(1);
+
+        let dropped = handle.dropped_metrics();
+        assert_eq!(2, dropped.get(DropReason::SendError));
+        assert_eq!(2, dropped.total());
+    }
+
+    #[test]
+    fn dropped_metrics_oversize() {
+        let env = Environ::new(None);
+        let handle = env.recorder.handle();
+
+        let key = Key::from_name("a".repeat(MAX_UDP_PAYLOAD));
+        let counter = env.recorder.register_counter(&key, &METADATA);
+        counter.increment(1);
+
+        let dropped = handle.dropped_metrics();
+        assert_eq!(1, dropped.get(DropReason::Oversize));
+        assert_eq!(0, dropped.get(DropReason::QueueFull));
+    }
 }
@@ -0,0 +1,313 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::{RwLock, Weak};
+use std::time::Duration;
+use std::{fmt, fs};
+
+use metrics::{KeyName, SharedString, Unit};
+
+use crate::recorder::Handle;
+use crate::registry::Registries;
+use crate::telemetry::LogFn;
+use crate::upkeep::Upkeep;
+
+/// The kind of metric a description was given for, i.e. which `describe_*` method was called.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum DescribedKind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+impl DescribedKind {
+    /// Lower case name of the kind, e.g. `counter`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DescribedKind::Counter => "counter",
+            DescribedKind::Gauge => "gauge",
+            DescribedKind::Histogram => "histogram",
+        }
+    }
+}
+
+impl fmt::Display for DescribedKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Everything that was supplied through one of the `describe_*` macros for a metric.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MetricDescription {
+    /// Name of the metric, without the prefix.
+    pub name: String,
+    /// Which `describe_*` method described the metric.
+    pub kind: DescribedKind,
+    /// The unit of the metric, if one was given.
+    pub unit: Option<Unit>,
+    /// Free form description of the metric.
+    pub description: String,
+}
+
+/// Registry of the descriptions given to a recorder, the latest description of a metric wins.
+#[derive(Debug, Default)]
+pub(crate) struct Catalog {
+    descriptions: RwLock<HashMap<(DescribedKind, String), MetricDescription>>,
+    /// The metrics whose descriptions disagreed, which are only reported once.
+    mismatched: RwLock<HashSet<(DescribedKind, String)>>,
+}
+
+impl Catalog {
+    /// Describe a metric, returns a line telling how the description disagrees with the one it
+    /// replaces, if it does, the first time the descriptions of the metric disagree.
+    pub(crate) fn describe(
+        &self,
+        kind: DescribedKind,
+        key: KeyName,
+        unit: Option<Unit>,
+        description: SharedString,
+    ) -> Option<String> {
+        let name = key.as_str().to_string();
+        let entry = MetricDescription {
+            name: name.clone(),
+            kind,
+            unit,
+            description: description.into_owned(),
+        };
+        let mut descriptions = self.descriptions.write().unwrap_or_else(|e| e.into_inner());
+        let previous = descriptions.insert((kind, name.clone()), entry.clone())?;
+        let mismatch = mismatch(&previous, &entry)?;
+        let mut mismatched = self.mismatched.write().unwrap_or_else(|e| e.into_inner());
+        mismatched.insert((kind, name)).then_some(mismatch)
+    }
+
+    /// The unit a metric was described with, if any.
+    pub(crate) fn unit(&self, kind: DescribedKind, name: &str) -> Option<Unit> {
+        let descriptions = self.descriptions.read().unwrap_or_else(|e| e.into_inner());
+        descriptions
+            .get(&(kind, name.to_string()))
+            .and_then(|description| description.unit)
+    }
+
+    /// All the descriptions, sorted by name and kind.
+    pub(crate) fn descriptions(&self) -> Vec<MetricDescription> {
+        let descriptions = self.descriptions.read().unwrap_or_else(|e| e.into_inner());
+        let mut all: Vec<_> = descriptions.values().cloned().collect();
+        all.sort_by(|a, b| (&a.name, a.kind).cmp(&(&b.name, b.kind)));
+        all
+    }
+}
+
+/// Render `descriptions` as a JSON array, with the tag keys each metric was registered with as
+/// found in `tags`, by name, see [`crate::StatsdHandle::descriptions_json`].
+pub(crate) fn to_json(
+    descriptions: &[MetricDescription],
+    tags: &HashMap<String, BTreeSet<String>>,
+) -> String {
+    let mut out = String::from("[");
+    for (i, description) in descriptions.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        out.push_str("{\"name\":");
+        push_json_string(&mut out, &description.name);
+        out.push_str(",\"type\":");
+        push_json_string(&mut out, description.kind.as_str());
+        out.push_str(",\"unit\":");
+        match description.unit {
+            Some(unit) => push_json_string(&mut out, unit.as_str()),
+            None => out.push_str("null"),
+        }
+        out.push_str(",\"description\":");
+        push_json_string(&mut out, &description.description);
+        out.push_str(",\"tags\":");
+        push_json_strings(&mut out, tags.get(&description.name).into_iter().flatten());
+        out.push('}');
+    }
+    out.push(']');
+    out
+}
+
+/// Periodically writes the metrics registered so far, as they're sent, to a file: their name,
+/// prefix included, their type and the keys of their tags, see
+/// [`StatsdBuilder::with_catalog_file`](crate::StatsdBuilder::with_catalog_file).
+pub(crate) struct CatalogWriter {
+    registries: Weak<Registries<Handle>>,
+    path: PathBuf,
+    log: Option<LogFn>,
+}
+
+impl CatalogWriter {
+    pub(crate) fn new(
+        registries: Weak<Registries<Handle>>,
+        path: PathBuf,
+        log: Option<LogFn>,
+    ) -> Self {
+        CatalogWriter {
+            registries,
+            path,
+            log,
+        }
+    }
+
+    /// Write on `interval` until the recorder goes away.
+    pub(crate) fn schedule(self, upkeep: &mut Upkeep, interval: Duration) {
+        upkeep.every(interval, move || match self.registries.upgrade() {
+            Some(registries) => {
+                if let Err(e) = self.write(&registries) {
+                    if let Some(log) = &self.log {
+                        log(&format!(
+                            "could not write the catalog to {}: {}",
+                            self.path.display(),
+                            e
+                        ));
+                    }
+                }
+                true
+            }
+            None => false,
+        });
+    }
+
+    /// Replace the file at once, so that it's never read half written.
+    fn write(&self, registries: &Registries<Handle>) -> std::io::Result<()> {
+        let mut written = self.path.clone().into_os_string();
+        written.push(".tmp");
+        fs::write(&written, observed_json(registries))?;
+        fs::rename(&written, &self.path)
+    }
+}
+
+/// The metrics registered in `registries` and not dropped, as a JSON array sorted by name and
+/// type.
+pub(crate) fn observed_json(registries: &Registries<Handle>) -> String {
+    let mut observed: BTreeMap<(String, &'static str), BTreeSet<String>> = BTreeMap::new();
+    registries.for_each(|handle| {
+        if let Some(tag_keys) = handle.tag_keys() {
+            let name = (handle.name().to_string(), handle.metric_type().code());
+            let keys = observed.entry(name).or_default();
+            keys.extend(tag_keys.map(str::to_string));
+        }
+    });
+    let mut out = String::from("[");
+    for (i, ((name, code), keys)) in observed.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        out.push_str("{\"name\":");
+        push_json_string(&mut out, name);
+        out.push_str(",\"type\":");
+        push_json_string(&mut out, code);
+        out.push_str(",\"tags\":");
+        push_json_strings(&mut out, keys);
+        out.push('}');
+    }
+    out.push(']');
+    out
+}
+
+/// Append `strings` to `out` as a JSON array.
+fn push_json_strings<'a>(out: &mut String, strings: impl IntoIterator<Item = &'a String>) {
+    out.push('[');
+    for (i, s) in strings.into_iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        push_json_string(out, s);
+    }
+    out.push(']');
+}
+
+/// Append `s` to `out` as a JSON string, quotes included.
+fn push_json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// How `current` disagrees with the `previous` description of the same metric, `None` when it
+/// doesn't.
+fn mismatch(previous: &MetricDescription, current: &MetricDescription) -> Option<String> {
+    let unit = |unit: Option<Unit>| unit.map_or("none", |unit| unit.as_str());
+    let mut differences = Vec::new();
+    if previous.unit != current.unit {
+        differences.push(format!(
+            "unit {}, it was {}",
+            unit(current.unit),
+            unit(previous.unit)
+        ));
+    }
+    if previous.description != current.description {
+        differences.push(format!(
+            "description {:?}, it was {:?}",
+            current.description, previous.description
+        ));
+    }
+    if differences.is_empty() {
+        return None;
+    }
+    Some(format!(
+        "{} {} is described with another {}",
+        current.kind,
+        current.name,
+        differences.join(" and ")
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics::{Key, Label, Recorder};
+
+    use super::*;
+    use crate::testing::METADATA;
+    use crate::StatsdBuilder;
+
+    #[test]
+    fn catalog_file() {
+        let path =
+            std::env::temp_dir().join(format!("statsd-observed-{}.json", std::process::id()));
+        let clock = crate::testing::ManualClock::new();
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(cadence::NopMetricSink)
+            .with_clock(clock.clone())
+            .with_catalog_file(&path, Duration::from_secs(60))
+            .with_default_tag("env", "prod")
+            .build(Some("app"))
+            .expect("should build a recorder with custom sink");
+        for labels in [
+            vec![Label::new("status", "ok")],
+            vec![
+                Label::new("method", "GET"),
+                Label::new("histogram", "timer"),
+            ],
+        ] {
+            let key = Key::from_parts("request.duration", labels);
+            recorder.register_histogram(&key, &METADATA).record(1.0);
+        }
+        recorder
+            .register_gauge(&Key::from_name("threads"), &METADATA)
+            .set(4.0);
+
+        clock.advance(Duration::from_secs(60));
+        recorder.shared.run_pending();
+        assert_eq!(
+            concat!(
+                r#"[{"name":"app.request.duration","type":"h","tags":["env","status"]},"#,
+                r#"{"name":"app.request.duration","type":"ms","tags":["env","method"]},"#,
+                r#"{"name":"app.threads","type":"g","tags":["env"]}]"#,
+            ),
+            std::fs::read_to_string(&path).unwrap()
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+}
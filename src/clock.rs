@@ -0,0 +1,40 @@
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+/// The source of time for everything the exporter does on its own schedule, e.g. reporting
+/// telemetry or flushing batches, and for the counter rates of [`StatsdHandle::snapshot`].
+///
+/// The exporter uses [`SystemClock`] unless another clock is given to
+/// [`StatsdBuilder::with_clock`]. Tests can use a
+/// [`ManualClock`](crate::testing::ManualClock) to control time instead of sleeping.
+///
+/// [`StatsdHandle::snapshot`]: crate::StatsdHandle::snapshot
+/// [`StatsdBuilder::with_clock`]: crate::StatsdBuilder::with_clock
+pub trait Clock: Send + Sync {
+    /// The current time.
+    fn now(&self) -> Instant;
+
+    /// Block the calling thread until [`Clock::now`] reaches `deadline`, returns right away if it
+    /// already has.
+    fn sleep_until(&self, deadline: Instant);
+}
+
+/// The wall clock, i.e. [`Instant::now`] and [`thread::sleep`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) {
+        let now = Instant::now();
+        if deadline > now {
+            thread::sleep(deadline - now);
+        }
+    }
+}
+
+pub(crate) type SharedClock = Arc<dyn Clock>;
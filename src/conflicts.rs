@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::types::MetricType;
+
+/// The types every name was registered as, to tell when a name is sent as several types, which
+/// backends don't handle well, e.g. a counter and a gauge with the same name.
+#[derive(Debug, Default)]
+pub(crate) struct MetricTypes {
+    /// The type a name was first registered as, followed by the other types it was registered as.
+    names: Mutex<HashMap<String, Vec<MetricType>>>,
+}
+
+/// A name registered as another type than the one it was first registered as.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Conflict {
+    /// The type the name was first registered as.
+    pub(crate) first: MetricType,
+    /// Whether the name wasn't registered as this type before.
+    pub(crate) new: bool,
+}
+
+impl MetricTypes {
+    /// Record that `name` is sent as `metric_type`, returns the conflict when it was first
+    /// registered as another type.
+    pub(crate) fn register(&self, name: &str, metric_type: MetricType) -> Option<Conflict> {
+        let mut names = self.names.lock().unwrap_or_else(|e| e.into_inner());
+        let types = match names.get_mut(name) {
+            Some(types) => types,
+            None => {
+                names.insert(name.to_string(), vec![metric_type]);
+                return None;
+            }
+        };
+        let first = types[0];
+        if first == metric_type {
+            return None;
+        }
+        let new = !types.contains(&metric_type);
+        if new {
+            types.push(metric_type);
+        }
+        Some(Conflict { first, new })
+    }
+}
+
+/// The line logged for a conflict, see [`StatsdBuilder::with_log`](crate::StatsdBuilder::with_log).
+pub(crate) fn describe(name: &str, metric_type: MetricType, first: MetricType) -> String {
+    format!(
+        "metric {} is registered as |{}, it was first registered as |{}",
+        name,
+        metric_type.code(),
+        first.code()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use metrics::{Key, Label, Recorder};
+
+    use super::*;
+    use crate::testing::METADATA;
+    use crate::{DropReason, StatsdBuilder};
+
+    #[test]
+    fn reports_conflicts_once() {
+        let types = MetricTypes::default();
+        assert_eq!(None, types.register("requests", MetricType::Counter));
+        assert_eq!(None, types.register("requests", MetricType::Counter));
+        assert_eq!(None, types.register("threads", MetricType::Gauge));
+        let conflict = |new| {
+            Some(Conflict {
+                first: MetricType::Counter,
+                new,
+            })
+        };
+        assert_eq!(
+            conflict(true),
+            types.register("requests", MetricType::Gauge)
+        );
+        assert_eq!(
+            conflict(false),
+            types.register("requests", MetricType::Gauge)
+        );
+        assert_eq!(conflict(true), types.register("requests", MetricType::Set));
+        assert_eq!(
+            "metric requests is registered as |g, it was first registered as |c",
+            describe("requests", MetricType::Gauge, MetricType::Counter)
+        );
+    }
+
+    #[test]
+    fn type_conflicts() {
+        let logged = Arc::new(Mutex::new(Vec::new()));
+        let log = logged.clone();
+        let sink = crate::testing::FakeSink::new();
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(sink.clone())
+            .with_log(move |line| log.lock().unwrap().push(line.to_string()))
+            .with_strict_validation()
+            .build(Some("app"))
+            .expect("should build a recorder with custom sink");
+        let requests = Key::from_name("requests");
+        recorder.register_counter(&requests, &METADATA).increment(1);
+        recorder.register_gauge(&requests, &METADATA).set(2.0);
+        let tagged = Key::from_parts("requests", vec![Label::new("status", "ok")]);
+        recorder.register_gauge(&tagged, &METADATA).set(3.0);
+        assert_eq!(vec!["app.requests:1|c"], sink.lines());
+        assert_eq!(
+            vec!["metric app.requests is registered as |g, it was first registered as |c"],
+            *logged.lock().unwrap()
+        );
+        let handle = recorder.handle();
+        assert_eq!(2, handle.dropped_metrics().get(DropReason::TypeConflict));
+
+        // without strict validation, the conflict is only logged.
+        let sink = crate::testing::FakeSink::new();
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(sink.clone())
+            .build(None)
+            .expect("should build a recorder with custom sink");
+        recorder.register_counter(&requests, &METADATA).increment(1);
+        recorder.register_gauge(&requests, &METADATA).set(2.0);
+        assert_eq!(vec!["requests:1|c", "requests:2|g"], sink.lines());
+    }
+}
@@ -0,0 +1,70 @@
+use std::os::raw::c_int;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, Once, Weak};
+use std::time::Duration;
+
+use crate::handle::Shared;
+
+extern "C" {
+    fn atexit(f: extern "C" fn()) -> c_int;
+}
+
+/// The recorders flushed when the process exits, along with how long to wait for each of them,
+/// see [`StatsdBuilder::with_exit_hook`](crate::StatsdBuilder::with_exit_hook).
+static RECORDERS: Mutex<Vec<(Weak<Shared>, Duration)>> = Mutex::new(Vec::new());
+static HOOK: Once = Once::new();
+
+/// Flush the recorder of `shared` when the process exits, if it's still around by then.
+pub(crate) fn register(shared: &Arc<Shared>, timeout: Duration) {
+    HOOK.call_once(|| {
+        // SAFETY: `flush` is a function without arguments that never unwinds. A single hook is
+        // registered, far from the 32 that every C library supports, so this doesn't fail.
+        unsafe { atexit(flush) };
+    });
+    let mut recorders = RECORDERS.lock().unwrap_or_else(|e| e.into_inner());
+    recorders.retain(|(shared, _)| shared.strong_count() > 0);
+    recorders.push((Arc::downgrade(shared), timeout));
+}
+
+/// Shut down every recorder still around, best effort: nothing must unwind out of an exit hook.
+pub(crate) extern "C" fn flush() {
+    let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+        let recorders = std::mem::take(&mut *RECORDERS.lock().unwrap_or_else(|e| e.into_inner()));
+        for (shared, timeout) in recorders {
+            if let Some(shared) = shared.upgrade() {
+                shared.shutdown(Some(timeout));
+            }
+        }
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics::{Key, Recorder};
+
+    use super::*;
+    use crate::testing::{Environ, METADATA};
+
+    #[test]
+    fn exit_hook_flushes_batches() {
+        let (server_socket, builder) = Environ::setup();
+        let recorder = builder
+            .with_thread_local_batching(100, Duration::from_secs(3600))
+            .with_exit_hook(Duration::from_secs(1))
+            .build(None)
+            .expect("test env should build a valid recorder");
+        let env = Environ {
+            server_socket,
+            recorder,
+        };
+
+        let counter = env
+            .recorder
+            .register_counter(&Key::from_name("counter.name"), &METADATA);
+        counter.increment(1);
+        // what the hook runs as the process exits.
+        flush();
+
+        assert_eq!("counter.name:1|c", env.receive_on_server());
+    }
+}
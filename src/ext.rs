@@ -0,0 +1,230 @@
+use std::time::Duration;
+
+use cadence::ext::MetricBackend;
+use cadence::StatsdClient;
+use metrics::Key;
+
+use crate::handle::{Scope, Shared};
+use crate::line::{ContextTags, Line, Value};
+use crate::pipeline::PipelineMetric;
+use crate::recorder::duration_to_millis;
+use crate::sampling;
+use crate::stats::DropReason;
+use crate::types::MetricType;
+use crate::{StatsdHandle, StatsdRecorder};
+
+/// Record the statsd metric types that don't map onto a [`metrics`] macro directly, without going
+/// through label hints.
+///
+/// Metrics recorded this way are prefixed, tagged and sampled like the ones recorded through
+/// [`metrics`], but they aren't registered: the name and tags are rendered on every call, which
+/// makes this a poor fit for the hottest paths.
+///
+/// ```
+/// use std::time::Duration;
+/// use metrics::Key;
+/// use metrics_exporter_statsd::{StatsdBuilder, StatsdExt};
+///
+/// let recorder = StatsdBuilder::from("127.0.0.1", 8125)
+///     .build(Some("prefix"))
+///     .expect("Could not create StatsdRecorder");
+/// let handle = recorder.handle();
+/// metrics::set_global_recorder(recorder);
+///
+/// handle.record_distribution(&Key::from_name("payload.size"), 512.0);
+/// handle.record_timer(&Key::from_name("request.duration"), Duration::from_millis(12));
+/// handle.record_set_member(&Key::from_name("users.unique"), "user-42");
+/// ```
+pub trait StatsdExt {
+    /// Send `value` as a distribution, i.e. `|d`.
+    fn record_distribution(&self, key: &Key, value: f64);
+
+    /// Send `duration` as a timer in milliseconds, i.e. `|ms`.
+    fn record_timer(&self, key: &Key, duration: Duration);
+
+    /// Send `member` as a member of a set, i.e. `|s`, which statsd uses to count unique values.
+    fn record_set_member(&self, key: &Key, member: &str);
+}
+
+impl StatsdExt for StatsdRecorder {
+    fn record_distribution(&self, key: &Key, value: f64) {
+        let value = self
+            .shared
+            .non_negative(value, MetricType::Distribution)
+            .and_then(|value| self.shared.bound(value))
+            .map(|value| self.shared.round(value, MetricType::Distribution));
+        if let Some(value) = value {
+            send(
+                &self.statsd,
+                &self.shared,
+                &self.scope,
+                key,
+                value,
+                MetricType::Distribution,
+            );
+        }
+    }
+
+    fn record_timer(&self, key: &Key, duration: Duration) {
+        let millis = self.shared.bound(duration_to_millis(duration));
+        if let Some(millis) = millis.map(|millis| self.shared.round(millis, MetricType::Timer)) {
+            send(
+                &self.statsd,
+                &self.shared,
+                &self.scope,
+                key,
+                millis,
+                MetricType::Timer,
+            );
+        }
+    }
+
+    fn record_set_member(&self, key: &Key, member: &str) {
+        send(
+            &self.statsd,
+            &self.shared,
+            &self.scope,
+            key,
+            member,
+            MetricType::Set,
+        );
+    }
+}
+
+/// Sends through the recorder the handle was obtained from, once that recorder has been installed.
+impl StatsdExt for StatsdHandle {
+    fn record_distribution(&self, key: &Key, value: f64) {
+        let value = self
+            .shared
+            .non_negative(value, MetricType::Distribution)
+            .and_then(|value| self.shared.bound(value))
+            .map(|value| self.shared.round(value, MetricType::Distribution));
+        if let (Some(statsd), Some(value)) = (self.statsd.upgrade(), value) {
+            send(
+                &statsd,
+                &self.shared,
+                &self.scope,
+                key,
+                value,
+                MetricType::Distribution,
+            );
+        }
+    }
+
+    fn record_timer(&self, key: &Key, duration: Duration) {
+        let millis = self
+            .shared
+            .bound(duration_to_millis(duration))
+            .map(|millis| self.shared.round(millis, MetricType::Timer));
+        if let (Some(statsd), Some(millis)) = (self.statsd.upgrade(), millis) {
+            send(
+                &statsd,
+                &self.shared,
+                &self.scope,
+                key,
+                millis,
+                MetricType::Timer,
+            );
+        }
+    }
+
+    fn record_set_member(&self, key: &Key, member: &str) {
+        if let Some(statsd) = self.statsd.upgrade() {
+            send(
+                &statsd,
+                &self.shared,
+                &self.scope,
+                key,
+                member,
+                MetricType::Set,
+            );
+        }
+    }
+}
+
+/// Send `value` as `metric_type`, without registering the metric.
+pub(crate) fn send<V: Value>(
+    statsd: &StatsdClient,
+    shared: &Shared,
+    scope: &Scope,
+    key: &Key,
+    value: V,
+    metric_type: MetricType,
+) {
+    if shared.paused() {
+        return;
+    }
+    let metric_type = MetricType::type_from(key).unwrap_or(metric_type);
+    let mut metric = PipelineMetric::new(key, metric_type, None);
+    if !shared.pipeline.register(&mut metric) {
+        return;
+    }
+    if let Some(value) = value.as_f64().filter(|_| shared.pipeline.records()) {
+        if !shared.pipeline.record(&metric, value) {
+            return;
+        }
+    }
+    if shared.strict && !(value.valid() && scope.valid(metric.name(), metric.labels().iter())) {
+        shared.stats.record_drop(DropReason::Malformed);
+        return;
+    }
+    let sample_rate = metric.sample_rate();
+    if sample_rate.is_some_and(|rate| !sampling::sampled(rate)) {
+        return;
+    }
+
+    let rendered = scope
+        .render(shared, metric.name(), metric.labels().iter())
+        .with_sample_rate(sample_rate);
+    // errors are accounted for by the sink, see `StatsdHandle::dropped_metrics`.
+    let context_tags = |tags: &mut ContextTags<'_>| shared.add_context_tags(tags);
+    // the type was picked explicitly by the caller, stages don't get to change it here.
+    let _ = rendered.with_line_and_tags(value, metric_type, context_tags, |line| {
+        statsd.send_metric(&Line(line))
+    });
+    shared.stats.record_emit(metric_type);
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics::Label;
+
+    use super::*;
+    use crate::testing::{FakeSink, MetricKind};
+    use crate::StatsdBuilder;
+
+    #[test]
+    fn records_through_recorder_and_handle() {
+        let sink = FakeSink::new();
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(sink.clone())
+            .with_default_tag("env", "test")
+            .build(Some("app"))
+            .unwrap();
+        let handle = recorder.handle();
+
+        let key = Key::from(("size", vec![Label::new("histogram", "timer")]));
+        recorder.record_distribution(&key, 1.5);
+        handle.record_timer(&Key::from_name("duration"), Duration::from_micros(2500));
+        handle.record_set_member(&Key::from_name("users"), "user-42");
+
+        assert_eq!(
+            vec![
+                "app.size:1.5|d|#env:test",
+                "app.duration:2.5|ms|#env:test",
+                "app.users:user-42|s|#env:test"
+            ],
+            sink.lines()
+        );
+        assert_eq!(
+            MetricKind::Set {
+                member: "user-42".to_string()
+            },
+            sink.emissions()[2].kind
+        );
+
+        drop(recorder);
+        handle.record_set_member(&Key::from_name("users"), "user-43");
+        assert_eq!(3, sink.lines().len());
+    }
+}
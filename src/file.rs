@@ -0,0 +1,181 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use cadence::{MetricSink, SinkStats};
+
+use crate::sink::SentStats;
+
+/// When the file given to [`StatsdBuilder::with_file_sink`](crate::StatsdBuilder::with_file_sink)
+/// is rotated, see
+/// [`StatsdBuilder::with_file_rotation`](crate::StatsdBuilder::with_file_rotation).
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Rotation {
+    pub(crate) max_bytes: u64,
+    /// How many rotated files are kept, as `<path>.1` for the most recent up to `<path>.<max_files>`.
+    pub(crate) max_files: usize,
+}
+
+/// Appends the metrics to a file, a line each, rotating it once it's too large.
+pub(crate) struct FileSink {
+    path: PathBuf,
+    rotation: Option<Rotation>,
+    file: Mutex<OpenFile>,
+    /// The bytes and lines written, a line counts as a packet.
+    sent: SentStats,
+}
+
+struct OpenFile {
+    file: File,
+    len: u64,
+}
+
+impl FileSink {
+    pub(crate) fn open(path: PathBuf, rotation: Option<Rotation>) -> io::Result<Self> {
+        let file = Mutex::new(open(&path)?);
+        Ok(FileSink {
+            path,
+            rotation,
+            file,
+            sent: SentStats::default(),
+        })
+    }
+
+    /// The path of the `n`th most recent rotated file.
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        PathBuf::from(path)
+    }
+
+    fn rotate(&self, open_file: &mut OpenFile, max_files: usize) -> io::Result<()> {
+        if max_files == 0 {
+            open_file.file.set_len(0)?;
+            open_file.len = 0;
+            return Ok(());
+        }
+        for n in (1..max_files).rev() {
+            match fs::rename(self.rotated(n), self.rotated(n + 1)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        fs::rename(&self.path, self.rotated(1))?;
+        *open_file = open(&self.path)?;
+        Ok(())
+    }
+
+    fn append(&self, open_file: &mut OpenFile, line: &[u8]) -> io::Result<()> {
+        if let Some(rotation) = self.rotation {
+            if open_file.len > 0 && open_file.len + line.len() as u64 > rotation.max_bytes {
+                self.rotate(open_file, rotation.max_files)?;
+            }
+        }
+        open_file.file.write_all(line)?;
+        open_file.len += line.len() as u64;
+        Ok(())
+    }
+}
+
+fn open(path: &Path) -> io::Result<OpenFile> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let len = file.metadata()?.len();
+    Ok(OpenFile { file, len })
+}
+
+impl MetricSink for FileSink {
+    fn emit(&self, metric: &str) -> io::Result<usize> {
+        let mut line = Vec::with_capacity(metric.len() + 1);
+        line.extend_from_slice(metric.as_bytes());
+        line.push(b'\n');
+
+        let lines = metric.split('\n').count() as u64;
+        let mut open_file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        match self.append(&mut open_file, &line) {
+            Ok(()) => {
+                self.sent.sent(line.len(), lines);
+                Ok(metric.len())
+            }
+            Err(e) => {
+                self.sent.dropped(line.len(), lines);
+                Err(e)
+            }
+        }
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.file
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .file
+            .flush()
+    }
+
+    fn stats(&self) -> SinkStats {
+        self.sent.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use metrics::{Key, Recorder};
+
+    use super::*;
+    use crate::testing::METADATA;
+    use crate::{StatsdBuilder, StatsdError};
+
+    #[test]
+    fn rotates_by_size() {
+        let dir = std::env::temp_dir().join(format!("statsd-file-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("metrics.log");
+        let rotation = Rotation {
+            max_bytes: 22,
+            max_files: 2,
+        };
+        let sink = FileSink::open(path.clone(), Some(rotation)).unwrap();
+        for n in 1..=7 {
+            sink.emit(&format!("metric:{}|c", n)).unwrap();
+        }
+
+        let read = |path: PathBuf| fs::read_to_string(path).unwrap_or_default();
+        assert_eq!("metric:7|c\n", read(path.clone()));
+        assert_eq!("metric:5|c\nmetric:6|c\n", read(sink.rotated(1)));
+        assert_eq!("metric:3|c\nmetric:4|c\n", read(sink.rotated(2)));
+        assert!(!sink.rotated(3).exists());
+        let sent = sink.stats();
+        assert_eq!((77, 7), (sent.bytes_sent, sent.packets_sent));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn file_sink() {
+        let path = std::env::temp_dir().join(format!("statsd-capture-{}.log", std::process::id()));
+        let recorder = StatsdBuilder::from("", 0)
+            .with_file_sink(&path)
+            .with_shutdown_timeout(Duration::from_secs(5))
+            .build(Some("app"))
+            .expect("should build a recorder writing to a file");
+        let handle = recorder.handle();
+        recorder
+            .register_counter(&Key::from_name("requests"), &METADATA)
+            .increment(1);
+        recorder
+            .register_gauge(&Key::from_name("threads"), &METADATA)
+            .set(4.0);
+        drop(recorder);
+        handle.shutdown();
+
+        let lines = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!("app.requests:1|c\napp.threads:4|g\n", lines);
+
+        let result = StatsdBuilder::from("", 0)
+            .with_file_sink(std::env::temp_dir().join("missing").join("statsd.log"))
+            .build(None);
+        assert!(matches!(result, Err(StatsdError::File { .. })));
+    }
+}
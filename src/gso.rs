@@ -0,0 +1,178 @@
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::os::fd::AsRawFd;
+use std::os::raw::{c_int, c_void};
+use std::sync::{Arc, Mutex};
+
+use cadence::{MetricSink, SinkStats};
+
+use crate::sink::{SentStats, MAX_UDP_PAYLOAD};
+use crate::stats::{DropReason, Stats};
+
+const SOL_UDP: c_int = 17;
+const UDP_SEGMENT: c_int = 103;
+/// Most segments the kernel takes in a single send.
+const MAX_SEGMENTS: usize = 64;
+
+extern "C" {
+    fn setsockopt(
+        socket: c_int,
+        level: c_int,
+        name: c_int,
+        value: *const c_void,
+        len: u32,
+    ) -> c_int;
+}
+
+/// Have the kernel split every send on `socket` larger than `segment_size` into datagrams of
+/// `segment_size` bytes, fails on kernels without UDP GSO.
+pub(crate) fn enable(socket: &UdpSocket, segment_size: usize) -> io::Result<()> {
+    let segment_size = c_int::try_from(segment_size)
+        .ok()
+        .filter(|size| *size > 0 && *size <= c_int::from(u16::MAX))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid GSO segment size"))?;
+    // SAFETY: the pointer and length describe `segment_size`, which outlives the call.
+    let result = unsafe {
+        setsockopt(
+            socket.as_raw_fd(),
+            SOL_UDP,
+            UDP_SEGMENT,
+            &segment_size as *const c_int as *const c_void,
+            std::mem::size_of::<c_int>() as u32,
+        )
+    };
+    match result {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Sends the packets of a [`PackingSink`](crate::packet::PackingSink) several at a time over a
+/// socket with GSO enabled, see [`enable`]: the packets are padded with newlines to the segment
+/// size, which statsd servers skip, so that each of them ends up in a datagram of its own.
+///
+/// Packets are sent once there's no room for another one, or on flush.
+pub(crate) struct GsoSink {
+    socket: UdpSocket,
+    addr: SocketAddr,
+    segment_size: usize,
+    max_len: usize,
+    buffer: Mutex<Vec<u8>>,
+    stats: Arc<Stats>,
+    /// The bytes and datagrams sent, padding included.
+    sent: SentStats,
+}
+
+impl GsoSink {
+    /// A sink sending to `addr` through `socket`, which GSO is enabled on with `segment_size`.
+    pub(crate) fn new(
+        socket: UdpSocket,
+        addr: SocketAddr,
+        segment_size: usize,
+        stats: Arc<Stats>,
+    ) -> Self {
+        let segments = (MAX_UDP_PAYLOAD / segment_size).clamp(1, MAX_SEGMENTS);
+        GsoSink {
+            socket,
+            addr,
+            segment_size,
+            max_len: segments * segment_size,
+            buffer: Mutex::new(Vec::with_capacity(segments * segment_size)),
+            stats,
+            sent: SentStats::default(),
+        }
+    }
+
+    /// Send the packets in `buffer`, counting their metrics as dropped when that fails.
+    fn send(&self, buffer: &mut Vec<u8>) {
+        if buffer.is_empty() {
+            return;
+        }
+        let datagrams = buffer.len().div_ceil(self.segment_size) as u64;
+        match self.socket.send_to(buffer, self.addr) {
+            Ok(sent) => self.sent.sent(sent, datagrams),
+            Err(e) => {
+                let lines = buffer.split(|b| *b == b'\n').filter(|l| !l.is_empty());
+                self.stats
+                    .record_drops(DropReason::SendError, lines.count() as u64);
+                self.stats.record_error(&e);
+                self.sent.dropped(buffer.len(), datagrams);
+            }
+        }
+        buffer.clear();
+    }
+}
+
+impl MetricSink for GsoSink {
+    fn emit(&self, packet: &str) -> io::Result<usize> {
+        // the kernel would split it across datagrams.
+        if packet.len() > self.segment_size {
+            self.stats
+                .record_drops(DropReason::Oversize, packet.split('\n').count() as u64);
+            self.sent.dropped(packet.len(), 1);
+            return Ok(packet.len());
+        }
+        let mut buffer = self.buffer.lock().unwrap_or_else(|e| e.into_inner());
+        // only the last segment of a send may be shorter.
+        let padded = buffer.len().next_multiple_of(self.segment_size);
+        if padded + packet.len() > self.max_len {
+            self.send(&mut buffer);
+        } else {
+            buffer.resize(padded, b'\n');
+        }
+        buffer.extend_from_slice(packet.as_bytes());
+        Ok(packet.len())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.send(&mut self.buffer.lock().unwrap_or_else(|e| e.into_inner()));
+        Ok(())
+    }
+
+    fn stats(&self) -> SinkStats {
+        self.sent.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn sends_a_datagram_per_packet() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        // kernels before 4.18, and some sandboxes, don't support GSO.
+        if enable(&socket, 16).is_err() {
+            return;
+        }
+        let stats = Arc::new(Stats::default());
+        let sink = GsoSink::new(socket, server.local_addr().unwrap(), 16, stats.clone());
+
+        sink.emit("a:1|c\nb:1|c").unwrap();
+        sink.emit("requests:12|c").unwrap();
+        sink.emit("much.too.long.for.a.segment:1|c").unwrap();
+        sink.emit("c:1|c").unwrap();
+        sink.flush().unwrap();
+
+        let mut datagrams = Vec::new();
+        let mut buf = [0; 64];
+        for _ in 0..3 {
+            let len = server.recv(&mut buf).unwrap();
+            datagrams.push(String::from_utf8_lossy(&buf[..len]).to_string());
+        }
+        assert_eq!(
+            vec!["a:1|c\nb:1|c\n\n\n\n\n", "requests:12|c\n\n\n", "c:1|c"],
+            datagrams
+        );
+        assert_eq!(1, stats.dropped().get(DropReason::Oversize));
+        let sent = sink.stats();
+        assert_eq!((37, 3), (sent.bytes_sent, sent.packets_sent));
+        assert_eq!((31, 1), (sent.bytes_dropped, sent.packets_dropped));
+    }
+}
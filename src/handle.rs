@@ -0,0 +1,585 @@
+use std::collections::{BTreeSet, HashMap};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use std::{fs, iter};
+
+use cadence::StatsdClient;
+use metrics::{Key, Label};
+
+use crate::allowed::AllowedValues;
+use crate::ambient::{self, TagGuard};
+use crate::catalog::{self, Catalog, MetricDescription};
+use crate::conflicts::MetricTypes;
+use crate::intern::Interner;
+use crate::line::{shorten_name, ContextTags, RenderedKey};
+use crate::mapping::LiveMapping;
+use crate::pipeline::Pipeline;
+use crate::quota::Quotas;
+use crate::rates::CounterRates;
+use crate::recorder::Handle;
+use crate::registry::Registries;
+use crate::sampling::SampleRateSemantics;
+use crate::sink::{QueueSink, RecentLines};
+use crate::sketch::Sketching;
+use crate::smoothing::Smoothing;
+use crate::snapshot::{self, ActiveKey, Activity, LastValue, LastValues, TopEmitter};
+use crate::stats::{DropReason, DroppedMetrics, Stats};
+use crate::strict;
+use crate::summary::Percentiles;
+use crate::telemetry::LogFn;
+use crate::types::{DualEmit, HistogramType, MetricType};
+use crate::upkeep::UpkeepThread;
+use crate::values::{ValueBounds, ValuePolicy};
+use crate::StatsdError;
+
+/// Adds the tags of the current context, see [`crate::StatsdBuilder::with_context_tags`].
+pub(crate) type ContextTagsFn = Arc<dyn Fn(&mut ContextTags<'_>) + Send + Sync>;
+
+/// State shared between a recorder and all of its handles.
+#[derive(Default)]
+pub(crate) struct Shared {
+    pub(crate) stats: Arc<Stats>,
+    /// The stages every metric goes through as it's registered, the mapping and the sample rates
+    /// included.
+    pub(crate) pipeline: Pipeline,
+    /// Tags added to every metric as it is recorded.
+    pub(crate) context_tags: Option<ContextTagsFn>,
+    /// Whether the described unit of a metric is appended to its name.
+    pub(crate) unit_suffixes: bool,
+    pub(crate) allowed_values: AllowedValues,
+    /// Most tags a metric is sent with, default tags included.
+    pub(crate) max_tags: Option<usize>,
+    /// Label keys that are kept first when a metric has too many tags.
+    pub(crate) tag_priority: Vec<String>,
+    /// Whether the default tags and the labels are sent sorted rather than in the given order.
+    pub(crate) sort_tags: bool,
+    /// Whether the metrics that would make lines the server can't parse are dropped.
+    pub(crate) strict: bool,
+    /// Longest name a metric is sent with, prefix included, longer ones are shortened.
+    pub(crate) max_name_len: Option<usize>,
+    /// Whether handles count the bytes they send, for the top series report.
+    pub(crate) count_bytes: bool,
+    pub(crate) sample_rate_semantics: SampleRateSemantics,
+    pub(crate) value_bounds: Option<ValueBounds>,
+    /// The granularity the values of each type are rounded to, as indexed by
+    /// [`MetricType::index`], zero when they aren't.
+    pub(crate) rounding: [f64; MetricType::ALL.len()],
+    /// What happens to negative histogram values, `None` to only drop the negative timers.
+    pub(crate) negative_values: Option<ValuePolicy>,
+    /// Whether the per-second rates of the counters are sent, and their counts.
+    pub(crate) counter_rates: Option<CounterRates>,
+    /// The quantiles sent for the histograms, `None` unless they're summarized on the client.
+    pub(crate) summaries: Option<Percentiles>,
+    /// Which distributions are sketched, `None` unless they're sketched on the client.
+    pub(crate) sketches: Option<Sketching>,
+    /// The significant digits of the timers and their quantiles, `None` unless they're kept in
+    /// HDR histograms on the client.
+    pub(crate) hdr_timers: Option<(u8, Percentiles)>,
+    /// The token buckets of the counters and gauges, `None` unless bursts are smoothed.
+    pub(crate) smoothing: Option<Smoothing>,
+    /// The histograms also sent as another type.
+    pub(crate) dual_emit: DualEmit,
+    /// The mapping stage of the pipeline, kept apart to reload it.
+    pub(crate) mapping: Arc<LiveMapping>,
+    pub(crate) queue: Option<Weak<QueueSink>>,
+    pub(crate) recent: Option<Arc<RecentLines>>,
+    /// Runs the periodic work, e.g. flushes and telemetry, `None` when there is none.
+    pub(crate) upkeep: Option<Arc<UpkeepThread>>,
+    pub(crate) catalog: Catalog,
+    /// Where the catalog is written on shutdown, see [`StatsdHandle::shutdown`].
+    pub(crate) descriptions_file: Option<PathBuf>,
+    pub(crate) last_values: Option<LastValues>,
+    /// Times the lines of every metric, `None` unless the active keys are tracked.
+    pub(crate) activity: Option<Activity>,
+    /// Most names [`StatsdHandle::top_emitters`] returns, `None` unless they're tracked.
+    pub(crate) top_emitters: Option<usize>,
+    /// Whether handles count the lines they send, for the active keys and the top emitters.
+    pub(crate) count_emits: bool,
+    pub(crate) interner: Arc<Interner>,
+    /// Every registry of the recorder, scoped ones included, to evict the idle handles.
+    pub(crate) registries: Arc<Registries<Handle>>,
+    /// The limits of the targets given a quota.
+    pub(crate) quotas: Quotas,
+    /// The types every name was registered as, to report the names registered as several.
+    pub(crate) types: MetricTypes,
+    /// Told about the problems of the exporter, e.g. the names registered as several types.
+    pub(crate) log: Option<LogFn>,
+    /// Whether the metrics are dropped rather than sent, see [`StatsdHandle::pause`].
+    pub(crate) paused: AtomicBool,
+}
+
+impl Shared {
+    /// See [`UpkeepThread::run_pending`].
+    #[cfg(test)]
+    pub(crate) fn run_pending(&self) {
+        if let Some(upkeep) = &self.upkeep {
+            upkeep.run_pending();
+        }
+    }
+
+    pub(crate) fn paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Every registered metric along with the lines it sent, sorted by name.
+    pub(crate) fn active_keys(&self) -> Vec<ActiveKey> {
+        let mut active_keys = Vec::new();
+        self.registries
+            .for_each(|handle| active_keys.extend(handle.active_key()));
+        active_keys.sort_by(|a, b| (&a.name, &a.key).cmp(&(&b.name, &b.key)));
+        active_keys
+    }
+
+    /// The names that sent the most lines.
+    pub(crate) fn top_emitters(&self) -> Vec<TopEmitter> {
+        let Some(count) = self.top_emitters else {
+            return Vec::new();
+        };
+        let mut emits = Vec::new();
+        self.registries
+            .for_each(|handle| emits.push((handle.name().to_string(), handle.emits())));
+        snapshot::top_emitters(emits.iter().map(|(name, n)| (name.as_str(), *n)), count)
+    }
+
+    /// See [`StatsdHandle::shutdown`], waits for the queue for `timeout` rather than for the
+    /// shutdown timeout when given one.
+    pub(crate) fn shutdown(&self, timeout: Option<Duration>) -> u64 {
+        if let Some(upkeep) = &self.upkeep {
+            upkeep.stop();
+        }
+        if let Some(path) = &self.descriptions_file {
+            let written = fs::write(path, self.descriptions_json());
+            if let (Err(e), Some(log)) = (written, &self.log) {
+                log(&format!(
+                    "could not write the descriptions to {}: {}",
+                    path.display(),
+                    e
+                ));
+            }
+        }
+        match (self.queue.as_ref().and_then(Weak::upgrade), timeout) {
+            (Some(queue), Some(timeout)) => queue.drain_for(timeout),
+            (Some(queue), None) => queue.drain(),
+            (None, _) => 0,
+        }
+    }
+
+    /// Add the tags of the context a metric is recorded in, after the default tags and the labels.
+    pub(crate) fn add_context_tags(&self, tags: &mut ContextTags<'_>) {
+        if let Some(context_tags) = &self.context_tags {
+            context_tags(tags);
+        }
+        ambient::add_tags(tags);
+    }
+
+    /// The descriptions as JSON, with the tag keys of the metrics registered so far.
+    pub(crate) fn descriptions_json(&self) -> String {
+        let mut tags: HashMap<String, BTreeSet<String>> = HashMap::new();
+        self.registries.for_each(|handle| {
+            let keys = handle.key().labels().map(Label::key).filter(|key| {
+                *key != HistogramType::HISTOGRAM_HINT && *key != MetricType::TYPE_LABEL
+            });
+            let name = handle.key().name();
+            for key in keys {
+                if !tags.get(name).is_some_and(|tags| tags.contains(key)) {
+                    let tags = tags.entry(name.to_string()).or_default();
+                    tags.insert(key.to_string());
+                }
+            }
+        });
+        catalog::to_json(&self.catalog.descriptions(), &tags)
+    }
+
+    /// `value` of a histogram sent as `metric_type` once the policy for negative values is
+    /// applied, `None` when it's dropped.
+    pub(crate) fn non_negative(&self, value: f64, metric_type: MetricType) -> Option<f64> {
+        if value >= 0.0 || value.is_nan() {
+            return Some(value);
+        }
+        match self.negative_values {
+            // a timer is a duration, which can't be negative.
+            None if metric_type == MetricType::Timer => None,
+            None | Some(ValuePolicy::PassThrough) => Some(value),
+            Some(ValuePolicy::Clamp) => Some(0.0),
+            Some(ValuePolicy::Drop) => {
+                self.stats.record_drop(DropReason::InvalidValue);
+                None
+            }
+        }
+    }
+
+    /// `value` once the value bounds are applied, `None` when it's dropped.
+    pub(crate) fn bound(&self, value: f64) -> Option<f64> {
+        let Some(bounds) = &self.value_bounds else {
+            return Some(value);
+        };
+        let value = bounds.apply(value);
+        if value.is_none() {
+            self.stats.record_drop(DropReason::InvalidValue);
+        }
+        value
+    }
+
+    /// `value` sent as `metric_type` rounded to the granularity of the type, if any.
+    pub(crate) fn round(&self, value: f64, metric_type: MetricType) -> f64 {
+        let granularity = self.rounding[metric_type.index()];
+        if granularity <= 0.0 || !value.is_finite() {
+            return value;
+        }
+        let rounded = (value / granularity).round() * granularity;
+        // e.g. 12 * 0.1 is 1.2000000000000002, keep as many decimals as the granularity has.
+        let decimals = (-granularity.log10()).ceil().clamp(0.0, 15.0);
+        let scale = 10f64.powf(decimals);
+        (rounded * scale).round() / scale
+    }
+
+    /// The labels a metric is sent with, once the values that aren't allowed are replaced and the
+    /// labels that don't fit along with `default_tags` default tags are dropped.
+    ///
+    /// The labels that are kept are those with a key in the priority list, in that order, then the
+    /// others in alphabetical order, and the first given of those that are the same. They keep the
+    /// order they were given in.
+    pub(crate) fn labels<'a>(
+        &self,
+        labels: impl Iterator<Item = &'a Label>,
+        default_tags: usize,
+    ) -> Vec<Label> {
+        let mut labels: Vec<_> = labels.map(|l| self.allowed_values.apply(l)).collect();
+        let room = match self.max_tags {
+            Some(max_tags) if labels.len() + default_tags > max_tags => {
+                max_tags.saturating_sub(default_tags)
+            }
+            _ => return labels,
+        };
+
+        let priority = |label: &Label| {
+            let priority = self.tag_priority.iter().position(|k| k == label.key());
+            priority.unwrap_or(usize::MAX)
+        };
+        // the sort is stable, the same labels are ranked in the order they were given in.
+        let mut ranked: Vec<usize> = (0..labels.len()).collect();
+        ranked.sort_by_key(|&i| (priority(&labels[i]), labels[i].key(), labels[i].value()));
+        let mut kept = vec![false; labels.len()];
+        for &i in ranked.iter().take(room) {
+            kept[i] = true;
+        }
+        let given = labels.len();
+        let mut kept = kept.into_iter();
+        labels.retain(|_| kept.next().unwrap_or(false));
+        self.stats
+            .record_dropped_tags((given - labels.len()) as u64);
+        labels
+    }
+}
+
+/// The prefix and tags of the metrics of a recorder, the only state that isn't shared with the
+/// recorders made by [`StatsdRecorder::scoped`](crate::StatsdRecorder::scoped).
+#[derive(Default)]
+pub(crate) struct Scope {
+    /// The prefix, formatted with its trailing dot.
+    pub(crate) prefix: String,
+    pub(crate) default_tags: Vec<(String, String)>,
+}
+
+impl Scope {
+    /// Whether a metric named `name` with the `labels` makes lines the server can parse, along
+    /// with the prefix and the default tags.
+    pub(crate) fn valid<'a>(
+        &self,
+        name: &str,
+        mut labels: impl Iterator<Item = &'a Label>,
+    ) -> bool {
+        (self.prefix.is_empty() || strict::valid_name(&self.prefix))
+            && strict::valid_name(name)
+            && self
+                .default_tags
+                .iter()
+                .all(|(key, value)| strict::valid_tag(key, value))
+            && labels.all(|label| strict::valid_tag(label.key(), label.value()))
+    }
+
+    /// Render the name and the tags of a metric registered in this scope.
+    pub(crate) fn render<'a>(
+        &self,
+        shared: &Shared,
+        name: &str,
+        labels: impl Iterator<Item = &'a Label>,
+    ) -> RenderedKey {
+        let labels = shared.labels(labels, self.default_tags.len());
+        let shortened;
+        let (prefix, name) = match shared.max_name_len {
+            Some(max_len) if self.prefix.len() + name.len() > max_len => {
+                shortened = shorten_name(&format!("{}{}", self.prefix, name), max_len);
+                ("", shortened.as_str())
+            }
+            _ => (self.prefix.as_str(), name),
+        };
+        if !shared.sort_tags {
+            return RenderedKey::new(
+                prefix,
+                name,
+                &self.default_tags,
+                labels.iter(),
+                &shared.interner,
+            );
+        }
+
+        let mut tags = self.default_tags.clone();
+        tags.extend(
+            labels
+                .iter()
+                .map(|l| (l.key().to_string(), l.value().to_string())),
+        );
+        tags.sort();
+        RenderedKey::new(prefix, name, &tags, iter::empty(), &shared.interner)
+    }
+}
+
+/// A cheaply cloneable handle to the state shared with a [`StatsdRecorder`].
+///
+/// The recorder itself is usually moved into [`metrics::set_global_recorder`], a handle should be
+/// obtained with [`StatsdRecorder::handle`] before that happens so that the application can still
+/// inspect the exporter afterwards.
+///
+/// [`StatsdRecorder`]: crate::StatsdRecorder
+/// [`StatsdRecorder::handle`]: crate::StatsdRecorder::handle
+#[derive(Clone)]
+pub struct StatsdHandle {
+    pub(crate) shared: Arc<Shared>,
+    pub(crate) scope: Arc<Scope>,
+    /// Only used by [`StatsdExt`](crate::StatsdExt), which does nothing once the recorder and all
+    /// of its metrics are gone.
+    pub(crate) statsd: Weak<StatsdClient>,
+}
+
+impl StatsdHandle {
+    /// Number of metrics that were dropped so far, broken down by reason.
+    pub fn dropped_metrics(&self) -> DroppedMetrics {
+        self.shared.stats.dropped()
+    }
+
+    /// Approximate number of metrics currently waiting in the queue to be sent, summed over all the
+    /// queues when there are several workers.
+    ///
+    /// Returns `None` when the recorder was built with a custom sink, in which case there is no
+    /// queue managed by this crate, or once the recorder has been dropped.
+    pub fn queue_depth(&self) -> Option<u64> {
+        let queue = self.shared.queue.as_ref()?.upgrade()?;
+        Some(queue.queued())
+    }
+
+    /// The most recently emitted statsd lines, oldest first, exactly as they were handed to the
+    /// sink. This is empty unless the recorder was built with
+    /// [`StatsdBuilder::with_recent_lines`](crate::StatsdBuilder::with_recent_lines).
+    pub fn recent_lines(&self) -> Vec<String> {
+        self.shared
+            .recent
+            .as_ref()
+            .map(|recent| recent.lines())
+            .unwrap_or_default()
+    }
+
+    /// Stop the background thread that does the periodic work of the exporter, e.g. flushing
+    /// batches and packets or reporting telemetry, once it has done that work one last time. Call
+    /// this before the application exits so that metrics waiting in a batch aren't lost.
+    ///
+    /// The thread otherwise stops on its own once the recorder is dropped. Metrics recorded after
+    /// this call are still sent, but only once a batch or packet fills up.
+    ///
+    /// With [`StatsdBuilder::with_shutdown_timeout`](crate::StatsdBuilder::with_shutdown_timeout),
+    /// this then waits for the queue to be sent, up to the timeout. Returns the number of metrics
+    /// still waiting in the queue, which are abandoned if the application exits right away.
+    pub fn shutdown(&self) -> u64 {
+        self.shared.shutdown(None)
+    }
+
+    /// Stop sending metrics until [`StatsdHandle::resume`] is called, e.g. to relieve an
+    /// overloaded agent during an incident. Every recorder sharing this handle, scoped ones
+    /// included, drops the values it's given in the meantime, after a single check of a flag.
+    /// Registered metrics stay valid and send again once resumed.
+    ///
+    /// The metrics of the exporter itself, e.g. the heartbeat, are still sent.
+    pub fn pause(&self) {
+        self.shared.paused.store(true, Ordering::Relaxed);
+    }
+
+    /// Send metrics again after [`StatsdHandle::pause`].
+    pub fn resume(&self) {
+        self.shared.paused.store(false, Ordering::Relaxed);
+    }
+
+    /// Whether the metrics are dropped, see [`StatsdHandle::pause`].
+    pub fn is_paused(&self) -> bool {
+        self.shared.paused()
+    }
+
+    /// Number of times a thread of the exporter panicked and was restarted, e.g. because a custom
+    /// sink panicked. See also
+    /// [`StatsdBuilder::with_log`](crate::StatsdBuilder::with_log).
+    pub fn worker_restarts(&self) -> u64 {
+        self.shared.stats.worker_restarts()
+    }
+
+    /// Read the file given to
+    /// [`StatsdBuilder::with_mapping_file`](crate::StatsdBuilder::with_mapping_file) again and
+    /// swap the rules of the recorder for the new ones at once, e.g. when the process gets
+    /// `SIGHUP`. When the file isn't valid, the error is returned and the rules are left as they
+    /// were. Does nothing when the recorder has no mapping file.
+    ///
+    /// The new rules apply to metrics as they are registered, which callers of the `metrics`
+    /// macros do on every invocation. Handles that were registered before and kept around, e.g. a
+    /// [`metrics::Counter`] stored in a struct, keep sending the way they were.
+    pub fn reload_mapping(&self) -> Result<(), StatsdError> {
+        self.shared.mapping.reload()
+    }
+
+    /// Number of tags that were dropped because metrics had more than
+    /// [`StatsdBuilder::with_max_tags`](crate::StatsdBuilder::with_max_tags) tags. This is counted
+    /// once when a metric is registered, and every time a metric is sent through
+    /// [`StatsdExt`](crate::StatsdExt).
+    pub fn dropped_tags(&self) -> u64 {
+        self.shared.stats.dropped_tags()
+    }
+
+    /// Descriptions supplied via the `describe_*` macros, sorted by name.
+    pub fn descriptions(&self) -> Vec<MetricDescription> {
+        self.shared.catalog.descriptions()
+    }
+
+    /// The [`descriptions`](StatsdHandle::descriptions) as a JSON array, e.g. for a documentation
+    /// pipeline, along with the keys of the tags each metric was registered with so far, sorted:
+    ///
+    /// ```json
+    /// [{"name":"request.duration","type":"histogram","unit":"seconds","description":"time spent serving a request","tags":["method","status"]}]
+    /// ```
+    ///
+    /// The `unit` is `null` when the metric was described without one. See also
+    /// [`StatsdBuilder::with_descriptions_file`](crate::StatsdBuilder::with_descriptions_file).
+    pub fn descriptions_json(&self) -> String {
+        self.shared.descriptions_json()
+    }
+
+    /// The last value seen for each metric, sorted by name. This is empty unless the recorder was
+    /// built with [`StatsdBuilder::with_last_values`](crate::StatsdBuilder::with_last_values).
+    pub fn snapshot(&self) -> Vec<(Key, LastValue)> {
+        self.shared
+            .last_values
+            .as_ref()
+            .map(|last_values| last_values.snapshot())
+            .unwrap_or_default()
+    }
+
+    /// Every metric registered so far, sorted by name, along with the number of lines it sent and
+    /// when it last did, e.g. for an admin endpoint to tell whether a metric is still sent without
+    /// querying the backend. This is empty unless the recorder was built with
+    /// [`StatsdBuilder::with_active_keys`](crate::StatsdBuilder::with_active_keys).
+    ///
+    /// Metrics of scoped recorders are included. Metrics a stage of the pipeline drops are listed
+    /// without ever sending a line.
+    pub fn active_keys(&self) -> Vec<ActiveKey> {
+        self.shared.active_keys()
+    }
+
+    /// The metric names that sent the most lines so far, most first, along with their share of
+    /// the lines sent by every metric, e.g. to find the few names behind most of the packets from
+    /// inside the process. The lines of a name are summed over all of its tag sets. This is empty
+    /// unless the recorder was built with
+    /// [`StatsdBuilder::with_top_emitters`](crate::StatsdBuilder::with_top_emitters).
+    pub fn top_emitters(&self) -> Vec<TopEmitter> {
+        self.shared.top_emitters()
+    }
+
+    /// Add `tags` to every metric recorded on the current thread until the returned guard is
+    /// dropped, see [`StatsdRecorder::push_tags`](crate::StatsdRecorder::push_tags).
+    pub fn push_tags<I, K, V>(&self, tags: I) -> TagGuard
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: ToString,
+        V: ToString,
+    {
+        ambient::push_tags(tags)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics::Recorder;
+
+    use super::*;
+    use crate::testing::METADATA;
+    use crate::StatsdBuilder;
+
+    #[test]
+    fn max_tags() {
+        let sink = crate::testing::FakeSink::new();
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(sink.clone())
+            .with_default_tag("env", "test")
+            .with_max_tags(3)
+            .with_tag_priority(["z"])
+            .build(None)
+            .expect("should build a recorder with custom sink");
+        let handle = recorder.handle();
+
+        let labels = ["c", "z", "a", "b"].map(|key| Label::new(key, "v"));
+        let key = Key::from(("counter.name", labels.to_vec()));
+        recorder.register_counter(&key, &METADATA).increment(1);
+        let key = Key::from(("other.name", labels[..2].to_vec()));
+        recorder.register_counter(&key, &METADATA).increment(1);
+
+        assert_eq!(
+            vec![
+                "counter.name:1|c|#env:test,z:v,a:v",
+                "other.name:1|c|#env:test,c:v,z:v"
+            ],
+            sink.lines()
+        );
+        assert_eq!(2, handle.dropped_tags());
+    }
+
+    #[test]
+    fn max_tags_with_duplicate_labels() {
+        let sink = crate::testing::FakeSink::new();
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(sink.clone())
+            .with_max_tags(2)
+            .build(None)
+            .expect("should build a recorder with custom sink");
+        let handle = recorder.handle();
+
+        let labels = ["b", "a", "a", "a"].map(|key| Label::new(key, "v"));
+        let key = Key::from(("counter.name", labels.to_vec()));
+        recorder.register_counter(&key, &METADATA).increment(1);
+
+        assert_eq!(vec!["counter.name:1|c|#a:v,a:v"], sink.lines());
+        assert_eq!(2, handle.dropped_tags());
+    }
+
+    #[test]
+    fn sorted_tags() {
+        let sink = crate::testing::FakeSink::new();
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(sink.clone())
+            .with_default_tag("env", "test")
+            .with_sorted_tags()
+            .build(None)
+            .expect("should build a recorder with custom sink");
+
+        let labels = vec![Label::new("z", "1"), Label::new("a", "2")];
+        let key = Key::from(("counter.name", labels));
+        recorder.register_counter(&key, &METADATA).increment(1);
+        let labels = vec![Label::new("a", "2"), Label::new("z", "1")];
+        let key = Key::from(("counter.name", labels));
+        recorder.register_counter(&key, &METADATA).increment(1);
+
+        assert_eq!(
+            vec![
+                "counter.name:1|c|#a:2,env:test,z:1",
+                "counter.name:1|c|#a:2,env:test,z:1"
+            ],
+            sink.lines()
+        );
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::line::RenderedKey;
+use crate::recorder::duration_to_millis;
+
+/// An HDR histogram: values are counted in buckets whose width doubles every power of two, with
+/// enough buckets between two powers of two that the value of a bucket keeps `significant_digits`
+/// digits of every value counted in it. Only the buckets with values are kept.
+#[derive(Debug)]
+pub(crate) struct HdrHistogram {
+    /// The values below `2^(magnitude + 1)` are counted exactly.
+    magnitude: u32,
+    /// The count of every bucket, by the lowest value it holds.
+    counts: BTreeMap<u64, u64>,
+    count: u64,
+    min: u64,
+    max: u64,
+}
+
+impl HdrHistogram {
+    /// A histogram keeping `significant_digits` digits, from 1 to 5.
+    pub(crate) fn new(significant_digits: u8) -> Self {
+        // twice as many buckets as needed between two powers of two, rounded up to a power of two.
+        let buckets = 2 * 10u64.pow(u32::from(significant_digits));
+        HdrHistogram {
+            magnitude: buckets.next_power_of_two().trailing_zeros() - 1,
+            counts: BTreeMap::new(),
+            count: 0,
+            min: u64::MAX,
+            max: 0,
+        }
+    }
+
+    /// The number of low bits of `value` its bucket doesn't tell apart.
+    fn shift(&self, value: u64) -> u32 {
+        (u64::BITS - value.leading_zeros()).saturating_sub(self.magnitude + 1)
+    }
+
+    pub(crate) fn add(&mut self, value: u64) {
+        let shift = self.shift(value);
+        *self.counts.entry(value >> shift << shift).or_default() += 1;
+        self.count += 1;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    pub(crate) fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub(crate) fn min(&self) -> u64 {
+        self.min
+    }
+
+    pub(crate) fn max(&self) -> u64 {
+        self.max
+    }
+
+    /// The highest value of the bucket holding the `quantile` of the values, never more than the
+    /// largest value, `None` when there are none.
+    pub(crate) fn quantile(&self, quantile: f64) -> Option<u64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((quantile * self.count as f64).ceil() as u64).clamp(1, self.count);
+        let mut seen = 0;
+        self.counts.iter().find_map(|(lowest, count)| {
+            seen += count;
+            let highest = lowest + ((1 << self.shift(*lowest)) - 1);
+            (seen >= rank).then(|| highest.min(self.max))
+        })
+    }
+}
+
+/// The durations a timer recorded since they were last sent, see
+/// [`StatsdBuilder::with_hdr_timers`](crate::StatsdBuilder::with_hdr_timers).
+#[derive(Debug)]
+pub(crate) struct TimerHistogram {
+    /// The gauge of every quantile.
+    quantiles: Vec<(f64, RenderedKey)>,
+    min: RenderedKey,
+    max: RenderedKey,
+    count: RenderedKey,
+    significant_digits: u8,
+    /// In nanoseconds.
+    histogram: Mutex<HdrHistogram>,
+}
+
+/// What a timer sends on every flush, see [`TimerHistogram::take`].
+pub(crate) enum TimerStat {
+    /// In milliseconds.
+    Gauge(f64),
+    Count(u64),
+}
+
+impl TimerHistogram {
+    pub(crate) fn new(
+        quantiles: Vec<(f64, RenderedKey)>,
+        min: RenderedKey,
+        max: RenderedKey,
+        count: RenderedKey,
+        significant_digits: u8,
+    ) -> Self {
+        TimerHistogram {
+            quantiles,
+            min,
+            max,
+            count,
+            significant_digits,
+            histogram: Mutex::new(HdrHistogram::new(significant_digits)),
+        }
+    }
+
+    pub(crate) fn add(&self, duration: Duration) {
+        let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+        self.histogram
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .add(nanos);
+    }
+
+    /// Hand every quantile, the min, the max and the count of the durations recorded since the
+    /// last call to `f`, along with their metric, nothing when no duration was recorded.
+    pub(crate) fn take(&self, mut f: impl FnMut(&RenderedKey, TimerStat)) {
+        let histogram = std::mem::replace(
+            &mut *self.histogram.lock().unwrap_or_else(|e| e.into_inner()),
+            HdrHistogram::new(self.significant_digits),
+        );
+        if histogram.count() == 0 {
+            return;
+        }
+        let millis = |nanos: u64| TimerStat::Gauge(duration_to_millis(Duration::from_nanos(nanos)));
+        for (quantile, rendered) in &self.quantiles {
+            if let Some(nanos) = histogram.quantile(*quantile) {
+                f(rendered, millis(nanos));
+            }
+        }
+        f(&self.min, millis(histogram.min()));
+        f(&self.max, millis(histogram.max()));
+        f(&self.count, TimerStat::Count(histogram.count()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics::{Key, Recorder};
+
+    use super::*;
+    use crate::testing::METADATA;
+    use crate::{PercentileNaming, StatsdBuilder};
+
+    #[test]
+    fn keeps_significant_digits() {
+        let mut histogram = HdrHistogram::new(3);
+        // 1µs to 10s, in nanoseconds.
+        for value in (0..=7).map(|exponent| 10u64.pow(exponent) * 1000) {
+            histogram.add(value);
+            histogram.add(value + 1234);
+        }
+        assert_eq!(16, histogram.count());
+        assert_eq!(1000, histogram.min());
+        assert_eq!(10_000_001_234, histogram.max());
+        for (quantile, expected) in [(0.0, 1000), (0.5, 1_001_234), (0.9, 10_000_000_000)] {
+            let value = histogram.quantile(quantile).unwrap();
+            assert!(value.abs_diff(expected) <= expected / 1000, "{}", value);
+        }
+        assert_eq!(Some(10_000_001_234), histogram.quantile(1.0));
+        // small values are exact.
+        let mut histogram = HdrHistogram::new(3);
+        histogram.add(1999);
+        assert_eq!(Some(1999), histogram.quantile(0.5));
+    }
+
+    #[test]
+    fn hdr_timers() {
+        let clock = crate::testing::ManualClock::new();
+        let sink = crate::testing::FakeSink::new();
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(sink.clone())
+            .with_clock(clock.clone())
+            .histogram_is_timer()
+            .with_hdr_timers(Duration::from_secs(10), 3)
+            .with_percentiles(&[0.5, 0.99], PercentileNaming::Short)
+            .build(None)
+            .expect("should build a recorder with custom sink");
+        let latency = recorder.register_histogram(&Key::from_name("latency"), &METADATA);
+        for micros in 1..=100 {
+            latency.record(Duration::from_micros(micros * 10).as_secs_f64());
+        }
+
+        clock.advance(Duration::from_secs(10));
+        recorder.shared.run_pending();
+        assert_eq!(
+            vec![
+                // the quantiles are within 3 significant digits, min and max are exact.
+                "latency.p50:0.500223|g",
+                "latency.p99:0.990207|g",
+                "latency.min:0.01|g",
+                "latency.max:1|g",
+                "latency.count:100|c",
+            ],
+            sink.lines()
+        );
+    }
+}
@@ -0,0 +1,54 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+/// Pool of shared strings, interning a string that is already in the pool returns the existing
+/// allocation.
+///
+/// Services with many series usually combine a small set of tags, so the rendered tags of the
+/// registered metrics are interned to share one allocation between all the series with the same
+/// tags. Interned strings are evicted along with idle handles, see
+/// [`crate::StatsdBuilder::with_idle_timeout`].
+#[derive(Debug, Default)]
+pub(crate) struct Interner {
+    strings: Mutex<HashSet<Arc<str>>>,
+}
+
+impl Interner {
+    pub(crate) fn intern(&self, s: &str) -> Arc<str> {
+        let mut strings = self.strings.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(interned) = strings.get(s) {
+            return interned.clone();
+        }
+        let interned: Arc<str> = Arc::from(s);
+        strings.insert(interned.clone());
+        interned
+    }
+
+    /// Forget the strings that are only held by the pool, e.g. once the handles using them were
+    /// evicted.
+    pub(crate) fn evict_unused(&self) {
+        let mut strings = self.strings.lock().unwrap_or_else(|e| e.into_inner());
+        strings.retain(|s| Arc::strong_count(s) > 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shares_allocations() {
+        let interner = Interner::default();
+        let first = interner.intern("|#env:prod");
+        let second = interner.intern(&String::from("|#env:prod"));
+        let other = interner.intern("|#env:staging");
+
+        assert!(Arc::ptr_eq(&first, &second));
+        assert!(!Arc::ptr_eq(&first, &other));
+
+        drop((second, other));
+        interner.evict_unused();
+        assert_eq!(1, interner.strings.lock().unwrap().len());
+        assert!(Arc::ptr_eq(&first, &interner.intern("|#env:prod")));
+    }
+}
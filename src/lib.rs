@@ -6,9 +6,9 @@
 //! * **Versions of this crate are tightly coupled to metrics crate versions.**
 //!
 //! * [`metrics::Counter::absolute`], [`metrics::Gauge::increment`], and
-//!     [`metrics::Gauge::decrement`] are not supported. Statsd doesn't have these concepts.
-//!     Unfortunately this means that if the application is using these methods, the metrics will
-//!     silently be missing or wrong.
+//!   [`metrics::Gauge::decrement`] are not supported. Statsd doesn't have these concepts.
+//!   Unfortunately this means that if the application is using these methods, the metrics will
+//!   silently be missing or wrong.
 //!
 //! # Usage
 //!
@@ -36,7 +36,8 @@
 //! metrics::gauge!("gauge.name", "tag" => "value").set(100.0);
 //!```
 //! will translate to `gauge.name:50.25|g|#tag:value` and should render appropriately in systems
-//! like Datadog.
+//! like Datadog. A label with an empty value is sent as a bare tag, e.g. `"canary" => ""` is sent
+//! as `#canary`.
 //!
 //! # Queue Size and Buffer Size
 //!
@@ -57,6 +58,31 @@
 //! in production. This interface doesn't allow you to configure an unbounded queue, you must provide
 //! a queue size or one is chosen for you.
 //!
+//! # Dropped metrics
+//!
+//! Metrics can be dropped on their way to statsd, most commonly because the queue is full. The
+//! number of dropped metrics, broken down by [`DropReason`], is available from a [`StatsdHandle`]:
+//!
+//! ```
+//! use metrics_exporter_statsd::{DropReason, StatsdBuilder};
+//!
+//! let recorder = StatsdBuilder::from("127.0.0.1", 8125)
+//! .build(Some("prefix"))
+//! .expect("Could not create StatsdRecorder");
+//! let handle = recorder.handle();
+//!
+//! metrics::set_global_recorder(recorder);
+//!
+//! let dropped = handle.dropped_metrics();
+//! println!("{} metrics dropped because the queue was full", dropped.get(DropReason::QueueFull));
+//! ```
+//!
+//! # Testing
+//!
+//! The [`testing`] module has a [`CapturingRecorder`](testing::CapturingRecorder) that keeps the
+//! metrics it formats in memory, so that applications can assert on what they report without
+//! running a statsd server.
+//!
 //! # Histograms
 //! The default behavior if you do not specify a global preference, or an explict tag is to send
 //! `histogram!` metrics as Histograms.  If you do set an alternative global preference but would
@@ -85,6 +111,11 @@
 //! This will emit a metric like this: `metric.name:100|d|#tag:value`, note the metric type has
 //! changed from `h` to `d`.
 //!
+//! The [`distribution!`] macro applies the hint for you:
+//! ```
+//! metrics_exporter_statsd::distribution!("metric.name", "tag"=>"value").record(100.0)
+//! ```
+//!
 //! # Timers
 //! StatsD specification does have the concept of timers that more or less behave like histograms e.g.
 //! they are aggregated at the agent, support for timer metrics is similar to distribution.
@@ -96,6 +127,11 @@
 //! This will emit a metric like this: `metric.name:100|ms|#tag:value`, note the metric type has
 //! changed from `h` to `ms`.
 //!
+//! The [`timing!`] macro applies the hint for you and takes a [`Duration`](std::time::Duration):
+//! ```
+//! metrics_exporter_statsd::timing!("metric.name", std::time::Duration::from_millis(5), "tag"=>"value")
+//! ```
+//!
 //! # Chaging the default type of histogram
 //!
 //! If your application mostly is interested in distribution or timers, you can indicate that to
@@ -123,6 +159,18 @@
 //! This will emit a metric like this: `metric.name:100|d|#tag:value`, note the metric type has
 //! emitted here is `d` and not `h`.
 //!
+//! # Forcing the type of any metric
+//! The `statsd_type` label forces the type a metric is sent as, whatever it's recorded as, with
+//! one of `c`, `g`, `ms`, `h`, `d`, `s` or `m`. It wins over the histogram hint and, like it,
+//! doesn't end up in the tags. This helps with legacy servers and with migrating a metric from
+//! one type to another:
+//!
+//! ```
+//! metrics::counter!("metric.name", "statsd_type"=>"m", "tag"=>"value").increment(1)
+//! ```
+//! This will emit a metric like this: `metric.name:1|m|#tag:value`. Labels with an unknown type
+//! are dropped and the metric is sent as usual.
+//!
 //! **Note:** Most of the other metrics-rs builders provide a convenience method for installing a global recorder. E.g
 //! for Prometheus or TCP metrics exporters you could do something along the lines of `PrometheusBuilder::new().install()`.
 //!
@@ -134,7 +182,72 @@ mod recorder;
 
 pub use self::recorder::*;
 
+mod allowed;
+mod ambient;
+mod batch;
 mod builder;
+mod catalog;
+mod clock;
+mod conflicts;
+mod exit;
+mod ext;
+mod file;
+#[cfg(target_os = "linux")]
+mod gso;
+mod handle;
+mod hdr;
+mod intern;
+mod line;
+mod macros;
+mod mapping;
+mod mirror;
+mod origin;
+mod packet;
+mod pipeline;
+mod quota;
+mod rates;
+mod registry;
+mod replay;
+mod ring;
+mod routing;
+mod sampling;
+mod sink;
+mod sketch;
+mod smoothing;
+mod snapshot;
+mod socks;
+mod stats;
+mod stream;
+mod strict;
+mod summary;
+mod targets;
+mod tee;
+mod telemetry;
 mod types;
+mod upkeep;
+mod values;
 
+pub use self::ambient::{with_tags, TagGuard, WithTags};
 pub use self::builder::*;
+pub use self::catalog::{DescribedKind, MetricDescription};
+pub use self::clock::{Clock, SystemClock};
+pub use self::ext::StatsdExt;
+pub use self::handle::StatsdHandle;
+pub use self::line::ContextTags;
+pub use self::pipeline::{PipelineMetric, PipelineStage};
+pub use self::rates::CounterRates;
+pub use self::replay::Replay;
+pub use self::sampling::SampleRateSemantics;
+pub use self::sink::InnerSink;
+pub use self::snapshot::{ActiveKey, LastValue, TopEmitter};
+pub use self::stats::{DropReason, DroppedMetrics};
+pub use self::summary::PercentileNaming;
+pub use self::types::MetricType;
+pub use self::values::ValuePolicy;
+
+pub mod testing;
+
+#[doc(hidden)]
+pub mod __private {
+    pub use metrics;
+}
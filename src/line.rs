@@ -0,0 +1,439 @@
+use std::cell::RefCell;
+use std::fmt::Write;
+use std::sync::Arc;
+
+use cadence::Metric;
+use metrics::Label;
+
+use crate::intern::Interner;
+use crate::strict;
+use crate::types::MetricType;
+
+thread_local! {
+    /// Lines are formatted into this buffer so that sending a metric doesn't allocate.
+    static LINE_BUFFER: RefCell<String> = const { RefCell::new(String::new()) };
+}
+
+/// A fully formatted statsd line, including the prefix and tags, that is sent verbatim.
+pub(crate) struct Line<'a>(pub(crate) &'a str);
+
+impl Metric for Line<'_> {
+    fn as_metric_str(&self) -> &str {
+        self.0
+    }
+}
+
+/// The name and tags of a metric, rendered once when the metric is registered. Only the value and
+/// the type have to be formatted each time the metric is sent.
+#[derive(Clone, Debug)]
+pub(crate) struct RenderedKey {
+    /// The metric name, prefix included.
+    name: String,
+    /// The `|#tag:value,...` suffix, empty when there are no tags. Metrics with the same tags share
+    /// it through the [`Interner`].
+    tags: Arc<str>,
+    /// The `|@rate` suffix of sampled metrics, empty otherwise.
+    sample_rate: String,
+}
+
+impl RenderedKey {
+    /// Render `name` with the (already formatted) `prefix`. The default tags come first, followed
+    /// by the labels, which matches the order used by [`cadence::StatsdClient`].
+    pub(crate) fn new<'a>(
+        prefix: &str,
+        name: &str,
+        default_tags: &[(String, String)],
+        labels: impl Iterator<Item = &'a Label>,
+        interner: &Interner,
+    ) -> Self {
+        let mut tags = String::new();
+        let default_tags = default_tags.iter().map(|(k, v)| (k.as_str(), v.as_str()));
+        for (key, value) in default_tags.chain(labels.map(|l| (l.key(), l.value()))) {
+            tags.push_str(if tags.is_empty() { "|#" } else { "," });
+            push_tag(&mut tags, key, value);
+        }
+
+        RenderedKey {
+            name: format!("{}{}", prefix, name),
+            tags: interner.intern(&tags),
+            sample_rate: String::new(),
+        }
+    }
+
+    /// The metric name, prefix included.
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The keys of the tags, in the order they're sent.
+    pub(crate) fn tag_keys(&self) -> impl Iterator<Item = &str> {
+        self.tags
+            .strip_prefix("|#")
+            .into_iter()
+            .flat_map(|tags| tags.split(','))
+            .map(|tag| tag.split_once(':').map_or(tag, |(key, _)| key))
+    }
+
+    /// Tell statsd that only a `rate` fraction of the values of this metric is sent, so that it
+    /// scales them back up.
+    pub(crate) fn with_sample_rate(mut self, rate: Option<f64>) -> Self {
+        self.sample_rate = match rate {
+            Some(rate) => format!("|@{}", rate),
+            None => String::new(),
+        };
+        self
+    }
+
+    /// Format a line reporting `value` as a metric of the given type and hand it to `f`.
+    ///
+    /// The line is formatted in a buffer that is reused by the current thread, so this doesn't
+    /// allocate once the buffer has grown to fit the longest line. If `f` ends up formatting
+    /// another line, e.g. a sink that records metrics of its own, that one gets a new buffer.
+    pub(crate) fn with_line<V, R>(
+        &self,
+        value: V,
+        metric_type: MetricType,
+        f: impl FnOnce(&str) -> R,
+    ) -> R
+    where
+        V: Value,
+    {
+        self.with_line_and_tags(value, metric_type, |_| {}, f)
+    }
+
+    /// Same as [`RenderedKey::with_line`], with the tags added by `more_tags` after the rendered
+    /// ones.
+    pub(crate) fn with_line_and_tags<V, R>(
+        &self,
+        value: V,
+        metric_type: MetricType,
+        more_tags: impl FnOnce(&mut ContextTags<'_>),
+        f: impl FnOnce(&str) -> R,
+    ) -> R
+    where
+        V: Value,
+    {
+        LINE_BUFFER.with(|buffer| match buffer.try_borrow_mut() {
+            Ok(mut buffer) => {
+                buffer.clear();
+                self.write_line(&mut buffer, value, metric_type, more_tags);
+                f(&buffer)
+            }
+            Err(_) => {
+                let mut buffer = String::new();
+                self.write_line(&mut buffer, value, metric_type, more_tags);
+                f(&buffer)
+            }
+        })
+    }
+
+    fn write_line<V: Value>(
+        &self,
+        out: &mut String,
+        value: V,
+        metric_type: MetricType,
+        more_tags: impl FnOnce(&mut ContextTags<'_>),
+    ) {
+        out.push_str(&self.name);
+        out.push(':');
+        value.write_to(out);
+        out.push('|');
+        out.push_str(metric_type.code());
+        out.push_str(&self.sample_rate);
+        out.push_str(&self.tags);
+        // the tags are last on the line, so more can simply be appended.
+        more_tags(&mut ContextTags {
+            has_tags: !self.tags.is_empty(),
+            out,
+        });
+    }
+}
+
+/// Appends tags to a line as it is being sent, see
+/// [`StatsdBuilder::with_context_tags`](crate::StatsdBuilder::with_context_tags).
+pub struct ContextTags<'a> {
+    out: &'a mut String,
+    has_tags: bool,
+}
+
+impl ContextTags<'_> {
+    /// Add the tag `key:value`, after the default tags and the labels of the metric. The tag is
+    /// a bare `key` when `value` is empty.
+    pub fn add(&mut self, key: &str, value: &str) {
+        self.out.push_str(if self.has_tags { "," } else { "|#" });
+        self.has_tags = true;
+        push_tag(self.out, key, value);
+    }
+}
+
+/// Append `key:value`, or a bare `key` when the value is empty, which DogStatsD supports for
+/// flag-like tags such as `production`.
+pub(crate) fn push_tag(out: &mut String, key: &str, value: &str) {
+    out.push_str(key);
+    if !value.is_empty() {
+        out.push(':');
+        out.push_str(value);
+    }
+}
+
+/// A metric value that can be appended to a line.
+///
+/// Going through [`Display`](std::fmt::Display) costs a trip through the formatting machinery for
+/// every metric, which shows at high rates. Integers, and floats holding an integer which is by far
+/// the common case, are written digit by digit instead. The output is identical to `Display`.
+pub(crate) trait Value {
+    fn write_to(self, out: &mut String);
+
+    /// The value as a number, for the stages of the pipeline, `None` for the members of sets.
+    fn as_f64(&self) -> Option<f64>;
+
+    /// Whether the value makes a line the server can parse, see [`crate::strict`].
+    fn valid(&self) -> bool;
+}
+
+impl Value for &str {
+    fn write_to(self, out: &mut String) {
+        out.push_str(self);
+    }
+
+    fn as_f64(&self) -> Option<f64> {
+        None
+    }
+
+    fn valid(&self) -> bool {
+        strict::valid_member(self)
+    }
+}
+
+impl Value for u64 {
+    fn write_to(self, out: &mut String) {
+        let mut digits = [0u8; 20];
+        let mut start = digits.len();
+        let mut value = self;
+        loop {
+            start -= 1;
+            digits[start] = b'0' + (value % 10) as u8;
+            value /= 10;
+            if value == 0 {
+                break;
+            }
+        }
+        out.extend(digits[start..].iter().map(|&d| char::from(d)));
+    }
+
+    fn as_f64(&self) -> Option<f64> {
+        Some(*self as f64)
+    }
+
+    fn valid(&self) -> bool {
+        true
+    }
+}
+
+/// Integral values are always written without a fraction, e.g. `50` rather than `50.0`, which
+/// some statsd servers fail to parse, and `-0.0` is written as `0`. Other values are written the
+/// way `Display` writes them.
+impl Value for f64 {
+    fn write_to(self, out: &mut String) {
+        // above 2^53 not every integer is representable, leave those to `Display`.
+        const MAX_EXACT: f64 = (1u64 << 53) as f64;
+
+        if self.fract() == 0.0 && self.abs() < MAX_EXACT {
+            if self.is_sign_negative() && self != 0.0 {
+                out.push('-');
+            }
+            (self.abs() as u64).write_to(out);
+        } else {
+            let _ = write!(out, "{}", self);
+        }
+    }
+
+    fn as_f64(&self) -> Option<f64> {
+        Some(*self)
+    }
+
+    fn valid(&self) -> bool {
+        strict::valid_number(*self)
+    }
+}
+
+/// Format the prefix the same way [`cadence::StatsdClient`] does, i.e. with a single trailing dot
+/// unless it is empty.
+pub(crate) fn format_prefix(prefix: &str) -> String {
+    if prefix.is_empty() {
+        String::new()
+    } else {
+        format!("{}.", prefix.trim_end_matches('.'))
+    }
+}
+
+/// `name` shortened to at most `max_len` bytes when it's longer: truncated and suffixed with `_`
+/// and a hash of the whole name, so that two long names sharing a beginning stay apart and a name
+/// is always shortened the same way.
+pub(crate) fn shorten_name(name: &str, max_len: usize) -> String {
+    if name.len() <= max_len {
+        return name.to_string();
+    }
+    // FNV-1a, which unlike the hashers of the standard library is the same on every version.
+    let hash = name.bytes().fold(0x811c_9dc5_u32, |hash, byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+    });
+    let suffix = format!("_{:08x}", hash);
+    let mut keep = max_len.saturating_sub(suffix.len());
+    while !name.is_char_boundary(keep) {
+        keep -= 1;
+    }
+    let mut shortened = format!("{}{}", &name[..keep], suffix);
+    shortened.truncate(max_len);
+    shortened
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_default_tags_before_labels() {
+        let labels = [Label::new("t1", "v1")];
+        let key = RenderedKey::new(
+            "prefix.",
+            "counter.name",
+            &[("env".to_string(), "prod".to_string())],
+            labels.iter(),
+            &Interner::default(),
+        );
+        assert_eq!(
+            "prefix.counter.name:1|c|#env:prod,t1:v1",
+            key.with_line(1u64, MetricType::Counter, str::to_string)
+        );
+    }
+
+    #[test]
+    fn renders_bare_tags() {
+        let labels = [Label::new("canary", ""), Label::new("t1", "v1")];
+        let key = RenderedKey::new(
+            "",
+            "counter.name",
+            &[("production".to_string(), String::new())],
+            labels.iter(),
+            &Interner::default(),
+        );
+        let context = |tags: &mut ContextTags<'_>| tags.add("sampled", "");
+        assert_eq!(
+            "counter.name:1|c|#production,canary,t1:v1,sampled",
+            key.with_line_and_tags(1u64, MetricType::Counter, context, str::to_string)
+        );
+    }
+
+    #[test]
+    fn renders_sample_rate_before_tags() {
+        let labels = [Label::new("t1", "v1")];
+        let key = RenderedKey::new("", "counter.name", &[], labels.iter(), &Interner::default())
+            .with_sample_rate(Some(0.25));
+        assert_eq!(
+            "counter.name:1|c|@0.25|#t1:v1",
+            key.with_line(1u64, MetricType::Counter, str::to_string)
+        );
+    }
+
+    #[test]
+    fn appends_context_tags() {
+        let labels = [Label::new("t1", "v1")];
+        let tagged = RenderedKey::new("", "tagged", &[], labels.iter(), &Interner::default());
+        let untagged = RenderedKey::new(
+            "",
+            "untagged",
+            &[],
+            std::iter::empty(),
+            &Interner::default(),
+        );
+        let context = |tags: &mut ContextTags<'_>| tags.add("tenant", "acme");
+
+        assert_eq!(
+            "tagged:1|c|#t1:v1,tenant:acme",
+            tagged.with_line_and_tags(1u64, MetricType::Counter, context, str::to_string)
+        );
+        assert_eq!(
+            "untagged:1|c|#tenant:acme",
+            untagged.with_line_and_tags(1u64, MetricType::Counter, context, str::to_string)
+        );
+    }
+
+    #[test]
+    fn reentrant_lines() {
+        let key = RenderedKey::new("", "outer", &[], std::iter::empty(), &Interner::default());
+        let inner = RenderedKey::new("", "inner", &[], std::iter::empty(), &Interner::default());
+
+        let (outer, inner) = key.with_line(1u64, MetricType::Counter, |outer| {
+            let inner = inner.with_line(2.5, MetricType::Gauge, str::to_string);
+            (outer.to_string(), inner)
+        });
+        assert_eq!("outer:1|c", outer);
+        assert_eq!("inner:2.5|g", inner);
+    }
+
+    #[test]
+    fn shortens_long_names() {
+        assert_eq!("short.name", shorten_name("short.name", 10));
+        let first = shorten_name("service.requests.by_endpoint.first", 24);
+        let second = shorten_name("service.requests.by_endpoint.second", 24);
+        assert_eq!(24, first.len());
+        assert!(first.starts_with("service.request_"));
+        assert_ne!(first, second);
+        assert_eq!(
+            first,
+            shorten_name("service.requests.by_endpoint.first", 24)
+        );
+        // never cut within a character.
+        let shortened = shorten_name("ééééééééé", 12);
+        assert_eq!(11, shortened.len());
+        assert!(shortened.starts_with("é_"));
+    }
+
+    fn written<V: Value>(value: V) -> String {
+        let mut out = String::new();
+        value.write_to(&mut out);
+        out
+    }
+
+    #[test]
+    fn values_match_display() {
+        for value in [0, 1, 9, 10, 1234567890, u64::MAX] {
+            assert_eq!(value.to_string(), written(value));
+        }
+        for value in [
+            0.0,
+            1.0,
+            -42.0,
+            100.0,
+            0.5,
+            -2.25,
+            1.0 / 3.0,
+            9007199254740991.0,
+            9007199254740992.0,
+            1e300,
+            f64::MIN_POSITIVE,
+            f64::NAN,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+        ] {
+            assert_eq!(value.to_string(), written(value));
+        }
+    }
+
+    #[test]
+    fn integral_values_have_no_fraction() {
+        assert_eq!("50", written(50.0));
+        assert_eq!("-50", written(-50.0));
+        assert_eq!("0", written(-0.0));
+        assert_eq!("9007199254740992", written(9007199254740992.0));
+        assert_eq!("50.5", written(50.5));
+    }
+
+    #[test]
+    fn prefix() {
+        assert_eq!("", format_prefix(""));
+        assert_eq!("app.", format_prefix("app"));
+        assert_eq!("app.", format_prefix("app.."));
+    }
+}
@@ -0,0 +1,78 @@
+/// Register a histogram that is reported as a distribution, i.e. `|d`, regardless of the default
+/// set with [`StatsdBuilder::histogram_is_distribution`](crate::StatsdBuilder::histogram_is_distribution).
+///
+/// This is [`metrics::histogram!`] with the `"histogram" => "distribution"` hint already applied,
+/// labels are given the same way:
+///
+/// ```
+/// use metrics_exporter_statsd::distribution;
+///
+/// distribution!("request.size").record(512.0);
+/// distribution!("request.size", "endpoint" => "upload").record(4096.0);
+/// ```
+#[macro_export]
+macro_rules! distribution {
+    ($name:expr $(, $label_key:expr => $label_value:expr)* $(,)?) => {
+        $crate::__private::metrics::histogram!(
+            $name,
+            "histogram" => "distribution"
+            $(, $label_key => $label_value)*
+        )
+    };
+}
+
+/// Record a [`Duration`](std::time::Duration) into a timer, i.e. `|ms`, regardless of the
+/// default set with [`StatsdBuilder::histogram_is_timer`](crate::StatsdBuilder::histogram_is_timer).
+///
+/// This is [`metrics::histogram!`] with the `"histogram" => "timer"` hint already applied. The
+/// duration is sent in milliseconds, fractions included, so there is no need to convert it to
+/// seconds first:
+///
+/// ```
+/// use std::time::{Duration, Instant};
+/// use metrics_exporter_statsd::timing;
+///
+/// let start = Instant::now();
+/// timing!("request.duration", start.elapsed());
+/// timing!("request.duration", Duration::from_micros(1500), "endpoint" => "upload");
+/// ```
+#[macro_export]
+macro_rules! timing {
+    ($name:expr, $duration:expr $(, $label_key:expr => $label_value:expr)* $(,)?) => {
+        $crate::__private::metrics::histogram!(
+            $name,
+            "histogram" => "timer"
+            $(, $label_key => $label_value)*
+        )
+        .record($duration)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::testing::capture_lines;
+
+    #[test]
+    fn distribution() {
+        let lines = capture_lines(|| {
+            crate::distribution!("plain").record(1.0);
+            crate::distribution!("tagged", "t1" => "v1", "t2" => "v2",).record(2.5);
+        });
+        assert_eq!(vec!["plain:1|d", "tagged:2.5|d|#t1:v1,t2:v2"], lines);
+    }
+
+    #[test]
+    fn timing() {
+        let lines = capture_lines(|| {
+            crate::timing!("plain", Duration::from_secs(2));
+            crate::timing!("tagged", Duration::from_micros(1500), "t1" => "v1");
+            crate::timing!("tiny", Duration::from_nanos(1));
+        });
+        assert_eq!(
+            vec!["plain:2000|ms", "tagged:1.5|ms|#t1:v1", "tiny:0.000001|ms"],
+            lines
+        );
+    }
+}
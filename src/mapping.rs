@@ -0,0 +1,638 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::time::{Duration, SystemTime};
+
+use metrics::Label;
+
+use crate::pipeline::{PipelineMetric, PipelineStage};
+use crate::types::{HistogramType, MetricType};
+use crate::upkeep::Upkeep;
+use crate::StatsdError;
+
+/// Rules that rename, relabel, retype or drop metrics by name, loaded from a mapping file, see
+/// [`crate::StatsdBuilder::with_mapping_file`]. The first rule matching a name applies.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Mapping {
+    rules: Vec<Rule>,
+}
+
+#[derive(Clone, Debug)]
+struct Rule {
+    pattern: Glob,
+    drop: bool,
+    name: Option<String>,
+    labels: Vec<(String, String)>,
+    histogram_type: Option<HistogramType>,
+}
+
+/// What a rule does to a metric.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Mapped {
+    /// The metric isn't sent at all.
+    Drop,
+    Map {
+        /// The name the metric is sent with, the original name when `None`.
+        name: Option<String>,
+        /// Added to the labels of the metric, replacing the labels with the same key.
+        labels: Vec<Label>,
+        /// Replaces the type of a histogram.
+        histogram_type: Option<HistogramType>,
+    },
+}
+
+impl Mapped {
+    /// The name the metric is sent with, when the rule renames it.
+    pub(crate) fn name(&self) -> Option<&str> {
+        match self {
+            Mapped::Map { name, .. } => name.as_deref(),
+            Mapped::Drop => None,
+        }
+    }
+
+    pub(crate) fn histogram_type(&self) -> Option<HistogramType> {
+        match self {
+            Mapped::Map { histogram_type, .. } => *histogram_type,
+            Mapped::Drop => None,
+        }
+    }
+
+    /// The labels of the metric once the labels of the rule are added.
+    pub(crate) fn labels<'a>(&self, labels: impl Iterator<Item = &'a Label>) -> Vec<Label> {
+        let mut labels: Vec<_> = labels.cloned().collect();
+        if let Mapped::Map { labels: added, .. } = self {
+            labels.retain(|l| !added.iter().any(|a| a.key() == l.key()));
+            labels.extend(added.iter().cloned());
+        }
+        labels
+    }
+}
+
+impl Mapping {
+    pub(crate) fn load(path: &Path) -> Result<Mapping, StatsdError> {
+        Mapping::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Parse the rules from `config`, a TOML document made of `[[mappings]]` tables:
+    ///
+    /// ```toml
+    /// [[mappings]]
+    /// match = "http.*.requests"
+    /// name = "http.requests"
+    /// labels = { method = "$1" }
+    ///
+    /// [[mappings]]
+    /// match = "debug.*"
+    /// action = "drop"
+    /// ```
+    ///
+    /// In `match`, `*` stands for any part of a dot separated component of the name, and every
+    /// `*` is captured so that `$1`, `$2`, ... can refer to it in `name` and in the label values.
+    /// `type` is one of `histogram`, `distribution` or `timer` and only applies to histograms.
+    ///
+    /// Only the part of TOML needed for this is supported: strings, inline tables of strings and
+    /// comments.
+    pub(crate) fn parse(config: &str) -> Result<Mapping, StatsdError> {
+        let mut rules = Vec::new();
+        let mut table: Option<(usize, Vec<(String, Value)>)> = None;
+        for (index, line) in config.lines().enumerate() {
+            let line_number = index + 1;
+            let invalid = |reason: &str| StatsdError::InvalidMapping {
+                line: line_number,
+                reason: reason.to_string(),
+            };
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line == "[[mappings]]" {
+                if let Some((start, entries)) = table.take() {
+                    rules.push(Rule::new(start, entries)?);
+                }
+                table = Some((line_number, Vec::new()));
+                continue;
+            }
+            let Some((_, entries)) = &mut table else {
+                return Err(invalid("expected `[[mappings]]`"));
+            };
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| invalid("expected `=`"))?;
+            let value = parse_value(value.trim()).map_err(|reason| invalid(&reason))?;
+            entries.push((key.trim().to_string(), value));
+        }
+        if let Some((start, entries)) = table {
+            rules.push(Rule::new(start, entries)?);
+        }
+        Ok(Mapping { rules })
+    }
+
+    /// What the first rule matching `name` does to it, `None` when no rule does.
+    pub(crate) fn apply(&self, name: &str) -> Option<Mapped> {
+        self.rules.iter().find_map(|rule| {
+            let captures = rule.pattern.captures(name)?;
+            if rule.drop {
+                return Some(Mapped::Drop);
+            }
+            Some(Mapped::Map {
+                name: rule.name.as_ref().map(|n| expand(n, &captures)),
+                labels: rule
+                    .labels
+                    .iter()
+                    .map(|(k, v)| Label::new(k.clone(), expand(v, &captures)))
+                    .collect(),
+                histogram_type: rule.histogram_type,
+            })
+        })
+    }
+}
+
+impl Rule {
+    fn new(line: usize, entries: Vec<(String, Value)>) -> Result<Rule, StatsdError> {
+        let invalid = |reason: String| StatsdError::InvalidMapping { line, reason };
+        let mut pattern = None;
+        let mut rule = Rule {
+            pattern: Glob::default(),
+            drop: false,
+            name: None,
+            labels: Vec::new(),
+            histogram_type: None,
+        };
+        for (key, value) in entries {
+            match (key.as_str(), value) {
+                ("match", Value::String(glob)) => {
+                    pattern = Some(Glob::new(&glob).map_err(invalid)?);
+                }
+                ("name", Value::String(name)) => rule.name = Some(name),
+                ("labels", Value::Table(labels)) => rule.labels = labels,
+                ("action", Value::String(action)) => match action.as_str() {
+                    "map" => rule.drop = false,
+                    "drop" => rule.drop = true,
+                    _ => return Err(invalid(format!("unknown action `{}`", action))),
+                },
+                ("type", Value::String(kind)) => match kind.as_str() {
+                    "histogram" | "distribution" | "timer" => {
+                        rule.histogram_type = Some(HistogramType::from(kind.as_str()));
+                    }
+                    _ => return Err(invalid(format!("unknown type `{}`", kind))),
+                },
+                ("match" | "name" | "action" | "type", _) => {
+                    return Err(invalid(format!("`{}` must be a string", key)));
+                }
+                ("labels", _) => return Err(invalid("`labels` must be a table".to_string())),
+                _ => return Err(invalid(format!("unknown key `{}`", key))),
+            }
+        }
+        rule.pattern = pattern.ok_or_else(|| invalid("missing `match`".to_string()))?;
+        Ok(rule)
+    }
+}
+
+/// The mapping of a recorder, which is swapped as a whole when the mapping file is reloaded.
+#[derive(Debug, Default)]
+pub(crate) struct LiveMapping {
+    path: Option<PathBuf>,
+    current: RwLock<Arc<Mapping>>,
+    /// Bumped on every reload, so that registries know when their handles are out of date.
+    generation: AtomicU64,
+    /// When the file was last modified as of the last time it was loaded.
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl LiveMapping {
+    pub(crate) fn load(path: Option<PathBuf>) -> Result<LiveMapping, StatsdError> {
+        let live = LiveMapping {
+            path,
+            ..LiveMapping::default()
+        };
+        live.reload()?;
+        Ok(live)
+    }
+
+    pub(crate) fn current(&self) -> Arc<Mapping> {
+        self.current
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// Read the mapping file again, the current rules are kept when it isn't valid.
+    pub(crate) fn reload(&self) -> Result<(), StatsdError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        // read before loading, so that a change made while loading is picked up next time.
+        let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+        let mapping = Mapping::load(path)?;
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(mapping);
+        *self.modified.lock().unwrap_or_else(|e| e.into_inner()) = modified;
+        self.generation.fetch_add(1, Ordering::Release);
+        Ok(())
+    }
+
+    /// Check whether the mapping file was modified on `interval`, and reload it when it was, until
+    /// the recorder goes away.
+    pub(crate) fn schedule_reload(
+        live: Weak<LiveMapping>,
+        upkeep: &mut Upkeep,
+        interval: Duration,
+    ) {
+        upkeep.every(interval, move || match live.upgrade() {
+            Some(live) => {
+                live.reload_if_modified();
+                true
+            }
+            None => false,
+        });
+    }
+
+    fn reload_if_modified(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+        if modified != *self.modified.lock().unwrap_or_else(|e| e.into_inner()) {
+            // an invalid file is retried once it's modified again, there is nobody to report to.
+            let _ = self.reload();
+        }
+    }
+}
+
+impl PipelineStage for LiveMapping {
+    fn register(&self, metric: &mut PipelineMetric) -> bool {
+        let Some(mapped) = self.current().apply(metric.name()) else {
+            return true;
+        };
+        if mapped == Mapped::Drop {
+            return false;
+        }
+        if let Some(name) = mapped.name() {
+            metric.set_name(name);
+        }
+        *metric.labels_mut() = mapped.labels(metric.labels().iter());
+        if let (
+            Some(histogram_type),
+            MetricType::Histogram | MetricType::Distribution | MetricType::Timer,
+        ) = (mapped.histogram_type(), metric.metric_type)
+        {
+            metric.metric_type = MetricType::from(histogram_type);
+        }
+        true
+    }
+}
+
+/// A pattern matching dot separated names, where `*` matches any part of a single component.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Glob {
+    /// The text before and after the `*` of every component, `None` for a literal component.
+    components: Vec<(String, Option<String>)>,
+}
+
+impl Glob {
+    pub(crate) fn new(pattern: &str) -> Result<Glob, String> {
+        let components = pattern
+            .split('.')
+            .map(|component| match component.split_once('*') {
+                Some((_, suffix)) if suffix.contains('*') => Err(format!(
+                    "`{}` has more than one `*` in a component",
+                    pattern
+                )),
+                Some((prefix, suffix)) => Ok((prefix.to_string(), Some(suffix.to_string()))),
+                None => Ok((component.to_string(), None)),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Glob { components })
+    }
+
+    pub(crate) fn matches(&self, name: &str) -> bool {
+        self.captures(name).is_some()
+    }
+
+    /// What every `*` matched in `name`, `None` when `name` doesn't match.
+    fn captures<'a>(&self, name: &'a str) -> Option<Vec<&'a str>> {
+        let mut captures = Vec::new();
+        let mut parts = name.split('.');
+        for (prefix, suffix) in &self.components {
+            let part = parts.next()?;
+            match suffix {
+                None if part == prefix => {}
+                None => return None,
+                Some(suffix) => {
+                    let rest = part.strip_prefix(prefix.as_str())?;
+                    captures.push(rest.strip_suffix(suffix.as_str())?);
+                }
+            }
+        }
+        parts.next().is_none().then_some(captures)
+    }
+}
+
+/// Replace `$1`, `$2`, ... in `template` with what the matching `*` captured.
+fn expand(template: &str, captures: &[&str]) -> String {
+    let mut expanded = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(dollar) = rest.find('$') {
+        expanded.push_str(&rest[..dollar]);
+        rest = &rest[dollar + 1..];
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        match rest[..digits].parse::<usize>() {
+            Ok(n) => {
+                expanded.push_str(
+                    n.checked_sub(1)
+                        .and_then(|i| captures.get(i))
+                        .unwrap_or(&""),
+                );
+            }
+            Err(_) => expanded.push('$'),
+        }
+        rest = &rest[digits..];
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+enum Value {
+    String(String),
+    Table(Vec<(String, String)>),
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn parse_value(value: &str) -> Result<Value, String> {
+    if let Some(table) = value.strip_prefix('{') {
+        let table = table
+            .strip_suffix('}')
+            .ok_or_else(|| "expected `}`".to_string())?;
+        let mut entries = Vec::new();
+        let mut rest = table.trim();
+        while !rest.is_empty() {
+            let (key, value) = rest
+                .split_once('=')
+                .ok_or_else(|| "expected `=`".to_string())?;
+            let (value, after) = parse_string(value.trim_start())?;
+            entries.push((unquote_key(key.trim())?, value));
+            rest = after.trim_start();
+            if let Some(after) = rest.strip_prefix(',') {
+                rest = after.trim_start();
+            } else if !rest.is_empty() {
+                return Err("expected `,`".to_string());
+            }
+        }
+        return Ok(Value::Table(entries));
+    }
+    match parse_string(value)? {
+        (value, "") => Ok(Value::String(value)),
+        _ => Err("unexpected text after the value".to_string()),
+    }
+}
+
+fn unquote_key(key: &str) -> Result<String, String> {
+    if key.starts_with('"') {
+        match parse_string(key)? {
+            (key, "") => Ok(key),
+            _ => Err("unexpected text after the key".to_string()),
+        }
+    } else {
+        Ok(key.to_string())
+    }
+}
+
+/// Parse the basic string at the start of `input`, returning it along with what comes after it.
+fn parse_string(input: &str) -> Result<(String, &str), String> {
+    let rest = input
+        .strip_prefix('"')
+        .ok_or_else(|| "expected a string".to_string())?;
+    let mut value = String::new();
+    let mut chars = rest.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((value, &rest[i + 1..])),
+            '\\' => match chars.next() {
+                Some((_, '"')) => value.push('"'),
+                Some((_, '\\')) => value.push('\\'),
+                Some((_, 'n')) => value.push('\n'),
+                Some((_, 't')) => value.push('\t'),
+                _ => return Err("unsupported escape sequence".to_string()),
+            },
+            c => value.push(c),
+        }
+    }
+    Err("unterminated string".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics::{Key, Recorder};
+
+    use super::*;
+    use crate::testing::METADATA;
+    use crate::StatsdBuilder;
+
+    const CONFIG: &str = r#"
+        # requests are tagged with their method rather than named after it
+        [[mappings]]
+        match = "http.*.requests"
+        name = "http.requests"
+        labels = { method = "$1", "source" = "mapping" }
+
+        [[mappings]]
+        match = "debug.*"
+        action = "drop" # too noisy
+
+        [[mappings]]
+        match = "db.query_*"
+        type = "timer"
+        labels = { query = "$1" }
+    "#;
+
+    #[test]
+    fn applies_first_matching_rule() {
+        let mapping = Mapping::parse(CONFIG).unwrap();
+
+        assert_eq!(
+            Some(Mapped::Map {
+                name: Some("http.requests".to_string()),
+                labels: vec![Label::new("method", "get"), Label::new("source", "mapping")],
+                histogram_type: None,
+            }),
+            mapping.apply("http.get.requests")
+        );
+        assert_eq!(Some(Mapped::Drop), mapping.apply("debug.cache"));
+        assert_eq!(
+            Some(Mapped::Map {
+                name: None,
+                labels: vec![Label::new("query", "users")],
+                histogram_type: Some(HistogramType::Timer),
+            }),
+            mapping.apply("db.query_users")
+        );
+        assert_eq!(None, mapping.apply("http.get.requests.total"));
+        assert_eq!(None, mapping.apply("debug"));
+    }
+
+    #[test]
+    fn expands_captures() {
+        assert_eq!("a.b-$.", expand("$1.$2-$.$3", &["a", "b"]));
+    }
+
+    #[test]
+    fn reports_invalid_lines() {
+        for (config, line) in [
+            ("match = \"a\"", 1),
+            ("[[mappings]]\n\nmatch = a", 3),
+            ("[[mappings]]\nname = \"a\"", 1),
+            ("[[mappings]]\nmatch = \"a.**\"", 1),
+            ("[[mappings]]\nmatch = \"a\"\ncolor = \"red\"", 1),
+            (
+                "[[mappings]]\nmatch = \"a\"\nlabels = { a = \"b\" c = \"d\" }",
+                3,
+            ),
+        ] {
+            match Mapping::parse(config) {
+                Err(StatsdError::InvalidMapping { line: l, .. }) => {
+                    assert_eq!(line, l, "{}", config)
+                }
+                other => panic!("{:?} for {}", other.map(|_| ()), config),
+            }
+        }
+    }
+
+    #[test]
+    fn mapping_file() {
+        let path = std::env::temp_dir().join(format!("statsd-mapping-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"
+            [[mappings]]
+            match = "http.*.requests"
+            name = "http.requests"
+            labels = { method = "$1" }
+
+            [[mappings]]
+            match = "debug.*"
+            action = "drop"
+
+            [[mappings]]
+            match = "db.duration"
+            type = "timer"
+            "#,
+        )
+        .unwrap();
+        let sink = crate::testing::FakeSink::new();
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(sink.clone())
+            .with_mapping_file(&path)
+            .build(Some("app"))
+            .expect("should build a recorder with custom sink");
+        std::fs::remove_file(&path).unwrap();
+
+        let key = Key::from(("http.get.requests", vec![Label::new("method", "?")]));
+        recorder.register_counter(&key, &METADATA).increment(1);
+        recorder
+            .register_gauge(&Key::from_name("debug.cache"), &METADATA)
+            .set(1.0);
+        recorder
+            .register_histogram(&Key::from_name("db.duration"), &METADATA)
+            .record(0.5);
+        crate::StatsdExt::record_set_member(&recorder, &Key::from_name("debug.users"), "user-42");
+
+        assert_eq!(
+            vec![
+                "app.http.requests:1|c|#method:get",
+                "app.db.duration:500|ms"
+            ],
+            sink.lines()
+        );
+    }
+
+    #[test]
+    fn mapping_reload() {
+        let path = std::env::temp_dir().join(format!("statsd-reload-{}.toml", std::process::id()));
+        let rename = |name: &str| {
+            let rule = format!("[[mappings]]\nmatch = \"requests\"\nname = \"{}\"\n", name);
+            std::fs::write(&path, rule).unwrap();
+        };
+        rename("first");
+        let clock = crate::testing::ManualClock::new();
+        let sink = crate::testing::FakeSink::new();
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(sink.clone())
+            .with_clock(clock.clone())
+            .with_mapping_file(&path)
+            .with_mapping_reload(Duration::from_secs(1))
+            .build(None)
+            .expect("should build a recorder with custom sink");
+        let handle = recorder.handle();
+        let key = Key::from_name("requests");
+        recorder.register_counter(&key, &METADATA).increment(1);
+
+        rename("second");
+        handle.reload_mapping().unwrap();
+        recorder.register_counter(&key, &METADATA).increment(1);
+
+        // an invalid file leaves the rules as they were.
+        std::fs::write(&path, "[[mappings]]\n").unwrap();
+        assert!(handle.reload_mapping().is_err());
+        recorder.register_counter(&key, &METADATA).increment(1);
+
+        rename("third");
+        // make sure the modification time differs from the one of the last valid file.
+        let later = std::time::SystemTime::now() + Duration::from_secs(10);
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        clock.advance(Duration::from_secs(1));
+        recorder.shared.run_pending();
+        recorder.register_counter(&key, &METADATA).increment(1);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            vec!["first:1|c", "second:1|c", "second:1|c", "third:1|c"],
+            sink.lines()
+        );
+    }
+
+    #[test]
+    fn invalid_mapping_file() {
+        let path = std::env::temp_dir().join(format!("statsd-invalid-{}.toml", std::process::id()));
+        std::fs::write(&path, "[[mappings]]\nmatch = \"a\"\naction = \"skip\"\n").unwrap();
+        let result = StatsdBuilder::from("", 0)
+            .with_sink(cadence::NopMetricSink)
+            .with_mapping_file(&path)
+            .build(None);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            result,
+            Err(StatsdError::InvalidMapping { line: 1, .. })
+        ));
+
+        let result = StatsdBuilder::from("", 0)
+            .with_sink(cadence::NopMetricSink)
+            .with_mapping_file(&path)
+            .build(None);
+        assert!(matches!(result, Err(StatsdError::IoError(_))));
+    }
+}
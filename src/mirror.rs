@@ -0,0 +1,93 @@
+use std::io;
+use std::panic::AssertUnwindSafe;
+
+use cadence::ext::MetricBackend;
+use cadence::{MetricSink, SinkStats};
+
+use crate::line::Line;
+use crate::sampling;
+use crate::sink::SharedSink;
+use crate::StatsdRecorder;
+
+/// A [`MetricSink`] wrapper that also hands a share of the lines to other recorders, see
+/// [`StatsdBuilder::with_mirror`](crate::StatsdBuilder::with_mirror).
+///
+/// The lines are sent through the client of the other recorders as they are, they go through the
+/// queue and the connection of those recorders, so that the wrapped sink isn't held up by them.
+pub(crate) struct MirrorSink {
+    inner: SharedSink,
+    /// The recorders and the fraction of the lines each of them gets. Only the client of a
+    /// recorder is used, which is unwind safe, the recorder is kept for the sake of its upkeep.
+    mirrors: Vec<(AssertUnwindSafe<StatsdRecorder>, f64)>,
+}
+
+impl MirrorSink {
+    pub(crate) fn new(inner: SharedSink, mirrors: Vec<(StatsdRecorder, f64)>) -> Self {
+        let mirrors = mirrors
+            .into_iter()
+            .map(|(recorder, fraction)| (AssertUnwindSafe(recorder), fraction))
+            .collect();
+        MirrorSink { inner, mirrors }
+    }
+}
+
+impl MetricSink for MirrorSink {
+    fn emit(&self, metric: &str) -> io::Result<usize> {
+        for (mirror, fraction) in &self.mirrors {
+            if sampling::sampled(*fraction) {
+                // failures are accounted for by the sink of the mirror.
+                let _ = mirror.statsd.send_metric(&Line(metric));
+            }
+        }
+        self.inner.emit(metric)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn stats(&self) -> SinkStats {
+        self.inner.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics::{Key, Recorder};
+
+    use crate::testing::METADATA;
+    use crate::StatsdBuilder;
+
+    #[test]
+    fn mirror() {
+        let mirror_recorder = |sink: &crate::testing::FakeSink| {
+            StatsdBuilder::from("", 0)
+                .with_sink(sink.clone())
+                .build(Some("ignored"))
+                .expect("should build a recorder with custom sink")
+        };
+        let (primary, all, half, none) = (
+            crate::testing::FakeSink::new(),
+            crate::testing::FakeSink::new(),
+            crate::testing::FakeSink::new(),
+            crate::testing::FakeSink::new(),
+        );
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(primary.clone())
+            .with_mirror(mirror_recorder(&all), 100.0)
+            .with_mirror(mirror_recorder(&half), 50.0)
+            .with_mirror(mirror_recorder(&none), 0.0)
+            .build(Some("app"))
+            .expect("should build a recorder with custom sink");
+        let counter = recorder.register_counter(&Key::from_name("requests"), &METADATA);
+        for _ in 0..1000 {
+            counter.increment(1);
+        }
+
+        assert_eq!(1000, primary.lines().len());
+        assert_eq!(primary.lines(), all.lines());
+        assert!((350..=650).contains(&half.lines().len()));
+        assert!(half.lines().iter().all(|line| line == "app.requests:1|c"));
+        assert!(none.lines().is_empty());
+    }
+}
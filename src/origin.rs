@@ -0,0 +1,113 @@
+use std::cell::RefCell;
+use std::env;
+use std::io;
+
+use cadence::{MetricSink, SinkStats};
+
+use crate::sink::SharedSink;
+
+/// The environment variable the Datadog admission controller sets to the external data of a
+/// Kubernetes pod, which the agent resolves the origin of the metrics with.
+pub(crate) const EXTERNAL_DATA_ENV: &str = "DD_EXTERNAL_ENV";
+
+thread_local! {
+    /// Lines are extended in this buffer so that sending a metric doesn't allocate.
+    static FIELD_BUFFER: RefCell<String> = const { RefCell::new(String::new()) };
+}
+
+/// The external data set in [`EXTERNAL_DATA_ENV`], `None` when it's unset or empty.
+pub(crate) fn external_data_from_env() -> Option<String> {
+    env::var(EXTERNAL_DATA_ENV)
+        .ok()
+        .map(|value| sanitize(&value))
+        .filter(|value| !value.is_empty())
+}
+
+/// `value` without the characters that would end the field or the line.
+pub(crate) fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .filter(|c| !matches!(c, '|' | '\n' | '\r'))
+        .collect()
+}
+
+/// A [`MetricSink`] wrapper that appends the DogStatsD external data field, `|e:<value>`, to
+/// every line, see
+/// [`StatsdBuilder::with_external_data`](crate::StatsdBuilder::with_external_data). The field
+/// comes after the tags, so the line is complete by the time it gets here.
+pub(crate) struct ExternalDataSink {
+    inner: SharedSink,
+    /// The `|e:<value>` field.
+    field: String,
+}
+
+impl ExternalDataSink {
+    pub(crate) fn new(inner: SharedSink, external_data: &str) -> Self {
+        ExternalDataSink {
+            inner,
+            field: format!("|e:{}", external_data),
+        }
+    }
+
+    fn write(&self, out: &mut String, metric: &str) {
+        for (i, line) in metric.split('\n').enumerate() {
+            if i > 0 {
+                out.push('\n');
+            }
+            out.push_str(line);
+            if !line.is_empty() {
+                out.push_str(&self.field);
+            }
+        }
+    }
+}
+
+impl MetricSink for ExternalDataSink {
+    fn emit(&self, metric: &str) -> io::Result<usize> {
+        FIELD_BUFFER.with(|buffer| match buffer.try_borrow_mut() {
+            Ok(mut buffer) => {
+                buffer.clear();
+                self.write(&mut buffer, metric);
+                self.inner.emit(&buffer)
+            }
+            Err(_) => {
+                let mut buffer = String::new();
+                self.write(&mut buffer, metric);
+                self.inner.emit(&buffer)
+            }
+        })
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn stats(&self) -> SinkStats {
+        self.inner.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::testing::FakeSink;
+
+    #[test]
+    fn appends_the_field_to_every_line() {
+        let lines = FakeSink::new();
+        let sink = ExternalDataSink::new(Arc::new(lines.clone()), &sanitize("it-false,cn-app|x"));
+        sink.emit("requests:1|c|#env:prod").unwrap();
+        sink.emit("a:1|c\nb:2|g\n").unwrap();
+        assert_eq!(
+            vec![
+                "requests:1|c|#env:prod|e:it-false,cn-appx",
+                "a:1|c|e:it-false,cn-appx",
+                "b:2|g|e:it-false,cn-appx",
+                "",
+            ],
+            lines.lines()
+        );
+    }
+}
@@ -0,0 +1,175 @@
+use std::io;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use cadence::{MetricSink, SinkStats};
+
+use crate::sink::SharedSink;
+use crate::upkeep::Upkeep;
+
+/// How often a partially filled packet is sent, so that metrics don't sit in a [`PackingSink`]
+/// while the application is quiet.
+pub(crate) const PACKET_FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A [`MetricSink`] wrapper that packs as many whole lines as fit in `max_packet_size` bytes into
+/// each write to the wrapped sink, i.e. each datagram, newline separated.
+///
+/// Lines are never split across packets. A write that holds several lines, e.g. a batch, is split
+/// and repacked, and a line that doesn't fit in a packet on its own is sent by itself.
+pub(crate) struct PackingSink {
+    inner: SharedSink,
+    max_packet_size: usize,
+    packet: Mutex<String>,
+}
+
+impl PackingSink {
+    /// Wrap `inner`, which is expected to account for its own errors: once a line is packed there
+    /// is nobody left to report them to.
+    pub(crate) fn new(inner: SharedSink, max_packet_size: usize) -> Self {
+        PackingSink {
+            inner,
+            max_packet_size,
+            packet: Mutex::new(String::with_capacity(max_packet_size)),
+        }
+    }
+
+    fn send(&self, packet: &mut String) {
+        if !packet.is_empty() {
+            let _ = self.inner.emit(packet);
+            packet.clear();
+        }
+    }
+}
+
+impl MetricSink for PackingSink {
+    fn emit(&self, metric: &str) -> io::Result<usize> {
+        let mut packet = self.packet.lock().unwrap_or_else(|e| e.into_inner());
+        for line in metric.split('\n') {
+            if !packet.is_empty() && packet.len() + 1 + line.len() > self.max_packet_size {
+                self.send(&mut packet);
+            }
+            if line.len() > self.max_packet_size {
+                let _ = self.inner.emit(line);
+                continue;
+            }
+            if !packet.is_empty() {
+                packet.push('\n');
+            }
+            packet.push_str(line);
+        }
+        Ok(metric.len())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.send(&mut self.packet.lock().unwrap_or_else(|e| e.into_inner()));
+        self.inner.flush()
+    }
+
+    fn stats(&self) -> SinkStats {
+        self.inner.stats()
+    }
+}
+
+/// Sends the partially filled packet of a [`PackingSink`] on an interval, until the sink goes
+/// away.
+pub(crate) struct PacketFlusher {
+    sink: Weak<PackingSink>,
+}
+
+impl PacketFlusher {
+    pub(crate) fn new(sink: &Arc<PackingSink>) -> Self {
+        PacketFlusher {
+            sink: Arc::downgrade(sink),
+        }
+    }
+
+    pub(crate) fn schedule(self, upkeep: &mut Upkeep, interval: Duration) {
+        upkeep.every(interval, move || match self.sink.upgrade() {
+            Some(sink) => {
+                let _ = sink.flush();
+                true
+            }
+            None => false,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+
+    use metrics::{Key, Recorder};
+
+    use super::*;
+    use crate::testing::{Environ, METADATA};
+    use crate::{MetricType, StatsdBuilder};
+
+    #[derive(Default)]
+    struct PacketsSink {
+        packets: Mutex<Vec<String>>,
+    }
+
+    impl MetricSink for PacketsSink {
+        fn emit(&self, metric: &str) -> io::Result<usize> {
+            self.packets.lock().unwrap().push(metric.to_string());
+            Ok(metric.len())
+        }
+    }
+
+    #[test]
+    fn packs_whole_lines() {
+        let packets = Arc::new(PacketsSink::default());
+        let sink = PackingSink::new(packets.clone(), 12);
+
+        sink.emit("a:1|c").unwrap();
+        sink.emit("b:1|c\nc:1|c").unwrap();
+        sink.emit("too.long.for.a.packet:1|c").unwrap();
+        sink.flush().unwrap();
+
+        assert_eq!(
+            vec!["a:1|c\nb:1|c", "c:1|c", "too.long.for.a.packet:1|c"],
+            *packets.packets.lock().unwrap()
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn udp_gso() {
+        let server_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = server_socket.local_addr().unwrap().port();
+        StatsdBuilder::from("127.0.0.1", port)
+            .with_udp_gso()
+            .send_once(None, MetricType::Counter, "requests", 1.0, &[])
+            .expect("should send with GSO");
+        let mut buf = [0; 64];
+        let len = server_socket.recv(&mut buf).unwrap();
+        assert_eq!(b"requests:1|c", &buf[..len]);
+    }
+
+    #[test]
+    fn max_packet_size() {
+        let (server_socket, builder) = Environ::setup();
+        let recorder = builder
+            .with_queue_size(10)
+            .with_max_packet_size(40)
+            .build(None)
+            .expect("test env should build a valid recorder");
+        let env = Environ {
+            server_socket,
+            recorder,
+        };
+
+        let counter = env
+            .recorder
+            .register_counter(&Key::from_name("counter.name"), &METADATA);
+        counter.increment(1);
+        counter.increment(2);
+        counter.increment(3);
+
+        assert_eq!(
+            "counter.name:1|c\ncounter.name:2|c",
+            env.receive_on_server()
+        );
+        assert_eq!("counter.name:3|c", env.receive_on_server());
+    }
+}
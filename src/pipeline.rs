@@ -0,0 +1,245 @@
+use std::sync::Arc;
+
+use metrics::{Key, Label, Level};
+
+use crate::types::{HistogramType, MetricType};
+
+/// A step that metrics go through before they are sent, see
+/// [`StatsdBuilder::with_stage`](crate::StatsdBuilder::with_stage).
+///
+/// Stages shape a metric once, when it's registered, and can then look at every value it's sent
+/// with. The built-in stages, i.e. the mapping file and the sample rates given to the builder,
+/// come first, in that order, followed by the stages added with `with_stage` in the order they
+/// were added.
+///
+/// ```
+/// use metrics_exporter_statsd::{PipelineMetric, PipelineStage, StatsdBuilder};
+///
+/// /// Drops the metrics of a noisy dependency and samples its cache metrics.
+/// struct Quiet;
+///
+/// impl PipelineStage for Quiet {
+///     fn register(&self, metric: &mut PipelineMetric) -> bool {
+///         if metric.name().starts_with("cache.") {
+///             metric.set_sample_rate(Some(0.1));
+///         }
+///         !metric.name().starts_with("noisy.")
+///     }
+/// }
+///
+/// let recorder = StatsdBuilder::from("127.0.0.1", 8125)
+///     .with_stage(Quiet)
+///     .build(None)
+///     .expect("Could not create StatsdRecorder");
+/// ```
+pub trait PipelineStage: Send + Sync + 'static {
+    /// Rename, relabel or resample `metric` as it's registered, or return `false` to drop it
+    /// altogether, in which case the later stages don't see it.
+    fn register(&self, metric: &mut PipelineMetric) -> bool;
+
+    /// Look at a value of `metric` before it's sampled, as it's sent, i.e. timers in milliseconds,
+    /// and return `false` to drop it. Members of sets aren't numbers and aren't passed here.
+    fn record(&self, metric: &PipelineMetric, value: f64) -> bool {
+        let _ = (metric, value);
+        true
+    }
+}
+
+/// A metric going through the [`PipelineStage`]s.
+#[derive(Clone, Debug)]
+pub struct PipelineMetric {
+    name: String,
+    labels: Vec<Label>,
+    pub(crate) metric_type: MetricType,
+    level: Option<Level>,
+    sample_rate: Option<f64>,
+}
+
+impl PipelineMetric {
+    /// `key` about to be sent as `metric_type`, without its histogram hint nor its type label.
+    pub(crate) fn new(key: &Key, metric_type: MetricType, level: Option<&Level>) -> Self {
+        PipelineMetric {
+            name: key.name().to_string(),
+            labels: key
+                .labels()
+                .filter(|l| {
+                    l.key() != HistogramType::HISTOGRAM_HINT && l.key() != MetricType::TYPE_LABEL
+                })
+                .cloned()
+                .collect(),
+            metric_type,
+            level: level.copied(),
+            sample_rate: None,
+        }
+    }
+
+    /// The name of the metric, without the prefix.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Rename the metric.
+    pub fn set_name<S: Into<String>>(&mut self, name: S) {
+        self.name = name.into();
+    }
+
+    /// The labels the metric is sent with, on top of the default tags.
+    pub fn labels(&self) -> &[Label] {
+        &self.labels
+    }
+
+    /// Add, change or remove labels.
+    pub fn labels_mut(&mut self) -> &mut Vec<Label> {
+        &mut self.labels
+    }
+
+    /// The type the metric is sent as.
+    pub fn metric_type(&self) -> MetricType {
+        self.metric_type
+    }
+
+    /// The level the metric was registered with, `None` for the metrics sent through
+    /// [`StatsdExt`](crate::StatsdExt).
+    pub fn level(&self) -> Option<&Level> {
+        self.level.as_ref()
+    }
+
+    /// The fraction of the values that are sent, `None` when all of them are.
+    pub fn sample_rate(&self) -> Option<f64> {
+        self.sample_rate
+    }
+
+    /// Send a `rate` fraction of the values of the metric, picked at random. A rate of 1 or more
+    /// is the same as `None`, every value is sent.
+    pub fn set_sample_rate(&mut self, rate: Option<f64>) {
+        self.sample_rate = rate.filter(|rate| *rate < 1.0);
+    }
+}
+
+/// The stages of a recorder, in the order they run.
+#[derive(Default)]
+pub(crate) struct Pipeline {
+    stages: Vec<Arc<dyn PipelineStage>>,
+    /// Whether any stage was added with `with_stage`, only those look at values.
+    custom: bool,
+}
+
+impl Pipeline {
+    pub(crate) fn new(
+        builtin: Vec<Arc<dyn PipelineStage>>,
+        custom: Vec<Arc<dyn PipelineStage>>,
+    ) -> Self {
+        Pipeline {
+            custom: !custom.is_empty(),
+            stages: builtin.into_iter().chain(custom).collect(),
+        }
+    }
+
+    /// Run `metric` through every stage, `false` when one of them drops it.
+    pub(crate) fn register(&self, metric: &mut PipelineMetric) -> bool {
+        self.stages.iter().all(|stage| stage.register(metric))
+    }
+
+    /// Whether values need to go through [`Pipeline::record`].
+    pub(crate) fn records(&self) -> bool {
+        self.custom
+    }
+
+    pub(crate) fn record(&self, metric: &PipelineMetric, value: f64) -> bool {
+        self.stages.iter().all(|stage| stage.record(metric, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics::Recorder;
+
+    use super::*;
+    use crate::testing::METADATA;
+    use crate::StatsdBuilder;
+
+    struct Rename(&'static str);
+
+    impl PipelineStage for Rename {
+        fn register(&self, metric: &mut PipelineMetric) -> bool {
+            metric.set_name(format!("{}.{}", metric.name(), self.0));
+            true
+        }
+    }
+
+    struct DropAll;
+
+    impl PipelineStage for DropAll {
+        fn register(&self, _metric: &mut PipelineMetric) -> bool {
+            false
+        }
+
+        fn record(&self, _metric: &PipelineMetric, value: f64) -> bool {
+            value < 10.0
+        }
+    }
+
+    #[test]
+    fn runs_stages_in_order() {
+        let key = Key::from(("name", vec![Label::new("histogram", "timer")]));
+        let mut metric = PipelineMetric::new(&key, MetricType::Timer, None);
+        assert!(metric.labels().is_empty());
+
+        let pipeline = Pipeline::new(vec![Arc::new(Rename("a"))], vec![Arc::new(Rename("b"))]);
+        assert!(pipeline.register(&mut metric));
+        assert_eq!("name.a.b", metric.name());
+        assert!(pipeline.records());
+
+        let pipeline = Pipeline::new(vec![Arc::new(DropAll)], vec![Arc::new(Rename("c"))]);
+        assert!(!pipeline.register(&mut metric));
+        assert_eq!("name.a.b", metric.name());
+        assert!(pipeline.record(&metric, 1.0));
+        assert!(!pipeline.record(&metric, 10.0));
+    }
+
+    #[test]
+    fn stages() {
+        struct Cap;
+
+        impl PipelineStage for Cap {
+            fn register(&self, metric: &mut crate::PipelineMetric) -> bool {
+                metric.labels_mut().push(Label::new("capped", "true"));
+                metric.name() != "dropped"
+            }
+
+            fn record(&self, _metric: &crate::PipelineMetric, value: f64) -> bool {
+                value <= 10.0
+            }
+        }
+
+        struct Suffix;
+
+        impl PipelineStage for Suffix {
+            fn register(&self, metric: &mut crate::PipelineMetric) -> bool {
+                metric.set_name(format!("{}.{}", metric.name(), metric.labels().len()));
+                true
+            }
+        }
+
+        let sink = crate::testing::FakeSink::new();
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(sink.clone())
+            .with_stage(Cap)
+            .with_stage(Suffix)
+            .build(None)
+            .expect("should build a recorder with custom sink");
+
+        let counter = recorder.register_counter(&Key::from_name("counter"), &METADATA);
+        counter.increment(1);
+        counter.increment(11);
+        recorder
+            .register_gauge(&Key::from_name("dropped"), &METADATA)
+            .set(1.0);
+        crate::StatsdExt::record_distribution(&recorder, &Key::from_name("size"), 3.0);
+
+        assert_eq!(
+            vec!["counter.1:1|c|#capped:true", "size.1:3|d|#capped:true"],
+            sink.lines()
+        );
+    }
+}
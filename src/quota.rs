@@ -0,0 +1,213 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::clock::SharedClock;
+use crate::targets;
+
+/// The limits of a target, see
+/// [`StatsdBuilder::with_target_quota`](crate::StatsdBuilder::with_target_quota).
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Limits {
+    /// Most metrics registered from the target at once.
+    pub(crate) max_metrics: Option<usize>,
+    /// Most values sent from the target every second.
+    pub(crate) per_second: Option<u64>,
+}
+
+/// The quotas of every target given a limit.
+#[derive(Default)]
+pub(crate) struct Quotas {
+    quotas: Vec<(String, Arc<Quota>)>,
+}
+
+impl Quotas {
+    pub(crate) fn new(limits: Vec<(String, Limits)>, clock: SharedClock) -> Self {
+        let quotas = limits
+            .into_iter()
+            .map(|(target, limits)| {
+                let quota = Quota {
+                    target: target.clone(),
+                    limits,
+                    metrics: AtomicUsize::new(0),
+                    window: Mutex::new((clock.now(), 0)),
+                    clock: clock.clone(),
+                    exceeded: AtomicBool::new(false),
+                };
+                (target, Arc::new(quota))
+            })
+            .collect();
+        Quotas { quotas }
+    }
+
+    /// A slot in the quota of the longest target that `target` is or is within, `None` when
+    /// there's none.
+    pub(crate) fn slot(&self, target: &str) -> Option<QuotaSlot> {
+        let (_, quota) = self
+            .quotas
+            .iter()
+            .filter(|(mapped, _)| targets::within(target, mapped))
+            .max_by_key(|(mapped, _)| mapped.len())?;
+        // `None` when the metrics of the target aren't limited.
+        let counted = quota.limits.max_metrics.map(|max_metrics| {
+            quota
+                .metrics
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |metrics| {
+                    (metrics < max_metrics).then_some(metrics + 1)
+                })
+                .is_ok()
+        });
+        Some(QuotaSlot {
+            quota: quota.clone(),
+            counted: counted == Some(true),
+            over: counted == Some(false),
+        })
+    }
+}
+
+pub(crate) struct Quota {
+    target: String,
+    limits: Limits,
+    /// The metrics registered from the target that count towards the limit.
+    metrics: AtomicUsize,
+    /// The start of the current second and the values sent in it.
+    window: Mutex<(Instant, u64)>,
+    clock: SharedClock,
+    /// Whether the target went over its limit of metrics, which is only logged once.
+    exceeded: AtomicBool,
+}
+
+/// The share of a metric in the quota of its target, given back when the metric goes away.
+pub(crate) struct QuotaSlot {
+    quota: Arc<Quota>,
+    /// Whether the metric counts towards the limit of metrics.
+    counted: bool,
+    /// Whether the target was at its limit of metrics when the metric was registered.
+    over: bool,
+}
+
+impl QuotaSlot {
+    /// Whether the metric is dropped for being over the limit of metrics of its target.
+    pub(crate) fn over(&self) -> bool {
+        self.over
+    }
+
+    /// The line logged the first time the target goes over its limit of metrics, see
+    /// [`StatsdBuilder::with_log`](crate::StatsdBuilder::with_log).
+    pub(crate) fn first_exceeded(&self, name: &str) -> Option<String> {
+        let max_metrics = self.quota.limits.max_metrics?;
+        if !self.over || self.quota.exceeded.swap(true, Ordering::Relaxed) {
+            return None;
+        }
+        Some(format!(
+            "target {} is over its quota of {} metrics, {} and the metrics registered after it are dropped",
+            self.quota.target, max_metrics, name
+        ))
+    }
+
+    /// Whether a value fits in the values the target may send this second.
+    pub(crate) fn admit(&self) -> bool {
+        let Some(per_second) = self.quota.limits.per_second else {
+            return true;
+        };
+        let now = self.quota.clock.now();
+        let mut window = self.quota.window.lock().unwrap_or_else(|e| e.into_inner());
+        let (start, sent) = &mut *window;
+        if now.saturating_duration_since(*start) >= Duration::from_secs(1) {
+            *start = now;
+            *sent = 0;
+        }
+        if *sent >= per_second {
+            return false;
+        }
+        *sent += 1;
+        true
+    }
+}
+
+impl Drop for QuotaSlot {
+    fn drop(&mut self) {
+        if self.counted {
+            self.quota.metrics.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics::{Key, Label, Recorder};
+
+    use super::*;
+    use crate::testing::{ManualClock, METADATA};
+    use crate::{DropReason, StatsdBuilder};
+
+    #[test]
+    fn limits_metrics_and_values() {
+        let clock = ManualClock::new();
+        let limits = Limits {
+            max_metrics: Some(1),
+            per_second: Some(2),
+        };
+        let quotas = Quotas::new(vec![("noisy".to_string(), limits)], Arc::new(clock.clone()));
+        assert!(quotas.slot("quiet").is_none());
+
+        let first = quotas.slot("noisy::client").unwrap();
+        let second = quotas.slot("noisy").unwrap();
+        assert!(!first.over());
+        assert!(second.over());
+        assert!(second.first_exceeded("noisy.requests").is_some());
+        assert!(quotas.slot("noisy").unwrap().first_exceeded("x").is_none());
+        // the slot is given back with the metric.
+        drop(first);
+        assert!(!quotas.slot("noisy").unwrap().over());
+
+        let slot = quotas.slot("noisy").unwrap();
+        assert_eq!(
+            vec![true, true, false],
+            vec![slot.admit(), slot.admit(), slot.admit()]
+        );
+        clock.advance(Duration::from_secs(1));
+        assert!(slot.admit());
+    }
+
+    #[test]
+    fn target_quotas() {
+        let clock = crate::testing::ManualClock::new();
+        let sink = crate::testing::FakeSink::new();
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(sink.clone())
+            .with_clock(clock.clone())
+            .with_target_quota("noisy", 2)
+            .with_target_rate_quota("noisy::client", 1)
+            .build(None)
+            .expect("should build a recorder with custom sink");
+        let handle = recorder.handle();
+        let noisy = metrics::Metadata::new("noisy::server", metrics::Level::INFO, None);
+        let client = metrics::Metadata::new("noisy::client", metrics::Level::INFO, None);
+
+        for id in ["1", "2", "3"] {
+            let key = Key::from_parts("requests", vec![Label::new("id", id)]);
+            recorder.register_counter(&key, &noisy).increment(1);
+        }
+        let retries = recorder.register_counter(&Key::from_name("retries"), &client);
+        retries.increment(1);
+        retries.increment(1);
+        clock.advance(Duration::from_secs(1));
+        retries.increment(1);
+        recorder
+            .register_counter(&Key::from_name("requests"), &METADATA)
+            .increment(1);
+
+        assert_eq!(
+            vec![
+                "requests:1|c|#id:1",
+                "requests:1|c|#id:2",
+                "retries:1|c",
+                "retries:1|c",
+                "requests:1|c",
+            ],
+            sink.lines()
+        );
+        assert_eq!(2, handle.dropped_metrics().get(DropReason::OverQuota));
+    }
+}
@@ -0,0 +1,98 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::line::RenderedKey;
+
+/// Whether the per-second rates of the counters are sent along with their counts or instead of
+/// them, see [`StatsdBuilder::with_counter_rates`](crate::StatsdBuilder::with_counter_rates).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CounterRates {
+    /// Send the counts as usual, as well as the rates.
+    Alongside,
+    /// Only send the rates.
+    Instead,
+}
+
+/// The count of a counter since its rate was last sent.
+#[derive(Debug)]
+pub(crate) struct Rate {
+    /// The `.rate` gauge of the counter.
+    pub(crate) rendered: RenderedKey,
+    count: AtomicU64,
+}
+
+impl Rate {
+    pub(crate) fn new(rendered: RenderedKey) -> Self {
+        Rate {
+            rendered,
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn add(&self, value: u64) {
+        self.count.fetch_add(value, Ordering::Relaxed);
+    }
+
+    /// The count per second over `elapsed`, starting over.
+    pub(crate) fn take(&self, elapsed: Duration) -> f64 {
+        self.count.swap(0, Ordering::Relaxed) as f64 / elapsed.as_secs_f64()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics::{Key, Label, Recorder};
+
+    use super::*;
+    use crate::intern::Interner;
+    use crate::testing::METADATA;
+    use crate::StatsdBuilder;
+
+    #[test]
+    fn rates_per_second() {
+        let rate = Rate::new(RenderedKey::new(
+            "",
+            "hits.rate",
+            &[],
+            std::iter::empty(),
+            &Interner::default(),
+        ));
+        rate.add(10);
+        rate.add(5);
+        assert_eq!(1.5, rate.take(Duration::from_secs(10)));
+        assert_eq!(0.0, rate.take(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn counter_rates() {
+        for (rates, counts) in [(CounterRates::Alongside, 2), (CounterRates::Instead, 0)] {
+            let clock = crate::testing::ManualClock::new();
+            let sink = crate::testing::FakeSink::new();
+            let recorder = StatsdBuilder::from("", 0)
+                .with_sink(sink.clone())
+                .with_clock(clock.clone())
+                .with_counter_rates(rates, Duration::from_secs(10))
+                .build(None)
+                .expect("should build a recorder with custom sink");
+            let key = Key::from(("requests", vec![Label::new("path", "/")]));
+            let counter = recorder.register_counter(&key, &METADATA);
+            counter.increment(20);
+            counter.increment(5);
+            recorder
+                .register_gauge(&Key::from_name("gauge"), &METADATA)
+                .set(1.0);
+
+            clock.advance(Duration::from_secs(10));
+            recorder.shared.run_pending();
+            let lines = sink.lines();
+            assert_eq!(
+                counts,
+                lines.iter().filter(|l| l.ends_with("|c|#path:/")).count()
+            );
+            assert_eq!(
+                Some("requests.rate:2.5|g|#path:/"),
+                lines.last().map(String::as_str)
+            );
+        }
+    }
+}
@@ -1,95 +1,835 @@
-use std::sync::Arc;
-use std::time::Duration;
+use std::borrow::Cow;
+use std::iter;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
 
-use cadence::{Counted, Distributed, Gauged, Histogrammed, MetricBuilder, StatsdClient, Timed};
+use cadence::ext::MetricBackend;
+use cadence::StatsdClient;
 use metrics::{Counter, CounterFn, SharedString};
 use metrics::{Gauge, GaugeFn};
 use metrics::{Histogram, HistogramFn};
-use metrics::{Key, KeyName, Label, Metadata, Recorder, Unit};
+use metrics::{Key, KeyName, Level, Metadata, Recorder, Unit};
 
-use crate::types::HistogramType;
+use crate::ambient::{self, TagGuard};
+use crate::catalog::{DescribedKind, MetricDescription};
+use crate::clock::SharedClock;
+use crate::conflicts;
+use crate::handle::{Scope, Shared, StatsdHandle};
+use crate::hdr::{TimerHistogram, TimerStat};
+use crate::line::{format_prefix, ContextTags, Line, RenderedKey, Value};
+use crate::pipeline::PipelineMetric;
+use crate::quota::QuotaSlot;
+use crate::rates::{CounterRates, Rate};
+use crate::registry::{Registries, Registry};
+use crate::routing::Routes;
+use crate::sampling;
+use crate::sketch::Sketch;
+use crate::smoothing::TokenBucket;
+use crate::snapshot::{ActiveKey, Emits};
+use crate::stats::DropReason;
+use crate::summary::Summary;
+use crate::targets::TargetPrefixes;
+use crate::tee::{SharedRecorder, Tee};
+use crate::types::{HistogramType, MetricType};
+use crate::upkeep::Upkeep;
 
 /// A recorder for sending the reported metrics to Statsd.
 /// Under the hood this recorder uses [`StatsdClient`] implementation provided by [`cadence`] crate.
-/// The calls to `register_*` methods are pretty much ignored because statsd doesn't have any facility
-/// for registering metrics with descriptions. This recorder's main responsibility is to map metrics
-/// library's interface/types to a supported [`StatsdClient`] calls/types.
+/// Statsd doesn't have any facility for registering metrics with descriptions, so the descriptions
+/// given to the `describe_*` methods are only kept in memory, see [`StatsdRecorder::descriptions`].
+/// This recorder's main responsibility is to map metrics library's interface/types to a supported
+/// [`StatsdClient`] calls/types.
+///
+/// Everything this recorder is given is also forwarded to the recorder set with
+/// [`StatsdBuilder::tee`](crate::StatsdBuilder::tee), if any. The metrics matching a route, see
+/// [`StatsdBuilder::with_route_by_tag`](crate::StatsdBuilder::with_route_by_tag), are handed to
+/// the recorder of the route instead, routes can also take the metrics of a level or a target.
+///
+/// Cloning a recorder is cheap, clones share the client, the registered metrics and everything
+/// else, e.g. so that the same recorder can be handed to [`metrics::with_local_recorder`] in
+/// several places.
+#[derive(Clone)]
 pub struct StatsdRecorder {
     pub(crate) statsd: Arc<StatsdClient>,
     pub(crate) default_histogram: HistogramType,
+    pub(crate) shared: Arc<Shared>,
+    pub(crate) scope: Arc<Scope>,
+    pub(crate) registry: Arc<Registry<Handle>>,
+    pub(crate) tee: Option<SharedRecorder>,
+    /// The recorders taking some of the metrics instead of this one.
+    pub(crate) routes: Arc<Routes>,
+    /// Prefixes the metrics with a segment derived from their target.
+    pub(crate) targets: Option<Arc<TargetPrefixes>>,
 }
 
-impl Recorder for StatsdRecorder {
-    fn describe_counter(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {
-        // statsd recording does not support descriptions.
+impl StatsdRecorder {
+    /// Returns a [`StatsdHandle`] that stays usable after this recorder has been installed, e.g.
+    /// to find out how many metrics were dropped.
+    pub fn handle(&self) -> StatsdHandle {
+        StatsdHandle {
+            shared: self.shared.clone(),
+            scope: self.scope.clone(),
+            statsd: Arc::downgrade(&self.statsd),
+        }
     }
 
-    fn describe_gauge(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {
-        // statsd recording does not support descriptions.
+    /// Descriptions supplied via the `describe_*` macros, sorted by name. Use
+    /// [`StatsdHandle::descriptions`] once the recorder has been installed.
+    pub fn descriptions(&self) -> Vec<MetricDescription> {
+        self.shared.catalog.descriptions()
     }
 
-    fn describe_histogram(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {
-        // statsd recording does not support descriptions.
+    /// The descriptions as JSON, see [`StatsdHandle::descriptions_json`].
+    pub fn descriptions_json(&self) -> String {
+        self.shared.descriptions_json()
     }
 
-    fn register_counter(&self, key: &Key, _metadata: &Metadata<'_>) -> Counter {
-        Counter::from_arc(Arc::new(Handle::new(
-            key.clone(),
-            self.statsd.clone(),
-            self.default_histogram,
-        )))
+    /// Add `tags` to every metric recorded on the current thread until the returned guard is
+    /// dropped, e.g. the phase of the application in synchronous code or in a test. This is the
+    /// synchronous form of [`with_tags`](crate::with_tags), which it stacks with, and the tags
+    /// come after the same ones. Guards are meant to be dropped in the reverse order they were
+    /// pushed, dropping a guard also removes the tags pushed after it.
+    ///
+    /// Tags are kept by thread rather than by recorder, so they're added to the metrics of every
+    /// recorder of this crate, scoped ones included.
+    ///
+    /// ```
+    /// use metrics_exporter_statsd::StatsdBuilder;
+    ///
+    /// let recorder = StatsdBuilder::from("127.0.0.1", 8125)
+    ///     .build(None)
+    ///     .expect("Could not create StatsdRecorder");
+    /// let _startup = recorder.push_tags([("phase", "startup")]);
+    /// // emits `cache.loaded:1|c|#phase:startup`
+    /// metrics::with_local_recorder(&recorder, || metrics::counter!("cache.loaded").increment(1));
+    /// ```
+    pub fn push_tags<I, K, V>(&self, tags: I) -> TagGuard
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: ToString,
+        V: ToString,
+    {
+        ambient::push_tags(tags)
+    }
+
+    /// A recorder for a part of the application, e.g. a library, that sends its metrics through the
+    /// same client as this one, prefixed with `prefix` on top of the prefix of this recorder and
+    /// tagged with `tags` on top of the default tags. A tag in `tags` replaces the default tag with
+    /// the same key.
+    ///
+    /// Scoped recorders are cheap to make and share everything else with this recorder, e.g. the
+    /// queue, the dropped metrics and the descriptions. They can be scoped further.
+    ///
+    /// ```
+    /// use metrics_exporter_statsd::StatsdBuilder;
+    ///
+    /// let recorder = StatsdBuilder::from("127.0.0.1", 8125)
+    ///     .build(Some("app"))
+    ///     .expect("Could not create StatsdRecorder");
+    /// let db = recorder.scoped("db", [("pool", "primary")]);
+    ///
+    /// // emits `app.db.queries:1|c|#pool:primary`
+    /// metrics::with_local_recorder(&db, || metrics::counter!("queries").increment(1));
+    /// ```
+    pub fn scoped<I, K, V>(&self, prefix: &str, tags: I) -> StatsdRecorder
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: ToString,
+        V: ToString,
+    {
+        let tags: Vec<(String, String)> = tags
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        let mut default_tags = self.scope.default_tags.clone();
+        for (key, value) in &tags {
+            match default_tags.iter_mut().find(|(k, _)| k == key) {
+                Some(tag) => tag.1 = value.clone(),
+                None => default_tags.push((key.clone(), value.clone())),
+            }
+        }
+        // the same key renders differently in another scope, so handles can't be shared.
+        let registry = Arc::default();
+        self.shared.registries.add(&registry);
+        StatsdRecorder {
+            statsd: self.statsd.clone(),
+            default_histogram: self.default_histogram,
+            shared: self.shared.clone(),
+            scope: Arc::new(Scope {
+                prefix: format!("{}{}", self.scope.prefix, format_prefix(prefix)),
+                default_tags,
+            }),
+            registry,
+            tee: self.tee.clone(),
+            routes: Arc::new(self.routes.scoped(prefix, &tags)),
+            targets: self
+                .targets
+                .as_ref()
+                .map(|targets| Arc::new(targets.fresh())),
+        }
+    }
+
+    /// The recorder scoped to the prefix segment of the target of `metadata` that registers the
+    /// metric, `None` when it's this one, see [`TargetPrefixes`].
+    fn target_scope(&self, metadata: &Metadata<'_>) -> Option<StatsdRecorder> {
+        let targets = self.targets.as_ref()?;
+        targets.scope(metadata.target(), |segment| StatsdRecorder {
+            // the metrics are teed and routed by this recorder.
+            tee: None,
+            routes: Arc::default(),
+            targets: None,
+            ..self.scoped(segment, iter::empty::<(String, String)>())
+        })
+    }
+
+    /// Register the `specs` ahead of time, e.g. the metrics of the hottest paths at startup, so
+    /// that the first time they're recorded doesn't pay for their registration: the metrics go
+    /// through the pipeline, e.g. the mapping, and their lines are rendered up front. Returns the
+    /// specs of the metrics that won't be sent, i.e. that are dropped by a stage of the pipeline
+    /// or, see [`StatsdBuilder::with_strict_validation`](crate::StatsdBuilder::with_strict_validation),
+    /// that are malformed or registered as another type before, so that mistakes show at
+    /// startup.
+    ///
+    /// A metric is registered with the route its spec matches, if any, but not with the recorder
+    /// given to [`StatsdBuilder::tee`](crate::StatsdBuilder::tee). Give a spec the target the
+    /// metric is recorded with when routes or target prefixes depend on it.
+    ///
+    /// ```
+    /// use metrics_exporter_statsd::{MetricSpec, StatsdBuilder};
+    ///
+    /// let recorder = StatsdBuilder::from("127.0.0.1", 8125)
+    ///     .build(Some("app"))
+    ///     .expect("Could not create StatsdRecorder");
+    /// let dropped = recorder.preregister(&[
+    ///     MetricSpec::counter("requests"),
+    ///     MetricSpec::histogram("request.duration"),
+    /// ]);
+    /// assert!(dropped.is_empty());
+    /// ```
+    pub fn preregister(&self, specs: &[MetricSpec]) -> Vec<MetricSpec> {
+        specs
+            .iter()
+            .filter(|spec| !self.preregister_one(spec))
+            .cloned()
+            .collect()
+    }
+
+    /// Register `spec`, returns whether the metric is sent.
+    fn preregister_one(&self, spec: &MetricSpec) -> bool {
+        let metadata = Metadata::new(&spec.target, spec.level, None);
+        match self.routes.route(&spec.key, &metadata) {
+            Some(route) => route.preregister_one(spec),
+            None => {
+                let handle = self.local_handle(&spec.key, spec.kind, &metadata);
+                !handle.dropped && !handle.malformed && !handle.conflicting && !handle.over_quota()
+            }
+        }
+    }
+
+    /// Add a description to the catalog, logging how it disagrees with the one it replaces.
+    fn describe(
+        &self,
+        kind: DescribedKind,
+        key: KeyName,
+        unit: Option<Unit>,
+        description: SharedString,
+    ) {
+        let mismatch = self.shared.catalog.describe(kind, key, unit, description);
+        if let (Some(mismatch), Some(log)) = (mismatch, &self.shared.log) {
+            log(&mismatch);
+        }
+    }
+
+    /// The handle of `key` registered as `kind`, with this recorder or with the one scoped to the
+    /// target of `metadata`.
+    fn local_handle(&self, key: &Key, kind: DescribedKind, metadata: &Metadata<'_>) -> Arc<Handle> {
+        let scoped = self.target_scope(metadata);
+        let recorder = scoped.as_ref().unwrap_or(self);
+        recorder.registry.sync(self.shared.mapping.generation());
+        match kind {
+            DescribedKind::Counter => recorder.registry.counter(key, |key| {
+                recorder.new_handle(key, MetricType::Counter, metadata)
+            }),
+            DescribedKind::Gauge => recorder.registry.gauge(key, |key| {
+                recorder.new_handle(key, MetricType::Gauge, metadata)
+            }),
+            DescribedKind::Histogram => recorder.registry.histogram(key, |key| {
+                // the histogram hint only picks the type of the metric, it doesn't end up in the
+                // tags.
+                let histogram_type =
+                    HistogramType::type_from(key).unwrap_or(self.default_histogram);
+                recorder.new_handle(key, MetricType::from(histogram_type), metadata)
+            }),
+        }
+    }
+
+    /// `name` with the unit `key` was described with, see
+    /// [`crate::StatsdBuilder::with_unit_suffixes`].
+    fn name<'a>(&self, key: &Key, name: &'a str, kind: DescribedKind) -> Cow<'a, str> {
+        if !self.shared.unit_suffixes {
+            return Cow::Borrowed(name);
+        }
+        match self.shared.catalog.unit(kind, key.name()) {
+            Some(unit) if !name.ends_with(unit.as_str()) => {
+                Cow::Owned(format!("{}.{}", name, unit.as_str()))
+            }
+            _ => Cow::Borrowed(name),
+        }
+    }
+
+    /// A handle sending `key` as `metric_type` once it went through the pipeline.
+    fn new_handle(
+        &self,
+        key: &Arc<Key>,
+        metric_type: MetricType,
+        metadata: &Metadata<'_>,
+    ) -> Handle {
+        let metric_type = MetricType::type_from(key).unwrap_or(metric_type);
+        let mut metric = PipelineMetric::new(key, metric_type, Some(metadata.level()));
+        let dropped = !self.shared.pipeline.register(&mut metric);
+        // timers are always sent in milliseconds, whatever unit the histogram was described with.
+        let name = match metric.metric_type {
+            MetricType::Counter => self.name(key, metric.name(), DescribedKind::Counter),
+            MetricType::Gauge => self.name(key, metric.name(), DescribedKind::Gauge),
+            MetricType::Histogram | MetricType::Distribution => {
+                self.name(key, metric.name(), DescribedKind::Histogram)
+            }
+            MetricType::Timer | MetricType::Set | MetricType::Meter => Cow::Borrowed(metric.name()),
+        };
+        let (annotated, scale) = self
+            .shared
+            .sample_rate_semantics
+            .split(metric.metric_type, metric.sample_rate());
+        let malformed = self.shared.strict && !self.scope.valid(&name, metric.labels().iter());
+        let rendered = self
+            .scope
+            .render(&self.shared, &name, metric.labels().iter())
+            .with_sample_rate(annotated);
+        let conflict = match dropped {
+            true => None,
+            false => self
+                .shared
+                .types
+                .register(rendered.name(), metric.metric_type),
+        };
+        if let (Some(conflict), Some(log)) = (conflict.filter(|c| c.new), &self.shared.log) {
+            log(&conflicts::describe(
+                rendered.name(),
+                metric.metric_type,
+                conflict.first,
+            ));
+        }
+        let conflicting = self.shared.strict && conflict.is_some();
+        let quota = match dropped {
+            true => None,
+            false => self.shared.quotas.slot(metadata.target()),
+        };
+        let exceeded = quota
+            .as_ref()
+            .and_then(|quota| quota.first_exceeded(rendered.name()));
+        if let (Some(exceeded), Some(log)) = (exceeded, &self.shared.log) {
+            log(&exceeded);
+        }
+        let over_quota = quota.as_ref().is_some_and(QuotaSlot::over);
+        let sent = !dropped && !conflicting && !over_quota;
+        let rate = match (self.shared.counter_rates, metric.metric_type) {
+            (Some(_), MetricType::Counter) if sent => Some(Rate::new(self.scope.render(
+                &self.shared,
+                &format!("{}.rate", name),
+                metric.labels().iter(),
+            ))),
+            _ => None,
+        };
+        let summary = match &self.shared.summaries {
+            Some(percentiles) if metric.metric_type == MetricType::Histogram && sent => {
+                let quantiles = percentiles
+                    .quantiles(metric.name())
+                    .iter()
+                    .map(|quantile| {
+                        let name = format!("{}{}", name, percentiles.naming.suffix(*quantile));
+                        let rendered =
+                            self.scope
+                                .render(&self.shared, &name, metric.labels().iter());
+                        (*quantile, rendered)
+                    })
+                    .collect();
+                Some(Summary::new(quantiles))
+            }
+            _ => None,
+        };
+        let sketch = match &self.shared.sketches {
+            Some(sketching)
+                if metric.metric_type == MetricType::Distribution
+                    && sent
+                    && sketching.applies(metric.name()) =>
+            {
+                let rendered = self
+                    .scope
+                    .render(&self.shared, &name, metric.labels().iter());
+                Some(Sketch::new(rendered, sketching.relative_accuracy))
+            }
+            _ => None,
+        };
+        let timer_histogram = match &self.shared.hdr_timers {
+            Some((significant_digits, percentiles))
+                if metric.metric_type == MetricType::Timer && sent =>
+            {
+                let render = |suffix: &str| {
+                    let name = format!("{}{}", name, suffix);
+                    self.scope
+                        .render(&self.shared, &name, metric.labels().iter())
+                };
+                let quantiles = percentiles
+                    .quantiles(metric.name())
+                    .iter()
+                    .map(|quantile| (*quantile, render(&percentiles.naming.suffix(*quantile))))
+                    .collect();
+                Some(TimerHistogram::new(
+                    quantiles,
+                    render(".min"),
+                    render(".max"),
+                    render(".count"),
+                    *significant_digits,
+                ))
+            }
+            _ => None,
+        };
+        let bucket = match (&self.shared.smoothing, metric.metric_type) {
+            (Some(smoothing), MetricType::Counter | MetricType::Gauge) if sent => {
+                Some(smoothing.bucket())
+            }
+            _ => None,
+        };
+        let also = if !sent {
+            None
+        } else {
+            self.shared
+                .dual_emit
+                .also(metric.name(), metric.metric_type)
+        };
+        Handle {
+            key: key.clone(),
+            rendered,
+            rate,
+            summary,
+            sketch,
+            timer_histogram,
+            statsd: self.statsd.clone(),
+            metric_type: metric.metric_type,
+            also,
+            sample_rate: metric.sample_rate(),
+            scale,
+            dropped,
+            malformed,
+            conflicting,
+            quota,
+            bucket,
+            metric: self.shared.pipeline.records().then(|| Arc::new(metric)),
+            bytes: AtomicU64::new(0),
+            emits: Emits::default(),
+            shared: self.shared.clone(),
+        }
     }
+}
 
-    fn register_gauge(&self, key: &Key, _metadata: &Metadata<'_>) -> Gauge {
-        Gauge::from_arc(Arc::new(Handle::new(
+impl Recorder for StatsdRecorder {
+    fn describe_counter(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.describe(
+            DescribedKind::Counter,
             key.clone(),
-            self.statsd.clone(),
-            self.default_histogram,
-        )))
+            unit,
+            description.clone(),
+        );
+        for route in self.routes.recorders() {
+            route.describe_counter(key.clone(), unit, description.clone());
+        }
+        if let Some(tee) = &self.tee {
+            tee.describe_counter(key, unit, description);
+        }
+    }
+
+    fn describe_gauge(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.describe(DescribedKind::Gauge, key.clone(), unit, description.clone());
+        for route in self.routes.recorders() {
+            route.describe_gauge(key.clone(), unit, description.clone());
+        }
+        if let Some(tee) = &self.tee {
+            tee.describe_gauge(key, unit, description);
+        }
     }
 
-    fn register_histogram(&self, key: &Key, _metadata: &Metadata<'_>) -> Histogram {
-        Histogram::from_arc(Arc::new(Handle::new(
+    fn describe_histogram(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.describe(
+            DescribedKind::Histogram,
             key.clone(),
-            self.statsd.clone(),
-            self.default_histogram,
-        )))
+            unit,
+            description.clone(),
+        );
+        for route in self.routes.recorders() {
+            route.describe_histogram(key.clone(), unit, description.clone());
+        }
+        if let Some(tee) = &self.tee {
+            tee.describe_histogram(key, unit, description);
+        }
+    }
+
+    fn register_counter(&self, key: &Key, metadata: &Metadata<'_>) -> Counter {
+        let counter = match self.routes.route(key, metadata) {
+            Some(route) => route.register_counter(key, metadata),
+            None => Counter::from_arc(self.local_handle(key, DescribedKind::Counter, metadata)),
+        };
+        match &self.tee {
+            Some(tee) => {
+                let other = tee.register_counter(key, metadata);
+                Counter::from_arc(Arc::new(Tee::new(counter, other)))
+            }
+            None => counter,
+        }
+    }
+
+    fn register_gauge(&self, key: &Key, metadata: &Metadata<'_>) -> Gauge {
+        let gauge = match self.routes.route(key, metadata) {
+            Some(route) => route.register_gauge(key, metadata),
+            None => Gauge::from_arc(self.local_handle(key, DescribedKind::Gauge, metadata)),
+        };
+        match &self.tee {
+            Some(tee) => {
+                let other = tee.register_gauge(key, metadata);
+                Gauge::from_arc(Arc::new(Tee::new(gauge, other)))
+            }
+            None => gauge,
+        }
+    }
+
+    fn register_histogram(&self, key: &Key, metadata: &Metadata<'_>) -> Histogram {
+        let histogram = match self.routes.route(key, metadata) {
+            Some(route) => route.register_histogram(key, metadata),
+            None => Histogram::from_arc(self.local_handle(key, DescribedKind::Histogram, metadata)),
+        };
+        // the other recorder gets the key as is, hint included, since it may make use of it too.
+        match &self.tee {
+            Some(tee) => {
+                let other = tee.register_histogram(key, metadata);
+                Histogram::from_arc(Arc::new(Tee::new(histogram, other)))
+            }
+            None => histogram,
+        }
+    }
+}
+
+/// A metric to register ahead of time, see [`StatsdRecorder::preregister`].
+#[derive(Clone, Debug)]
+pub struct MetricSpec {
+    /// Which macro the metric is recorded with.
+    pub kind: DescribedKind,
+    pub key: Key,
+    /// The target the metric is recorded with, empty unless set.
+    pub target: String,
+    /// The level the metric is recorded at, `INFO` unless set.
+    pub level: Level,
+}
+
+impl MetricSpec {
+    /// A metric recorded with `counter!`.
+    pub fn counter<K: Into<Key>>(key: K) -> Self {
+        MetricSpec::new(DescribedKind::Counter, key.into())
+    }
+
+    /// A metric recorded with `gauge!`.
+    pub fn gauge<K: Into<Key>>(key: K) -> Self {
+        MetricSpec::new(DescribedKind::Gauge, key.into())
+    }
+
+    /// A metric recorded with `histogram!`, its histogram hint label included if it has one.
+    pub fn histogram<K: Into<Key>>(key: K) -> Self {
+        MetricSpec::new(DescribedKind::Histogram, key.into())
+    }
+
+    fn new(kind: DescribedKind, key: Key) -> Self {
+        MetricSpec {
+            kind,
+            key,
+            target: String::new(),
+            level: Level::INFO,
+        }
+    }
+
+    /// The metric is recorded with `target`, e.g. `module_path!()` for the macros.
+    pub fn with_target<T: Into<String>>(mut self, target: T) -> Self {
+        self.target = target.into();
+        self
+    }
+
+    /// The metric is recorded at `level`.
+    pub fn with_level(mut self, level: Level) -> Self {
+        self.level = level;
+        self
     }
 }
 
-struct Handle {
-    key: Key,
+pub(crate) struct Handle {
+    /// Shared with the registry, the name and tags that are sent are in `rendered`.
+    key: Arc<Key>,
+    rendered: RenderedKey,
+    /// The count since the rate was last sent, for counters when rates are sent.
+    rate: Option<Rate>,
+    /// The values recorded since the quantiles were last sent, for histograms when they're
+    /// summarized on the client.
+    summary: Option<Summary>,
+    /// The values recorded since they were last sent, for distributions when they're sketched on
+    /// the client.
+    sketch: Option<Sketch>,
+    /// The durations recorded since their stats were last sent, for timers when they're kept in
+    /// HDR histograms on the client.
+    timer_histogram: Option<TimerHistogram>,
     statsd: Arc<StatsdClient>,
-    default_histogram: HistogramType,
+    /// What the metric is sent as once it went through the pipeline, e.g. the histogram hint of
+    /// the key for histograms.
+    metric_type: MetricType,
+    /// The type the histogram is also sent as, with the same values, see
+    /// [`crate::StatsdBuilder::with_dual_emit`].
+    also: Option<MetricType>,
+    sample_rate: Option<f64>,
+    /// What the counter values are multiplied by when they're pre-scaled, see
+    /// [`crate::SampleRateSemantics::PreScaled`].
+    scale: Option<f64>,
+    /// Whether a stage of the pipeline drops the metric.
+    dropped: bool,
+    /// Whether the name or the tags would make lines the server can't parse, in strict mode.
+    malformed: bool,
+    /// Whether the name was first registered as another type, in strict mode.
+    conflicting: bool,
+    /// The share of the metric in the quota of its target, when it has one, see
+    /// [`crate::StatsdBuilder::with_target_quota`].
+    quota: Option<QuotaSlot>,
+    /// Defers the values beyond a burst, for counters and gauges when bursts are smoothed, see
+    /// [`crate::StatsdBuilder::with_burst_smoothing`].
+    bucket: Option<TokenBucket>,
+    /// The metric as it came out of the pipeline, only kept when its stages look at the values.
+    metric: Option<Arc<PipelineMetric>>,
+    /// Bytes sent since the last top series report, see
+    /// [`crate::StatsdBuilder::with_top_series_report`].
+    bytes: AtomicU64,
+    /// The lines sent, only counted when the active keys or the top emitters are tracked, see
+    /// [`crate::StatsdBuilder::with_active_keys`] and [`crate::StatsdBuilder::with_top_emitters`].
+    emits: Emits,
+    shared: Arc<Shared>,
 }
 
 impl Handle {
-    fn new(key: Key, statsd: Arc<StatsdClient>, default_histogram: HistogramType) -> Self {
-        Handle {
-            key,
-            statsd,
-            default_histogram,
+    /// The key the metric was registered with.
+    pub(crate) fn key(&self) -> &Key {
+        &self.key
+    }
+
+    /// The name the metric is sent with, prefix included.
+    pub(crate) fn name(&self) -> &str {
+        self.rendered.name()
+    }
+
+    /// What the metric is sent as.
+    pub(crate) fn metric_type(&self) -> MetricType {
+        self.metric_type
+    }
+
+    /// The keys of the tags the metric is sent with, default tags included, `None` when it isn't
+    /// sent.
+    pub(crate) fn tag_keys(&self) -> Option<impl Iterator<Item = &str>> {
+        (!self.dropped && !self.conflicting && !self.over_quota()).then(|| self.rendered.tag_keys())
+    }
+
+    /// Whether the target of the metric was over its quota of metrics when it was registered.
+    fn over_quota(&self) -> bool {
+        self.quota.as_ref().is_some_and(QuotaSlot::over)
+    }
+
+    /// The metric along with the lines it sent, `None` unless the active keys are tracked.
+    pub(crate) fn active_key(&self) -> Option<ActiveKey> {
+        let activity = self.shared.activity.as_ref()?;
+        Some(ActiveKey {
+            key: Key::clone(&self.key),
+            name: self.name().to_string(),
+            metric_type: self.metric_type,
+            emits: self.emits.count(),
+            last_emit: self.emits.last(activity),
+        })
+    }
+
+    /// Number of lines sent, only counted when the active keys or the top emitters are tracked.
+    pub(crate) fn emits(&self) -> u64 {
+        self.emits.count()
+    }
+
+    /// Account for a line sent as `metric_type`.
+    fn emitted(&self, metric_type: MetricType) {
+        self.shared.stats.record_emit(metric_type);
+        if self.shared.count_emits {
+            self.emits.record(self.shared.activity.as_ref());
         }
     }
 
-    fn apply_tags<'m, 'c, M>(
-        labels: Vec<&'m Label>,
-        mb: MetricBuilder<'m, 'c, M>,
-    ) -> MetricBuilder<'m, 'c, M>
-    where
-        M: cadence::Metric + From<String>,
-    {
-        labels
-            .into_iter()
-            .fold(mb, |acc, l| acc.with_tag(l.key(), l.value()))
+    /// Bytes sent since the last call, only counted when top series are reported.
+    pub(crate) fn take_bytes(&self) -> u64 {
+        self.bytes.swap(0, Ordering::Relaxed)
+    }
+
+    /// Send the quantiles of the values a histogram recorded since they were last sent.
+    pub(crate) fn send_summary(&self, _elapsed: Duration) {
+        if let Some(summary) = &self.summary {
+            summary.take(|rendered, value| {
+                if self.shared.paused() {
+                    return;
+                }
+                let _ = rendered.with_line(value, MetricType::Gauge, |line| {
+                    self.statsd.send_metric(&Line(line))
+                });
+                self.emitted(MetricType::Gauge);
+            });
+        }
+    }
+
+    /// Send the values a distribution recorded since they were last sent, a line per bin of the
+    /// sketch.
+    pub(crate) fn send_sketch(&self, _elapsed: Duration) {
+        if let Some(sketch) = &self.sketch {
+            sketch.take(|rendered, value| {
+                if self.shared.paused() {
+                    return;
+                }
+                let _ = rendered.with_line(value, MetricType::Distribution, |line| {
+                    self.statsd.send_metric(&Line(line))
+                });
+                self.emitted(MetricType::Distribution);
+            });
+        }
+    }
+
+    /// Send the quantiles, the min, the max and the count of the durations a timer recorded since
+    /// they were last sent.
+    pub(crate) fn send_timer_histogram(&self, _elapsed: Duration) {
+        if let Some(timer_histogram) = &self.timer_histogram {
+            timer_histogram.take(|rendered, stat| {
+                if self.shared.paused() {
+                    return;
+                }
+                let send = |line: &str| self.statsd.send_metric(&Line(line));
+                let _ = match stat {
+                    TimerStat::Gauge(millis) => {
+                        self.emitted(MetricType::Gauge);
+                        rendered.with_line(millis, MetricType::Gauge, send)
+                    }
+                    TimerStat::Count(count) => {
+                        self.emitted(MetricType::Counter);
+                        rendered.with_line(count, MetricType::Counter, send)
+                    }
+                };
+            });
+        }
+    }
+
+    /// Send the values of a counter or a gauge deferred by its token bucket, coalesced, once
+    /// there's a token for them.
+    pub(crate) fn send_deferred(&self, _elapsed: Duration) {
+        if let Some(value) = self.bucket.as_ref().and_then(TokenBucket::take) {
+            if !self.shared.paused() {
+                self.emit(value, self.metric_type);
+            }
+        }
+    }
+
+    /// Send the rate of a counter over the `elapsed` time since it was last sent.
+    pub(crate) fn send_rate(&self, elapsed: Duration) {
+        if let Some(rate) = &self.rate {
+            let value = rate.take(elapsed);
+            if self.shared.paused() {
+                return;
+            }
+            let _ = rate.rendered.with_line(value, MetricType::Gauge, |line| {
+                self.statsd.send_metric(&Line(line))
+            });
+            self.emitted(MetricType::Gauge);
+        }
+    }
+
+    /// Send `value` once the value bounds are applied.
+    fn send_number(&self, value: f64, metric_type: MetricType) {
+        if let Some(value) = self.shared.bound(value) {
+            self.send(self.shared.round(value, metric_type), metric_type);
+        }
+    }
+
+    fn send<V: Value + Copy>(&self, value: V, metric_type: MetricType) {
+        if self.dropped || self.shared.paused() {
+            return;
+        }
+        if self.conflicting {
+            self.shared.stats.record_drop(DropReason::TypeConflict);
+            return;
+        }
+        if self.over_quota() {
+            self.shared.stats.record_drop(DropReason::OverQuota);
+            return;
+        }
+        if self.shared.strict && (self.malformed || !value.valid()) {
+            self.shared.stats.record_drop(DropReason::Malformed);
+            return;
+        }
+        if let (Some(metric), Some(value)) = (&self.metric, value.as_f64()) {
+            if !self.shared.pipeline.record(metric, value) {
+                return;
+            }
+        }
+        // sampled out values are dropped before any work is done for them.
+        if self
+            .sample_rate
+            .is_some_and(|rate| !sampling::sampled(rate))
+        {
+            return;
+        }
+        if self.quota.as_ref().is_some_and(|quota| !quota.admit()) {
+            self.shared.stats.record_drop(DropReason::OverQuota);
+            return;
+        }
+        if let (Some(bucket), Some(value)) = (&self.bucket, value.as_f64()) {
+            if !bucket.admit(value, metric_type) {
+                return;
+            }
+        }
+        self.emit(value, metric_type);
+    }
+
+    /// Hand `value` to the client, along with the types the metric is also sent as.
+    fn emit<V: Value + Copy>(&self, value: V, metric_type: MetricType) {
+        // errors are accounted for by the sink, see `StatsdHandle::dropped_metrics`.
+        let context_tags = |tags: &mut ContextTags<'_>| self.shared.add_context_tags(tags);
+        for metric_type in iter::once(metric_type).chain(self.also) {
+            let _ = self
+                .rendered
+                .with_line_and_tags(value, metric_type, context_tags, |line| {
+                    if self.shared.count_bytes {
+                        self.bytes.fetch_add(line.len() as u64, Ordering::Relaxed);
+                    }
+                    self.statsd.send_metric(&Line(line))
+                });
+            self.emitted(metric_type);
+        }
     }
 }
 
 impl CounterFn for Handle {
     fn increment(&self, value: u64) {
-        // this is an unfortunate conversion, probably deserves an issue on cadence?
-        let mb = self.statsd.count_with_tags(self.key.name(), value);
-        Self::apply_tags(self.key.labels().collect(), mb).send();
+        if let Some(rate) = &self.rate {
+            rate.add(value);
+        }
+        match self.scale {
+            _ if self.shared.counter_rates == Some(CounterRates::Instead) => {}
+            Some(scale) => self.send_number(value as f64 * scale, self.metric_type),
+            None => self.send(value, self.metric_type),
+        }
+        if let Some(last_values) = &self.shared.last_values {
+            last_values.counter(&self.key, value);
+        }
     }
 
     fn absolute(&self, _value: u64) {
@@ -109,31 +849,96 @@ impl GaugeFn for Handle {
     }
 
     fn set(&self, value: f64) {
-        let mb = self.statsd.gauge_with_tags(self.key.name(), value);
-        Self::apply_tags(self.key.labels().collect(), mb).send();
+        self.send_number(value, self.metric_type);
+        if let Some(last_values) = &self.shared.last_values {
+            last_values.gauge(&self.key, value);
+        }
     }
 }
 
 impl HistogramFn for Handle {
     fn record(&self, value: f64) {
-        let (hist_type, labels) = HistogramType::type_from(&self.key);
-        match hist_type.unwrap_or(self.default_histogram) {
-            HistogramType::Distribution => {
-                let mb = self.statsd.distribution_with_tags(self.key.name(), value);
-                Self::apply_tags(labels, mb).send();
-            }
-            HistogramType::Timer => {
-                // Cadence expects the timer to be in milliseconds and metrics lib reports those as seconds
-                // we translate the seconds to milliseconds. Unfortunately there's a downcase involved here
-                // from u128 to u64.
-                let time_in_ms = Duration::from_secs_f64(value).as_millis() as u64;
-                let mb = self.statsd.time_with_tags(self.key.name(), time_in_ms);
-                Self::apply_tags(labels, mb).send();
-            }
-            HistogramType::Histogram => {
-                let mb = self.statsd.histogram_with_tags(self.key.name(), value);
-                Self::apply_tags(labels, mb).send();
+        match self.shared.non_negative(value, self.metric_type) {
+            Some(value) if self.metric_type == MetricType::Timer => {
+                // Statsd expects the timer to be in milliseconds and metrics lib reports those as seconds
+                // we translate the seconds to milliseconds. Negative values only get here when
+                // they're passed through.
+                if let Ok(duration) = Duration::try_from_secs_f64(value.abs()) {
+                    let millis = duration_to_millis(duration).copysign(value);
+                    match &self.timer_histogram {
+                        // the histograms only hold positive durations.
+                        Some(timer_histogram) if value >= 0.0 => {
+                            if self.shared.bound(millis).is_some() {
+                                timer_histogram.add(duration);
+                            }
+                        }
+                        _ => self.send_number(millis, MetricType::Timer),
+                    }
+                }
             }
+            Some(value) => match (&self.summary, &self.sketch) {
+                (Some(summary), _) => {
+                    if let Some(value) = self.shared.bound(value) {
+                        summary.add(value);
+                    }
+                }
+                (_, Some(sketch)) => {
+                    if let Some(value) = self.shared.bound(value) {
+                        sketch.add(value);
+                    }
+                }
+                (None, None) => self.send_number(value, self.metric_type),
+            },
+            None => {}
         };
+        if let Some(last_values) = &self.shared.last_values {
+            last_values.histogram(&self.key, value);
+        }
+    }
+}
+
+/// Statsd expects timers in milliseconds. Going through whole nanoseconds keeps e.g. 1.5ms exact.
+pub(crate) fn duration_to_millis(duration: Duration) -> f64 {
+    duration.as_nanos() as f64 / 1e6
+}
+
+/// Hands every handle to a function on an interval along with the time elapsed since the last
+/// time, e.g. to send the rates of the counters.
+pub(crate) struct HandleFlusher {
+    registries: Weak<Registries<Handle>>,
+    clock: SharedClock,
+    last: Instant,
+    flush: fn(&Handle, Duration),
+}
+
+impl HandleFlusher {
+    pub(crate) fn new(
+        registries: Weak<Registries<Handle>>,
+        clock: SharedClock,
+        flush: fn(&Handle, Duration),
+    ) -> Self {
+        HandleFlusher {
+            last: clock.now(),
+            registries,
+            clock,
+            flush,
+        }
+    }
+
+    /// Flush on `interval` until the recorder goes away.
+    pub(crate) fn schedule(mut self, upkeep: &mut Upkeep, interval: Duration) {
+        upkeep.every(interval, move || match self.registries.upgrade() {
+            Some(registries) => {
+                let now = self.clock.now();
+                let elapsed = now - self.last;
+                self.last = now;
+                // the upkeep runs late rather than early, this only guards a clock that stood still.
+                if !elapsed.is_zero() {
+                    registries.for_each(|handle| (self.flush)(handle, elapsed));
+                }
+                true
+            }
+            None => false,
+        });
     }
 }
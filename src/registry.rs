@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+
+use metrics::Key;
+
+/// Handles that were already built for a key, so that registering the same metric again, which
+/// callers of the `metrics` macros do on every invocation, returns the existing handle instead of
+/// building a new one.
+///
+/// Counters, gauges and histograms are kept apart because the same key renders differently
+/// depending on the kind of metric. The key is stored once, behind an [`Arc`] that is handed to
+/// the function building the handle, so handles can hold on to it without another copy of the
+/// name and labels.
+#[derive(Debug)]
+pub(crate) struct Registry<H> {
+    counters: RwLock<HashMap<Arc<Key>, Entry<H>>>,
+    gauges: RwLock<HashMap<Arc<Key>, Entry<H>>>,
+    histograms: RwLock<HashMap<Arc<Key>, Entry<H>>>,
+    /// What the handles were built from, see [`Registry::sync`].
+    generation: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Entry<H> {
+    handle: Arc<H>,
+    /// Whether the handle was registered since the last time idle handles were evicted.
+    used: AtomicBool,
+}
+
+impl<H> Default for Registry<H> {
+    fn default() -> Self {
+        Registry {
+            counters: RwLock::default(),
+            gauges: RwLock::default(),
+            histograms: RwLock::default(),
+            generation: AtomicU64::new(0),
+        }
+    }
+}
+
+impl<H> Registry<H> {
+    /// Forget every handle when `generation` differs from the one they were built from, e.g.
+    /// because the mapping they were built with was reloaded since.
+    pub(crate) fn sync(&self, generation: u64) {
+        let current = self.generation.load(Ordering::Acquire);
+        if current == generation
+            || self
+                .generation
+                .compare_exchange(current, generation, Ordering::AcqRel, Ordering::Acquire)
+                .is_err()
+        {
+            return;
+        }
+        for handles in [&self.counters, &self.gauges, &self.histograms] {
+            handles.write().unwrap_or_else(|e| e.into_inner()).clear();
+        }
+    }
+
+    pub(crate) fn counter(&self, key: &Key, create: impl FnOnce(&Arc<Key>) -> H) -> Arc<H> {
+        Self::get_or_create(&self.counters, key, create)
+    }
+
+    pub(crate) fn gauge(&self, key: &Key, create: impl FnOnce(&Arc<Key>) -> H) -> Arc<H> {
+        Self::get_or_create(&self.gauges, key, create)
+    }
+
+    pub(crate) fn histogram(&self, key: &Key, create: impl FnOnce(&Arc<Key>) -> H) -> Arc<H> {
+        Self::get_or_create(&self.histograms, key, create)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        [&self.counters, &self.gauges, &self.histograms]
+            .iter()
+            .map(|handles| handles.read().unwrap().len())
+            .sum()
+    }
+
+    pub(crate) fn for_each(&self, mut f: impl FnMut(&H)) {
+        for handles in [&self.counters, &self.gauges, &self.histograms] {
+            let handles = handles.read().unwrap_or_else(|e| e.into_inner());
+            handles.values().for_each(|entry| f(&entry.handle));
+        }
+    }
+
+    /// Forget the handles that weren't registered since the last call, returns how many.
+    pub(crate) fn evict_idle(&self) -> usize {
+        let mut evicted = 0;
+        for handles in [&self.counters, &self.gauges, &self.histograms] {
+            let mut handles = handles.write().unwrap_or_else(|e| e.into_inner());
+            let before = handles.len();
+            handles.retain(|_, entry| entry.used.swap(false, Ordering::Relaxed));
+            evicted += before - handles.len();
+        }
+        evicted
+    }
+
+    fn get_or_create(
+        handles: &RwLock<HashMap<Arc<Key>, Entry<H>>>,
+        key: &Key,
+        create: impl FnOnce(&Arc<Key>) -> H,
+    ) -> Arc<H> {
+        if let Some(entry) = handles.read().unwrap_or_else(|e| e.into_inner()).get(key) {
+            // only written when it changes, hot handles are registered from many threads at once.
+            if !entry.used.load(Ordering::Relaxed) {
+                entry.used.store(true, Ordering::Relaxed);
+            }
+            return entry.handle.clone();
+        }
+
+        let mut handles = handles.write().unwrap_or_else(|e| e.into_inner());
+        if let Some(entry) = handles.get(key) {
+            entry.used.store(true, Ordering::Relaxed);
+            return entry.handle.clone();
+        }
+        let key = Arc::new(key.clone());
+        let handle = Arc::new(create(&key));
+        handles.insert(
+            key,
+            Entry {
+                handle: handle.clone(),
+                used: AtomicBool::new(true),
+            },
+        );
+        handle
+    }
+}
+
+/// The registries of a recorder and of the recorders scoped from it, see
+/// [`crate::StatsdBuilder::with_idle_timeout`].
+#[derive(Debug)]
+pub(crate) struct Registries<H> {
+    registries: Mutex<Vec<Weak<Registry<H>>>>,
+}
+
+impl<H> Default for Registries<H> {
+    fn default() -> Self {
+        Registries {
+            registries: Mutex::default(),
+        }
+    }
+}
+
+impl<H> Registries<H> {
+    pub(crate) fn add(&self, registry: &Arc<Registry<H>>) {
+        let mut registries = self.registries.lock().unwrap_or_else(|e| e.into_inner());
+        registries.push(Arc::downgrade(registry));
+    }
+
+    /// Call `f` with every handle of every registry.
+    pub(crate) fn for_each(&self, mut f: impl FnMut(&H)) {
+        let registries = self.registries.lock().unwrap_or_else(|e| e.into_inner());
+        for registry in registries.iter().filter_map(Weak::upgrade) {
+            registry.for_each(&mut f);
+        }
+    }
+
+    /// Evict the idle handles of every registry, and forget the registries that are gone.
+    pub(crate) fn evict_idle(&self) -> usize {
+        let mut registries = self.registries.lock().unwrap_or_else(|e| e.into_inner());
+        registries.retain(|registry| registry.strong_count() > 0);
+        registries
+            .iter()
+            .filter_map(Weak::upgrade)
+            .map(|registry| registry.evict_idle())
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuses_handles() {
+        let registry = Registry::default();
+        let key = Key::from_name("metric.name");
+
+        let first = registry.counter(&key, |_| 1);
+        let second = registry.counter(&key, |_| 2);
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(1, *second);
+
+        let gauge = registry.gauge(&key, |_| 3);
+        assert!(!Arc::ptr_eq(&first, &gauge));
+    }
+
+    #[test]
+    fn forgets_handles_of_other_generations() {
+        let registry = Registry::default();
+        let key = Key::from_name("metric.name");
+
+        registry.sync(0);
+        let first = registry.counter(&key, |_| 1);
+        registry.sync(0);
+        assert!(Arc::ptr_eq(&first, &registry.counter(&key, |_| 2)));
+
+        registry.sync(1);
+        assert_eq!(2, *registry.counter(&key, |_| 2));
+    }
+
+    #[test]
+    fn evicts_idle_handles() {
+        let registry = Arc::new(Registry::default());
+        let registries = Registries::default();
+        registries.add(&registry);
+        let (idle, busy) = (Key::from_name("idle"), Key::from_name("busy"));
+        registry.counter(&idle, |_| 1);
+        registry.counter(&busy, |_| 1);
+
+        // everything was used since it was created.
+        assert_eq!(0, registries.evict_idle());
+        registry.counter(&busy, |_| 2);
+        assert_eq!(1, registries.evict_idle());
+        assert_eq!(2, *registry.counter(&idle, |_| 2));
+        assert_eq!(1, *registry.counter(&busy, |_| 2));
+
+        drop(registry);
+        assert_eq!(0, registries.evict_idle());
+        assert!(registries.registries.lock().unwrap().is_empty());
+    }
+}
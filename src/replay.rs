@@ -0,0 +1,133 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use cadence::MetricSink;
+
+/// Sends the statsd lines captured in a file, e.g. with
+/// [`StatsdBuilder::with_file_sink`](crate::StatsdBuilder::with_file_sink), through a sink again,
+/// e.g. to backfill metrics captured offline or to load test an agent.
+///
+/// Lines are sent as they are, one write each, and empty lines are skipped.
+///
+/// ```no_run
+/// use std::net::UdpSocket;
+/// use cadence::UdpMetricSink;
+/// use metrics_exporter_statsd::Replay;
+///
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let socket = UdpSocket::bind("0.0.0.0:0")?;
+/// let sink = UdpMetricSink::from("127.0.0.1:8125", socket)?;
+/// let sent = Replay::new(sink)
+///     .with_rate(10_000.0)
+///     .replay_file("/var/spool/metrics/statsd.log")?;
+/// println!("replayed {} metrics", sent);
+/// #     Ok(())
+/// # }
+/// ```
+pub struct Replay<S> {
+    sink: S,
+    lines_per_second: Option<f64>,
+}
+
+impl<S: MetricSink> Replay<S> {
+    /// Replay through `sink`, as fast as it takes the lines.
+    pub fn new(sink: S) -> Self {
+        Replay {
+            sink,
+            lines_per_second: None,
+        }
+    }
+
+    /// Send at most `lines_per_second` lines a second, evenly spread over the second.
+    pub fn with_rate(mut self, lines_per_second: f64) -> Self {
+        self.lines_per_second = Some(lines_per_second).filter(|rate| *rate > 0.0);
+        self
+    }
+
+    /// Send every line of the file at `path`, see [`Replay::replay`].
+    pub fn replay_file<P: AsRef<Path>>(&self, path: P) -> io::Result<u64> {
+        self.replay(BufReader::new(File::open(path)?))
+    }
+
+    /// Send every line of `reader` and flush the sink. Returns the number of lines sent, or the
+    /// first error of the reader or the sink, once the lines before it have been sent.
+    pub fn replay<R: BufRead>(&self, reader: R) -> io::Result<u64> {
+        let start = Instant::now();
+        let mut sent = 0;
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.trim_end();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(rate) = self.lines_per_second {
+                // a line due later than an `Instant` can tell waits for good.
+                let wait = start
+                    .checked_add(delay(sent, rate))
+                    .map_or(Duration::MAX, |due| {
+                        due.saturating_duration_since(Instant::now())
+                    });
+                if !wait.is_zero() {
+                    thread::sleep(wait);
+                }
+            }
+            self.sink.emit(line)?;
+            sent += 1;
+        }
+        self.sink.flush()?;
+        Ok(sent)
+    }
+}
+
+/// How long after the first line the line numbered `sent` is due at `rate` lines a second,
+/// [`Duration::MAX`] when that's longer than a `Duration` holds, e.g. for a tiny rate.
+fn delay(sent: u64, rate: f64) -> Duration {
+    Duration::try_from_secs_f64(sent as f64 / rate).unwrap_or(Duration::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::FakeSink;
+
+    #[test]
+    fn replays_lines() {
+        let sink = FakeSink::new();
+        let lines = "requests:1|c\n\nthreads:4|g|#pool:db\r\nlatency:5|ms\n";
+        let sent = Replay::new(sink.clone()).replay(lines.as_bytes()).unwrap();
+        assert_eq!(3, sent);
+        assert_eq!(
+            vec!["requests:1|c", "threads:4|g|#pool:db", "latency:5|ms"],
+            sink.lines()
+        );
+    }
+
+    #[test]
+    fn paces_lines() {
+        let lines = "requests:1|c\n".repeat(11);
+        let start = Instant::now();
+        let sent = Replay::new(FakeSink::new())
+            .with_rate(200.0)
+            .replay(lines.as_bytes())
+            .unwrap();
+        assert_eq!(11, sent);
+        // the 11th line is due 50ms after the first one.
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn saturates_the_delay_of_tiny_rates() {
+        assert_eq!(Duration::from_millis(50), delay(10, 200.0));
+        assert_eq!(Duration::MAX, delay(1, 1e-20));
+
+        // the first line is due right away whatever the rate.
+        let sent = Replay::new(FakeSink::new())
+            .with_rate(1e-20)
+            .replay("requests:1|c\n".as_bytes())
+            .unwrap();
+        assert_eq!(1, sent);
+    }
+}
@@ -0,0 +1,240 @@
+use std::collections::VecDeque;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use cadence::{MetricSink, SinkStats};
+
+use crate::sink::SharedSink;
+use crate::stats::{DropReason, Stats};
+
+/// A queue of metrics in slots allocated up front, drained by a thread of its own into a sink,
+/// see [`StatsdBuilder::with_preallocated_queue`](crate::StatsdBuilder::with_preallocated_queue).
+///
+/// Metrics are copied into a free slot, which only allocates when a metric is larger than any
+/// the slot held so far. Once the queue is full, metrics are rejected until a slot is free.
+pub(crate) struct RingQueue {
+    shared: Arc<Ring>,
+    sink: SharedSink,
+}
+
+struct Ring {
+    slots: Mutex<Slots>,
+    filled: Condvar,
+    panics: AtomicU64,
+}
+
+struct Slots {
+    /// The slots holding metrics, oldest first.
+    queued: VecDeque<Vec<u8>>,
+    /// The slots that are free.
+    free: Vec<Vec<u8>>,
+    closed: bool,
+}
+
+impl RingQueue {
+    /// A queue of `capacity` slots of `slot_size` bytes, drained into `sink`. The errors of the
+    /// sink are counted in `stats`.
+    pub(crate) fn new(
+        capacity: usize,
+        slot_size: usize,
+        sink: SharedSink,
+        stats: Arc<Stats>,
+    ) -> Self {
+        let capacity = capacity.max(1);
+        let shared = Arc::new(Ring {
+            slots: Mutex::new(Slots {
+                queued: VecDeque::with_capacity(capacity),
+                free: (0..capacity)
+                    .map(|_| Vec::with_capacity(slot_size))
+                    .collect(),
+                closed: false,
+            }),
+            filled: Condvar::new(),
+            panics: AtomicU64::new(0),
+        });
+        let ring = shared.clone();
+        let drained = sink.clone();
+        thread::spawn(move || ring.drain(drained, stats, Vec::with_capacity(slot_size)));
+        RingQueue { shared, sink }
+    }
+
+    /// Number of metrics waiting in the queue.
+    pub(crate) fn queued(&self) -> u64 {
+        self.shared.lock().queued.len() as u64
+    }
+
+    /// Number of times sending a metric panicked.
+    pub(crate) fn panics(&self) -> u64 {
+        self.shared.panics.load(Ordering::Relaxed)
+    }
+}
+
+impl Ring {
+    fn lock(&self) -> std::sync::MutexGuard<'_, Slots> {
+        self.slots.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Send the queued metrics until the queue is closed and empty. The slot being sent is
+    /// swapped with `spare`, so that the queue isn't locked meanwhile.
+    fn drain(&self, sink: SharedSink, stats: Arc<Stats>, mut spare: Vec<u8>) {
+        loop {
+            {
+                let mut slots = self.lock();
+                let mut slot = loop {
+                    match slots.queued.pop_front() {
+                        Some(slot) => break slot,
+                        None if slots.closed => return,
+                        None => slots = self.filled.wait(slots).unwrap_or_else(|e| e.into_inner()),
+                    }
+                };
+                std::mem::swap(&mut slot, &mut spare);
+                slot.clear();
+                slots.free.push(slot);
+            }
+            // slots only ever hold whole `&str`s.
+            let metric = String::from_utf8_lossy(&spare);
+            match panic::catch_unwind(AssertUnwindSafe(|| sink.emit(&metric))) {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => {
+                    stats.record_drop(DropReason::SendError);
+                    stats.record_error(&e);
+                }
+                Err(_) => {
+                    self.panics.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+}
+
+/// The thread sends what's left in the queue, then stops.
+impl Drop for RingQueue {
+    fn drop(&mut self) {
+        self.shared.lock().closed = true;
+        self.shared.filled.notify_one();
+    }
+}
+
+impl MetricSink for RingQueue {
+    fn emit(&self, metric: &str) -> io::Result<usize> {
+        let mut slots = self.shared.lock();
+        let mut slot = slots
+            .free
+            .pop()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::WouldBlock, "the queue is full"))?;
+        slot.extend_from_slice(metric.as_bytes());
+        slots.queued.push_back(slot);
+        drop(slots);
+        self.shared.filled.notify_one();
+        Ok(metric.len())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.sink.flush()
+    }
+
+    fn stats(&self) -> SinkStats {
+        self.sink.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use metrics::{Key, Recorder};
+
+    use super::*;
+    use crate::testing::{FakeSink, METADATA};
+    use crate::StatsdBuilder;
+
+    #[test]
+    fn sends_in_order_and_rejects_when_full() {
+        struct SlowSink(FakeSink);
+
+        impl MetricSink for SlowSink {
+            fn emit(&self, metric: &str) -> io::Result<usize> {
+                thread::sleep(Duration::from_millis(20));
+                self.0.emit(metric)
+            }
+        }
+
+        let sink = FakeSink::new();
+        let stats = Arc::new(Stats::default());
+        let queue = RingQueue::new(2, 16, Arc::new(SlowSink(sink.clone())), stats);
+        let sent: Vec<bool> = ["a:1|c", "b:1|c", "c:1|c", "d:1|c"]
+            .iter()
+            .map(|metric| queue.emit(metric).is_ok())
+            .collect();
+        // the first one may already be on its way, which frees its slot.
+        assert!(sent[..2].iter().all(|sent| *sent));
+        assert!(!sent[3]);
+
+        for _ in 0..100 {
+            if queue.queued() == 0 && sink.lines().len() >= 2 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        let expected: Vec<&str> = ["a:1|c", "b:1|c", "c:1|c"]
+            .into_iter()
+            .zip(&sent)
+            .filter(|(_, sent)| **sent)
+            .map(|(metric, _)| metric)
+            .collect();
+        assert_eq!(expected, sink.lines());
+    }
+
+    #[test]
+    fn preallocated_queue() {
+        let path = std::env::temp_dir().join(format!("statsd-ring-{}.log", std::process::id()));
+        let recorder = StatsdBuilder::from("", 0)
+            .with_file_sink(&path)
+            // smaller than the lines, the slots grow to fit them.
+            .with_preallocated_queue(8)
+            .with_shutdown_timeout(Duration::from_secs(5))
+            .build(Some("app"))
+            .expect("should build a recorder");
+        let handle = recorder.handle();
+        recorder
+            .register_counter(&Key::from_name("requests"), &METADATA)
+            .increment(1);
+        recorder
+            .register_gauge(&Key::from_name("threads"), &METADATA)
+            .set(4.0);
+        drop(recorder);
+        handle.shutdown();
+
+        let lines = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!("app.requests:1|c\napp.threads:4|g\n", lines);
+
+        // nothing listens on the port, the queue is stuck reconnecting once a line fills the
+        // buffer.
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let recorder = StatsdBuilder::from("127.0.0.1", port)
+            .with_tcp()
+            .with_reconnect_backoff(Duration::from_secs(60), Duration::from_secs(60))
+            .with_buffer_size(1)
+            .with_queue_size(2)
+            .with_preallocated_queue(64)
+            .build(None)
+            .expect("should build a recorder");
+        let handle = recorder.handle();
+        let counter = recorder.register_counter(&Key::from_name("requests"), &METADATA);
+        for _ in 0..10 {
+            counter.increment(1);
+        }
+        // the first one may be taken by the worker.
+        let dropped = handle.dropped_metrics().get(DropReason::QueueFull);
+        assert!((7..=8).contains(&dropped), "dropped {}", dropped);
+        assert!(handle.queue_depth().is_some_and(|depth| depth <= 2));
+    }
+}
@@ -0,0 +1,160 @@
+use metrics::{Key, Level, Metadata};
+
+use crate::StatsdRecorder;
+
+/// Which metrics a route takes.
+#[derive(Clone, Debug)]
+pub(crate) enum RouteMatch {
+    /// The metrics with a label `key` whose value is `value`.
+    Tag { key: String, value: String },
+    /// The metrics recorded at a level at least as severe as this one.
+    Level(Level),
+    /// The metrics whose target is this one or is within it, e.g. `app::auth` is within `app`.
+    Target(String),
+}
+
+impl RouteMatch {
+    fn matches(&self, key: &Key, metadata: &Metadata<'_>) -> bool {
+        match self {
+            RouteMatch::Tag { key: tag, value } => key
+                .labels()
+                .any(|label| label.key() == tag && label.value() == value),
+            RouteMatch::Level(level) => metadata.level() >= level,
+            RouteMatch::Target(target) => metadata
+                .target()
+                .strip_prefix(target.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with("::")),
+        }
+    }
+}
+
+/// The recorders that take some of the metrics instead of the recorder they're given to, see
+/// [`StatsdBuilder::with_route_by_tag`](crate::StatsdBuilder::with_route_by_tag),
+/// [`StatsdBuilder::with_route_by_level`](crate::StatsdBuilder::with_route_by_level) and
+/// [`StatsdBuilder::with_route_by_target`](crate::StatsdBuilder::with_route_by_target).
+#[derive(Clone, Default)]
+pub(crate) struct Routes {
+    routes: Vec<(RouteMatch, StatsdRecorder)>,
+}
+
+impl Routes {
+    pub(crate) fn push(&mut self, route: RouteMatch, recorder: StatsdRecorder) {
+        self.routes.push((route, recorder));
+    }
+
+    /// The recorder of the first route taking the metric, `None` when it stays with the recorder
+    /// the routes were given to.
+    pub(crate) fn route(&self, key: &Key, metadata: &Metadata<'_>) -> Option<&StatsdRecorder> {
+        self.routes
+            .iter()
+            .find(|(route, _)| route.matches(key, metadata))
+            .map(|(_, recorder)| recorder)
+    }
+
+    /// Every recorder a metric may be routed to.
+    pub(crate) fn recorders(&self) -> impl Iterator<Item = &StatsdRecorder> {
+        self.routes.iter().map(|(_, recorder)| recorder)
+    }
+
+    /// The same routes to recorders scoped as per [`StatsdRecorder::scoped`].
+    pub(crate) fn scoped(&self, prefix: &str, tags: &[(String, String)]) -> Routes {
+        Routes {
+            routes: self
+                .routes
+                .iter()
+                .map(|(route, recorder)| (route.clone(), recorder.scoped(prefix, tags.to_vec())))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics::{Label, Recorder};
+
+    use super::*;
+    use crate::testing::METADATA;
+    use crate::StatsdBuilder;
+
+    #[test]
+    fn route_by_tag() {
+        let route = |prefix| {
+            let sink = crate::testing::FakeSink::new();
+            let recorder = StatsdBuilder::from("", 0)
+                .with_sink(sink.clone())
+                .build(Some(prefix))
+                .expect("should build a recorder with custom sink");
+            (sink, recorder)
+        };
+        let (a_sink, a) = route("a");
+        let (b_sink, b) = route("b");
+        let sink = crate::testing::FakeSink::new();
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(sink.clone())
+            .with_route_by_tag("tenant", [("a", a), ("b", b)])
+            .build(Some("default"))
+            .expect("should build a recorder with custom sink");
+        let scoped = recorder.scoped("db", [("pool", "primary")]);
+
+        for tenant in ["a", "b", "c"] {
+            let key = Key::from(("requests", vec![Label::new("tenant", tenant)]));
+            recorder.register_counter(&key, &METADATA).increment(1);
+        }
+        recorder
+            .register_gauge(&Key::from_name("threads"), &METADATA)
+            .set(4.0);
+        let key = Key::from(("queries", vec![Label::new("tenant", "a")]));
+        scoped.register_histogram(&key, &METADATA).record(2.0);
+
+        assert_eq!(
+            vec![
+                "a.requests:1|c|#tenant:a",
+                "a.db.queries:2|h|#pool:primary,tenant:a"
+            ],
+            a_sink.lines()
+        );
+        assert_eq!(vec!["b.requests:1|c|#tenant:b"], b_sink.lines());
+        assert_eq!(
+            vec!["default.requests:1|c|#tenant:c", "default.threads:4|g"],
+            sink.lines()
+        );
+    }
+
+    #[test]
+    fn route_by_metadata() {
+        let route = || {
+            let sink = crate::testing::FakeSink::new();
+            let recorder = StatsdBuilder::from("", 0)
+                .with_sink(sink.clone())
+                .build(None)
+                .expect("should build a recorder with custom sink");
+            (sink, recorder)
+        };
+        let (errors_sink, errors) = route();
+        let (auth_sink, auth) = route();
+        let sink = crate::testing::FakeSink::new();
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(sink.clone())
+            .with_route_by_level(Level::ERROR, errors)
+            .with_route_by_target("app::auth", auth)
+            .build(None)
+            .expect("should build a recorder with custom sink");
+
+        for (name, target, level) in [
+            ("failures", "app::db", Level::ERROR),
+            ("logins", "app::auth::session", Level::INFO),
+            ("tokens", "app::auth", Level::DEBUG),
+            ("authors", "app::authors", Level::INFO),
+            ("queries", "app::db", Level::WARN),
+        ] {
+            let metadata = metrics::Metadata::new(target, level, None);
+            recorder
+                .register_counter(&Key::from_name(name), &metadata)
+                .increment(1);
+        }
+
+        assert_eq!(vec!["failures:1|c"], errors_sink.lines());
+        assert_eq!(vec!["logins:1|c", "tokens:1|c"], auth_sink.lines());
+        assert_eq!(vec!["authors:1|c", "queries:1|c"], sink.lines());
+    }
+}
@@ -0,0 +1,437 @@
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+use metrics::Level;
+
+use crate::mapping::Glob;
+use crate::pipeline::{PipelineMetric, PipelineStage};
+use crate::types::MetricType;
+
+thread_local! {
+    /// State of the xorshift generator of the current thread, seeded from the random keys the
+    /// standard library uses for `HashMap`. It must never be zero.
+    static STATE: Cell<u64> = Cell::new(RandomState::new().build_hasher().finish() | 1);
+}
+
+/// How the counters that are sent for more than what they count, e.g. sampled ones, are told apart
+/// so that statsd still computes the right totals, see
+/// [`StatsdBuilder::with_sample_rate_semantics`](crate::StatsdBuilder::with_sample_rate_semantics).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SampleRateSemantics {
+    /// Send the values as they are, with an `|@rate` field that statsd scales them up by, e.g.
+    /// `hits:1|c|@0.1` counts 10 hits. This is the default, and what DogStatsD expects.
+    #[default]
+    Annotated,
+    /// Scale the counter values up on the client and send them without `|@rate`, e.g. `hits:10|c`,
+    /// for the servers that ignore the field. Histograms are still annotated, they can't be
+    /// scaled up.
+    PreScaled,
+}
+
+impl SampleRateSemantics {
+    /// The rate a metric of `metric_type` sampled at `rate` is annotated with, and the factor its
+    /// values are scaled up by.
+    pub(crate) fn split(
+        self,
+        metric_type: MetricType,
+        rate: Option<f64>,
+    ) -> (Option<f64>, Option<f64>) {
+        match (self, metric_type) {
+            (SampleRateSemantics::PreScaled, MetricType::Counter) => {
+                (None, rate.map(|rate| 1.0 / rate))
+            }
+            _ => (rate, None),
+        }
+    }
+}
+
+/// The sample rates configured on the builder, resolved when a metric is registered. The rate of
+/// the name of a metric wins over the rate of its level, which wins over the rate of its kind,
+/// which wins over the default rate.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct SampleRates {
+    pub(crate) default: Option<f64>,
+    pub(crate) counters: Option<f64>,
+    pub(crate) histograms: Option<f64>,
+    pub(crate) levels: Vec<(Level, f64)>,
+    /// The rates of the names matching a pattern, the first matching one wins.
+    names: Vec<(String, Glob, f64)>,
+    /// The patterns that aren't valid, along with why, reported when building the recorder.
+    pub(crate) invalid_patterns: Vec<(String, String)>,
+}
+
+impl SampleRates {
+    /// Whether every rate is in `(0, 1]`.
+    pub(crate) fn is_valid(&self) -> bool {
+        [self.default, self.counters, self.histograms]
+            .into_iter()
+            .flatten()
+            .chain(self.levels.iter().map(|(_, rate)| *rate))
+            .chain(self.names.iter().map(|(_, _, rate)| *rate))
+            .all(|rate| rate > 0.0 && rate <= 1.0)
+    }
+
+    /// Set the rate of `level`, replacing the one it had, if any.
+    pub(crate) fn set_level(&mut self, level: Level, rate: f64) {
+        self.levels.retain(|(l, _)| *l != level);
+        self.levels.push((level, rate));
+    }
+
+    /// Set the rate of the names matching `pattern`, replacing the one it had, if any.
+    pub(crate) fn set_name(&mut self, pattern: &str, rate: f64) {
+        match Glob::new(pattern) {
+            Ok(glob) => match self.names.iter_mut().find(|(p, _, _)| p == pattern) {
+                Some(name) => name.2 = rate,
+                None => self.names.push((pattern.to_string(), glob, rate)),
+            },
+            Err(reason) => self.invalid_patterns.push((pattern.to_string(), reason)),
+        }
+    }
+
+    pub(crate) fn counter(&self, name: &str, level: Option<&Level>) -> Option<f64> {
+        sampled_only(
+            self.name(name)
+                .or(self.level(level))
+                .or(self.counters)
+                .or(self.default),
+        )
+    }
+
+    /// The rate of everything recorded through `histogram!`, be it sent as a histogram, a
+    /// distribution or a timer.
+    pub(crate) fn histogram(&self, name: &str, level: Option<&Level>) -> Option<f64> {
+        sampled_only(
+            self.name(name)
+                .or(self.level(level))
+                .or(self.histograms)
+                .or(self.default),
+        )
+    }
+
+    fn name(&self, name: &str) -> Option<f64> {
+        self.names
+            .iter()
+            .find(|(_, glob, _)| glob.matches(name))
+            .map(|(_, _, rate)| *rate)
+    }
+
+    fn level(&self, level: Option<&Level>) -> Option<f64> {
+        let level = level?;
+        self.levels
+            .iter()
+            .find(|(l, _)| l == level)
+            .map(|(_, rate)| *rate)
+    }
+}
+
+/// Gauges only report their latest value and every member of a set matters, neither is sampled.
+impl PipelineStage for SampleRates {
+    fn register(&self, metric: &mut PipelineMetric) -> bool {
+        let rate = match metric.metric_type() {
+            MetricType::Counter => self.counter(metric.name(), metric.level()),
+            MetricType::Histogram | MetricType::Distribution | MetricType::Timer => {
+                self.histogram(metric.name(), metric.level())
+            }
+            MetricType::Gauge | MetricType::Set | MetricType::Meter => None,
+        };
+        metric.set_sample_rate(rate);
+        true
+    }
+}
+
+/// `None` for a rate of 1, there is nothing to sample nor to tell statsd then.
+fn sampled_only(rate: Option<f64>) -> Option<f64> {
+    rate.filter(|rate| *rate < 1.0)
+}
+
+/// Decide whether a metric recorded with `rate` should be sent. This runs on the recording thread
+/// before the line is formatted, so metrics that are sampled out cost next to nothing.
+pub(crate) fn sampled(rate: f64) -> bool {
+    random() < rate
+}
+
+/// A random number uniformly distributed in `[0, 1)`.
+pub(crate) fn random() -> f64 {
+    STATE.with(|state| {
+        let mut x = state.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        state.set(x);
+        // the top 53 bits make a uniformly distributed float in [0, 1).
+        (x >> 11) as f64 / (1u64 << 53) as f64
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    use cadence::MetricSink;
+    use metrics::{Key, Label, Recorder};
+
+    use super::*;
+    use crate::testing::METADATA;
+    use crate::{StatsdBuilder, StatsdError};
+
+    #[test]
+    fn pre_scales_counters_only() {
+        let semantics = SampleRateSemantics::PreScaled;
+        assert_eq!(
+            (None, Some(4.0)),
+            semantics.split(MetricType::Counter, Some(0.25))
+        );
+        assert_eq!(
+            (Some(0.25), None),
+            semantics.split(MetricType::Timer, Some(0.25))
+        );
+        assert_eq!((None, None), semantics.split(MetricType::Counter, None));
+        assert_eq!(
+            (Some(0.25), None),
+            SampleRateSemantics::Annotated.split(MetricType::Counter, Some(0.25))
+        );
+    }
+
+    #[test]
+    fn samples_at_rate() {
+        assert!((0..1000).all(|_| sampled(1.0)));
+        assert!((0..1000).all(|_| !sampled(0.0)));
+
+        let kept = (0..10_000).filter(|_| sampled(0.25)).count();
+        assert!((2000..3000).contains(&kept), "kept {} out of 10000", kept);
+    }
+
+    #[test]
+    fn kind_rates_win_over_default() {
+        let rates = SampleRates {
+            default: Some(0.5),
+            histograms: Some(0.1),
+            ..SampleRates::default()
+        };
+        assert_eq!(Some(0.5), rates.counter("name", None));
+        assert_eq!(Some(0.1), rates.histogram("name", None));
+
+        let rates = SampleRates {
+            default: Some(0.5),
+            counters: Some(1.0),
+            ..SampleRates::default()
+        };
+        assert_eq!(None, rates.counter("name", None));
+        assert!(rates.is_valid());
+        assert!(!SampleRates {
+            histograms: Some(0.0),
+            ..SampleRates::default()
+        }
+        .is_valid());
+    }
+
+    #[test]
+    fn level_rates_win_over_kind() {
+        let mut rates = SampleRates {
+            histograms: Some(0.1),
+            ..SampleRates::default()
+        };
+        rates.set_level(Level::DEBUG, 0.5);
+        rates.set_level(Level::DEBUG, 0.01);
+        rates.set_level(Level::INFO, 1.0);
+
+        assert_eq!(Some(0.01), rates.counter("name", Some(&Level::DEBUG)));
+        assert_eq!(Some(0.01), rates.histogram("name", Some(&Level::DEBUG)));
+        assert_eq!(None, rates.histogram("name", Some(&Level::INFO)));
+        assert_eq!(Some(0.1), rates.histogram("name", Some(&Level::WARN)));
+        assert_eq!(Some(0.1), rates.histogram("name", None));
+        assert_eq!(2, rates.levels.len());
+    }
+
+    #[test]
+    fn name_rates_win_over_level() {
+        let mut rates = SampleRates::default();
+        rates.set_level(Level::DEBUG, 0.5);
+        rates.set_name("http.request.duration", 0.05);
+        rates.set_name("cache.*.hits", 0.1);
+        rates.set_name("cache.*", 0.2);
+        rates.set_name("cache.*", 0.3);
+        rates.set_name("bad.**", 0.3);
+
+        let debug = Some(&Level::DEBUG);
+        assert_eq!(Some(0.05), rates.histogram("http.request.duration", debug));
+        assert_eq!(Some(0.5), rates.histogram("http.request.size", debug));
+        assert_eq!(Some(0.1), rates.counter("cache.users.hits", None));
+        assert_eq!(Some(0.3), rates.counter("cache.users", None));
+        assert_eq!(None, rates.counter("cache", None));
+        assert_eq!("bad.**", rates.invalid_patterns[0].0);
+        assert!(rates.is_valid());
+    }
+
+    #[test]
+    fn sample_rate() {
+        struct LinesSink(Arc<Mutex<Vec<String>>>);
+
+        impl MetricSink for LinesSink {
+            fn emit(&self, metric: &str) -> io::Result<usize> {
+                self.0.lock().unwrap().push(metric.to_string());
+                Ok(metric.len())
+            }
+        }
+
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(LinesSink(lines.clone()))
+            .with_sample_rate(0.5)
+            .build(None)
+            .expect("should build a recorder with custom sink");
+
+        let counter = recorder.register_counter(&Key::from_name("counter.name"), &METADATA);
+        let gauge = recorder.register_gauge(&Key::from_name("gauge.name"), &METADATA);
+        for _ in 0..1000 {
+            counter.increment(1);
+        }
+        gauge.set(1.0);
+
+        let lines = lines.lock().unwrap();
+        let counters = lines
+            .iter()
+            .filter(|l| *l == "counter.name:1|c|@0.5")
+            .count();
+        assert!((350..650).contains(&counters), "sent {} counters", counters);
+        assert_eq!(counters + 1, lines.len());
+        assert_eq!(Some("gauge.name:1|g"), lines.last().map(String::as_str));
+    }
+
+    #[test]
+    fn pre_scaled_sample_rate() {
+        let sink = crate::testing::FakeSink::new();
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(sink.clone())
+            .with_sample_rate(0.5)
+            .with_sample_rate_semantics(SampleRateSemantics::PreScaled)
+            .build(None)
+            .unwrap();
+
+        let counter = recorder.register_counter(&Key::from_name("counter.name"), &METADATA);
+        let histogram = recorder.register_histogram(&Key::from_name("histogram.name"), &METADATA);
+        for _ in 0..100 {
+            counter.increment(3);
+            histogram.record(1.0);
+        }
+        let lines = sink.lines();
+        assert!(!lines.is_empty());
+        for line in lines {
+            assert!(
+                [
+                    "counter.name:6|c",
+                    "ext.name:6|c",
+                    "histogram.name:1|h|@0.5"
+                ]
+                .contains(&line.as_str()),
+                "{}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn sample_rate_for() {
+        let sink = crate::testing::FakeSink::new();
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(sink.clone())
+            .with_sample_rate_for("http.*.duration", 0.5)
+            .build(None)
+            .unwrap();
+        recorder
+            .register_histogram(&Key::from_name("http.request.duration"), &METADATA)
+            .record(1.0);
+        recorder
+            .register_histogram(&Key::from_name("db.query.duration"), &METADATA)
+            .record(1.0);
+        let lines = sink.lines();
+        assert_eq!(
+            Some("db.query.duration:1|h"),
+            lines.last().map(String::as_str)
+        );
+        assert!(lines.len() == 1 || lines[0] == "http.request.duration:1|h|@0.5");
+
+        let result = StatsdBuilder::from("", 0)
+            .with_sink(cadence::NopMetricSink)
+            .with_sample_rate_for("http.**", 0.5)
+            .build(None);
+        assert!(matches!(
+            result,
+            Err(StatsdError::InvalidPattern { pattern, .. }) if pattern == "http.**"
+        ));
+    }
+
+    #[test]
+    fn histogram_sample_rate() {
+        let sink = crate::testing::FakeSink::new();
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(sink.clone())
+            .with_histogram_sample_rate(0.5)
+            .build(None)
+            .expect("should build a recorder with custom sink");
+
+        let counter = recorder.register_counter(&Key::from_name("counter.name"), &METADATA);
+        let key = Key::from((
+            "histogram.name",
+            vec![Label::new("histogram", "distribution")],
+        ));
+        let histogram = recorder.register_histogram(&key, &METADATA);
+        for _ in 0..1000 {
+            counter.increment(1);
+            histogram.record(1.0);
+        }
+
+        let lines = sink.lines();
+        let counters = lines.iter().filter(|l| *l == "counter.name:1|c").count();
+        let histograms = lines
+            .iter()
+            .filter(|l| *l == "histogram.name:1|d|@0.5")
+            .count();
+        assert_eq!(1000, counters);
+        assert!((350..650).contains(&histograms), "sent {}", histograms);
+        assert_eq!(counters + histograms, lines.len());
+    }
+
+    #[test]
+    fn level_sample_rate() {
+        let sink = crate::testing::FakeSink::new();
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(sink.clone())
+            .with_level_sample_rate(metrics::Level::DEBUG, 0.5)
+            .build(None)
+            .expect("should build a recorder with custom sink");
+
+        let debug = metrics::Metadata::new(module_path!(), metrics::Level::DEBUG, None);
+        let verbose = recorder.register_counter(&Key::from_name("verbose"), &debug);
+        let counter = recorder.register_counter(&Key::from_name("counter.name"), &METADATA);
+        for _ in 0..1000 {
+            verbose.increment(1);
+            counter.increment(1);
+        }
+
+        let lines = sink.lines();
+        let verbose = lines.iter().filter(|l| *l == "verbose:1|c|@0.5").count();
+        let counters = lines.iter().filter(|l| *l == "counter.name:1|c").count();
+        assert_eq!(1000, counters);
+        assert!((350..650).contains(&verbose), "sent {}", verbose);
+        assert_eq!(counters + verbose, lines.len());
+    }
+
+    #[test]
+    fn invalid_sample_rate() {
+        for rate in [0.0, -1.0, 1.5, f64::NAN] {
+            let result = StatsdBuilder::from("", 0)
+                .with_sink(cadence::NopMetricSink)
+                .with_sample_rate(rate)
+                .build(None);
+            assert!(matches!(result, Err(StatsdError::InvalidSampleRate)));
+            let result = StatsdBuilder::from("", 0)
+                .with_sink(cadence::NopMetricSink)
+                .with_counter_sample_rate(rate)
+                .build(None);
+            assert!(matches!(result, Err(StatsdError::InvalidSampleRate)));
+        }
+    }
+}
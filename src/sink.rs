@@ -0,0 +1,860 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::panic::{AssertUnwindSafe, RefUnwindSafe};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use cadence::{MetricSink, QueuingMetricSink, SinkStats};
+
+use crate::clock::SharedClock;
+use crate::line::Value;
+use crate::ring::RingQueue;
+use crate::stats::{DropReason, Stats};
+use crate::types::MetricType;
+use crate::upkeep::Upkeep;
+
+/// Largest payload that fits in a single UDP datagram over IPv4.
+pub(crate) const MAX_UDP_PAYLOAD: usize = 65_507;
+
+/// A [`MetricSink`] wrapper that keeps track of the metrics that never made it to the wrapped
+/// sink. A write can hold several newline separated metrics, each of them is counted.
+pub(crate) struct CountingSink<T> {
+    inner: T,
+    stats: Arc<Stats>,
+    max_line_len: Option<usize>,
+    error_reason: DropReason,
+}
+
+impl<T: MetricSink> CountingSink<T> {
+    /// Wrap `inner`, attributing any error it returns to `error_reason`.
+    pub(crate) fn new(inner: T, stats: Arc<Stats>, error_reason: DropReason) -> Self {
+        CountingSink {
+            inner,
+            stats,
+            max_line_len: None,
+            error_reason,
+        }
+    }
+
+    /// Reject, and count, the lines longer than `max_line_len` before they reach the wrapped sink.
+    pub(crate) fn with_max_line_len(mut self, max_line_len: usize) -> Self {
+        self.max_line_len = Some(max_line_len);
+        self
+    }
+}
+
+impl<T: MetricSink> MetricSink for CountingSink<T> {
+    fn emit(&self, metric: &str) -> io::Result<usize> {
+        if self.max_line_len.is_some_and(|max| metric.len() > max) {
+            self.stats
+                .record_drops(DropReason::Oversize, line_count(metric));
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "metric is too large to be sent",
+            ));
+        }
+
+        self.inner.emit(metric).inspect_err(|e| {
+            self.stats
+                .record_drops(self.error_reason, line_count(metric));
+            self.stats.record_error(e);
+        })
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn stats(&self) -> SinkStats {
+        self.inner.stats()
+    }
+}
+
+fn line_count(metric: &str) -> u64 {
+    metric.bytes().filter(|b| *b == b'\n').count() as u64 + 1
+}
+
+/// The [`SinkStats`] of the sinks that write the metrics themselves, cadence's sinks keep their
+/// own.
+#[derive(Debug, Default)]
+pub(crate) struct SentStats {
+    bytes_sent: AtomicU64,
+    packets_sent: AtomicU64,
+    bytes_dropped: AtomicU64,
+    packets_dropped: AtomicU64,
+}
+
+impl SentStats {
+    pub(crate) fn sent(&self, bytes: usize, packets: u64) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.packets_sent.fetch_add(packets, Ordering::Relaxed);
+    }
+
+    pub(crate) fn dropped(&self, bytes: usize, packets: u64) {
+        self.bytes_dropped
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        self.packets_dropped.fetch_add(packets, Ordering::Relaxed);
+    }
+
+    pub(crate) fn get(&self) -> SinkStats {
+        SinkStats {
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            packets_sent: self.packets_sent.load(Ordering::Relaxed),
+            bytes_dropped: self.bytes_dropped.load(Ordering::Relaxed),
+            packets_dropped: self.packets_dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Sink shared between the [`cadence::StatsdClient`] and the background work of a recorder.
+pub(crate) type SharedSink = Arc<dyn MetricSink + Sync + Send + RefUnwindSafe>;
+
+/// Hands a [`SharedSink`] to the [`cadence::StatsdClient`], which wants to own its sink.
+pub(crate) struct SharedSinkRef(pub(crate) SharedSink);
+
+impl MetricSink for SharedSinkRef {
+    fn emit(&self, metric: &str) -> io::Result<usize> {
+        self.0.emit(metric)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.0.flush()
+    }
+
+    fn stats(&self) -> SinkStats {
+        self.0.stats()
+    }
+}
+
+/// The sink built by [`StatsdBuilder`](crate::StatsdBuilder), as handed to the wrappers given to
+/// [`StatsdBuilder::with_sink_wrapper`](crate::StatsdBuilder::with_sink_wrapper).
+///
+/// Writes are queued, and their failures are already counted in
+/// [`StatsdHandle::dropped_metrics`](crate::StatsdHandle::dropped_metrics).
+pub struct InnerSink(pub(crate) SharedSink);
+
+impl MetricSink for InnerSink {
+    fn emit(&self, metric: &str) -> io::Result<usize> {
+        self.0.emit(metric)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.0.flush()
+    }
+
+    fn stats(&self) -> SinkStats {
+        self.0.stats()
+    }
+}
+
+/// A queue in front of the connection sink, cadence's or one with preallocated slots, see
+/// [`StatsdBuilder::with_preallocated_queue`](crate::StatsdBuilder::with_preallocated_queue).
+pub(crate) enum Queue {
+    Channel(QueuingMetricSink),
+    Ring(RingQueue),
+}
+
+impl Queue {
+    fn queued(&self) -> u64 {
+        match self {
+            Queue::Channel(queue) => queue.queued(),
+            Queue::Ring(queue) => queue.queued(),
+        }
+    }
+
+    fn panics(&self) -> u64 {
+        match self {
+            Queue::Channel(queue) => queue.panics(),
+            Queue::Ring(queue) => queue.panics(),
+        }
+    }
+}
+
+impl MetricSink for Queue {
+    fn emit(&self, metric: &str) -> io::Result<usize> {
+        match self {
+            Queue::Channel(queue) => queue.emit(metric),
+            Queue::Ring(queue) => queue.emit(metric),
+        }
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        match self {
+            Queue::Channel(queue) => queue.flush(),
+            Queue::Ring(queue) => queue.flush(),
+        }
+    }
+
+    fn stats(&self) -> SinkStats {
+        match self {
+            Queue::Channel(queue) => queue.stats(),
+            Queue::Ring(queue) => queue.stats(),
+        }
+    }
+}
+
+/// The queues in front of the default UDP sink, each drained by its own worker thread. Metrics
+/// are spread over the queues in a round robin fashion.
+///
+/// Dropping any clone of a [`QueuingMetricSink`] stops its worker thread, so the queues are only
+/// ever owned here and this sink is shared behind an [`Arc`] instead, which lets the recorder
+/// inspect it while the client owns it.
+pub(crate) struct QueueSink {
+    queues: Vec<Queue>,
+    next: AtomicUsize,
+    /// How long to wait for the queues to drain on shutdown.
+    drain_timeout: Option<Duration>,
+    /// Number of metrics each queue holds.
+    capacity: usize,
+    /// How full a queue may be for a metric of each type to be queued, as a fraction of the
+    /// capacity.
+    max_fill: [f64; MetricType::ALL.len()],
+    /// The counter increments that didn't fit in the queues, summed by the line they're sent with
+    /// but the value, when they're coalesced.
+    coalesced: Option<Mutex<HashMap<(String, String), f64>>>,
+    /// The times the metrics waiting in each queue were queued at, in the order of the queues,
+    /// when they have a maximum age.
+    ages: Vec<Arc<QueueAges>>,
+    /// Whether the queues all send to the same sink, whose stats are then only counted once.
+    shared_sink: bool,
+    stats: Arc<Stats>,
+}
+
+/// How often the coalesced counters are queued again, see [`QueueSink::with_coalescing`].
+pub(crate) const REQUEUE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often the queues are checked while waiting for them to drain.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+impl QueueSink {
+    pub(crate) fn new(
+        queues: Vec<Queue>,
+        drain_timeout: Option<Duration>,
+        stats: Arc<Stats>,
+    ) -> Self {
+        QueueSink {
+            queues,
+            next: AtomicUsize::new(0),
+            drain_timeout,
+            capacity: 0,
+            max_fill: [1.0; MetricType::ALL.len()],
+            coalesced: None,
+            ages: Vec::new(),
+            shared_sink: false,
+            stats,
+        }
+    }
+
+    /// Count the stats of the sink once rather than once per queue, for queues that all send to
+    /// the same sink, e.g. a file.
+    pub(crate) fn with_shared_sink(mut self) -> Self {
+        self.shared_sink = true;
+        self
+    }
+
+    /// Keep track of the time the metrics are queued at, one of `ages` per queue, for each queue
+    /// to send them through a [`StaleSink`] made of its own.
+    pub(crate) fn with_ages(mut self, ages: Vec<Arc<QueueAges>>) -> Self {
+        self.ages = ages;
+        self
+    }
+
+    /// Queue `metric` in the queue at `index`, noting when it's queued when the metrics have a maximum age.
+    fn enqueue(&self, index: usize, metric: &str) -> io::Result<usize> {
+        match self.ages.get(index) {
+            Some(ages) => ages.enqueue(&self.queues[index], metric),
+            None => self.queues[index].emit(metric),
+        }
+    }
+
+    /// Sum the counter increments that don't fit in the queues rather than dropping them, see
+    /// [`StatsdBuilder::with_counter_coalescing`](crate::StatsdBuilder::with_counter_coalescing).
+    /// The sums are queued by [`QueueSink::requeue`].
+    pub(crate) fn with_coalescing(mut self) -> Self {
+        self.coalesced = Some(Mutex::default());
+        self
+    }
+
+    /// The index of the queue the next metric goes to.
+    fn next_queue(&self) -> usize {
+        self.next.fetch_add(1, Ordering::Relaxed) % self.queues.len()
+    }
+
+    /// Add the counters of `metric`, which didn't fit in the queues, to the sums, and drop the
+    /// other metrics. At most as many sums as a queue holds are kept.
+    fn coalesce(&self, coalesced: &Mutex<HashMap<(String, String), f64>>, metric: &str) {
+        let mut coalesced = coalesced.lock().unwrap_or_else(|e| e.into_inner());
+        for line in metric.split('\n') {
+            match split_counter(line) {
+                Some((key, value))
+                    if coalesced.len() < self.capacity || coalesced.contains_key(&key) =>
+                {
+                    *coalesced.entry(key).or_default() += value;
+                }
+                _ => self.stats.record_drop(DropReason::QueueFull),
+            }
+        }
+    }
+
+    /// Queue the sums of the coalesced counters, keeping those that still don't fit.
+    pub(crate) fn requeue(&self) {
+        if let Some(coalesced) = &self.coalesced {
+            let mut coalesced = coalesced.lock().unwrap_or_else(|e| e.into_inner());
+            coalesced.retain(|(head, tail), value| {
+                let mut line = head.clone();
+                value.write_to(&mut line);
+                line.push_str(tail);
+                self.enqueue(self.next_queue(), &line).is_err()
+            });
+        }
+    }
+
+    fn coalesced(&self) -> u64 {
+        self.coalesced.as_ref().map_or(0, |coalesced| {
+            coalesced.lock().unwrap_or_else(|e| e.into_inner()).len() as u64
+        })
+    }
+
+    /// Shed the metrics of each type once the queue they'd go to, which holds `capacity` metrics,
+    /// is more than `max_fill` full, as indexed by [`MetricType::index`].
+    pub(crate) fn with_max_fill(
+        mut self,
+        capacity: usize,
+        max_fill: [f64; MetricType::ALL.len()],
+    ) -> Self {
+        self.capacity = capacity;
+        self.max_fill = max_fill;
+        self
+    }
+
+    /// How full a queue may be for `metric` to be queued, the most of its lines when it holds
+    /// several of them.
+    fn max_fill(&self, metric: &str) -> f64 {
+        metric
+            .split('\n')
+            .map(|line| {
+                line.split('|')
+                    .nth(1)
+                    .and_then(MetricType::from_code)
+                    .map_or(1.0, |metric_type| self.max_fill[metric_type.index()])
+            })
+            .fold(0.0, f64::max)
+    }
+
+    /// Number of metrics waiting in all the queues, and to be queued once coalesced.
+    pub(crate) fn queued(&self) -> u64 {
+        self.queues.iter().map(|queue| queue.queued()).sum::<u64>() + self.coalesced()
+    }
+
+    /// Number of times the threads of the queues panicked, cadence restarts them when they do.
+    pub(crate) fn panics(&self) -> u64 {
+        self.queues.iter().map(|queue| queue.panics()).sum()
+    }
+
+    /// Wait for the queues to drain, for at most the shutdown timeout, and return the number of
+    /// metrics that are still waiting. Doesn't wait at all without a shutdown timeout.
+    pub(crate) fn drain(&self) -> u64 {
+        self.drain_for(self.drain_timeout.unwrap_or_default())
+    }
+
+    /// Same as [`QueueSink::drain`], for at most `timeout`.
+    pub(crate) fn drain_for(&self, timeout: Duration) -> u64 {
+        let deadline = Instant::now() + timeout;
+        loop {
+            self.requeue();
+            let queued = self.queued();
+            let now = Instant::now();
+            if queued == 0 || now >= deadline {
+                // the lines the connections still buffer are written too.
+                let _ = self.flush();
+                return queued;
+            }
+            thread::sleep(DRAIN_POLL_INTERVAL.min(deadline - now));
+        }
+    }
+}
+
+/// The queues would still be sent by their threads, but nothing waits for those threads once the
+/// application exits.
+impl Drop for QueueSink {
+    fn drop(&mut self) {
+        if self.drain_timeout.is_some() {
+            let abandoned = self.drain();
+            self.stats.record_drops(DropReason::Abandoned, abandoned);
+        }
+    }
+}
+
+impl MetricSink for QueueSink {
+    fn emit(&self, metric: &str) -> io::Result<usize> {
+        let index = self.next_queue();
+        let max_fill = self.max_fill(metric);
+        if max_fill < 1.0 && self.queues[index].queued() as f64 >= max_fill * self.capacity as f64 {
+            self.stats
+                .record_drops(DropReason::Shed, line_count(metric));
+            return Ok(metric.len());
+        }
+        match (self.enqueue(index, metric), &self.coalesced) {
+            (Err(_), Some(coalesced)) => {
+                self.coalesce(coalesced, metric);
+                Ok(metric.len())
+            }
+            (result, _) => result,
+        }
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        for queue in &self.queues {
+            queue.flush()?;
+        }
+        Ok(())
+    }
+
+    fn stats(&self) -> SinkStats {
+        let mut stats = SinkStats::default();
+        let queues = if self.shared_sink {
+            1
+        } else {
+            self.queues.len()
+        };
+        for queue in &self.queues[..queues] {
+            let queue_stats = queue.stats();
+            stats.bytes_sent += queue_stats.bytes_sent;
+            stats.packets_sent += queue_stats.packets_sent;
+            stats.bytes_dropped += queue_stats.bytes_dropped;
+            stats.packets_dropped += queue_stats.packets_dropped;
+        }
+        stats
+    }
+}
+
+/// The line of a counter split into everything but its value, and its value.
+fn split_counter(line: &str) -> Option<((String, String), f64)> {
+    let (name, rest) = line.split_once(':')?;
+    let (value, tail) = rest.split_once('|')?;
+    if tail != "c" && !tail.starts_with("c|") {
+        return None;
+    }
+    let value = value.parse().ok()?;
+    Some(((format!("{}:", name), format!("|{}", tail)), value))
+}
+
+/// Queues the coalesced counters of a [`QueueSink`] on an interval, until the sink goes away.
+pub(crate) struct Requeuer {
+    sink: Weak<QueueSink>,
+}
+
+impl Requeuer {
+    pub(crate) fn new(sink: &Arc<QueueSink>) -> Self {
+        Requeuer {
+            sink: Arc::downgrade(sink),
+        }
+    }
+
+    pub(crate) fn schedule(self, upkeep: &mut Upkeep, interval: Duration) {
+        upkeep.every(interval, move || match self.sink.upgrade() {
+            Some(sink) => {
+                sink.requeue();
+                true
+            }
+            None => false,
+        });
+    }
+}
+
+/// The times the metrics waiting in a queue were queued at, see
+/// [`StatsdBuilder::with_max_queue_age`](crate::StatsdBuilder::with_max_queue_age). The queue
+/// sends metrics in the order they were queued, so the times are kept beside it in the same
+/// order, and its [`StaleSink`] takes the oldest as it's handed each metric.
+pub(crate) struct QueueAges {
+    /// Only ever read, a panic can't leave it broken.
+    clock: AssertUnwindSafe<SharedClock>,
+    max_age: Duration,
+    /// Oldest first.
+    queued: Mutex<VecDeque<Instant>>,
+}
+
+impl QueueAges {
+    pub(crate) fn new(clock: SharedClock, max_age: Duration) -> Self {
+        QueueAges {
+            clock: AssertUnwindSafe(clock),
+            max_age,
+            queued: Mutex::default(),
+        }
+    }
+
+    /// Queue `metric` in `queue` and note when. The times are locked until the metric is queued,
+    /// so that they stay in the order of the queue, which never blocks.
+    fn enqueue(&self, queue: &Queue, metric: &str) -> io::Result<usize> {
+        let mut queued = self.queued.lock().unwrap_or_else(|e| e.into_inner());
+        let written = queue.emit(metric)?;
+        queued.push_back(self.clock.now());
+        Ok(written)
+    }
+
+    /// Whether the metric the queue is sending now waited too long to be sent.
+    fn is_stale(&self) -> bool {
+        let queued = self
+            .queued
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .pop_front();
+        queued
+            .is_some_and(|queued| self.clock.now().saturating_duration_since(queued) > self.max_age)
+    }
+}
+
+/// A [`MetricSink`] wrapper in between a queue and the connection sink, which drops the metrics
+/// that waited in the queue for too long, see [`QueueAges`].
+pub(crate) struct StaleSink<T> {
+    inner: T,
+    ages: Arc<QueueAges>,
+    stats: Arc<Stats>,
+}
+
+impl<T: MetricSink> StaleSink<T> {
+    pub(crate) fn new(inner: T, ages: Arc<QueueAges>, stats: Arc<Stats>) -> Self {
+        StaleSink { inner, ages, stats }
+    }
+}
+
+impl<T: MetricSink> MetricSink for StaleSink<T> {
+    fn emit(&self, metric: &str) -> io::Result<usize> {
+        if self.ages.is_stale() {
+            self.stats
+                .record_drops(DropReason::Stale, line_count(metric));
+            return Ok(metric.len());
+        }
+        self.inner.emit(metric)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn stats(&self) -> SinkStats {
+        self.inner.stats()
+    }
+}
+
+/// Bounded buffer of the most recently emitted lines, kept around for debugging.
+#[derive(Debug)]
+pub(crate) struct RecentLines {
+    capacity: usize,
+    lines: Mutex<VecDeque<String>>,
+}
+
+impl RecentLines {
+    pub(crate) fn new(capacity: usize) -> Self {
+        RecentLines {
+            capacity,
+            lines: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    fn push(&self, line: &str) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut lines = self.lines.lock().unwrap_or_else(|e| e.into_inner());
+        if lines.len() == self.capacity {
+            lines.pop_front();
+        }
+        lines.push_back(line.to_string());
+    }
+
+    /// The buffered lines, oldest first.
+    pub(crate) fn lines(&self) -> Vec<String> {
+        let lines = self.lines.lock().unwrap_or_else(|e| e.into_inner());
+        lines.iter().cloned().collect()
+    }
+}
+
+/// A [`MetricSink`] wrapper that remembers the lines it successfully handed to the wrapped sink.
+pub(crate) struct RecentLinesSink {
+    pub(crate) inner: SharedSink,
+    pub(crate) recent: Arc<RecentLines>,
+}
+
+impl MetricSink for RecentLinesSink {
+    fn emit(&self, metric: &str) -> io::Result<usize> {
+        let written = self.inner.emit(metric)?;
+        self.recent.push(metric);
+        Ok(written)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn stats(&self) -> SinkStats {
+        self.inner.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Condvar;
+
+    use metrics::{Key, Recorder};
+
+    use super::*;
+    use crate::testing::{Environ, METADATA};
+    use crate::StatsdBuilder;
+
+    /// Holds the writes until it's opened.
+    #[derive(Default)]
+    struct GatedSink {
+        open: Mutex<bool>,
+        opened: Condvar,
+        lines: Mutex<Vec<String>>,
+    }
+
+    impl MetricSink for GatedSink {
+        fn emit(&self, metric: &str) -> io::Result<usize> {
+            let mut open = self.open.lock().unwrap();
+            while !*open {
+                open = self.opened.wait(open).unwrap();
+            }
+            self.lines.lock().unwrap().push(metric.to_string());
+            Ok(metric.len())
+        }
+    }
+
+    fn wait_for(condition: impl Fn() -> bool) {
+        for _ in 0..500 {
+            if condition() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        panic!("timed out");
+    }
+
+    #[test]
+    fn coalesces_counters() {
+        let gated = Arc::new(GatedSink::default());
+        let queue = QueuingMetricSink::with_capacity(SharedSinkRef(gated.clone()), 2);
+        let stats = Arc::new(Stats::default());
+        let sink = QueueSink::new(vec![Queue::Channel(queue)], None, stats.clone())
+            .with_max_fill(2, [1.0; MetricType::ALL.len()])
+            .with_coalescing();
+
+        // the worker takes the first one and waits on the gate.
+        sink.emit("a:1|c").unwrap();
+        wait_for(|| sink.queued() == 0);
+        for metric in ["b:1|g", "c:1|c", "a:2|c", "a:3|c|#t:1", "a:4|c", "d:1|g"] {
+            sink.emit(metric).unwrap();
+        }
+        assert_eq!(4, sink.queued());
+        assert_eq!(1, stats.dropped().get(DropReason::QueueFull));
+
+        *gated.open.lock().unwrap() = true;
+        gated.opened.notify_all();
+        wait_for(|| {
+            sink.requeue();
+            gated.lines.lock().unwrap().len() == 5
+        });
+        let mut lines = gated.lines.lock().unwrap().clone();
+        lines[3..].sort();
+        assert_eq!(
+            vec!["a:1|c", "b:1|g", "c:1|c", "a:3|c|#t:1", "a:6|c"],
+            lines
+        );
+    }
+
+    #[test]
+    fn counts_a_shared_sink_once() {
+        struct SentSink(SentStats);
+
+        impl MetricSink for SentSink {
+            fn emit(&self, metric: &str) -> io::Result<usize> {
+                self.0.sent(metric.len(), 1);
+                Ok(metric.len())
+            }
+
+            fn stats(&self) -> SinkStats {
+                self.0.get()
+            }
+        }
+
+        let shared = Arc::new(SentSink(SentStats::default()));
+        let queues = |count| -> Vec<Queue> {
+            (0..count)
+                .map(|_| {
+                    let queue = QueuingMetricSink::with_capacity(SharedSinkRef(shared.clone()), 2);
+                    Queue::Channel(queue)
+                })
+                .collect()
+        };
+        shared.emit("a:1|c").unwrap();
+        let stats = Arc::new(Stats::default());
+        let sink = QueueSink::new(queues(2), None, stats.clone());
+        assert_eq!(2, sink.stats().packets_sent);
+        let sink = QueueSink::new(queues(2), None, stats).with_shared_sink();
+        assert_eq!(1, sink.stats().packets_sent);
+    }
+
+    #[test]
+    fn drops_stale_metrics() {
+        let clock = crate::testing::ManualClock::new();
+        let ages = Arc::new(QueueAges::new(
+            Arc::new(clock.clone()),
+            Duration::from_secs(1),
+        ));
+        let stats = Arc::new(Stats::default());
+        let gated = Arc::new(GatedSink::default());
+        let stale = StaleSink::new(SharedSinkRef(gated.clone()), ages.clone(), stats.clone());
+        let queue = QueuingMetricSink::with_capacity(stale, 4);
+        let sink =
+            QueueSink::new(vec![Queue::Channel(queue)], None, stats.clone()).with_ages(vec![ages]);
+
+        // the worker takes the first one while it's fresh and waits on the gate.
+        sink.emit("a:1|c").unwrap();
+        wait_for(|| sink.queued() == 0);
+        sink.emit("b:1|g").unwrap();
+        clock.advance(Duration::from_secs(2));
+        sink.emit("c:1|c").unwrap();
+
+        *gated.open.lock().unwrap() = true;
+        gated.opened.notify_all();
+        wait_for(|| sink.queued() == 0 && gated.lines.lock().unwrap().len() == 2);
+        assert_eq!(vec!["a:1|c", "c:1|c"], *gated.lines.lock().unwrap());
+        assert_eq!(1, stats.dropped().get(DropReason::Stale));
+    }
+
+    #[test]
+    fn recent_lines_keeps_the_newest() {
+        let recent = RecentLines::new(2);
+        recent.push("a:1|c");
+        recent.push("b:1|c");
+        recent.push("c:1|c");
+        assert_eq!(vec!["b:1|c", "c:1|c"], recent.lines());
+    }
+
+    #[test]
+    fn queue_workers() {
+        let (server_socket, builder) = Environ::setup();
+        let recorder = builder
+            .with_queue_workers(2)
+            .build(None)
+            .expect("test env should build a valid recorder");
+        let env = Environ {
+            server_socket,
+            recorder,
+        };
+
+        let counter = env
+            .recorder
+            .register_counter(&Key::from_name("counter.name"), &METADATA);
+        counter.increment(1);
+        counter.increment(2);
+
+        let mut received = vec![env.receive_on_server(), env.receive_on_server()];
+        received.sort();
+        assert_eq!(vec!["counter.name:1|c", "counter.name:2|c"], received);
+        assert_eq!(Some(0), env.recorder.handle().queue_depth());
+    }
+
+    #[test]
+    fn queue_priority() {
+        // nothing listens on the port, the queue is stuck reconnecting once a line fills the
+        // buffer.
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let recorder = StatsdBuilder::from("127.0.0.1", port)
+            .with_tcp()
+            .with_reconnect_backoff(Duration::from_secs(60), Duration::from_secs(60))
+            .with_buffer_size(1)
+            .with_queue_size(10)
+            .with_queue_priority(MetricType::Histogram, 0.5)
+            .build(None)
+            .expect("should build a recorder");
+        let handle = recorder.handle();
+        let histogram = recorder.register_histogram(&Key::from_name("histogram"), &METADATA);
+        let counter = recorder.register_counter(&Key::from_name("counter"), &METADATA);
+        for _ in 0..20 {
+            histogram.record(1.0);
+        }
+        for _ in 0..20 {
+            counter.increment(1);
+        }
+
+        // the first metric may already be stuck in the sink rather than in the queue.
+        let dropped = handle.dropped_metrics();
+        let shed = dropped.get(DropReason::Shed);
+        assert!((14..=15).contains(&shed), "{}", shed);
+        // the counters fill the rest of the queue, none of them is shed.
+        assert_eq!(15, dropped.get(DropReason::QueueFull));
+    }
+
+    #[test]
+    fn counter_coalescing() {
+        // nothing listens on the port, the queue is stuck reconnecting once a line fills the
+        // buffer.
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let recorder = StatsdBuilder::from("127.0.0.1", port)
+            .with_tcp()
+            .with_reconnect_backoff(Duration::from_secs(60), Duration::from_secs(60))
+            .with_buffer_size(1)
+            .with_queue_size(2)
+            .with_counter_coalescing()
+            .build(None)
+            .expect("should build a recorder");
+        let handle = recorder.handle();
+        for name in ["a", "b", "c", "d"] {
+            let counter = recorder.register_counter(&Key::from_name(name), &METADATA);
+            for _ in 0..10 {
+                counter.increment(1);
+            }
+        }
+        recorder
+            .register_gauge(&Key::from_name("gauge"), &METADATA)
+            .set(1.0);
+
+        // the queue is full of increments of `a`, the rest of them and those of `b` are summed,
+        // which leaves no room for `c`, `d` and the gauge.
+        let dropped = handle.dropped_metrics();
+        assert_eq!(21, dropped.get(DropReason::QueueFull));
+        assert_eq!(Some(4), handle.queue_depth());
+    }
+
+    #[test]
+    fn shutdown_timeout() {
+        // nothing listens on the port, the queue is stuck reconnecting once a line fills the
+        // buffer.
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let recorder = StatsdBuilder::from("127.0.0.1", port)
+            .with_tcp()
+            .with_reconnect_backoff(Duration::from_secs(60), Duration::from_secs(60))
+            .with_buffer_size(1)
+            .with_shutdown_timeout(Duration::from_millis(50))
+            .build(None)
+            .expect("should build a recorder");
+        let handle = recorder.handle();
+        let counter = recorder.register_counter(&Key::from_name("counter"), &METADATA);
+        for _ in 0..3 {
+            counter.increment(1);
+        }
+
+        // the first metric may already be stuck in the sink rather than in the queue.
+        let abandoned = handle.shutdown();
+        assert!((2..=3).contains(&abandoned), "{}", abandoned);
+        drop((counter, recorder));
+        let dropped = handle.dropped_metrics();
+        assert_eq!(abandoned, dropped.get(DropReason::Abandoned));
+    }
+}
@@ -0,0 +1,290 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use crate::line::RenderedKey;
+use crate::mapping::Glob;
+
+/// Most bins a sketch keeps for the positive values, and as many for the negative ones. Once
+/// there are more, the bins of the values closest to zero are merged, which only costs accuracy
+/// for the smallest values.
+const MAX_BINS: usize = 1024;
+/// Values closer to zero than this are counted as zero.
+const MIN_INDEXABLE: f64 = 1e-9;
+
+/// Which distributions are sketched on the client, see
+/// [`StatsdBuilder::with_local_sketches`](crate::StatsdBuilder::with_local_sketches).
+#[derive(Clone, Debug)]
+pub(crate) struct Sketching {
+    pub(crate) relative_accuracy: f64,
+    /// The names of the distributions sketched, every distribution when there's none.
+    names: Vec<Glob>,
+    /// The patterns that aren't valid, along with why, reported when building the recorder.
+    pub(crate) invalid_patterns: Vec<(String, String)>,
+}
+
+impl Default for Sketching {
+    fn default() -> Self {
+        Sketching {
+            relative_accuracy: 0.01,
+            names: Vec::new(),
+            invalid_patterns: Vec::new(),
+        }
+    }
+}
+
+impl Sketching {
+    /// Whether the relative accuracy is in `(0, 1)`.
+    pub(crate) fn is_valid(&self) -> bool {
+        self.relative_accuracy > 0.0 && self.relative_accuracy < 1.0
+    }
+
+    pub(crate) fn add_name(&mut self, pattern: &str) {
+        match Glob::new(pattern) {
+            Ok(glob) => self.names.push(glob),
+            Err(reason) => self.invalid_patterns.push((pattern.to_string(), reason)),
+        }
+    }
+
+    /// Whether the distribution `name` is sketched.
+    pub(crate) fn applies(&self, name: &str) -> bool {
+        self.names.is_empty() || self.names.iter().any(|glob| glob.matches(name))
+    }
+}
+
+/// A DDSketch: values are counted in bins whose bounds grow geometrically, so that the value a
+/// bin stands for is within the relative accuracy of every value counted in it.
+#[derive(Debug)]
+pub(crate) struct DDSketch {
+    gamma: f64,
+    ln_gamma: f64,
+    /// The count of every bin, by index, the bin `i` holding the values in `(gamma^(i-1), gamma^i]`.
+    positive: BTreeMap<i32, u64>,
+    /// The same for the opposite of the negative values.
+    negative: BTreeMap<i32, u64>,
+    zeros: u64,
+}
+
+impl DDSketch {
+    pub(crate) fn new(relative_accuracy: f64) -> Self {
+        let gamma = (1.0 + relative_accuracy) / (1.0 - relative_accuracy);
+        DDSketch {
+            gamma,
+            ln_gamma: gamma.ln(),
+            positive: BTreeMap::new(),
+            negative: BTreeMap::new(),
+            zeros: 0,
+        }
+    }
+
+    pub(crate) fn add(&mut self, value: f64) {
+        if value.is_nan() {
+            return;
+        }
+        let bins = match value {
+            v if v.abs() < MIN_INDEXABLE => {
+                self.zeros += 1;
+                return;
+            }
+            v if v > 0.0 => &mut self.positive,
+            _ => &mut self.negative,
+        };
+        let index = (value.abs().ln() / self.ln_gamma).ceil() as i32;
+        *bins.entry(index).or_default() += 1;
+        if bins.len() > MAX_BINS {
+            collapse_lowest(bins);
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.zeros == 0 && self.positive.is_empty() && self.negative.is_empty()
+    }
+
+    /// Number of values added.
+    #[cfg(test)]
+    pub(crate) fn count(&self) -> u64 {
+        self.zeros + self.positive.values().sum::<u64>() + self.negative.values().sum::<u64>()
+    }
+
+    /// The value the bin `index` stands for, within the relative accuracy of any value in it.
+    fn value(&self, index: i32) -> f64 {
+        2.0 * self.gamma.powi(index) / (self.gamma + 1.0)
+    }
+
+    /// Hand the value of every bin to `f` along with how many values it counted, lowest first.
+    pub(crate) fn for_each_bin(&self, mut f: impl FnMut(f64, u64)) {
+        for (index, count) in self.negative.iter().rev() {
+            f(-self.value(*index), *count);
+        }
+        if self.zeros > 0 {
+            f(0.0, self.zeros);
+        }
+        for (index, count) in &self.positive {
+            f(self.value(*index), *count);
+        }
+    }
+
+    /// The value of the bin holding the `quantile` of the values, `None` when there are none.
+    #[cfg(test)]
+    pub(crate) fn quantile(&self, quantile: f64) -> Option<f64> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let rank = ((quantile * count as f64).ceil() as u64).clamp(1, count);
+        let mut seen = 0;
+        let mut found = None;
+        self.for_each_bin(|value, n| {
+            seen += n;
+            if found.is_none() && seen >= rank {
+                found = Some(value);
+            }
+        });
+        found
+    }
+}
+
+/// Merge the lowest bin into the next one.
+fn collapse_lowest(bins: &mut BTreeMap<i32, u64>) {
+    if let Some((_, count)) = bins.pop_first() {
+        if let Some(mut next) = bins.first_entry() {
+            *next.get_mut() += count;
+        }
+    }
+}
+
+/// The values a distribution recorded since they were last sent, sketched.
+#[derive(Debug)]
+pub(crate) struct Sketch {
+    /// The distribution, without the sample rate.
+    rendered: RenderedKey,
+    relative_accuracy: f64,
+    sketch: Mutex<DDSketch>,
+}
+
+impl Sketch {
+    pub(crate) fn new(rendered: RenderedKey, relative_accuracy: f64) -> Self {
+        Sketch {
+            rendered,
+            relative_accuracy,
+            sketch: Mutex::new(DDSketch::new(relative_accuracy)),
+        }
+    }
+
+    pub(crate) fn add(&self, value: f64) {
+        self.sketch
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .add(value);
+    }
+
+    /// Hand the value of every bin of the values recorded since the last call to `f`, along with
+    /// the distribution weighted by the values in the bin, nothing when no value was recorded.
+    pub(crate) fn take(&self, mut f: impl FnMut(&RenderedKey, f64)) {
+        let sketch = std::mem::replace(
+            &mut *self.sketch.lock().unwrap_or_else(|e| e.into_inner()),
+            DDSketch::new(self.relative_accuracy),
+        );
+        if sketch.is_empty() {
+            return;
+        }
+        sketch.for_each_bin(|value, count| {
+            // statsd counts a value sent at a rate `r` as `1/r` values.
+            let rate = (count > 1).then(|| 1.0 / count as f64);
+            f(&self.rendered.clone().with_sample_rate(rate), value);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use metrics::{Key, Recorder};
+
+    use super::*;
+    use crate::testing::METADATA;
+    use crate::{StatsdBuilder, StatsdError};
+
+    #[test]
+    fn quantiles_within_relative_accuracy() {
+        let mut sketch = DDSketch::new(0.01);
+        for value in 1..=10_000 {
+            sketch.add(f64::from(value));
+        }
+        sketch.add(0.0);
+        sketch.add(-5.0);
+        assert_eq!(10_002, sketch.count());
+        for (quantile, expected) in [(0.5, 4999.0), (0.99, 9900.0), (1.0, 10_000.0)] {
+            let value = sketch.quantile(quantile).unwrap();
+            assert!((value - expected).abs() <= expected * 0.01, "{}", value);
+        }
+        let lowest = sketch.quantile(0.0).unwrap();
+        assert!((lowest + 5.0).abs() <= 0.05);
+        assert_eq!(Some(0.0), sketch.quantile(0.00015));
+    }
+
+    #[test]
+    fn bounds_the_bins() {
+        let mut sketch = DDSketch::new(0.01);
+        // far enough apart to land in bins of their own.
+        for exponent in 0..2000 {
+            sketch.add(1.05f64.powi(exponent));
+        }
+        assert_eq!(MAX_BINS, sketch.positive.len());
+        assert_eq!(2000, sketch.count());
+        // the largest values keep their accuracy.
+        let largest = 1.05f64.powi(1999);
+        let highest = sketch.quantile(1.0).unwrap();
+        assert!((highest - largest).abs() <= largest * 0.01);
+    }
+
+    #[test]
+    fn local_sketches() {
+        let clock = crate::testing::ManualClock::new();
+        let sink = crate::testing::FakeSink::new();
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(sink.clone())
+            .with_clock(clock.clone())
+            .histogram_is_distribution()
+            .with_local_sketches(Duration::from_secs(10), 0.01)
+            .with_sketches_for("payload.*")
+            .build(None)
+            .expect("should build a recorder with custom sink");
+        let size = recorder.register_histogram(&Key::from_name("payload.size"), &METADATA);
+        for _ in 0..1000 {
+            size.record(100.0);
+        }
+        size.record(7.0);
+        recorder
+            .register_histogram(&Key::from_name("latency"), &METADATA)
+            .record(3.0);
+        assert_eq!(vec!["latency:3|d"], sink.lines());
+
+        clock.advance(Duration::from_secs(10));
+        recorder.shared.run_pending();
+        let lines = sink.lines();
+        assert_eq!(3, lines.len());
+        let bins: Vec<(f64, &str)> = lines[1..]
+            .iter()
+            .map(|line| {
+                let (value, rest) = line
+                    .strip_prefix("payload.size:")
+                    .and_then(|line| line.split_once('|'))
+                    .unwrap();
+                (value.parse().unwrap(), rest)
+            })
+            .collect();
+        assert!((bins[0].0 - 7.0).abs() <= 0.07);
+        assert_eq!("d", bins[0].1);
+        assert!((bins[1].0 - 100.0).abs() <= 1.0);
+        assert_eq!("d|@0.001", bins[1].1);
+    }
+
+    #[test]
+    fn invalid_relative_accuracy() {
+        let result = StatsdBuilder::from("127.0.0.1", 8125)
+            .with_local_sketches(Duration::from_secs(10), 1.0)
+            .build(None);
+        assert!(matches!(result, Err(StatsdError::InvalidRelativeAccuracy)));
+    }
+}
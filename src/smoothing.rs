@@ -0,0 +1,185 @@
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::clock::SharedClock;
+use crate::types::MetricType;
+
+/// The token buckets of the counters and gauges, see
+/// [`StatsdBuilder::with_burst_smoothing`](crate::StatsdBuilder::with_burst_smoothing).
+pub(crate) struct Smoothing {
+    per_second: f64,
+    burst: u64,
+    clock: SharedClock,
+}
+
+impl Smoothing {
+    pub(crate) fn new(per_second: f64, burst: u64, clock: SharedClock) -> Self {
+        Smoothing {
+            per_second,
+            burst,
+            clock,
+        }
+    }
+
+    /// A full bucket for a metric.
+    pub(crate) fn bucket(&self) -> TokenBucket {
+        TokenBucket {
+            per_second: self.per_second,
+            burst: self.burst as f64,
+            clock: self.clock.clone(),
+            state: Mutex::new(Bucket {
+                tokens: self.burst as f64,
+                last: self.clock.now(),
+                deferred: None,
+            }),
+        }
+    }
+}
+
+/// Lets a metric send `burst` values at once, then `per_second` values a second. The values
+/// beyond that are coalesced and sent later, once there's a token for them.
+pub(crate) struct TokenBucket {
+    per_second: f64,
+    burst: f64,
+    clock: SharedClock,
+    state: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    /// When the tokens were last added.
+    last: Instant,
+    /// The values waiting for a token, coalesced.
+    deferred: Option<f64>,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant, per_second: f64, burst: f64) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * per_second).min(burst);
+        self.last = now;
+    }
+
+    fn take_token(&mut self) -> bool {
+        let available = self.tokens >= 1.0;
+        if available {
+            self.tokens -= 1.0;
+        }
+        available
+    }
+}
+
+impl TokenBucket {
+    /// Whether `value` can be sent now, otherwise it's deferred along with the values deferred
+    /// so far: counters add up, gauges keep the last value. Once values are deferred, the next
+    /// ones are too, so that they're sent in order.
+    pub(crate) fn admit(&self, value: f64, metric_type: MetricType) -> bool {
+        let mut bucket = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        bucket.refill(self.clock.now(), self.per_second, self.burst);
+        if bucket.deferred.is_none() && bucket.take_token() {
+            return true;
+        }
+        bucket.deferred = Some(match (bucket.deferred, metric_type) {
+            (Some(deferred), MetricType::Counter) => deferred + value,
+            _ => value,
+        });
+        false
+    }
+
+    /// The deferred values coalesced, once there's a token for them.
+    pub(crate) fn take(&self) -> Option<f64> {
+        let mut bucket = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        bucket.deferred?;
+        bucket.refill(self.clock.now(), self.per_second, self.burst);
+        bucket
+            .take_token()
+            .then(|| bucket.deferred.take())
+            .flatten()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use metrics::{Key, Recorder};
+
+    use super::*;
+    use crate::testing::{ManualClock, METADATA};
+    use crate::{StatsdBuilder, StatsdError};
+
+    #[test]
+    fn coalesces_the_excess() {
+        let clock = ManualClock::new();
+        let smoothing = Smoothing::new(0.5, 2, Arc::new(clock.clone()));
+        let counter = smoothing.bucket();
+        let admitted: Vec<bool> = (1..=4)
+            .map(|value| counter.admit(f64::from(value), MetricType::Counter))
+            .collect();
+        assert_eq!(vec![true, true, false, false], admitted);
+        assert_eq!(None, counter.take());
+
+        // a token every two seconds.
+        clock.advance(Duration::from_secs(1));
+        assert!(!counter.admit(5.0, MetricType::Counter));
+        assert_eq!(None, counter.take());
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(Some(12.0), counter.take());
+        assert_eq!(None, counter.take());
+
+        let gauge = smoothing.bucket();
+        for value in [1.0, 2.0, 3.0, 4.0] {
+            gauge.admit(value, MetricType::Gauge);
+        }
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(Some(4.0), gauge.take());
+    }
+
+    #[test]
+    fn burst_smoothing() {
+        let clock = crate::testing::ManualClock::new();
+        let sink = crate::testing::FakeSink::new();
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(sink.clone())
+            .with_clock(clock.clone())
+            .with_burst_smoothing(Duration::from_secs(1), 1.0, 2)
+            .build(None)
+            .expect("should build a recorder with custom sink");
+        let requests = recorder.register_counter(&Key::from_name("requests"), &METADATA);
+        for _ in 0..100_000 {
+            requests.increment(1);
+        }
+        let threads = recorder.register_gauge(&Key::from_name("threads"), &METADATA);
+        for value in 1..=4 {
+            threads.set(f64::from(value));
+        }
+        recorder
+            .register_histogram(&Key::from_name("latency"), &METADATA)
+            .record(3.0);
+        assert_eq!(
+            vec![
+                "requests:1|c",
+                "requests:1|c",
+                "threads:1|g",
+                "threads:2|g",
+                "latency:3|h"
+            ],
+            sink.lines()
+        );
+
+        clock.advance(Duration::from_secs(1));
+        recorder.shared.run_pending();
+        let mut deferred = sink.lines().split_off(5);
+        deferred.sort();
+        assert_eq!(vec!["requests:99998|c", "threads:4|g"], deferred);
+    }
+
+    #[test]
+    fn invalid_burst_smoothing() {
+        let result = StatsdBuilder::from("127.0.0.1", 8125)
+            .with_burst_smoothing(Duration::from_secs(1), 0.0, 10)
+            .build(None);
+        assert!(matches!(result, Err(StatsdError::InvalidBurstSmoothing)));
+    }
+}
@@ -0,0 +1,417 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use metrics::Key;
+
+use crate::clock::SharedClock;
+use crate::types::MetricType;
+
+/// Counter rates are computed over windows of this length.
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// The last value seen for a metric, as returned by [`StatsdHandle::snapshot`].
+///
+/// [`StatsdHandle::snapshot`]: crate::StatsdHandle::snapshot
+#[derive(Clone, Debug, PartialEq)]
+pub enum LastValue {
+    /// A counter along with the sum of all the increments seen so far and the per second rate of
+    /// increments over the last ten seconds.
+    Counter { total: u64, rate: f64 },
+    /// The last value a gauge was set to.
+    Gauge(f64),
+    /// The last value recorded into a histogram.
+    Histogram(f64),
+}
+
+/// A metric registered with a recorder, as returned by [`StatsdHandle::active_keys`].
+///
+/// [`StatsdHandle::active_keys`]: crate::StatsdHandle::active_keys
+#[derive(Clone, Debug, PartialEq)]
+pub struct ActiveKey {
+    /// The key the metric was registered with.
+    pub key: Key,
+    /// The name the metric is sent with, prefix included.
+    pub name: String,
+    /// What the metric is sent as.
+    pub metric_type: MetricType,
+    /// Number of lines the metric sent, those of its rates and quantiles included.
+    pub emits: u64,
+    /// When the metric last sent a line, as told by the clock of the recorder, `None` if it never
+    /// did.
+    pub last_emit: Option<Instant>,
+}
+
+/// The clock the lines of the metrics are timed with, see
+/// [`StatsdBuilder::with_active_keys`](crate::StatsdBuilder::with_active_keys).
+pub(crate) struct Activity {
+    clock: SharedClock,
+    /// What the times of the lines are relative to, so that they fit in an atomic.
+    start: Instant,
+}
+
+impl Activity {
+    pub(crate) fn new(clock: SharedClock) -> Self {
+        let start = clock.now();
+        Activity { clock, start }
+    }
+}
+
+/// A metric that sent some of the lines, as returned by [`StatsdHandle::top_emitters`].
+///
+/// [`StatsdHandle::top_emitters`]: crate::StatsdHandle::top_emitters
+#[derive(Clone, Debug, PartialEq)]
+pub struct TopEmitter {
+    /// The name the metric is sent with, prefix included.
+    pub name: String,
+    /// Number of lines sent with the name, over all of its tag sets.
+    pub emits: u64,
+    /// The fraction of the lines of every metric sent with the name, from 0 to 1.
+    pub share: f64,
+}
+
+/// The names that sent the most lines out of `emits`, the lines sent by every registered metric,
+/// at most `count` of them.
+pub(crate) fn top_emitters<'a>(
+    emits: impl IntoIterator<Item = (&'a str, u64)>,
+    count: usize,
+) -> Vec<TopEmitter> {
+    let mut names: HashMap<&str, u64> = HashMap::new();
+    for (name, emits) in emits {
+        *names.entry(name).or_default() += emits;
+    }
+    let total: u64 = names.values().sum();
+    let mut names: Vec<_> = names.into_iter().filter(|(_, emits)| *emits > 0).collect();
+    names.sort_by(|(a, a_emits), (b, b_emits)| b_emits.cmp(a_emits).then(a.cmp(b)));
+    names
+        .into_iter()
+        .take(count)
+        .map(|(name, emits)| TopEmitter {
+            name: name.to_string(),
+            emits,
+            share: emits as f64 / total as f64,
+        })
+        .collect()
+}
+
+/// The lines a metric sent, see [`Activity`].
+#[derive(Debug, Default)]
+pub(crate) struct Emits {
+    count: AtomicU64,
+    /// In nanoseconds since the start of the activity.
+    last: AtomicU64,
+}
+
+impl Emits {
+    /// Count a line, and time it when there's an `activity`.
+    pub(crate) fn record(&self, activity: Option<&Activity>) {
+        if let Some(activity) = activity {
+            let elapsed = activity
+                .clock
+                .now()
+                .saturating_duration_since(activity.start);
+            let nanos = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+            self.last.fetch_max(nanos, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// When the last line was sent, `None` if none was.
+    pub(crate) fn last(&self, activity: &Activity) -> Option<Instant> {
+        let last = Duration::from_nanos(self.last.load(Ordering::Relaxed));
+        (self.count() > 0).then(|| activity.start + last)
+    }
+}
+
+struct CounterWindow {
+    start: Instant,
+    count: u64,
+    last_rate: Option<f64>,
+}
+
+impl CounterWindow {
+    fn new(now: Instant) -> Self {
+        CounterWindow {
+            start: now,
+            count: 0,
+            last_rate: None,
+        }
+    }
+
+    fn add(&mut self, now: Instant, value: u64) {
+        let elapsed = now.duration_since(self.start);
+        if elapsed >= RATE_WINDOW {
+            self.last_rate = Some(self.count as f64 / elapsed.as_secs_f64());
+            self.start = now;
+            self.count = 0;
+        }
+        self.count += value;
+    }
+
+    fn rate(&self, now: Instant) -> f64 {
+        match self.last_rate {
+            Some(rate) => rate,
+            None => {
+                let elapsed = now.duration_since(self.start).as_secs_f64();
+                if elapsed > 0.0 {
+                    self.count as f64 / elapsed
+                } else {
+                    0.0
+                }
+            }
+        }
+    }
+}
+
+enum Entry {
+    Counter { total: u64, window: CounterWindow },
+    Gauge(f64),
+    Histogram(f64),
+}
+
+/// Remembers the last value of up to `max_keys` metrics. Metrics registered after the limit was
+/// reached are not tracked.
+pub(crate) struct LastValues {
+    max_keys: usize,
+    clock: SharedClock,
+    entries: Mutex<HashMap<Arc<Key>, Entry>>,
+}
+
+impl LastValues {
+    pub(crate) fn new(max_keys: usize, clock: SharedClock) -> Self {
+        LastValues {
+            max_keys,
+            clock,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn update(&self, key: &Arc<Key>, new: impl FnOnce() -> Entry, update: impl FnOnce(&mut Entry)) {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let len = entries.len();
+        match entries.get_mut(key) {
+            Some(entry) => update(entry),
+            None if len < self.max_keys => {
+                let mut entry = new();
+                update(&mut entry);
+                entries.insert(key.clone(), entry);
+            }
+            None => {}
+        }
+    }
+
+    pub(crate) fn counter(&self, key: &Arc<Key>, value: u64) {
+        let now = self.clock.now();
+        self.update(
+            key,
+            || Entry::Counter {
+                total: 0,
+                window: CounterWindow::new(now),
+            },
+            |entry| {
+                if let Entry::Counter { total, window } = entry {
+                    *total += value;
+                    window.add(now, value);
+                }
+            },
+        );
+    }
+
+    pub(crate) fn gauge(&self, key: &Arc<Key>, value: f64) {
+        self.update(
+            key,
+            || Entry::Gauge(value),
+            |entry| *entry = Entry::Gauge(value),
+        );
+    }
+
+    pub(crate) fn histogram(&self, key: &Arc<Key>, value: f64) {
+        self.update(
+            key,
+            || Entry::Histogram(value),
+            |entry| *entry = Entry::Histogram(value),
+        );
+    }
+
+    /// All the tracked metrics sorted by name.
+    pub(crate) fn snapshot(&self) -> Vec<(Key, LastValue)> {
+        let now = self.clock.now();
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let mut snapshot: Vec<_> = entries
+            .iter()
+            .map(|(key, entry)| {
+                let value = match entry {
+                    Entry::Counter { total, window } => LastValue::Counter {
+                        total: *total,
+                        rate: window.rate(now),
+                    },
+                    Entry::Gauge(value) => LastValue::Gauge(*value),
+                    Entry::Histogram(value) => LastValue::Histogram(*value),
+                };
+                (Key::clone(key), value)
+            })
+            .collect();
+        snapshot.sort_by(|(a, _), (b, _)| a.name().cmp(b.name()));
+        snapshot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics::{Label, Recorder};
+
+    use super::*;
+    use crate::clock::SystemClock;
+    use crate::testing::METADATA;
+    use crate::{Clock, StatsdBuilder};
+
+    #[test]
+    fn top_emitters_by_name() {
+        let emits = [
+            ("requests", 50),
+            ("threads", 20),
+            ("requests", 30),
+            ("idle", 0),
+        ];
+        assert_eq!(
+            vec![
+                TopEmitter {
+                    name: "requests".to_string(),
+                    emits: 80,
+                    share: 0.8,
+                },
+                TopEmitter {
+                    name: "threads".to_string(),
+                    emits: 20,
+                    share: 0.2,
+                },
+            ],
+            top_emitters(emits, 3)
+        );
+        assert_eq!(1, top_emitters(emits, 1).len());
+        assert!(top_emitters([("idle", 0)], 3).is_empty());
+    }
+
+    #[test]
+    fn bounded() {
+        let last_values = LastValues::new(1, Arc::new(SystemClock));
+        let first = Arc::new(Key::from_name("first"));
+        last_values.gauge(&first, 1.0);
+        last_values.gauge(&Arc::new(Key::from_name("second")), 2.0);
+        last_values.gauge(&first, 3.0);
+
+        assert_eq!(
+            vec![(Key::from_name("first"), LastValue::Gauge(3.0))],
+            last_values.snapshot()
+        );
+    }
+
+    #[test]
+    fn counter_rate_over_a_full_window() {
+        let start = Instant::now();
+        let mut window = CounterWindow::new(start);
+        window.add(start, 10);
+        window.add(start + Duration::from_secs(5), 10);
+        window.add(start + RATE_WINDOW, 100);
+
+        assert_eq!(2.0, window.rate(start + RATE_WINDOW));
+    }
+
+    #[test]
+    fn last_values() {
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(cadence::NopMetricSink)
+            .with_last_values(10)
+            .build(None)
+            .expect("should build a recorder with custom sink");
+        let handle = recorder.handle();
+
+        let gauge_key = Key::from(("gauge.name", vec![Label::new("t1", "v1")]));
+        let gauge = recorder.register_gauge(&gauge_key, &METADATA);
+        gauge.set(1.0);
+        gauge.set(2.5);
+        let counter_key = Key::from_name("counter.name");
+        let counter = recorder.register_counter(&counter_key, &METADATA);
+        counter.increment(2);
+        counter.increment(3);
+
+        let snapshot = handle.snapshot();
+        assert_eq!(2, snapshot.len());
+        assert!(matches!(
+            &snapshot[0],
+            (key, LastValue::Counter { total: 5, .. }) if *key == counter_key
+        ));
+        assert_eq!((gauge_key, LastValue::Gauge(2.5)), snapshot[1]);
+    }
+
+    #[test]
+    fn active_keys() {
+        let clock = crate::testing::ManualClock::new();
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(cadence::NopMetricSink)
+            .with_clock(clock.clone())
+            .with_active_keys()
+            .build(Some("app"))
+            .expect("should build a recorder with custom sink");
+        let handle = recorder.handle();
+
+        let requests = recorder.register_counter(&Key::from_name("requests"), &METADATA);
+        requests.increment(1);
+        clock.advance(Duration::from_secs(5));
+        let sent = clock.now();
+        requests.increment(1);
+        let threads_key = Key::from_parts("threads", vec![Label::new("pool", "io")]);
+        let _threads = recorder.register_gauge(&threads_key, &METADATA);
+        clock.advance(Duration::from_secs(5));
+
+        assert_eq!(
+            vec![
+                ActiveKey {
+                    key: Key::from_name("requests"),
+                    name: "app.requests".to_string(),
+                    metric_type: MetricType::Counter,
+                    emits: 2,
+                    last_emit: Some(sent),
+                },
+                ActiveKey {
+                    key: threads_key,
+                    name: "app.threads".to_string(),
+                    metric_type: MetricType::Gauge,
+                    emits: 0,
+                    last_emit: None,
+                },
+            ],
+            handle.active_keys()
+        );
+    }
+
+    #[test]
+    fn top_emitters_of_a_recorder() {
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(cadence::NopMetricSink)
+            .with_top_emitters(1)
+            .build(Some("app"))
+            .expect("should build a recorder with custom sink");
+        for status in ["ok", "error", "ok"] {
+            let key = Key::from_parts("requests", vec![Label::new("status", status)]);
+            recorder.register_counter(&key, &METADATA).increment(1);
+        }
+        recorder
+            .register_gauge(&Key::from_name("threads"), &METADATA)
+            .set(4.0);
+
+        assert_eq!(
+            vec![TopEmitter {
+                name: "app.requests".to_string(),
+                emits: 3,
+                share: 0.75,
+            }],
+            recorder.handle().top_emitters()
+        );
+    }
+}
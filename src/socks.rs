@@ -0,0 +1,247 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const USERNAME_PASSWORD: u8 = 2;
+const CONNECT: u8 = 1;
+const DOMAIN_NAME: u8 = 3;
+const IPV4: u8 = 1;
+const IPV6: u8 = 4;
+
+/// How long connecting to the proxy, and every read and write of the handshake, may take by
+/// default, so that a proxy that went silent doesn't hold the queue worker forever.
+pub(crate) const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A SOCKS5 proxy that TCP connections go through, see
+/// [`StatsdBuilder::with_socks5_proxy`](crate::StatsdBuilder::with_socks5_proxy).
+#[derive(Clone, Debug)]
+pub(crate) struct Socks5Proxy {
+    pub(crate) host: String,
+    pub(crate) port: u16,
+    /// The username and password to authenticate with, as per RFC 1929.
+    pub(crate) auth: Option<(String, String)>,
+    /// How long connecting and every step of the handshake may take.
+    pub(crate) timeout: Duration,
+}
+
+impl Socks5Proxy {
+    /// Connect to `host` and `port` through the proxy. The host is resolved by the proxy, as the
+    /// name may only make sense on its side.
+    pub(crate) fn connect(&self, host: &str, port: u16) -> io::Result<TcpStream> {
+        let mut stream = self.connect_proxy()?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        self.handshake(&mut stream, host, port)?;
+        // the timeouts are for the handshake, metrics are written as over a direct connection.
+        stream.set_read_timeout(None)?;
+        stream.set_write_timeout(None)?;
+        Ok(stream)
+    }
+
+    /// Connect to the first address of the proxy that accepts the connection in time.
+    fn connect_proxy(&self) -> io::Result<TcpStream> {
+        let mut error = io::Error::new(io::ErrorKind::NotFound, "the proxy host has no address");
+        for addr in (self.host.as_str(), self.port).to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, self.timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => error = e,
+            }
+        }
+        Err(error)
+    }
+
+    fn handshake(&self, stream: &mut TcpStream, host: &str, port: u16) -> io::Result<()> {
+        self.authenticate(stream)?;
+
+        let host_len = u8::try_from(host.len())
+            .map_err(|_| invalid_input("the host is too long for SOCKS5"))?;
+        let mut request = vec![VERSION, CONNECT, 0, DOMAIN_NAME, host_len];
+        request.extend_from_slice(host.as_bytes());
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request)?;
+
+        let mut reply = [0; 4];
+        stream.read_exact(&mut reply)?;
+        if reply[0] != VERSION {
+            return Err(protocol_error("the proxy doesn't speak SOCKS5"));
+        }
+        if reply[1] != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("the proxy failed to connect: {}", reply_error(reply[1])),
+            ));
+        }
+        // the address the proxy bound to, which is of no use here.
+        let len = match reply[3] {
+            IPV4 => 4,
+            IPV6 => 16,
+            DOMAIN_NAME => {
+                let mut len = [0];
+                stream.read_exact(&mut len)?;
+                len[0] as usize
+            }
+            _ => return Err(protocol_error("unknown address type in the proxy reply")),
+        };
+        let mut bound = vec![0; len + 2];
+        stream.read_exact(&mut bound)?;
+        Ok(())
+    }
+
+    fn authenticate(&self, stream: &mut TcpStream) -> io::Result<()> {
+        let method = match self.auth {
+            Some(_) => USERNAME_PASSWORD,
+            None => NO_AUTH,
+        };
+        stream.write_all(&[VERSION, 1, method])?;
+        let mut reply = [0; 2];
+        stream.read_exact(&mut reply)?;
+        if reply[0] != VERSION {
+            return Err(protocol_error("the proxy doesn't speak SOCKS5"));
+        }
+        // the proxy answers 0xff when it accepts none of the methods.
+        if reply[1] != method {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "the proxy doesn't accept the authentication method",
+            ));
+        }
+
+        if let Some((username, password)) = &self.auth {
+            let username_len = u8::try_from(username.len())
+                .map_err(|_| invalid_input("the SOCKS5 username is too long"))?;
+            let password_len = u8::try_from(password.len())
+                .map_err(|_| invalid_input("the SOCKS5 password is too long"))?;
+            let mut request = vec![1, username_len];
+            request.extend_from_slice(username.as_bytes());
+            request.push(password_len);
+            request.extend_from_slice(password.as_bytes());
+            stream.write_all(&request)?;
+
+            stream.read_exact(&mut reply)?;
+            if reply[1] != 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "the proxy rejected the username and password",
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn reply_error(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+fn invalid_input(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+fn protocol_error(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+
+    fn read_vec(stream: &mut TcpStream, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        stream.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    /// Accepts a single client, checks its handshake and returns the line it sends.
+    fn serve(listener: TcpListener, accept_auth: bool) -> thread::JoinHandle<Option<String>> {
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            assert_eq!(vec![5, 1, 2], read_vec(&mut stream, 3));
+            stream.write_all(&[5, 2]).unwrap();
+            assert_eq!(vec![1, 4], read_vec(&mut stream, 2));
+            assert_eq!(b"user".to_vec(), read_vec(&mut stream, 4));
+            assert_eq!(vec![6], read_vec(&mut stream, 1));
+            assert_eq!(b"secret".to_vec(), read_vec(&mut stream, 6));
+            if !accept_auth {
+                stream.write_all(&[1, 1]).unwrap();
+                return None;
+            }
+            stream.write_all(&[1, 0]).unwrap();
+
+            assert_eq!(vec![5, 1, 0, 3, 12], read_vec(&mut stream, 5));
+            assert_eq!(b"statsd.local".to_vec(), read_vec(&mut stream, 12));
+            assert_eq!(8125u16.to_be_bytes().to_vec(), read_vec(&mut stream, 2));
+            stream.write_all(&[5, 0, 0, 1, 10, 0, 0, 1, 0, 80]).unwrap();
+
+            let mut line = String::new();
+            BufReader::new(stream).read_line(&mut line).unwrap();
+            Some(line)
+        })
+    }
+
+    fn proxy(listener: &TcpListener) -> Socks5Proxy {
+        Socks5Proxy {
+            host: "127.0.0.1".to_string(),
+            port: listener.local_addr().unwrap().port(),
+            auth: Some(("user".to_string(), "secret".to_string())),
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    #[test]
+    fn connects_through_the_proxy() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = proxy(&listener);
+        let server = serve(listener, true);
+
+        let mut stream = proxy.connect("statsd.local", 8125).unwrap();
+        stream.write_all(b"metric:1|c\n").unwrap();
+        assert_eq!(Some("metric:1|c\n".to_string()), server.join().unwrap());
+    }
+
+    #[test]
+    fn fails_when_rejected() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = proxy(&listener);
+        let server = serve(listener, false);
+
+        let error = proxy.connect("statsd.local", 8125).unwrap_err();
+        assert_eq!(io::ErrorKind::PermissionDenied, error.kind());
+        assert_eq!(None, server.join().unwrap());
+    }
+
+    #[test]
+    fn times_out_when_the_proxy_is_silent() {
+        // the connection is accepted by the kernel, nothing ever answers the greeting.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = Socks5Proxy {
+            timeout: Duration::from_millis(50),
+            ..proxy(&listener)
+        };
+
+        let error = proxy.connect("statsd.local", 8125).unwrap_err();
+        assert!(
+            matches!(
+                error.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ),
+            "{}",
+            error
+        );
+    }
+}
@@ -0,0 +1,200 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::types::MetricType;
+
+/// The reason a metric was dropped before it reached statsd.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum DropReason {
+    /// The [`QueuingMetricSink`](cadence::QueuingMetricSink) was at capacity when the metric was
+    /// submitted.
+    QueueFull,
+    /// The serialized metric was larger than what fits in a single datagram.
+    Oversize,
+    /// The sink failed to write the metric, e.g. because the socket returned an error.
+    SendError,
+    /// The metric was still waiting in the queue when the recorder was dropped, after the
+    /// [`StatsdBuilder::with_shutdown_timeout`](crate::StatsdBuilder::with_shutdown_timeout)
+    /// expired.
+    Abandoned,
+    /// The value was out of the bounds given to
+    /// [`StatsdBuilder::with_value_bounds`](crate::StatsdBuilder::with_value_bounds), or was
+    /// negative, see
+    /// [`StatsdBuilder::with_negative_values`](crate::StatsdBuilder::with_negative_values).
+    InvalidValue,
+    /// The queue was too full for the type of the metric, which is shed before the others, see
+    /// [`StatsdBuilder::with_queue_priority`](crate::StatsdBuilder::with_queue_priority).
+    Shed,
+    /// The metric waited in the queue for longer than the
+    /// [`StatsdBuilder::with_max_queue_age`](crate::StatsdBuilder::with_max_queue_age).
+    Stale,
+    /// The name, a tag or the value of the metric would have made a line the server can't parse,
+    /// see
+    /// [`StatsdBuilder::with_strict_validation`](crate::StatsdBuilder::with_strict_validation).
+    Malformed,
+    /// The name of the metric was first registered as another type, see
+    /// [`StatsdBuilder::with_strict_validation`](crate::StatsdBuilder::with_strict_validation).
+    TypeConflict,
+    /// The target the metric was registered from was over its quota, see
+    /// [`StatsdBuilder::with_target_quota`](crate::StatsdBuilder::with_target_quota).
+    OverQuota,
+}
+
+impl DropReason {
+    /// All the drop reasons, in the order they are reported by [`DroppedMetrics::iter`].
+    pub const ALL: [DropReason; 10] = [
+        DropReason::QueueFull,
+        DropReason::Oversize,
+        DropReason::SendError,
+        DropReason::Abandoned,
+        DropReason::InvalidValue,
+        DropReason::Shed,
+        DropReason::Stale,
+        DropReason::Malformed,
+        DropReason::TypeConflict,
+        DropReason::OverQuota,
+    ];
+
+    /// A short, stable name for this reason that is suitable for use as a tag value.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DropReason::QueueFull => "queue_full",
+            DropReason::Oversize => "oversize",
+            DropReason::SendError => "send_error",
+            DropReason::Abandoned => "abandoned",
+            DropReason::InvalidValue => "invalid_value",
+            DropReason::Shed => "shed",
+            DropReason::Stale => "stale",
+            DropReason::Malformed => "malformed",
+            DropReason::TypeConflict => "type_conflict",
+            DropReason::OverQuota => "over_quota",
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl fmt::Display for DropReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A point in time copy of the number of metrics dropped by a recorder, broken down by
+/// [`DropReason`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DroppedMetrics {
+    counts: [u64; DropReason::ALL.len()],
+}
+
+impl DroppedMetrics {
+    /// Number of metrics dropped for the given reason.
+    pub fn get(&self, reason: DropReason) -> u64 {
+        self.counts[reason.index()]
+    }
+
+    /// Number of metrics dropped for any reason.
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Iterate over every reason along with its count, including the ones that are zero.
+    pub fn iter(&self) -> impl Iterator<Item = (DropReason, u64)> + '_ {
+        DropReason::ALL.iter().map(|r| (*r, self.get(*r)))
+    }
+}
+
+/// Counters shared between the recorder, its handles and the sinks it writes to.
+#[derive(Debug, Default)]
+pub(crate) struct Stats {
+    dropped: [AtomicU64; DropReason::ALL.len()],
+    emitted: [AtomicU64; MetricType::ALL.len()],
+    dropped_tags: AtomicU64,
+    worker_restarts: AtomicU64,
+    /// The last error a sink returned, for the logs.
+    last_error: Mutex<Option<String>>,
+}
+
+impl Stats {
+    pub(crate) fn record_emit(&self, metric_type: MetricType) {
+        self.emitted[metric_type.index()].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn emitted(&self, metric_type: MetricType) -> u64 {
+        self.emitted[metric_type.index()].load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_drop(&self, reason: DropReason) {
+        self.record_drops(reason, 1);
+    }
+
+    pub(crate) fn record_drops(&self, reason: DropReason, count: u64) {
+        self.dropped[reason.index()].fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_dropped_tags(&self, count: u64) {
+        self.dropped_tags.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub(crate) fn dropped_tags(&self) -> u64 {
+        self.dropped_tags.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_worker_restarts(&self, count: u64) {
+        self.worker_restarts.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub(crate) fn worker_restarts(&self) -> u64 {
+        self.worker_restarts.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_error(&self, error: &dyn fmt::Display) {
+        *self.last_error.lock().unwrap_or_else(|e| e.into_inner()) = Some(error.to_string());
+    }
+
+    /// The last error recorded since the last call, if any.
+    pub(crate) fn take_error(&self) -> Option<String> {
+        self.last_error
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take()
+    }
+
+    pub(crate) fn dropped(&self) -> DroppedMetrics {
+        let mut counts = [0; DropReason::ALL.len()];
+        for (count, dropped) in counts.iter_mut().zip(self.dropped.iter()) {
+            *count = dropped.load(Ordering::Relaxed);
+        }
+        DroppedMetrics { counts }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reasons_are_indexed_in_order() {
+        for (i, reason) in DropReason::ALL.iter().enumerate() {
+            assert_eq!(i, reason.index());
+        }
+    }
+
+    #[test]
+    fn counts_by_reason() {
+        let stats = Stats::default();
+        stats.record_drop(DropReason::QueueFull);
+        stats.record_drop(DropReason::QueueFull);
+        stats.record_drop(DropReason::SendError);
+
+        let dropped = stats.dropped();
+        assert_eq!(2, dropped.get(DropReason::QueueFull));
+        assert_eq!(0, dropped.get(DropReason::Oversize));
+        assert_eq!(1, dropped.get(DropReason::SendError));
+        assert_eq!(3, dropped.total());
+    }
+}
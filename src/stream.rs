@@ -0,0 +1,448 @@
+use std::io::{self, Write};
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use cadence::{MetricSink, SinkStats};
+
+use crate::sampling;
+use crate::sink::SentStats;
+use crate::socks::Socks5Proxy;
+use crate::upkeep::Upkeep;
+
+/// Default delay before the first attempt to reconnect, see [`Backoff`].
+pub(crate) const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+/// Default longest delay between two attempts to reconnect, see [`Backoff`].
+pub(crate) const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// The stream transport picked on the builder, see
+/// [`StatsdBuilder::with_tcp`](crate::StatsdBuilder::with_tcp).
+#[derive(Clone, Debug)]
+pub(crate) enum StreamTransport {
+    /// TCP to the host and port of the builder, as they are when the recorder is built.
+    Tcp,
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl StreamTransport {
+    /// Where to connect to, `host` and `port` being those of the builder.
+    pub(crate) fn addr(&self, host: &str, port: u16) -> StreamAddr {
+        match self {
+            StreamTransport::Tcp => StreamAddr::Tcp(host.to_string(), port),
+            #[cfg(unix)]
+            StreamTransport::Unix(path) => StreamAddr::Unix(path.clone()),
+        }
+    }
+
+    /// Whether this connects to the host and port given to the builder.
+    pub(crate) fn is_tcp(&self) -> bool {
+        matches!(self, StreamTransport::Tcp)
+    }
+}
+
+/// Where a stream transport connects to.
+#[derive(Clone, Debug)]
+pub(crate) enum StreamAddr {
+    Tcp(String, u16),
+    /// TCP through a SOCKS5 proxy.
+    Socks5(String, u16, Socks5Proxy),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl StreamAddr {
+    /// Name of the transport, as reported by the client telemetry.
+    pub(crate) fn transport(&self) -> &'static str {
+        match self {
+            StreamAddr::Tcp(..) | StreamAddr::Socks5(..) => "tcp",
+            #[cfg(unix)]
+            StreamAddr::Unix(_) => "uds-stream",
+        }
+    }
+
+    /// Go through `proxy` when connecting over TCP.
+    pub(crate) fn with_proxy(self, proxy: Socks5Proxy) -> Self {
+        match self {
+            StreamAddr::Tcp(host, port) => StreamAddr::Socks5(host, port, proxy),
+            addr => addr,
+        }
+    }
+
+    fn connect(&self) -> io::Result<Box<dyn Write + Send>> {
+        Ok(match self {
+            StreamAddr::Tcp(host, port) => Box::new(TcpStream::connect((host.as_str(), *port))?),
+            StreamAddr::Socks5(host, port, proxy) => Box::new(proxy.connect(host, *port)?),
+            #[cfg(unix)]
+            StreamAddr::Unix(path) => Box::new(connect_unix(path)?),
+        })
+    }
+}
+
+/// Connect to the Unix socket at `path`, which is an address in the abstract namespace when it
+/// starts with a NUL byte, e.g. `\0dogstatsd`.
+#[cfg(target_os = "linux")]
+fn connect_unix(path: &Path) -> io::Result<UnixStream> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::SocketAddr;
+
+    match path.as_os_str().as_bytes() {
+        [0, name @ ..] => UnixStream::connect_addr(&SocketAddr::from_abstract_name(name)?),
+        _ => UnixStream::connect(path),
+    }
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn connect_unix(path: &Path) -> io::Result<UnixStream> {
+    UnixStream::connect(path)
+}
+
+/// Exponentially growing delays, with jitter so that clients that lost their connection at the
+/// same time don't all reconnect at the same time.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Backoff {
+    initial: Duration,
+    max: Duration,
+    next: Duration,
+}
+
+impl Backoff {
+    pub(crate) fn new(initial: Duration, max: Duration) -> Self {
+        Backoff {
+            initial,
+            max,
+            next: initial,
+        }
+    }
+
+    /// The delay before the next attempt, between half and all of the current backoff, which
+    /// doubles up to the maximum.
+    fn delay(&mut self) -> Duration {
+        let backoff = self.next;
+        self.next = (self.next * 2).min(self.max);
+        backoff.mul_f64(0.5 + sampling::random() / 2.0)
+    }
+
+    fn reset(&mut self) {
+        self.next = self.initial;
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff::new(DEFAULT_INITIAL_BACKOFF, DEFAULT_MAX_BACKOFF)
+    }
+}
+
+struct Connection {
+    stream: Option<Box<dyn Write + Send>>,
+    /// The lines waiting to be written, newline terminated.
+    pending: Vec<u8>,
+    backoff: Backoff,
+    /// When to reconnect once the connection is lost.
+    retry_at: Option<Instant>,
+}
+
+impl Connection {
+    /// Write the pending lines, connecting first when there's no connection and it's time to
+    /// reconnect. The lines that weren't written in full are kept for the next attempt.
+    fn write_pending(&mut self, addr: &StreamAddr, sent: &SentStats) -> io::Result<()> {
+        if self
+            .retry_at
+            .is_some_and(|retry_at| Instant::now() < retry_at)
+        {
+            return Err(io::ErrorKind::NotConnected.into());
+        }
+        let stream = match &mut self.stream {
+            Some(stream) => stream,
+            None => match addr.connect() {
+                Ok(stream) => self.stream.insert(stream),
+                Err(e) => return Err(self.lost(e)),
+            },
+        };
+        let mut written = 0;
+        let result = loop {
+            if written == self.pending.len() {
+                break stream.flush();
+            }
+            match stream.write(&self.pending[written..]) {
+                Ok(0) => break Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => written += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => break Err(e),
+            }
+        };
+        let lines = |written: &[u8]| written.iter().filter(|&&byte| byte == b'\n').count() as u64;
+        match result {
+            Ok(()) => {
+                sent.sent(self.pending.len(), lines(&self.pending));
+                self.pending.clear();
+                self.backoff.reset();
+                Ok(())
+            }
+            Err(e) => {
+                // whatever part of a line was written is lost with the connection, the whole line
+                // is written again once reconnected.
+                let whole_lines = self.pending[..written]
+                    .iter()
+                    .rposition(|&byte| byte == b'\n')
+                    .map_or(0, |newline| newline + 1);
+                sent.sent(whole_lines, lines(&self.pending[..whole_lines]));
+                self.pending.drain(..whole_lines);
+                Err(self.lost(e))
+            }
+        }
+    }
+
+    fn lost(&mut self, e: io::Error) -> io::Error {
+        self.stream = None;
+        self.retry_at = Some(Instant::now() + self.backoff.delay());
+        e
+    }
+
+    /// How long until it's time to reconnect.
+    fn retry_in(&self) -> Duration {
+        self.retry_at.map_or(Duration::ZERO, |retry_at| {
+            retry_at.saturating_duration_since(Instant::now())
+        })
+    }
+}
+
+/// A [`MetricSink`] writing newline terminated lines to a TCP or Unix stream socket.
+///
+/// Lines are buffered up to the buffer size, and written once the buffer is full or when the sink
+/// is flushed, see [`StreamFlusher`]. The connection is made on the first write. Once it's lost,
+/// writing a full buffer blocks while reconnecting with a [`Backoff`], and is retried until it
+/// succeeds. This is meant to run on the thread of a queue, so that metrics keep piling up in the
+/// queue, up to its capacity, until the connection is back.
+pub(crate) struct StreamSink {
+    addr: StreamAddr,
+    buffer_size: usize,
+    connection: Mutex<Connection>,
+    /// The bytes and lines written, a line counts as a packet.
+    sent: SentStats,
+}
+
+impl StreamSink {
+    pub(crate) fn new(addr: StreamAddr, buffer_size: usize, backoff: Backoff) -> Self {
+        StreamSink {
+            addr,
+            buffer_size,
+            connection: Mutex::new(Connection {
+                stream: None,
+                pending: Vec::with_capacity(buffer_size),
+                backoff,
+                retry_at: None,
+            }),
+            sent: SentStats::default(),
+        }
+    }
+
+    fn connection(&self) -> MutexGuard<'_, Connection> {
+        self.connection.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl MetricSink for StreamSink {
+    fn emit(&self, metric: &str) -> io::Result<usize> {
+        let mut connection = self.connection();
+        connection.pending.extend_from_slice(metric.as_bytes());
+        connection.pending.push(b'\n');
+        while connection.pending.len() >= self.buffer_size {
+            if connection.write_pending(&self.addr, &self.sent).is_err() {
+                // the flusher can tell it's not time to reconnect yet meanwhile.
+                let retry_in = connection.retry_in();
+                drop(connection);
+                thread::sleep(retry_in);
+                connection = self.connection();
+            }
+        }
+        Ok(metric.len())
+    }
+
+    /// Write the pending lines, unless the connection is lost and it's not time to reconnect yet.
+    fn flush(&self) -> io::Result<()> {
+        let mut connection = self.connection();
+        if connection.pending.is_empty() {
+            return Ok(());
+        }
+        connection.write_pending(&self.addr, &self.sent)
+    }
+
+    fn stats(&self) -> SinkStats {
+        self.sent.get()
+    }
+}
+
+/// The lines still buffered are written once more, e.g. as the thread of the queue stops.
+impl Drop for StreamSink {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+/// Writes the lines buffered by a [`StreamSink`] on an interval, until the sink goes away.
+pub(crate) struct StreamFlusher {
+    sink: Weak<StreamSink>,
+}
+
+impl StreamFlusher {
+    pub(crate) fn new(sink: &Arc<StreamSink>) -> Self {
+        StreamFlusher {
+            sink: Arc::downgrade(sink),
+        }
+    }
+
+    pub(crate) fn schedule(self, upkeep: &mut Upkeep, interval: Duration) {
+        upkeep.every(interval, move || match self.sink.upgrade() {
+            Some(sink) => {
+                let _ = sink.flush();
+                true
+            }
+            None => false,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics::{Key, Recorder};
+
+    use super::*;
+    use crate::testing::METADATA;
+    use crate::StatsdBuilder;
+
+    #[test]
+    fn backoff_grows_with_jitter() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_millis(300));
+        let delays: Vec<_> = (0..4).map(|_| backoff.delay()).collect();
+        let bounds = [100, 200, 300, 300].map(Duration::from_millis);
+        for (delay, bound) in delays.into_iter().zip(bounds) {
+            assert!(delay >= bound / 2 && delay <= bound, "{:?}", delay);
+        }
+
+        backoff.reset();
+        assert!(backoff.delay() <= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn buffers_until_flushed() {
+        use std::io::{BufRead, BufReader};
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let addr = StreamAddr::Tcp("127.0.0.1".to_string(), port);
+        let sink = StreamSink::new(addr, 64, Backoff::default());
+        sink.emit("a:1|c").unwrap();
+        sink.emit("b:1|c").unwrap();
+
+        // nothing is written, nor connected, before the buffer is full or flushed.
+        listener.set_nonblocking(true).unwrap();
+        assert_eq!(
+            io::ErrorKind::WouldBlock,
+            listener.accept().unwrap_err().kind()
+        );
+        sink.flush().unwrap();
+        listener.set_nonblocking(false).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        assert_eq!("a:1|c", lines.next().unwrap().unwrap());
+        assert_eq!("b:1|c", lines.next().unwrap().unwrap());
+        let sent = sink.stats();
+        assert_eq!((12, 2), (sent.bytes_sent, sent.packets_sent));
+    }
+
+    #[test]
+    fn tcp_reconnects() {
+        use std::io::{BufRead, BufReader};
+        use std::net::{TcpListener, TcpStream};
+
+        fn accept(listener: &TcpListener) -> BufReader<TcpStream> {
+            let (stream, _) = listener.accept().expect("should accept a connection");
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .expect("should set a read timeout");
+            BufReader::new(stream)
+        }
+
+        fn read_line(stream: &mut BufReader<TcpStream>) -> String {
+            let mut line = String::new();
+            stream.read_line(&mut line).expect("should read a line");
+            line
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").expect("should bind a listener");
+        let port = listener.local_addr().unwrap().port();
+        // the address is the one the builder has when the recorder is built.
+        let recorder = StatsdBuilder::from("127.0.0.1", 1)
+            .with_tcp()
+            .with_host_and_port("127.0.0.1", port)
+            .with_reconnect_backoff(Duration::from_millis(1), Duration::from_millis(10))
+            .build(None)
+            .expect("should build a recorder over tcp");
+        let counter = recorder.register_counter(&Key::from_name("counter.name"), &METADATA);
+
+        counter.increment(1);
+        let mut stream = accept(&listener);
+        assert_eq!("counter.name:1|c\n", read_line(&mut stream));
+
+        // the first writes after the peer went away may still succeed, keep writing until the
+        // exporter notices and reconnects.
+        drop(stream);
+        listener
+            .set_nonblocking(true)
+            .expect("should make the listener non blocking");
+        let mut stream = loop {
+            counter.increment(2);
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    stream
+                        .set_nonblocking(false)
+                        .expect("should make the stream blocking");
+                    stream
+                        .set_read_timeout(Some(Duration::from_secs(5)))
+                        .expect("should set a read timeout");
+                    break BufReader::new(stream);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(Duration::from_millis(10))
+                }
+                Err(e) => panic!("{}", e),
+            }
+        };
+        assert_eq!("counter.name:2|c\n", read_line(&mut stream));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn abstract_unix_stream() {
+        use std::io::{BufRead, BufReader};
+        use std::os::linux::net::SocketAddrExt;
+        use std::os::unix::net::{SocketAddr, UnixListener};
+
+        let name = format!("statsd-test-{}", std::process::id());
+        let addr = SocketAddr::from_abstract_name(&name).expect("should make an abstract address");
+        let listener = UnixListener::bind_addr(&addr).expect("should bind a listener");
+        let recorder = StatsdBuilder::from("", 0)
+            .with_unix_stream(format!("\0{}", name))
+            .build(None)
+            .expect("should build a recorder over a unix stream");
+
+        let counter = recorder.register_counter(&Key::from_name("counter.name"), &METADATA);
+        counter.increment(1);
+
+        let (stream, _) = listener.accept().expect("should accept a connection");
+        let mut line = String::new();
+        BufReader::new(stream)
+            .read_line(&mut line)
+            .expect("should read a line");
+        assert_eq!("counter.name:1|c\n", line);
+    }
+}
@@ -0,0 +1,82 @@
+//! What makes a line that a DogStatsD server can parse, see
+//! [`StatsdBuilder::with_strict_validation`](crate::StatsdBuilder::with_strict_validation).
+
+/// Whether `name`, prefix included, can be the name of a metric: it ends at the first `:` and
+/// the fields of the line are separated by `|`.
+pub(crate) fn valid_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains([':', '|', '@', '\n', '\r'])
+}
+
+/// Whether `key:value`, or `key` when `value` is empty, can be a tag: tags are separated by `,`
+/// and the key ends at the first `:`.
+pub(crate) fn valid_tag(key: &str, value: &str) -> bool {
+    !key.is_empty()
+        && !key.contains([':', ',', '|', '#', '\n', '\r'])
+        && !value.contains([',', '|', '\n', '\r'])
+}
+
+/// Whether a number can be sent, `NaN` and the infinities aren't numbers to statsd.
+pub(crate) fn valid_number(value: f64) -> bool {
+    value.is_finite()
+}
+
+/// Whether the member of a set can be sent, it's the value field of the line.
+pub(crate) fn valid_member(member: &str) -> bool {
+    !member.is_empty() && !member.contains(['|', '\n', '\r'])
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics::{Key, Label, Recorder};
+
+    use super::*;
+    use crate::testing::METADATA;
+    use crate::{DropReason, StatsdBuilder};
+
+    #[test]
+    fn rejects_what_breaks_the_line() {
+        assert!(valid_name("app.requests_total"));
+        assert!(!valid_name(""));
+        assert!(!valid_name("requests:total"));
+        assert!(!valid_name("requests|c"));
+
+        assert!(valid_tag("path", "/users:id"));
+        assert!(valid_tag("canary", ""));
+        assert!(!valid_tag("", "v"));
+        assert!(!valid_tag("a:b", "v"));
+        assert!(!valid_tag("path", "a,b"));
+        assert!(!valid_tag("path", "a|b"));
+
+        assert!(valid_number(-1.5));
+        assert!(!valid_number(f64::NAN));
+        assert!(!valid_number(f64::INFINITY));
+
+        assert!(valid_member("user-42"));
+        assert!(!valid_member("user|42"));
+    }
+
+    #[test]
+    fn strict_validation() {
+        let sink = crate::testing::FakeSink::new();
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(sink.clone())
+            .with_strict_validation()
+            .build(Some("app"))
+            .expect("should build a recorder with custom sink");
+        let handle = recorder.handle();
+        recorder
+            .register_counter(&Key::from_name("requests:total"), &METADATA)
+            .increment(1);
+        let labels = vec![Label::new("path", "/a,/b")];
+        recorder
+            .register_counter(&Key::from_parts("requests", labels), &METADATA)
+            .increment(1);
+        let gauge = recorder.register_gauge(&Key::from_name("ratio"), &METADATA);
+        gauge.set(f64::NAN);
+        gauge.set(0.5);
+        crate::StatsdExt::record_set_member(&recorder, &Key::from_name("users"), "a|b");
+
+        assert_eq!(vec!["app.ratio:0.5|g"], sink.lines());
+        assert_eq!(4, handle.dropped_metrics().get(DropReason::Malformed));
+    }
+}
@@ -0,0 +1,220 @@
+use std::sync::Mutex;
+
+use crate::line::RenderedKey;
+use crate::mapping::Glob;
+
+/// Quantiles sent for every histogram summarized on the client unless configured otherwise.
+pub(crate) const DEFAULT_QUANTILES: [f64; 3] = [0.5, 0.95, 0.99];
+
+/// How the gauges of the quantiles of a summarized histogram are named, see
+/// [`StatsdBuilder::with_percentiles`](crate::StatsdBuilder::with_percentiles).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PercentileNaming {
+    /// `name.p50`, `name.p95`, `name.p999` for the 0.5, 0.95 and 0.999 quantiles.
+    #[default]
+    Short,
+    /// `name.50percentile`, `name.95percentile`, `name.99_9percentile`, as statsd names the
+    /// percentiles of its timers.
+    Long,
+}
+
+impl PercentileNaming {
+    /// The suffix of the gauge of `quantile`, dot included.
+    pub(crate) fn suffix(self, quantile: f64) -> String {
+        // rounding keeps e.g. 0.999 from showing up as 99.89999999999999.
+        let percentile = ((quantile * 1e6).round() / 1e4).to_string();
+        match self {
+            PercentileNaming::Short => format!(".p{}", percentile.replace('.', "")),
+            PercentileNaming::Long => format!(".{}percentile", percentile.replace('.', "_")),
+        }
+    }
+}
+
+/// The quantiles sent for the histograms summarized on the client, for every histogram or for
+/// the names matching a pattern.
+#[derive(Clone, Debug)]
+pub(crate) struct Percentiles {
+    pub(crate) default: Vec<f64>,
+    pub(crate) naming: PercentileNaming,
+    /// The quantiles of the names matching a pattern, the first matching one wins.
+    names: Vec<(Glob, Vec<f64>)>,
+    /// The patterns that aren't valid, along with why, reported when building the recorder.
+    pub(crate) invalid_patterns: Vec<(String, String)>,
+}
+
+impl Default for Percentiles {
+    fn default() -> Self {
+        Percentiles {
+            default: DEFAULT_QUANTILES.to_vec(),
+            naming: PercentileNaming::default(),
+            names: Vec::new(),
+            invalid_patterns: Vec::new(),
+        }
+    }
+}
+
+impl Percentiles {
+    /// Whether every quantile is in `[0, 1]`.
+    pub(crate) fn is_valid(&self) -> bool {
+        self.default
+            .iter()
+            .chain(self.names.iter().flat_map(|(_, quantiles)| quantiles))
+            .all(|quantile| (0.0..=1.0).contains(quantile))
+    }
+
+    pub(crate) fn set_name(&mut self, pattern: &str, quantiles: &[f64]) {
+        match Glob::new(pattern) {
+            Ok(glob) => self.names.push((glob, quantiles.to_vec())),
+            Err(reason) => self.invalid_patterns.push((pattern.to_string(), reason)),
+        }
+    }
+
+    /// The quantiles sent for `name`.
+    pub(crate) fn quantiles(&self, name: &str) -> &[f64] {
+        self.names
+            .iter()
+            .find(|(glob, _)| glob.matches(name))
+            .map_or(&self.default, |(_, quantiles)| quantiles)
+    }
+}
+
+/// The values a histogram recorded since its quantiles were last sent.
+#[derive(Debug)]
+pub(crate) struct Summary {
+    /// The gauge of every quantile.
+    quantiles: Vec<(f64, RenderedKey)>,
+    values: Mutex<Vec<f64>>,
+}
+
+impl Summary {
+    pub(crate) fn new(quantiles: Vec<(f64, RenderedKey)>) -> Self {
+        Summary {
+            quantiles,
+            values: Mutex::default(),
+        }
+    }
+
+    pub(crate) fn add(&self, value: f64) {
+        self.values
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(value);
+    }
+
+    /// Hand every quantile of the values recorded since the last call to `f`, along with its gauge,
+    /// nothing when no value was recorded.
+    pub(crate) fn take(&self, mut f: impl FnMut(&RenderedKey, f64)) {
+        let mut values =
+            std::mem::take(&mut *self.values.lock().unwrap_or_else(|e| e.into_inner()));
+        if values.is_empty() {
+            return;
+        }
+        values.sort_by(f64::total_cmp);
+        for (quantile, rendered) in &self.quantiles {
+            f(rendered, nearest_rank(&values, *quantile));
+        }
+    }
+}
+
+/// The smallest of the `sorted` values that at least a `quantile` fraction of them are at or
+/// below.
+fn nearest_rank(sorted: &[f64], quantile: f64) -> f64 {
+    let rank = (quantile * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use metrics::{Key, Recorder};
+
+    use super::*;
+    use crate::testing::METADATA;
+    use crate::{StatsdBuilder, StatsdError};
+
+    #[test]
+    fn names_percentiles() {
+        let short = PercentileNaming::Short;
+        assert_eq!(".p50", short.suffix(0.5));
+        assert_eq!(".p999", short.suffix(0.999));
+        assert_eq!(".p100", short.suffix(1.0));
+        let long = PercentileNaming::Long;
+        assert_eq!(".95percentile", long.suffix(0.95));
+        assert_eq!(".99_9percentile", long.suffix(0.999));
+    }
+
+    #[test]
+    fn picks_nearest_rank() {
+        let values: Vec<f64> = (1..=100).map(f64::from).collect();
+        assert_eq!(50.0, nearest_rank(&values, 0.5));
+        assert_eq!(95.0, nearest_rank(&values, 0.95));
+        assert_eq!(100.0, nearest_rank(&values, 0.999));
+        assert_eq!(1.0, nearest_rank(&values, 0.0));
+        assert_eq!(7.0, nearest_rank(&[7.0], 0.5));
+    }
+
+    #[test]
+    fn quantiles_by_name() {
+        let mut percentiles = Percentiles::default();
+        percentiles.set_name("http.*.duration", &[0.999]);
+        percentiles.set_name("a.**", &[0.5]);
+        assert_eq!(&[0.999], percentiles.quantiles("http.get.duration"));
+        assert_eq!(&DEFAULT_QUANTILES, percentiles.quantiles("db.duration"));
+        assert_eq!(1, percentiles.invalid_patterns.len());
+        assert!(percentiles.is_valid());
+        percentiles.default.push(1.5);
+        assert!(!percentiles.is_valid());
+    }
+
+    #[test]
+    fn local_summaries() {
+        let clock = crate::testing::ManualClock::new();
+        let sink = crate::testing::FakeSink::new();
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(sink.clone())
+            .with_clock(clock.clone())
+            .with_local_summaries(Duration::from_secs(10))
+            .with_percentiles_for("db.*", &[0.999])
+            .build(None)
+            .expect("should build a recorder with custom sink");
+        let latency = recorder.register_histogram(&Key::from_name("latency"), &METADATA);
+        let query = recorder.register_histogram(&Key::from_name("db.query"), &METADATA);
+        for value in 1..=100 {
+            latency.record(f64::from(value));
+            query.record(f64::from(value));
+        }
+        recorder
+            .register_histogram(&Key::from_name("idle"), &METADATA)
+            .record(1.0);
+        recorder
+            .register_histogram(&Key::from_name("idle"), &METADATA)
+            .record(3.0);
+
+        clock.advance(Duration::from_secs(10));
+        recorder.shared.run_pending();
+        let mut lines = sink.lines();
+        lines.sort();
+        assert_eq!(
+            vec![
+                "db.query.p999:100|g",
+                "idle.p50:1|g",
+                "idle.p95:3|g",
+                "idle.p99:3|g",
+                "latency.p50:50|g",
+                "latency.p95:95|g",
+                "latency.p99:99|g",
+            ],
+            lines
+        );
+    }
+
+    #[test]
+    fn invalid_quantiles() {
+        let result = StatsdBuilder::from("127.0.0.1", 8125)
+            .with_local_summaries(Duration::from_secs(10))
+            .with_percentiles(&[0.5, 99.0], PercentileNaming::Short)
+            .build(None);
+        assert!(matches!(result, Err(StatsdError::InvalidQuantile)));
+    }
+}
@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use crate::StatsdRecorder;
+
+/// Prefixes the metrics with a segment derived from their target, see
+/// [`StatsdBuilder::with_target_prefixes`](crate::StatsdBuilder::with_target_prefixes).
+///
+/// The metrics of each segment are registered with a recorder scoped to it, so that the same key
+/// registered from two crates makes two metrics.
+#[derive(Default)]
+pub(crate) struct TargetPrefixes {
+    /// The segments of the targets given to
+    /// [`StatsdBuilder::with_target_prefix`](crate::StatsdBuilder::with_target_prefix).
+    mapped: Vec<(String, String)>,
+    /// The recorders scoped to each segment, made as they're needed.
+    scopes: RwLock<HashMap<String, StatsdRecorder>>,
+}
+
+impl TargetPrefixes {
+    pub(crate) fn new(mapped: Vec<(String, String)>) -> Self {
+        TargetPrefixes {
+            mapped,
+            scopes: RwLock::default(),
+        }
+    }
+
+    /// The same mapping, for a recorder scoped from the one these prefixes belong to.
+    pub(crate) fn fresh(&self) -> Self {
+        TargetPrefixes::new(self.mapped.clone())
+    }
+
+    /// The prefix segment of the metrics of `target`: the one of the longest mapped target that
+    /// is `target` or contains it, the crate of `target` otherwise. Empty when there's none.
+    fn segment<'a>(&'a self, target: &'a str) -> &'a str {
+        self.mapped
+            .iter()
+            .filter(|(mapped, _)| within(target, mapped))
+            .max_by_key(|(mapped, _)| mapped.len())
+            .map(|(_, segment)| segment.as_str())
+            .unwrap_or_else(|| target.split("::").next().unwrap_or_default())
+    }
+
+    /// The recorder that registers the metrics of `target`, `None` for the recorder these
+    /// prefixes belong to, when there's no segment. `scope` makes a recorder scoped to a segment.
+    pub(crate) fn scope(
+        &self,
+        target: &str,
+        scope: impl FnOnce(&str) -> StatsdRecorder,
+    ) -> Option<StatsdRecorder> {
+        let segment = self.segment(target);
+        if segment.is_empty() {
+            return None;
+        }
+        if let Some(recorder) = self
+            .scopes
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(segment)
+        {
+            return Some(recorder.clone());
+        }
+        let mut scopes = self.scopes.write().unwrap_or_else(|e| e.into_inner());
+        let recorder = scopes
+            .entry(segment.to_string())
+            .or_insert_with(|| scope(segment));
+        Some(recorder.clone())
+    }
+}
+
+/// Whether `target` is `mapped` or a module within it.
+pub(crate) fn within(target: &str, mapped: &str) -> bool {
+    target
+        .strip_prefix(mapped)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics::{Key, Recorder};
+
+    use super::*;
+    use crate::StatsdBuilder;
+
+    #[test]
+    fn picks_the_longest_mapped_target() {
+        let prefixes = TargetPrefixes::new(vec![
+            ("hyper".to_string(), "deps.hyper".to_string()),
+            ("app".to_string(), String::new()),
+            ("app::db".to_string(), "db".to_string()),
+        ]);
+        assert_eq!("deps.hyper", prefixes.segment("hyper::client::pool"));
+        assert_eq!("", prefixes.segment("app::http"));
+        assert_eq!("db", prefixes.segment("app::db::pool"));
+        assert_eq!("tokio", prefixes.segment("tokio::runtime"));
+        assert_eq!("hyper_util", prefixes.segment("hyper_util"));
+    }
+
+    #[test]
+    fn target_prefixes() {
+        let sink = crate::testing::FakeSink::new();
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(sink.clone())
+            .with_target_prefix("hyper", "deps.hyper")
+            .with_target_prefix("app", "")
+            .build(Some("svc"))
+            .expect("should build a recorder with custom sink");
+        let key = Key::from_name("connections");
+        let metadata = |target| metrics::Metadata::new(target, metrics::Level::INFO, None);
+        for target in [
+            "hyper::client::pool",
+            "tokio::runtime",
+            "app::http",
+            "hyper",
+        ] {
+            recorder.register_gauge(&key, &metadata(target)).set(1.0);
+        }
+        recorder
+            .scoped("db", std::iter::empty::<(&str, &str)>())
+            .register_gauge(&key, &metadata("sqlx::pool"))
+            .set(2.0);
+
+        assert_eq!(
+            vec![
+                "svc.deps.hyper.connections:1|g",
+                "svc.tokio.connections:1|g",
+                "svc.connections:1|g",
+                "svc.deps.hyper.connections:1|g",
+                "svc.db.sqlx.connections:2|g",
+            ],
+            sink.lines()
+        );
+    }
+}
@@ -0,0 +1,92 @@
+use std::sync::Arc;
+
+use metrics::{Counter, CounterFn, Gauge, GaugeFn, Histogram, HistogramFn, Recorder};
+
+/// The recorder given to [`StatsdBuilder::tee`](crate::StatsdBuilder::tee).
+pub(crate) type SharedRecorder = Arc<dyn Recorder + Send + Sync>;
+
+/// A metric registered with both the statsd recorder and the recorder given to
+/// [`StatsdBuilder::tee`](crate::StatsdBuilder::tee), every update goes to both of them.
+pub(crate) struct Tee<T> {
+    statsd: T,
+    other: T,
+}
+
+impl<T> Tee<T> {
+    pub(crate) fn new(statsd: T, other: T) -> Self {
+        Tee { statsd, other }
+    }
+}
+
+impl CounterFn for Tee<Counter> {
+    fn increment(&self, value: u64) {
+        self.statsd.increment(value);
+        self.other.increment(value);
+    }
+
+    fn absolute(&self, value: u64) {
+        self.statsd.absolute(value);
+        self.other.absolute(value);
+    }
+}
+
+impl GaugeFn for Tee<Gauge> {
+    fn increment(&self, value: f64) {
+        self.statsd.increment(value);
+        self.other.increment(value);
+    }
+
+    fn decrement(&self, value: f64) {
+        self.statsd.decrement(value);
+        self.other.decrement(value);
+    }
+
+    fn set(&self, value: f64) {
+        self.statsd.set(value);
+        self.other.set(value);
+    }
+}
+
+impl HistogramFn for Tee<Histogram> {
+    fn record(&self, value: f64) {
+        self.statsd.record(value);
+        self.other.record(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics::{Key, Label, Recorder};
+
+    use crate::testing::{Environ, METADATA};
+    use crate::StatsdBuilder;
+
+    #[test]
+    fn tee() {
+        let other_sink = crate::testing::FakeSink::new();
+        let other = StatsdBuilder::from("", 0)
+            .with_sink(other_sink.clone())
+            .build(None)
+            .expect("should build a recorder with custom sink");
+        let (server_socket, builder) = Environ::setup();
+        let recorder = builder
+            .with_default_tag("app_name", "test")
+            .tee(other)
+            .build(Some("prefix"))
+            .expect("test env should build a valid recorder");
+        let env = Environ {
+            server_socket,
+            recorder,
+        };
+
+        let key = Key::from(("histogram.name", vec![Label::new("histogram", "timer")]));
+        let histogram = env.recorder.register_histogram(&key, &METADATA);
+        histogram.record(1.0);
+
+        assert_eq!(
+            "prefix.histogram.name:1000|ms|#app_name:test",
+            env.receive_on_server()
+        );
+        assert_eq!(vec!["histogram.name:1000|ms"], other_sink.lines());
+    }
+}
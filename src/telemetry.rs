@@ -0,0 +1,885 @@
+use std::collections::HashMap;
+use std::panic::RefUnwindSafe;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+use cadence::ext::MetricBackend;
+use cadence::{MetricSink, SinkStats, StatsdClient};
+use metrics::Label;
+
+use crate::clock::SharedClock;
+use crate::intern::Interner;
+use crate::line::{push_tag, Line, RenderedKey};
+use crate::recorder::Handle;
+use crate::registry::Registries;
+use crate::sink::{QueueSink, SharedSink};
+use crate::stats::{DropReason, DroppedMetrics, Stats};
+use crate::types::MetricType;
+use crate::upkeep::Upkeep;
+
+/// Interval used by the official DogStatsD clients to report their telemetry.
+pub(crate) const DEFAULT_TELEMETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+const TELEMETRY_PREFIX: &str = "datadog.dogstatsd.client";
+
+/// Running totals as of the last report, telemetry is emitted as the delta since then.
+#[derive(Default)]
+struct Totals {
+    metrics_by_type: [u64; MetricType::ALL.len()],
+    queue_drops: u64,
+    sink: SinkStats,
+}
+
+/// Emits the client telemetry metrics that the official DogStatsD clients report, so that the
+/// exporter shows up in Datadog's client troubleshooting views.
+///
+/// Telemetry metrics are never prefixed, Datadog expects these exact names.
+pub(crate) struct Telemetry {
+    sink: Weak<dyn MetricSink + Sync + Send + RefUnwindSafe>,
+    stats: Arc<Stats>,
+    tags: String,
+    last: Totals,
+}
+
+impl Telemetry {
+    pub(crate) fn new(
+        sink: &SharedSink,
+        stats: Arc<Stats>,
+        transport: &str,
+        default_tags: &[(String, String)],
+    ) -> Self {
+        let mut tags = format!(
+            "client:rust,client_version:{},client_transport:{}",
+            env!("CARGO_PKG_VERSION"),
+            transport
+        );
+        for (key, value) in default_tags {
+            tags.push(',');
+            push_tag(&mut tags, key, value);
+        }
+
+        Telemetry {
+            sink: Arc::downgrade(sink),
+            stats,
+            tags,
+            last: Totals::default(),
+        }
+    }
+
+    /// Report on `interval` until the recorder, and with it the sink, goes away.
+    pub(crate) fn schedule(mut self, upkeep: &mut Upkeep, interval: Duration) {
+        upkeep.every(interval, move || match self.sink.upgrade() {
+            Some(sink) => {
+                self.report(sink.as_ref());
+                true
+            }
+            None => false,
+        });
+    }
+
+    fn report(&mut self, sink: &dyn MetricSink) {
+        let mut current = Totals {
+            queue_drops: self.stats.dropped().get(DropReason::QueueFull),
+            sink: sink.stats(),
+            ..Totals::default()
+        };
+        for metric_type in MetricType::ALL {
+            current.metrics_by_type[metric_type.index()] = self.stats.emitted(metric_type);
+        }
+
+        let mut metrics = 0;
+        for metric_type in MetricType::ALL {
+            let delta = current.metrics_by_type[metric_type.index()]
+                .saturating_sub(self.last.metrics_by_type[metric_type.index()]);
+            metrics += delta;
+            if let Some(name) = metric_type.telemetry_name() {
+                self.emit(sink, "metrics_by_type", delta, Some(("metrics_type", name)));
+            }
+        }
+        self.emit(sink, "metrics", metrics, None);
+
+        // the stats of a custom sink may be reset, or not be counted in one place.
+        let queue_drops = current.queue_drops.saturating_sub(self.last.queue_drops);
+        let writer_drops = current
+            .sink
+            .packets_dropped
+            .saturating_sub(self.last.sink.packets_dropped);
+        let bytes_dropped = current
+            .sink
+            .bytes_dropped
+            .saturating_sub(self.last.sink.bytes_dropped);
+        self.emit(
+            sink,
+            "bytes_sent",
+            current
+                .sink
+                .bytes_sent
+                .saturating_sub(self.last.sink.bytes_sent),
+            None,
+        );
+        self.emit(sink, "bytes_dropped", bytes_dropped, None);
+        self.emit(sink, "bytes_dropped_writer", bytes_dropped, None);
+        self.emit(
+            sink,
+            "packets_sent",
+            current
+                .sink
+                .packets_sent
+                .saturating_sub(self.last.sink.packets_sent),
+            None,
+        );
+        self.emit(sink, "packets_dropped", queue_drops + writer_drops, None);
+        self.emit(sink, "packets_dropped_queue", queue_drops, None);
+        self.emit(sink, "packets_dropped_writer", writer_drops, None);
+
+        self.last = current;
+    }
+
+    fn emit(&self, sink: &dyn MetricSink, name: &str, value: u64, tag: Option<(&str, &str)>) {
+        let line = match tag {
+            Some((key, tag_value)) => format!(
+                "{}.{}:{}|c|#{},{}:{}",
+                TELEMETRY_PREFIX, name, value, self.tags, key, tag_value
+            ),
+            None => format!("{}.{}:{}|c|#{}", TELEMETRY_PREFIX, name, value, self.tags),
+        };
+        // failures are already accounted for by the sink, there is nobody to report them to.
+        let _ = sink.emit(&line);
+    }
+}
+
+/// Name of the gauge reporting the number of metrics waiting in the queue.
+pub(crate) const QUEUE_DEPTH_METRIC: &str = "statsd.exporter.queue_depth";
+
+/// Periodically reports the occupancy of the queue as a gauge, so that `with_queue_size` can be
+/// tuned from data. Unlike [`Telemetry`] this is an ordinary metric of the application, it is
+/// prefixed and tagged like every other metric.
+pub(crate) struct QueueDepthReporter {
+    statsd: Weak<StatsdClient>,
+    queue: Weak<QueueSink>,
+    key: RenderedKey,
+}
+
+impl QueueDepthReporter {
+    pub(crate) fn new(
+        statsd: &Arc<StatsdClient>,
+        queue: &Arc<QueueSink>,
+        prefix: &str,
+        default_tags: &[(String, String)],
+    ) -> Self {
+        QueueDepthReporter {
+            statsd: Arc::downgrade(statsd),
+            queue: Arc::downgrade(queue),
+            key: RenderedKey::new(
+                prefix,
+                QUEUE_DEPTH_METRIC,
+                default_tags,
+                std::iter::empty(),
+                &Interner::default(),
+            ),
+        }
+    }
+
+    /// Report on `interval` until the recorder goes away.
+    pub(crate) fn schedule(self, upkeep: &mut Upkeep, interval: Duration) {
+        upkeep.every(interval, move || {
+            match (self.statsd.upgrade(), self.queue.upgrade()) {
+                (Some(statsd), Some(queue)) => {
+                    self.report(&statsd, &queue);
+                    true
+                }
+                _ => false,
+            }
+        });
+    }
+
+    fn report(&self, statsd: &StatsdClient, queue: &QueueSink) {
+        let _ = self
+            .key
+            .with_line(queue.queued(), MetricType::Gauge, |line| {
+                statsd.send_metric(&Line(line))
+            });
+    }
+}
+
+/// Name of the counter incremented on every heartbeat.
+pub(crate) const HEARTBEAT_METRIC: &str = "statsd.exporter.heartbeat";
+
+/// Periodically increments a counter from the upkeep thread, so that its absence on the backend
+/// tells a broken pipeline from a quiet application. Like [`QueueDepthReporter`], it is prefixed
+/// and tagged like every other metric.
+pub(crate) struct HeartbeatReporter {
+    statsd: Weak<StatsdClient>,
+    key: RenderedKey,
+}
+
+impl HeartbeatReporter {
+    pub(crate) fn new(
+        statsd: &Arc<StatsdClient>,
+        prefix: &str,
+        default_tags: &[(String, String)],
+    ) -> Self {
+        HeartbeatReporter {
+            statsd: Arc::downgrade(statsd),
+            key: RenderedKey::new(
+                prefix,
+                HEARTBEAT_METRIC,
+                default_tags,
+                std::iter::empty(),
+                &Interner::default(),
+            ),
+        }
+    }
+
+    /// Beat on `interval` until the recorder goes away.
+    pub(crate) fn schedule(self, upkeep: &mut Upkeep, interval: Duration) {
+        upkeep.every(interval, move || match self.statsd.upgrade() {
+            Some(statsd) => {
+                let _ = self.key.with_line(1, MetricType::Counter, |line| {
+                    statsd.send_metric(&Line(line))
+                });
+                true
+            }
+            None => false,
+        });
+    }
+}
+
+/// Name of the gauge reporting the seconds since the recorder was built.
+pub(crate) const UPTIME_METRIC: &str = "process.uptime.seconds";
+/// Name of the counter incremented once when the recorder is built.
+pub(crate) const START_METRIC: &str = "process.start";
+
+/// Increments a counter when the process starts, then periodically reports the time since as a
+/// gauge, both tagged with the version tags on top of the default tags, so that deploys and
+/// restarts show on the backend. Like [`QueueDepthReporter`], these are prefixed and tagged like
+/// every other metric.
+pub(crate) struct UptimeReporter {
+    statsd: Weak<StatsdClient>,
+    clock: SharedClock,
+    started: Instant,
+    key: RenderedKey,
+}
+
+impl UptimeReporter {
+    /// Sends the start counter right away.
+    pub(crate) fn new(
+        statsd: &Arc<StatsdClient>,
+        clock: SharedClock,
+        prefix: &str,
+        tags: &[(String, String)],
+    ) -> Self {
+        let start = RenderedKey::new(
+            prefix,
+            START_METRIC,
+            tags,
+            std::iter::empty(),
+            &Interner::default(),
+        );
+        let _ = start.with_line(1, MetricType::Counter, |line| {
+            statsd.send_metric(&Line(line))
+        });
+        UptimeReporter {
+            statsd: Arc::downgrade(statsd),
+            started: clock.now(),
+            clock,
+            key: RenderedKey::new(
+                prefix,
+                UPTIME_METRIC,
+                tags,
+                std::iter::empty(),
+                &Interner::default(),
+            ),
+        }
+    }
+
+    /// Report on `interval` until the recorder goes away.
+    pub(crate) fn schedule(self, upkeep: &mut Upkeep, interval: Duration) {
+        upkeep.every(interval, move || match self.statsd.upgrade() {
+            Some(statsd) => {
+                let uptime = self.clock.now().saturating_duration_since(self.started);
+                let _ = self
+                    .key
+                    .with_line(uptime.as_secs(), MetricType::Gauge, |line| {
+                        statsd.send_metric(&Line(line))
+                    });
+                true
+            }
+            None => false,
+        });
+    }
+}
+
+/// Name of the counter reporting the exporter threads that panicked and were restarted.
+pub(crate) const WORKER_RESTARTS_METRIC: &str = "statsd.exporter.worker_restarts";
+
+/// How often the [`Watchdog`] checks on the threads of the exporter.
+pub(crate) const WATCHDOG_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Told about the problems of the exporter, see [`crate::StatsdBuilder::with_log`].
+pub(crate) type LogFn = Arc<dyn Fn(&str) + Send + Sync>;
+
+/// Reports the threads of the exporter that panicked, i.e. the queue threads, which cadence
+/// restarts, and the tasks of the upkeep thread, which keep running. Metrics would otherwise go
+/// missing without a trace. Restarts are counted as a metric, prefixed and tagged like every other
+/// metric, and logged.
+pub(crate) struct Watchdog {
+    statsd: Weak<StatsdClient>,
+    queue: Weak<QueueSink>,
+    stats: Arc<Stats>,
+    log: Option<LogFn>,
+    key: RenderedKey,
+    queue_panics: u64,
+    restarts: u64,
+}
+
+impl Watchdog {
+    pub(crate) fn new(
+        statsd: &Arc<StatsdClient>,
+        queue: &Arc<QueueSink>,
+        stats: Arc<Stats>,
+        log: Option<LogFn>,
+        prefix: &str,
+        default_tags: &[(String, String)],
+    ) -> Self {
+        Watchdog {
+            statsd: Arc::downgrade(statsd),
+            queue: Arc::downgrade(queue),
+            stats,
+            log,
+            key: RenderedKey::new(
+                prefix,
+                WORKER_RESTARTS_METRIC,
+                default_tags,
+                std::iter::empty(),
+                &Interner::default(),
+            ),
+            queue_panics: 0,
+            restarts: 0,
+        }
+    }
+
+    /// Check on `interval` until the recorder goes away.
+    pub(crate) fn schedule(mut self, upkeep: &mut Upkeep, interval: Duration) {
+        upkeep.every(interval, move || {
+            match (self.statsd.upgrade(), self.queue.upgrade()) {
+                (Some(statsd), Some(queue)) => {
+                    self.check(&statsd, &queue);
+                    true
+                }
+                _ => false,
+            }
+        });
+    }
+
+    fn check(&mut self, statsd: &StatsdClient, queue: &QueueSink) {
+        let queue_panics = queue.panics();
+        self.stats
+            .record_worker_restarts(queue_panics - self.queue_panics);
+        self.queue_panics = queue_panics;
+
+        let restarts = self.stats.worker_restarts();
+        let new = restarts - self.restarts;
+        if new == 0 {
+            return;
+        }
+        self.restarts = restarts;
+        let _ = self.key.with_line(new, MetricType::Counter, |line| {
+            statsd.send_metric(&Line(line))
+        });
+        if let Some(log) = &self.log {
+            log(&format!(
+                "{} statsd exporter thread(s) panicked and were restarted",
+                new
+            ));
+        }
+    }
+}
+
+/// How often [`ErrorLog`] rolls the dropped metrics up into a line by default.
+pub(crate) const DEFAULT_ERROR_LOG_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Logs the metrics dropped over an interval as a single line, along with the last error a sink
+/// returned, so that an agent that is down doesn't produce a line per failed send.
+pub(crate) struct ErrorLog {
+    stats: Weak<Stats>,
+    log: LogFn,
+    interval: Duration,
+    last: DroppedMetrics,
+}
+
+impl ErrorLog {
+    pub(crate) fn new(stats: &Arc<Stats>, log: LogFn, interval: Duration) -> Self {
+        ErrorLog {
+            stats: Arc::downgrade(stats),
+            log,
+            interval,
+            last: DroppedMetrics::default(),
+        }
+    }
+
+    /// Log on `interval` until the recorder goes away.
+    pub(crate) fn schedule(mut self, upkeep: &mut Upkeep, interval: Duration) {
+        upkeep.every(interval, move || match self.stats.upgrade() {
+            Some(stats) => {
+                self.check(&stats);
+                true
+            }
+            None => false,
+        });
+    }
+
+    fn check(&mut self, stats: &Stats) {
+        let dropped = stats.dropped();
+        let error = stats.take_error();
+        let total = dropped.total() - self.last.total();
+        if total > 0 {
+            let reasons = dropped
+                .iter()
+                .zip(self.last.iter())
+                .filter(|((_, now), (_, last))| now > last)
+                .map(|((reason, now), (_, last))| format!("{}: {}", reason, now - last))
+                .collect::<Vec<_>>()
+                .join(", ");
+            let mut line = format!(
+                "dropped {} metrics in the last {}s ({})",
+                total,
+                self.interval.as_secs_f64(),
+                reasons
+            );
+            if let Some(error) = error {
+                line.push_str(": ");
+                line.push_str(&error);
+            }
+            (self.log)(&line);
+        }
+        self.last = dropped;
+    }
+}
+
+/// Name of the gauge reporting the metric names with the most series.
+pub(crate) const TOP_SERIES_METRIC: &str = "statsd.exporter.top_series";
+/// Name of the gauge reporting the metric names that sent the most bytes since the last report.
+pub(crate) const TOP_BYTES_METRIC: &str = "statsd.exporter.top_bytes";
+
+/// Periodically reports the `count` metric names with the most registered series, i.e. tag sets,
+/// and the ones that sent the most bytes, tagged with `metric:<name>`, to find where the
+/// cardinality and the volume come from. Like [`QueueDepthReporter`], these are prefixed and
+/// tagged like every other metric.
+pub(crate) struct TopSeriesReporter {
+    statsd: Weak<StatsdClient>,
+    registries: Weak<Registries<Handle>>,
+    count: usize,
+    prefix: String,
+    default_tags: Vec<(String, String)>,
+}
+
+impl TopSeriesReporter {
+    pub(crate) fn new(
+        statsd: &Arc<StatsdClient>,
+        registries: &Arc<Registries<Handle>>,
+        count: usize,
+        prefix: &str,
+        default_tags: &[(String, String)],
+    ) -> Self {
+        TopSeriesReporter {
+            statsd: Arc::downgrade(statsd),
+            registries: Arc::downgrade(registries),
+            count,
+            prefix: prefix.to_string(),
+            default_tags: default_tags.to_vec(),
+        }
+    }
+
+    /// Report on `interval` until the recorder goes away.
+    pub(crate) fn schedule(self, upkeep: &mut Upkeep, interval: Duration) {
+        upkeep.every(interval, move || {
+            match (self.statsd.upgrade(), self.registries.upgrade()) {
+                (Some(statsd), Some(registries)) => {
+                    self.report(&statsd, &registries);
+                    true
+                }
+                _ => false,
+            }
+        });
+    }
+
+    fn report(&self, statsd: &StatsdClient, registries: &Registries<Handle>) {
+        // series and bytes by metric name
+        let mut names: HashMap<String, (u64, u64)> = HashMap::new();
+        registries.for_each(|handle| {
+            let bytes = handle.take_bytes();
+            match names.get_mut(handle.name()) {
+                Some(totals) => *totals = (totals.0 + 1, totals.1 + bytes),
+                None => {
+                    names.insert(handle.name().to_string(), (1, bytes));
+                }
+            }
+        });
+
+        let mut names: Vec<_> = names.into_iter().collect();
+        names.sort_by(|(a, (a_series, _)), (b, (b_series, _))| {
+            b_series.cmp(a_series).then(a.cmp(b))
+        });
+        for (name, (series, _)) in names.iter().take(self.count) {
+            self.emit(statsd, TOP_SERIES_METRIC, name, *series);
+        }
+        names.sort_by(|(a, (_, a_bytes)), (b, (_, b_bytes))| b_bytes.cmp(a_bytes).then(a.cmp(b)));
+        for (name, (_, bytes)) in names.iter().filter(|(_, (_, b))| *b > 0).take(self.count) {
+            self.emit(statsd, TOP_BYTES_METRIC, name, *bytes);
+        }
+    }
+
+    fn emit(&self, statsd: &StatsdClient, metric: &str, name: &str, value: u64) {
+        let label = Label::new("metric", name.to_string());
+        let key = RenderedKey::new(
+            &self.prefix,
+            metric,
+            &self.default_tags,
+            std::iter::once(&label),
+            &Interner::default(),
+        );
+        let _ = key.with_line(value, MetricType::Gauge, |line| {
+            statsd.send_metric(&Line(line))
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::Mutex;
+
+    use metrics::{Key, Recorder};
+
+    use super::*;
+    use crate::testing::{Environ, METADATA};
+    use crate::StatsdBuilder;
+
+    #[derive(Default)]
+    struct LinesSink {
+        lines: Mutex<Vec<String>>,
+    }
+
+    impl MetricSink for LinesSink {
+        fn emit(&self, metric: &str) -> io::Result<usize> {
+            self.lines.lock().unwrap().push(metric.to_string());
+            Ok(metric.len())
+        }
+    }
+
+    struct PanickingSink;
+
+    impl MetricSink for PanickingSink {
+        fn emit(&self, _metric: &str) -> io::Result<usize> {
+            panic!("faulty sink");
+        }
+    }
+
+    #[test]
+    fn watchdog_reports_restarts() {
+        let stats = Arc::new(Stats::default());
+        let queue = Arc::new(QueueSink::new(
+            vec![crate::sink::Queue::Channel(
+                cadence::QueuingMetricSink::from(PanickingSink),
+            )],
+            None,
+            stats.clone(),
+        ));
+        let lines = Arc::new(LinesSink::default());
+        let sink: SharedSink = lines.clone();
+        let statsd = Arc::new(StatsdClient::from_sink(
+            "",
+            crate::sink::SharedSinkRef(sink),
+        ));
+        let logged = Arc::new(Mutex::new(Vec::new()));
+        let log = logged.clone();
+        let mut watchdog = Watchdog::new(
+            &statsd,
+            &queue,
+            stats.clone(),
+            Some(Arc::new(move |line: &str| {
+                log.lock().unwrap().push(line.to_string())
+            })),
+            "app.",
+            &[],
+        );
+
+        queue.emit("metric:1|c").unwrap();
+        for _ in 0..200 {
+            if queue.panics() > 0 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        stats.record_worker_restarts(1);
+        watchdog.check(&statsd, &queue);
+        watchdog.check(&statsd, &queue);
+
+        assert_eq!(2, stats.worker_restarts());
+        assert_eq!(
+            vec!["app.statsd.exporter.worker_restarts:2|c"],
+            *lines.lines.lock().unwrap()
+        );
+        assert_eq!(1, logged.lock().unwrap().len());
+    }
+
+    #[test]
+    fn error_log_rolls_drops_up() {
+        let stats = Arc::new(Stats::default());
+        let logged = Arc::new(Mutex::new(Vec::new()));
+        let log = logged.clone();
+        let mut error_log = ErrorLog::new(
+            &stats,
+            Arc::new(move |line: &str| log.lock().unwrap().push(line.to_string())),
+            Duration::from_secs(30),
+        );
+
+        for _ in 0..3 {
+            stats.record_drop(DropReason::SendError);
+            stats.record_error(&"Connection refused");
+        }
+        stats.record_drop(DropReason::QueueFull);
+        error_log.check(&stats);
+        // nothing was dropped since.
+        error_log.check(&stats);
+        stats.record_drop(DropReason::QueueFull);
+        error_log.check(&stats);
+
+        assert_eq!(
+            vec![
+                "dropped 4 metrics in the last 30s (queue_full: 1, send_error: 3): Connection refused",
+                "dropped 1 metrics in the last 30s (queue_full: 1)",
+            ],
+            *logged.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn reports_deltas() {
+        let lines = Arc::new(LinesSink::default());
+        let sink: SharedSink = lines.clone();
+        let stats = Arc::new(Stats::default());
+        let mut telemetry = Telemetry::new(
+            &sink,
+            stats.clone(),
+            "udp",
+            &[("env".to_string(), "test".to_string())],
+        );
+        let tags = format!(
+            "client:rust,client_version:{},client_transport:udp,env:test",
+            env!("CARGO_PKG_VERSION")
+        );
+
+        stats.record_emit(MetricType::Counter);
+        stats.record_emit(MetricType::Counter);
+        stats.record_emit(MetricType::Gauge);
+        stats.record_drop(DropReason::QueueFull);
+        telemetry.report(sink.as_ref());
+
+        let reported = lines.lines.lock().unwrap().clone();
+        assert!(reported.contains(&format!(
+            "datadog.dogstatsd.client.metrics_by_type:2|c|#{},metrics_type:count",
+            tags
+        )));
+        assert!(reported.contains(&format!("datadog.dogstatsd.client.metrics:3|c|#{}", tags)));
+        assert!(reported.contains(&format!(
+            "datadog.dogstatsd.client.packets_dropped_queue:1|c|#{}",
+            tags
+        )));
+
+        lines.lines.lock().unwrap().clear();
+        stats.record_emit(MetricType::Timer);
+        telemetry.report(sink.as_ref());
+
+        let reported = lines.lines.lock().unwrap().clone();
+        assert!(reported.contains(&format!("datadog.dogstatsd.client.metrics:1|c|#{}", tags)));
+        assert!(reported.contains(&format!(
+            "datadog.dogstatsd.client.packets_dropped_queue:0|c|#{}",
+            tags
+        )));
+    }
+
+    #[test]
+    fn reports_nothing_when_the_sink_stats_go_back() {
+        #[derive(Default)]
+        struct ResettingSink {
+            lines: LinesSink,
+            sent: Mutex<u64>,
+        }
+
+        impl MetricSink for ResettingSink {
+            fn emit(&self, metric: &str) -> io::Result<usize> {
+                self.lines.emit(metric)
+            }
+
+            fn stats(&self) -> SinkStats {
+                let sent = *self.sent.lock().unwrap();
+                SinkStats {
+                    bytes_sent: sent,
+                    packets_sent: sent,
+                    bytes_dropped: sent,
+                    packets_dropped: sent,
+                }
+            }
+        }
+
+        let reset = Arc::new(ResettingSink::default());
+        let sink: SharedSink = reset.clone();
+        let mut telemetry = Telemetry::new(&sink, Arc::new(Stats::default()), "custom", &[]);
+        *reset.sent.lock().unwrap() = 10;
+        telemetry.report(sink.as_ref());
+
+        reset.lines.lines.lock().unwrap().clear();
+        *reset.sent.lock().unwrap() = 4;
+        telemetry.report(sink.as_ref());
+
+        let reported = reset.lines.lines.lock().unwrap().clone();
+        let tags = format!(
+            "client:rust,client_version:{},client_transport:custom",
+            env!("CARGO_PKG_VERSION")
+        );
+        for name in [
+            "bytes_sent",
+            "packets_sent",
+            "bytes_dropped",
+            "packets_dropped",
+        ] {
+            assert!(reported.contains(&format!("datadog.dogstatsd.client.{}:0|c|#{}", name, tags)));
+        }
+    }
+
+    #[test]
+    fn telemetry() {
+        struct LinesSink(Arc<Mutex<Vec<String>>>);
+
+        impl MetricSink for LinesSink {
+            fn emit(&self, metric: &str) -> io::Result<usize> {
+                self.0.lock().unwrap().push(metric.to_string());
+                Ok(metric.len())
+            }
+        }
+
+        let s = Arc::new(Mutex::new(Vec::new()));
+        let clock = crate::testing::ManualClock::new();
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(LinesSink(Arc::clone(&s)))
+            .with_telemetry_interval(Duration::from_secs(10))
+            .with_clock(clock.clone())
+            .build(Some("example_app"))
+            .expect("should build a recorder with telemetry");
+
+        let key = Key::from_name("counter.name");
+        let counter = recorder.register_counter(&key, &METADATA);
+        counter.increment(1);
+
+        let expected = format!(
+            "datadog.dogstatsd.client.metrics:1|c|#client:rust,client_version:{},client_transport:custom",
+            env!("CARGO_PKG_VERSION")
+        );
+        clock.advance(Duration::from_secs(10));
+        recorder.shared.run_pending();
+        assert!(s.lock().unwrap().contains(&expected));
+    }
+
+    #[test]
+    fn queue_depth_gauge() {
+        let (server_socket, builder) = Environ::setup();
+        let recorder = builder
+            .with_queue_depth_gauge(Duration::from_millis(10))
+            .build(Some("blackbird"))
+            .expect("test env should build a valid recorder");
+        let env = Environ {
+            server_socket,
+            recorder,
+        };
+
+        assert_eq!(
+            "blackbird.statsd.exporter.queue_depth:0|g",
+            env.receive_on_server()
+        );
+    }
+
+    #[test]
+    fn heartbeat() {
+        let clock = crate::testing::ManualClock::new();
+        let sink = crate::testing::FakeSink::new();
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(sink.clone())
+            .with_clock(clock.clone())
+            .with_heartbeat(Duration::from_secs(10))
+            .with_default_tag("env", "prod")
+            .build(Some("app"))
+            .expect("should build a recorder with custom sink");
+
+        for _ in 0..2 {
+            clock.advance(Duration::from_secs(10));
+            recorder.shared.run_pending();
+        }
+        assert_eq!(
+            vec!["app.statsd.exporter.heartbeat:1|c|#env:prod"; 2],
+            sink.lines()
+        );
+    }
+
+    #[test]
+    fn uptime() {
+        let clock = crate::testing::ManualClock::new();
+        let sink = crate::testing::FakeSink::new();
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(sink.clone())
+            .with_clock(clock.clone())
+            .with_uptime(Duration::from_secs(10), [("version", "1.2.3")])
+            .with_default_tag("env", "prod")
+            .build(Some("app"))
+            .expect("should build a recorder with custom sink");
+        assert_eq!(
+            vec!["app.process.start:1|c|#env:prod,version:1.2.3"],
+            sink.lines()
+        );
+
+        for _ in 0..2 {
+            clock.advance(Duration::from_secs(10));
+            recorder.shared.run_pending();
+        }
+        assert_eq!(
+            vec![
+                "app.process.start:1|c|#env:prod,version:1.2.3",
+                "app.process.uptime.seconds:10|g|#env:prod,version:1.2.3",
+                "app.process.uptime.seconds:20|g|#env:prod,version:1.2.3",
+            ],
+            sink.lines()
+        );
+    }
+
+    #[test]
+    fn top_series_report() {
+        let clock = crate::testing::ManualClock::new();
+        let sink = crate::testing::FakeSink::new();
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(sink.clone())
+            .with_clock(clock.clone())
+            .with_top_series_report(1, Duration::from_secs(10))
+            .build(Some("app"))
+            .expect("should build a recorder with custom sink");
+        for path in ["/", "/users", "/orders"] {
+            let key = Key::from(("requests", vec![Label::new("path", path)]));
+            recorder.register_counter(&key, &METADATA).increment(1);
+        }
+        let payload = recorder.register_histogram(&Key::from_name("payload.size"), &METADATA);
+        for _ in 0..10 {
+            payload.record(1024.0);
+        }
+        sink.clear();
+
+        clock.advance(Duration::from_secs(10));
+        recorder.shared.run_pending();
+        assert_eq!(
+            vec![
+                "app.statsd.exporter.top_series:3|g|#metric:app.requests",
+                "app.statsd.exporter.top_bytes:230|g|#metric:app.payload.size"
+            ],
+            sink.lines()
+        );
+    }
+}
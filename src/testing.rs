@@ -0,0 +1,1049 @@
+//! Helpers for testing applications that report metrics through this exporter.
+//!
+//! [`CapturingRecorder`] is a real [`StatsdRecorder`] whose output is kept in memory instead of
+//! being sent, so tests see exactly what statsd would receive, parsed into [`Emission`]s:
+//!
+//! ```
+//! use metrics_exporter_statsd::testing::{CapturingRecorder, MetricKind};
+//!
+//! let recorder = CapturingRecorder::new();
+//! metrics::with_local_recorder(&recorder, || {
+//!     metrics::counter!("requests", "endpoint" => "login").increment(1);
+//! });
+//!
+//! let emissions = recorder.emissions();
+//! assert_eq!("requests", emissions[0].name);
+//! assert_eq!(MetricKind::Counter { value: 1 }, emissions[0].kind);
+//! assert_eq!(Some("login"), emissions[0].tag("endpoint"));
+//! ```
+//!
+//! A [`FakeSink`] captures the same way for recorders that are built by the application itself.
+//! [`capture`] installs a fresh recorder around a closure and returns what it recorded, with
+//! assertions, so that tests running in parallel each see their own metrics.
+//!
+//! End-to-end tests can send to a [`MockStatsdServer`] instead, which listens on a real socket.
+//! Periodic work, e.g. telemetry, can be driven by a [`ManualClock`] rather than by sleeping.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use cadence::MetricSink;
+use metrics::{Counter, Gauge, Histogram, Key, KeyName, Metadata, Recorder, SharedString, Unit};
+
+use crate::{Clock, StatsdBuilder, StatsdError, StatsdHandle, StatsdRecorder};
+
+/// The type and value of an [`Emission`].
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum MetricKind {
+    /// `|c`
+    Counter { value: u64 },
+    /// `|g`
+    Gauge { value: f64 },
+    /// `|h`
+    Histogram { value: f64 },
+    /// `|d`
+    Distribution { value: f64 },
+    /// `|ms`, in milliseconds.
+    Timer { value: f64 },
+    /// `|s`
+    Set { member: String },
+}
+
+/// A single metric as it would have been received by statsd.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Emission {
+    /// The name of the metric, prefix included.
+    pub name: String,
+    pub kind: MetricKind,
+    /// The tags by name, tags without a value map to an empty string. When a tag is repeated the
+    /// last value wins, like a label of a [`metrics::Key`] overriding a default tag does.
+    pub tags: BTreeMap<String, String>,
+}
+
+impl Emission {
+    /// Parse a statsd line such as `name:1|c|#tag:value`. Returns `None` for anything this
+    /// exporter wouldn't have sent.
+    pub fn parse(line: &str) -> Option<Emission> {
+        let (name, rest) = line.split_once(':')?;
+        let mut fields = rest.split('|');
+        let value = fields.next()?;
+        let kind = match fields.next()? {
+            "c" => MetricKind::Counter {
+                value: value.parse().ok()?,
+            },
+            "g" => MetricKind::Gauge {
+                value: value.parse().ok()?,
+            },
+            "h" => MetricKind::Histogram {
+                value: value.parse().ok()?,
+            },
+            "d" => MetricKind::Distribution {
+                value: value.parse().ok()?,
+            },
+            "ms" => MetricKind::Timer {
+                value: value.parse().ok()?,
+            },
+            "s" => MetricKind::Set {
+                member: value.to_string(),
+            },
+            _ => return None,
+        };
+
+        let mut tags = BTreeMap::new();
+        for field in fields {
+            if let Some(field_tags) = field.strip_prefix('#') {
+                for tag in field_tags.split(',') {
+                    let (key, value) = tag.split_once(':').unwrap_or((tag, ""));
+                    tags.insert(key.to_string(), value.to_string());
+                }
+            }
+        }
+
+        Some(Emission {
+            name: name.to_string(),
+            kind,
+            tags,
+        })
+    }
+
+    /// The value of the tag named `key`.
+    pub fn tag(&self, key: &str) -> Option<&str> {
+        self.tags.get(key).map(String::as_str)
+    }
+}
+
+/// A [`MetricSink`] that keeps everything written to it in memory, for recorders built with
+/// [`StatsdBuilder::with_sink`]. Clones share what was captured, so a clone can be handed to the
+/// builder while the test keeps the other one.
+///
+/// ```
+/// use metrics::Recorder;
+/// use metrics_exporter_statsd::StatsdBuilder;
+/// use metrics_exporter_statsd::testing::{FakeSink, MetricKind};
+///
+/// let sink = FakeSink::new();
+/// let recorder = StatsdBuilder::from("", 0)
+///     .with_sink(sink.clone())
+///     .with_default_tag("env", "test")
+///     .build(None)
+///     .unwrap();
+/// metrics::with_local_recorder(&recorder, || {
+///     metrics::counter!("jobs", "queue" => "default").increment(10);
+/// });
+///
+/// let emission = &sink.emissions()[0];
+/// assert_eq!(MetricKind::Counter { value: 10 }, emission.kind);
+/// assert_eq!(Some("test"), emission.tag("env"));
+/// assert_eq!(Some("default"), emission.tag("queue"));
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct FakeSink {
+    lines: Arc<Mutex<Vec<String>>>,
+}
+
+impl FakeSink {
+    /// A sink that hasn't captured anything yet.
+    pub fn new() -> Self {
+        FakeSink::default()
+    }
+
+    /// Every line captured so far, in the order they were written.
+    pub fn lines(&self) -> Vec<String> {
+        self.lines.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Every metric captured so far, in the order they were written. Lines that aren't metrics
+    /// this exporter would send are left out.
+    pub fn emissions(&self) -> Vec<Emission> {
+        self.lines()
+            .iter()
+            .filter_map(|line| Emission::parse(line))
+            .collect()
+    }
+
+    /// Forget the metrics captured so far.
+    pub fn clear(&self) {
+        self.lines.lock().unwrap_or_else(|e| e.into_inner()).clear();
+    }
+}
+
+impl MetricSink for FakeSink {
+    fn emit(&self, metric: &str) -> io::Result<usize> {
+        let mut lines = self.lines.lock().unwrap_or_else(|e| e.into_inner());
+        lines.extend(metric.split('\n').map(str::to_string));
+        Ok(metric.len())
+    }
+}
+
+/// A [`Recorder`] that formats metrics exactly like [`StatsdRecorder`] but keeps them in memory
+/// instead of sending them.
+///
+/// It can be installed for the duration of a test with [`metrics::with_local_recorder`], or used
+/// directly through the [`Recorder`] trait. Clones capture into the same memory.
+#[derive(Clone)]
+pub struct CapturingRecorder {
+    recorder: StatsdRecorder,
+    sink: FakeSink,
+}
+
+impl CapturingRecorder {
+    /// A recorder with the default settings and no prefix.
+    pub fn new() -> Self {
+        let sink = FakeSink::new();
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(sink.clone())
+            .build(None)
+            .expect("the default settings with a custom sink are valid");
+        CapturingRecorder { recorder, sink }
+    }
+
+    /// A recorder configured by `builder`, e.g. with default tags, whose sink is replaced by one
+    /// that captures the metrics.
+    pub fn from_builder(builder: StatsdBuilder, prefix: Option<&str>) -> Result<Self, StatsdError> {
+        let sink = FakeSink::new();
+        let recorder = builder.with_sink(sink.clone()).build(prefix)?;
+        Ok(CapturingRecorder { recorder, sink })
+    }
+
+    /// Every line captured so far, in the order they were recorded.
+    pub fn lines(&self) -> Vec<String> {
+        self.sink.lines()
+    }
+
+    /// Every metric captured so far, in the order they were recorded.
+    pub fn emissions(&self) -> Vec<Emission> {
+        self.sink.emissions()
+    }
+
+    /// Forget the metrics captured so far.
+    pub fn clear(&self) {
+        self.sink.clear()
+    }
+
+    /// See [`StatsdRecorder::handle`].
+    pub fn handle(&self) -> StatsdHandle {
+        self.recorder.handle()
+    }
+}
+
+impl Default for CapturingRecorder {
+    fn default() -> Self {
+        CapturingRecorder::new()
+    }
+}
+
+impl Recorder for CapturingRecorder {
+    fn describe_counter(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.recorder.describe_counter(key, unit, description)
+    }
+
+    fn describe_gauge(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.recorder.describe_gauge(key, unit, description)
+    }
+
+    fn describe_histogram(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.recorder.describe_histogram(key, unit, description)
+    }
+
+    fn register_counter(&self, key: &Key, metadata: &Metadata<'_>) -> Counter {
+        self.recorder.register_counter(key, metadata)
+    }
+
+    fn register_gauge(&self, key: &Key, metadata: &Metadata<'_>) -> Gauge {
+        self.recorder.register_gauge(key, metadata)
+    }
+
+    fn register_histogram(&self, key: &Key, metadata: &Metadata<'_>) -> Histogram {
+        self.recorder.register_histogram(key, metadata)
+    }
+}
+
+/// What a masked value is replaced with by [`SnapshotOptions`].
+pub const MASK: &str = "<masked>";
+
+/// Options for turning captured metrics into a stable list of lines, suitable for snapshot (golden)
+/// tests, e.g. with `insta`.
+///
+/// Lines are sorted, so that the order in which threads recorded metrics doesn't matter, and the
+/// values and tags that vary from run to run can be replaced with [`MASK`]:
+///
+/// ```
+/// use std::time::Instant;
+/// use metrics_exporter_statsd::testing::SnapshotOptions;
+///
+/// let lines = SnapshotOptions::new()
+///     .mask_value("handler.duration")
+///     .mask_tag("host")
+///     .capture(|| {
+///         let start = Instant::now();
+///         metrics::counter!("handler.calls", "host" => "web-1").increment(1);
+///         metrics::histogram!("handler.duration").record(start.elapsed());
+///     });
+///
+/// assert_eq!(
+///     vec!["handler.calls:1|c|#host:<masked>", "handler.duration:<masked>|h"],
+///     lines
+/// );
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct SnapshotOptions {
+    masked_values: Vec<String>,
+    mask_all_values: bool,
+    masked_tags: Vec<String>,
+}
+
+impl SnapshotOptions {
+    /// Options that only sort the lines.
+    pub fn new() -> Self {
+        SnapshotOptions::default()
+    }
+
+    /// Mask the value of the metrics named `name`, prefix included.
+    pub fn mask_value<S: Into<String>>(mut self, name: S) -> Self {
+        self.masked_values.push(name.into());
+        self
+    }
+
+    /// Mask the value of every metric, which leaves only names, types and tags to compare.
+    pub fn mask_all_values(mut self) -> Self {
+        self.mask_all_values = true;
+        self
+    }
+
+    /// Mask the value of the tag named `key` on every metric.
+    pub fn mask_tag<S: Into<String>>(mut self, key: S) -> Self {
+        self.masked_tags.push(key.into());
+        self
+    }
+
+    /// Run `f` with a [`CapturingRecorder`] installed as the local recorder and return the
+    /// normalized lines it recorded.
+    pub fn capture<F: FnOnce()>(&self, f: F) -> Vec<String> {
+        let recorder = CapturingRecorder::new();
+        metrics::with_local_recorder(&recorder, f);
+        self.normalize(recorder.lines())
+    }
+
+    /// Mask and sort `lines`, e.g. the ones of a [`CapturingRecorder`] or a [`MockStatsdServer`].
+    pub fn normalize(&self, lines: Vec<String>) -> Vec<String> {
+        let mut lines: Vec<String> = lines.iter().map(|line| self.mask(line)).collect();
+        lines.sort();
+        lines
+    }
+
+    fn mask(&self, line: &str) -> String {
+        let Some((name, rest)) = line.split_once(':') else {
+            return line.to_string();
+        };
+        let (value, fields) = rest.split_once('|').unwrap_or((rest, ""));
+        let mask_value = self.mask_all_values || self.masked_values.iter().any(|n| n == name);
+
+        let mut masked = format!("{}:{}", name, if mask_value { MASK } else { value });
+        for field in fields.split('|').filter(|field| !field.is_empty()) {
+            masked.push('|');
+            match field.strip_prefix('#') {
+                Some(tags) => {
+                    masked.push('#');
+                    let tags: Vec<String> = tags
+                        .split(',')
+                        .map(|tag| match tag.split_once(':') {
+                            Some((key, _)) if self.masked_tags.iter().any(|k| k == key) => {
+                                format!("{}:{}", key, MASK)
+                            }
+                            _ => tag.to_string(),
+                        })
+                        .collect();
+                    masked.push_str(&tags.join(","));
+                }
+                None => masked.push_str(field),
+            }
+        }
+        masked
+    }
+}
+
+/// The lines recorded by `f`, sorted, see [`SnapshotOptions`] to mask the parts that vary.
+pub fn capture_lines<F: FnOnce()>(f: F) -> Vec<String> {
+    SnapshotOptions::new().capture(f)
+}
+
+/// Run `f` with a fresh [`CapturingRecorder`] installed as the local recorder of the current
+/// thread, see [`metrics::with_local_recorder`], and return what `f` returned along with the
+/// metrics it recorded. Tests running in parallel each capture their own metrics, without
+/// installing a global recorder or opening a socket.
+///
+/// Only the metrics recorded on the thread running `f` are captured, not the ones of the threads
+/// it spawns.
+///
+/// ```
+/// use metrics_exporter_statsd::testing;
+///
+/// let (id, captured) = testing::capture(|| {
+///     metrics::counter!("jobs.enqueued", "queue" => "mail").increment(2);
+///     metrics::counter!("jobs.enqueued", "queue" => "mail").increment(1);
+///     metrics::gauge!("jobs.pending").set(7.0);
+///     42
+/// });
+///
+/// assert_eq!(42, id);
+/// captured
+///     .assert_counter("jobs.enqueued", 3)
+///     .assert_gauge("jobs.pending", 7.0)
+///     .assert_not_emitted("jobs.failed");
+/// ```
+pub fn capture<F: FnOnce() -> R, R>(f: F) -> (R, Captured) {
+    let recorder = CapturingRecorder::new();
+    let result = metrics::with_local_recorder(&recorder, f);
+    (result, Captured::new(recorder.lines()))
+}
+
+/// Same as [`capture`], with a recorder configured by `builder`, e.g. with default tags, see
+/// [`CapturingRecorder::from_builder`].
+pub fn capture_with<F: FnOnce() -> R, R>(
+    builder: StatsdBuilder,
+    prefix: Option<&str>,
+    f: F,
+) -> Result<(R, Captured), StatsdError> {
+    let recorder = CapturingRecorder::from_builder(builder, prefix)?;
+    let result = metrics::with_local_recorder(&recorder, f);
+    Ok((result, Captured::new(recorder.lines())))
+}
+
+/// The metrics recorded within [`capture`], along with assertions on them that panic with every
+/// captured line when they fail.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Captured {
+    lines: Vec<String>,
+    emissions: Vec<Emission>,
+}
+
+impl Captured {
+    fn new(lines: Vec<String>) -> Self {
+        let emissions = lines
+            .iter()
+            .filter_map(|line| Emission::parse(line))
+            .collect();
+        Captured { lines, emissions }
+    }
+
+    /// Every line captured, in the order they were recorded.
+    pub fn lines(&self) -> &[String] {
+        &self.lines
+    }
+
+    /// Every metric captured, in the order they were recorded.
+    pub fn emissions(&self) -> &[Emission] {
+        &self.emissions
+    }
+
+    /// The metrics named `name`, prefix included, in the order they were recorded.
+    pub fn named<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Emission> + 'a {
+        self.emissions.iter().filter(move |e| e.name == name)
+    }
+
+    /// The sum of the increments of the counters named `name`, over all their tags.
+    pub fn counter_total(&self, name: &str) -> u64 {
+        self.named(name)
+            .map(|e| match e.kind {
+                MetricKind::Counter { value } => value,
+                _ => 0,
+            })
+            .sum()
+    }
+
+    /// The last value a gauge named `name` was set to, over all its tags.
+    pub fn last_gauge(&self, name: &str) -> Option<f64> {
+        self.named(name)
+            .filter_map(|e| match e.kind {
+                MetricKind::Gauge { value } => Some(value),
+                _ => None,
+            })
+            .last()
+    }
+
+    /// Panic unless a metric named `name` was recorded.
+    #[track_caller]
+    pub fn assert_emitted(&self, name: &str) -> &Self {
+        if self.named(name).next().is_none() {
+            self.fail(format_args!("{} wasn't emitted", name));
+        }
+        self
+    }
+
+    /// Panic if a metric named `name` was recorded.
+    #[track_caller]
+    pub fn assert_not_emitted(&self, name: &str) -> &Self {
+        if self.named(name).next().is_some() {
+            self.fail(format_args!("{} was emitted", name));
+        }
+        self
+    }
+
+    /// Panic unless the counters named `name` were incremented by `total`, see
+    /// [`Captured::counter_total`].
+    #[track_caller]
+    pub fn assert_counter(&self, name: &str, total: u64) -> &Self {
+        let actual = self.counter_total(name);
+        if actual != total {
+            self.fail(format_args!("{} counted {}, not {}", name, actual, total));
+        }
+        self
+    }
+
+    /// Panic unless the last value of the gauges named `name` is `value`, see
+    /// [`Captured::last_gauge`].
+    #[track_caller]
+    pub fn assert_gauge(&self, name: &str, value: f64) -> &Self {
+        let actual = self.last_gauge(name);
+        if actual != Some(value) {
+            self.fail(format_args!("{} was {:?}, not {}", name, actual, value));
+        }
+        self
+    }
+
+    #[track_caller]
+    fn fail(&self, reason: std::fmt::Arguments<'_>) -> ! {
+        panic!("{}, captured:\n{}", reason, self.lines.join("\n"))
+    }
+}
+
+/// A [`Clock`] that only moves when it is told to, for use with
+/// [`StatsdBuilder::with_clock`]. Clones share the same time.
+///
+/// The periodic work of a recorder is scheduled from the time it is built, so advancing the clock
+/// by an interval right after building the recorder runs that work exactly once. The work still
+/// happens on a background thread, wait for its outcome, e.g. with [`MockStatsdServer::wait_for`].
+///
+/// ```
+/// use std::time::Duration;
+/// use metrics_exporter_statsd::testing::{ManualClock, MockStatsdServer};
+///
+/// let clock = ManualClock::new();
+/// let server = MockStatsdServer::udp().unwrap();
+/// let recorder = server
+///     .builder()
+///     .unwrap()
+///     .with_clock(clock.clone())
+///     .with_queue_depth_gauge(Duration::from_secs(60))
+///     .build(None)
+///     .unwrap();
+///
+/// clock.advance(Duration::from_secs(60));
+/// assert!(server
+///     .wait_for("statsd.exporter.queue_depth", Duration::from_secs(5))
+///     .is_some());
+/// ```
+#[derive(Clone)]
+pub struct ManualClock {
+    inner: Arc<ManualClockInner>,
+}
+
+struct ManualClockInner {
+    start: Instant,
+    elapsed: Mutex<Duration>,
+    advanced: Condvar,
+}
+
+impl ManualClock {
+    /// A clock that starts at the current time and stays there until it is advanced.
+    pub fn new() -> Self {
+        ManualClock {
+            inner: Arc::new(ManualClockInner {
+                start: Instant::now(),
+                elapsed: Mutex::new(Duration::ZERO),
+                advanced: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Move the clock forward by `duration`, waking up the threads sleeping until then.
+    pub fn advance(&self, duration: Duration) {
+        let mut elapsed = self.inner.elapsed.lock().unwrap_or_else(|e| e.into_inner());
+        *elapsed += duration;
+        self.inner.advanced.notify_all();
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.inner.start + *self.inner.elapsed.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn sleep_until(&self, deadline: Instant) {
+        let mut elapsed = self.inner.elapsed.lock().unwrap_or_else(|e| e.into_inner());
+        while self.inner.start + *elapsed < deadline {
+            elapsed = self
+                .inner
+                .advanced
+                .wait(elapsed)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+    }
+}
+
+/// How long the server's thread blocks on the socket before checking whether it should stop.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+enum ServerSocket {
+    Udp(UdpSocket),
+    #[cfg(unix)]
+    Unix(UnixDatagram),
+}
+
+impl ServerSocket {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ServerSocket::Udp(socket) => socket.recv(buf),
+            #[cfg(unix)]
+            ServerSocket::Unix(socket) => socket.recv(buf),
+        }
+    }
+}
+
+/// Lines received by a [`MockStatsdServer`], along with what it takes to wait for more.
+#[derive(Default)]
+struct Received {
+    lines: Mutex<Vec<String>>,
+    arrived: Condvar,
+    stop: AtomicBool,
+}
+
+/// A statsd server for end-to-end tests, which listens on a local socket and keeps everything it
+/// receives in memory.
+///
+/// ```
+/// use std::time::Duration;
+/// use metrics_exporter_statsd::testing::{MetricKind, MockStatsdServer};
+///
+/// let server = MockStatsdServer::udp().unwrap();
+/// let recorder = server.builder().unwrap().build(None).unwrap();
+/// metrics::with_local_recorder(&recorder, || metrics::counter!("requests").increment(1));
+///
+/// let emission = server.wait_for("requests", Duration::from_secs(5)).unwrap();
+/// assert_eq!(MetricKind::Counter { value: 1 }, emission.kind);
+/// ```
+///
+/// The server stops listening when it is dropped.
+pub struct MockStatsdServer {
+    received: Arc<Received>,
+    local_addr: Option<SocketAddr>,
+    #[cfg(unix)]
+    path: Option<PathBuf>,
+}
+
+impl MockStatsdServer {
+    /// Listen on an ephemeral UDP port of `127.0.0.1`.
+    pub fn udp() -> io::Result<Self> {
+        let socket = UdpSocket::bind("127.0.0.1:0")?;
+        let local_addr = socket.local_addr()?;
+        let mut server = Self::spawn(ServerSocket::Udp(socket))?;
+        server.local_addr = Some(local_addr);
+        Ok(server)
+    }
+
+    /// Listen on a Unix datagram socket bound to `path`, which is removed when the server is
+    /// dropped.
+    #[cfg(unix)]
+    pub fn unix<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let socket = UnixDatagram::bind(&path)?;
+        let mut server = Self::spawn(ServerSocket::Unix(socket))?;
+        server.path = Some(path);
+        Ok(server)
+    }
+
+    fn spawn(socket: ServerSocket) -> io::Result<Self> {
+        match &socket {
+            ServerSocket::Udp(socket) => socket.set_read_timeout(Some(POLL_INTERVAL))?,
+            #[cfg(unix)]
+            ServerSocket::Unix(socket) => socket.set_read_timeout(Some(POLL_INTERVAL))?,
+        }
+
+        let received = Arc::new(Received::default());
+        let receiver = received.clone();
+        thread::Builder::new()
+            .name("statsd-mock-server".to_string())
+            .spawn(move || {
+                let mut buf = vec![0; 65_536];
+                while !receiver.stop.load(Ordering::Relaxed) {
+                    // timeouts only give the loop a chance to stop.
+                    let Ok(size) = socket.recv(&mut buf) else {
+                        continue;
+                    };
+                    let datagram = String::from_utf8_lossy(&buf[..size]);
+                    let mut lines = receiver.lines.lock().unwrap_or_else(|e| e.into_inner());
+                    lines.extend(
+                        datagram
+                            .split('\n')
+                            .filter(|line| !line.is_empty())
+                            .map(str::to_string),
+                    );
+                    receiver.arrived.notify_all();
+                }
+            })?;
+
+        Ok(MockStatsdServer {
+            received,
+            local_addr: None,
+            #[cfg(unix)]
+            path: None,
+        })
+    }
+
+    /// The address the server listens on, `None` for a Unix socket.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    /// A builder that sends to this server, without buffering so that every metric is sent as
+    /// soon as it is recorded.
+    pub fn builder(&self) -> io::Result<StatsdBuilder> {
+        #[cfg(unix)]
+        if let Some(path) = &self.path {
+            let sink = cadence::UnixMetricSink::from(path, UnixDatagram::unbound()?);
+            return Ok(StatsdBuilder::from("", 0).with_sink(sink));
+        }
+
+        let addr = self
+            .local_addr
+            .ok_or_else(|| io::Error::other("the server has no address"))?;
+        Ok(StatsdBuilder::from(addr.ip().to_string(), addr.port())
+            .with_client_udp_host("127.0.0.1")
+            .with_buffer_size(0))
+    }
+
+    /// Every line received so far and not yet taken by [`MockStatsdServer::wait_for`], in the
+    /// order they arrived.
+    pub fn lines(&self) -> Vec<String> {
+        self.received
+            .lines
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Every metric received so far and not yet taken by [`MockStatsdServer::wait_for`], in the
+    /// order they arrived.
+    pub fn emissions(&self) -> Vec<Emission> {
+        self.lines()
+            .iter()
+            .filter_map(|line| Emission::parse(line))
+            .collect()
+    }
+
+    /// Wait up to `timeout` for a metric named `name` and take it, so that waiting again for the
+    /// same name returns the next one. Returns `None` if none arrived in time.
+    pub fn wait_for(&self, name: &str, timeout: Duration) -> Option<Emission> {
+        let deadline = Instant::now() + timeout;
+        let mut lines = self
+            .received
+            .lines
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        loop {
+            let found = lines.iter().enumerate().find_map(|(i, line)| {
+                Emission::parse(line)
+                    .filter(|emission| emission.name == name)
+                    .map(|emission| (i, emission))
+            });
+            if let Some((i, emission)) = found {
+                lines.remove(i);
+                return Some(emission);
+            }
+
+            let left = deadline.checked_duration_since(Instant::now())?;
+            lines = self
+                .received
+                .arrived
+                .wait_timeout(lines, left)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+    }
+
+    /// Forget everything received so far.
+    pub fn clear(&self) {
+        self.received
+            .lines
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+    }
+}
+
+impl Drop for MockStatsdServer {
+    fn drop(&mut self) {
+        self.received.stop.store(true, Ordering::Relaxed);
+        #[cfg(unix)]
+        if let Some(path) = &self.path {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Metadata of the metrics registered by the tests of the crate.
+#[cfg(test)]
+pub(crate) static METADATA: Metadata =
+    Metadata::new(module_path!(), metrics::Level::INFO, Some(module_path!()));
+
+/// A recorder built by the tests of the crate, sending to a udp socket they read from.
+#[cfg(test)]
+pub(crate) struct Environ {
+    pub(crate) server_socket: UdpSocket,
+    pub(crate) recorder: StatsdRecorder,
+}
+
+#[cfg(test)]
+impl Environ {
+    pub(crate) fn setup() -> (UdpSocket, StatsdBuilder) {
+        let server_socket = UdpSocket::bind("127.0.0.1:0")
+            .expect("localhost should always be a valid socket address");
+        server_socket
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .expect("failed to set the read timeout on our localhost socket");
+        let port = server_socket
+            .local_addr()
+            .expect("socket should have a local addr")
+            .port();
+
+        let builder = StatsdBuilder::from("127.0.0.1", port)
+            .with_queue_size(1)
+            .with_buffer_size(10);
+        (server_socket, builder)
+    }
+
+    pub(crate) fn new(prefix: Option<&str>) -> Self {
+        let (server_socket, builder) = Environ::setup();
+        let recorder = builder
+            .build(prefix)
+            .expect("test env should build a valid recorder");
+        Environ {
+            server_socket,
+            recorder,
+        }
+    }
+
+    pub(crate) fn new_histogram_is_distribution() -> Self {
+        let (server_socket, builder) = Environ::setup();
+        let recorder = builder
+            .histogram_is_distribution()
+            .build(None)
+            .expect("test env should build a valid recorder");
+        Environ {
+            server_socket,
+            recorder,
+        }
+    }
+
+    pub(crate) fn new_histogram_is_timer() -> Self {
+        let (server_socket, builder) = Environ::setup();
+        let recorder = builder
+            .histogram_is_timer()
+            .build(None)
+            .expect("test env should build a valid recorder");
+        Environ {
+            server_socket,
+            recorder,
+        }
+    }
+
+    pub(crate) fn receive_on_server(&self) -> String {
+        let mut buff = [0; 100];
+
+        let size = self
+            .server_socket
+            .recv(&mut buff)
+            .expect("could not receive on server socket");
+        let data = &buff[..size];
+        let request = std::str::from_utf8(data).expect("request is no a valid UTF-8 string");
+        String::from(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_lines() {
+        assert_eq!(
+            Some(Emission {
+                name: "timer.name".to_string(),
+                kind: MetricKind::Timer { value: 250.0 },
+                tags: BTreeMap::from([
+                    ("env".to_string(), "prod".to_string()),
+                    ("flag".to_string(), String::new()),
+                ]),
+            }),
+            Emission::parse("timer.name:250|ms|@0.5|#env:prod,flag")
+        );
+        assert_eq!(
+            Some(MetricKind::Gauge { value: 1.5 }),
+            Emission::parse("gauge.name:1.5|g").map(|e| e.kind)
+        );
+        assert_eq!(None, Emission::parse("unknown.name:1|x"));
+        assert_eq!(None, Emission::parse("garbage"));
+    }
+
+    #[test]
+    fn captures_with_builder_settings() {
+        let recorder = CapturingRecorder::from_builder(
+            StatsdBuilder::from("", 0)
+                .with_default_tag("env", "test")
+                .histogram_is_distribution(),
+            Some("app"),
+        )
+        .unwrap();
+
+        metrics::with_local_recorder(&recorder, || {
+            metrics::histogram!("latency").record(0.5);
+            metrics::gauge!("in_flight", "pool" => "main").set(3.0);
+        });
+
+        assert_eq!(
+            vec![
+                "app.latency:0.5|d|#env:test",
+                "app.in_flight:3|g|#env:test,pool:main"
+            ],
+            recorder.lines()
+        );
+        assert_eq!(
+            Some(MetricKind::Distribution { value: 0.5 }),
+            recorder.emissions().first().map(|e| e.kind.clone())
+        );
+
+        recorder.clear();
+        assert!(recorder.emissions().is_empty());
+    }
+
+    #[test]
+    fn captures_within_the_closure() {
+        let (_, captured) = capture_with(
+            StatsdBuilder::from("", 0).with_default_tag("env", "test"),
+            Some("app"),
+            || {
+                metrics::counter!("requests", "status" => "ok").increment(2);
+                metrics::counter!("requests", "status" => "error").increment(1);
+                metrics::gauge!("threads").set(4.0);
+                metrics::gauge!("threads").set(2.0);
+            },
+        )
+        .unwrap();
+        captured
+            .assert_emitted("app.requests")
+            .assert_counter("app.requests", 3)
+            .assert_gauge("app.threads", 2.0)
+            .assert_not_emitted("requests");
+        assert_eq!(4, captured.lines().len());
+
+        // nothing leaks out of the closure.
+        metrics::counter!("outside").increment(1);
+        let (_, nested) = capture(|| metrics::counter!("inside").increment(1));
+        assert_eq!(vec!["inside:1|c".to_string()], nested.lines());
+
+        let failed = std::panic::catch_unwind(|| {
+            captured.assert_counter("app.requests", 4);
+        });
+        let message = failed.unwrap_err().downcast::<String>().unwrap();
+        assert!(message.starts_with("app.requests counted 3, not 4, captured:\n"));
+    }
+
+    #[test]
+    fn mock_server_over_udp() {
+        let server = MockStatsdServer::udp().unwrap();
+        let recorder = server.builder().unwrap().build(Some("app")).unwrap();
+
+        let counter = recorder.register_counter(&Key::from_name("requests"), &METADATA);
+        counter.increment(1);
+        counter.increment(2);
+
+        let timeout = Duration::from_secs(5);
+        let first = server.wait_for("app.requests", timeout).unwrap();
+        assert_eq!(MetricKind::Counter { value: 1 }, first.kind);
+        let second = server.wait_for("app.requests", timeout).unwrap();
+        assert_eq!(MetricKind::Counter { value: 2 }, second.kind);
+        assert_eq!(
+            None,
+            server.wait_for("app.requests", Duration::from_millis(10))
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn mock_server_over_unix_socket() {
+        let path = std::env::temp_dir().join(format!("statsd-mock-{}.sock", std::process::id()));
+        let server = MockStatsdServer::unix(&path).unwrap();
+        let recorder = server.builder().unwrap().build(None).unwrap();
+
+        let gauge = recorder.register_gauge(&Key::from_name("in_flight"), &METADATA);
+        gauge.set(3.0);
+
+        let emission = server
+            .wait_for("in_flight", Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(MetricKind::Gauge { value: 3.0 }, emission.kind);
+
+        drop(server);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn manual_clock_drives_last_value_rates() {
+        let clock = ManualClock::new();
+        let recorder = CapturingRecorder::from_builder(
+            StatsdBuilder::from("", 0)
+                .with_clock(clock.clone())
+                .with_last_values(10),
+            None,
+        )
+        .unwrap();
+        let handle = recorder.handle();
+
+        let counter = recorder.register_counter(&Key::from_name("requests"), &METADATA);
+        counter.increment(10);
+        clock.advance(Duration::from_secs(5));
+        counter.increment(40);
+
+        assert_eq!(
+            vec![(
+                Key::from_name("requests"),
+                crate::LastValue::Counter {
+                    total: 50,
+                    rate: 10.0
+                }
+            )],
+            handle.snapshot()
+        );
+    }
+
+    #[test]
+    fn snapshot_lines() {
+        let lines = capture_lines(|| {
+            metrics::gauge!("b").set(2.0);
+            metrics::counter!("a").increment(1);
+        });
+        assert_eq!(vec!["a:1|c", "b:2|g"], lines);
+
+        let options = SnapshotOptions::new().mask_all_values().mask_tag("id");
+        assert_eq!(
+            vec!["a:<masked>|c|@0.5|#id:<masked>,env:prod"],
+            options.normalize(vec!["a:1|c|@0.5|#id:42,env:prod".to_string()])
+        );
+    }
+}
@@ -1,19 +1,10 @@
-use cadence::Metric;
-use metrics::{Key, Label};
+use metrics::Key;
 
-pub struct Histogram {
-    repr: String,
-}
-
-impl Metric for Histogram {
-    fn as_metric_str(&self) -> &str {
-        self.repr.as_str()
-    }
-}
+use crate::mapping::Glob;
 
 /// This enum represents all the different histogram transformations that we support. Each histogram
 /// value also takes tags which should be remaining tags after stripping of the `histogram` label.
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum HistogramType {
     Distribution,
     Timer,
@@ -21,16 +12,13 @@ pub enum HistogramType {
 }
 
 impl HistogramType {
-    const HISTOGRAM_HINT: &'static str = "histogram";
-    // Returns the type of histogram from the provided label, it also filters out the
-    // type hint from the labels so that it doesn't end up in the reporting system.
-    pub(crate) fn type_from(key: &Key) -> (Option<HistogramType>, Vec<&Label>) {
-        let (hist_label, labels): (Vec<&Label>, Vec<&Label>) =
-            key.labels().partition(|l| l.key() == Self::HISTOGRAM_HINT);
-
-        let hist_type = hist_label.first().map(|l| HistogramType::from(l.value()));
-
-        (hist_type, labels)
+    pub(crate) const HISTOGRAM_HINT: &'static str = "histogram";
+    // Returns the type of histogram from the provided label. The hint itself has to be filtered
+    // out of the labels so that it doesn't end up in the reporting system.
+    pub(crate) fn type_from(key: &Key) -> Option<HistogramType> {
+        key.labels()
+            .find(|l| l.key() == Self::HISTOGRAM_HINT)
+            .map(|l| HistogramType::from(l.value()))
     }
 }
 
@@ -43,3 +31,129 @@ impl From<&str> for HistogramType {
         }
     }
 }
+
+/// The statsd metric types emitted by the recorder.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum MetricType {
+    /// `|c`
+    Counter,
+    /// `|g`
+    Gauge,
+    /// `|h`
+    Histogram,
+    /// `|d`
+    Distribution,
+    /// `|ms`
+    Timer,
+    /// `|s`
+    Set,
+    /// `|m`, the meters of some legacy statsd servers, only sent when picked with the
+    /// `statsd_type` label.
+    Meter,
+}
+
+impl MetricType {
+    pub(crate) const ALL: [MetricType; 7] = [
+        MetricType::Counter,
+        MetricType::Gauge,
+        MetricType::Histogram,
+        MetricType::Distribution,
+        MetricType::Timer,
+        MetricType::Set,
+        MetricType::Meter,
+    ];
+
+    /// The label that forces the type a metric is sent as, whatever it's recorded as, e.g.
+    /// `statsd_type = "g"` to send a counter as a gauge. Like the histogram hint, it doesn't end
+    /// up in the tags.
+    pub(crate) const TYPE_LABEL: &'static str = "statsd_type";
+
+    /// The type forced by the `statsd_type` label of `key`, if it has one with a known type.
+    pub(crate) fn type_from(key: &Key) -> Option<MetricType> {
+        key.labels()
+            .find(|l| l.key() == Self::TYPE_LABEL)
+            .and_then(|l| Self::from_code(l.value()))
+    }
+
+    pub(crate) fn from_code(code: &str) -> Option<MetricType> {
+        Self::ALL.into_iter().find(|t| t.code() == code)
+    }
+
+    pub(crate) fn index(self) -> usize {
+        self as usize
+    }
+
+    /// The type as it appears on the wire, e.g. `c` in `name:1|c`.
+    pub(crate) fn code(self) -> &'static str {
+        match self {
+            MetricType::Counter => "c",
+            MetricType::Gauge => "g",
+            MetricType::Histogram => "h",
+            MetricType::Distribution => "d",
+            MetricType::Timer => "ms",
+            MetricType::Set => "s",
+            MetricType::Meter => "m",
+        }
+    }
+
+    /// Name used for this type by the DogStatsD client telemetry, `None` for the types DogStatsD
+    /// doesn't know about.
+    pub(crate) fn telemetry_name(self) -> Option<&'static str> {
+        match self {
+            MetricType::Counter => Some("count"),
+            MetricType::Gauge => Some("gauge"),
+            MetricType::Histogram => Some("histogram"),
+            MetricType::Distribution => Some("distribution"),
+            MetricType::Timer => Some("timing"),
+            MetricType::Set => Some("set"),
+            MetricType::Meter => None,
+        }
+    }
+}
+
+impl From<HistogramType> for MetricType {
+    fn from(hist_type: HistogramType) -> Self {
+        match hist_type {
+            HistogramType::Distribution => MetricType::Distribution,
+            HistogramType::Timer => MetricType::Timer,
+            HistogramType::Histogram => MetricType::Histogram,
+        }
+    }
+}
+
+/// The histograms also sent as another type, see
+/// [`StatsdBuilder::with_dual_emit`](crate::StatsdBuilder::with_dual_emit).
+#[derive(Clone, Debug, Default)]
+pub(crate) struct DualEmit {
+    /// The type the names matching a pattern are also sent as, the first matching one wins.
+    names: Vec<(Glob, MetricType)>,
+    /// The patterns that aren't valid, along with why, reported when building the recorder.
+    pub(crate) invalid_patterns: Vec<(String, String)>,
+}
+
+impl DualEmit {
+    pub(crate) fn add(&mut self, pattern: &str, metric_type: MetricType) {
+        match Glob::new(pattern) {
+            Ok(glob) => self.names.push((glob, metric_type)),
+            Err(reason) => self.invalid_patterns.push((pattern.to_string(), reason)),
+        }
+    }
+
+    /// The type the histogram `name`, sent as `metric_type`, is also sent as, if any.
+    pub(crate) fn also(&self, name: &str, metric_type: MetricType) -> Option<MetricType> {
+        let histogram_types = [
+            MetricType::Histogram,
+            MetricType::Timer,
+            MetricType::Distribution,
+        ];
+        if !histogram_types.contains(&metric_type) {
+            return None;
+        }
+        self.names
+            .iter()
+            .find(|(glob, _)| glob.matches(name))
+            .map(|(_, also)| *also)
+            .filter(|also| *also != metric_type && histogram_types.contains(also))
+    }
+}
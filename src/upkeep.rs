@@ -0,0 +1,263 @@
+use std::io;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::clock::SharedClock;
+use crate::sampling;
+use crate::stats::Stats;
+
+/// Work that runs on every tick until it returns `false`, e.g. once what it works on is gone.
+type Tick = Box<dyn FnMut() -> bool + Send>;
+
+/// The periodic work of a recorder, e.g. flushing batches or reporting telemetry, gathered while
+/// the recorder is built and then run by a single thread.
+#[derive(Default)]
+pub(crate) struct Upkeep {
+    tasks: Vec<(Duration, Tick)>,
+    jitter: Duration,
+}
+
+impl Upkeep {
+    /// Delay every tick by a random duration of up to `jitter`, or up to the interval of the task
+    /// when that is shorter, so that the replicas of a service don't all flush at the same time.
+    /// Ticks don't drift, they stay on average on their interval.
+    pub(crate) fn with_jitter(jitter: Duration) -> Self {
+        Upkeep {
+            tasks: Vec::new(),
+            jitter,
+        }
+    }
+
+    /// Run `tick` every `interval`, until it returns `false`.
+    pub(crate) fn every<F>(&mut self, interval: Duration, tick: F)
+    where
+        F: FnMut() -> bool + Send + 'static,
+    {
+        self.tasks.push((interval, Box::new(tick)));
+    }
+
+    /// Start the upkeep thread, unless there is nothing to do.
+    ///
+    /// Ticks are scheduled from the time this is called, not from when the thread gets to run, so a
+    /// manual clock advanced by an interval right after this returns always causes exactly one tick
+    /// of the work on that interval.
+    ///
+    /// A task that panics is counted as a worker restart in `stats` and keeps running on its
+    /// interval, so that one faulty task, e.g. the flush of a custom sink, doesn't stop the others.
+    pub(crate) fn spawn(
+        self,
+        clock: SharedClock,
+        stats: Arc<Stats>,
+    ) -> io::Result<Option<Arc<UpkeepThread>>> {
+        if self.tasks.is_empty() {
+            return Ok(None);
+        }
+
+        let now = clock.now();
+        let jitter = self.jitter;
+        let tasks = self
+            .tasks
+            .into_iter()
+            .map(|(interval, tick)| {
+                let mut task = Task {
+                    interval,
+                    jitter: jitter.min(interval),
+                    due: now + interval,
+                    next: now,
+                    tick,
+                };
+                task.next = task.jittered();
+                task
+            })
+            .collect();
+        let upkeep = Arc::new(UpkeepThread {
+            tasks: Mutex::new(tasks),
+            clock,
+            stats,
+        });
+
+        let thread = upkeep.clone();
+        thread::Builder::new()
+            .name("statsd-upkeep".to_string())
+            .spawn(move || {
+                // the thread exits once every task is done, or once it was stopped.
+                while let Some(next) = thread.next() {
+                    thread.clock.sleep_until(next);
+                    thread.tick(thread.clock.now());
+                }
+            })?;
+        Ok(Some(upkeep))
+    }
+}
+
+struct Task {
+    interval: Duration,
+    jitter: Duration,
+    /// When the task is due, without jitter.
+    due: Instant,
+    /// When the task runs next.
+    next: Instant,
+    tick: Tick,
+}
+
+impl Task {
+    fn jittered(&self) -> Instant {
+        self.due + self.jitter.mul_f64(sampling::random())
+    }
+}
+
+/// The tasks of a running upkeep thread.
+pub(crate) struct UpkeepThread {
+    tasks: Mutex<Vec<Task>>,
+    clock: SharedClock,
+    stats: Arc<Stats>,
+}
+
+impl UpkeepThread {
+    /// Run every task one last time, e.g. to flush what is pending, and let the thread exit.
+    ///
+    /// Tasks run in the reverse order they were added in, sinks are built from the inside out so
+    /// this flushes the outer sinks into the inner ones before flushing those.
+    pub(crate) fn stop(&self) {
+        let tasks = mem::take(&mut *self.tasks.lock().unwrap_or_else(|e| e.into_inner()));
+        for mut task in tasks.into_iter().rev() {
+            (task.tick)();
+        }
+    }
+
+    /// Run the tasks that are due on the calling thread, e.g. right after advancing a manual
+    /// clock. Once this returns, every tick due by now has run, here or on the upkeep thread.
+    #[cfg(test)]
+    pub(crate) fn run_pending(&self) {
+        self.tick(self.clock.now());
+    }
+
+    fn next(&self) -> Option<Instant> {
+        let tasks = self.tasks.lock().unwrap_or_else(|e| e.into_inner());
+        tasks.iter().map(|task| task.next).min()
+    }
+
+    fn tick(&self, now: Instant) {
+        let mut tasks = self.tasks.lock().unwrap_or_else(|e| e.into_inner());
+        tasks.retain_mut(|task| {
+            if task.next > now {
+                return true;
+            }
+            task.due += task.interval;
+            task.next = task.jittered();
+            match panic::catch_unwind(AssertUnwindSafe(&mut task.tick)) {
+                Ok(keep) => keep,
+                Err(_) => {
+                    self.stats.record_worker_restarts(1);
+                    true
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::clock::Clock;
+    use crate::testing::ManualClock;
+
+    #[test]
+    fn runs_tasks_on_their_interval() {
+        let clock = ManualClock::new();
+        let fast = Arc::new(AtomicUsize::new(0));
+        let slow = Arc::new(AtomicUsize::new(0));
+
+        let mut upkeep = Upkeep::default();
+        let count = fast.clone();
+        upkeep.every(Duration::from_secs(1), move || {
+            count.fetch_add(1, Ordering::SeqCst);
+            true
+        });
+        let count = slow.clone();
+        upkeep.every(Duration::from_secs(2), move || {
+            count.fetch_add(1, Ordering::SeqCst);
+            false
+        });
+        let upkeep = upkeep
+            .spawn(Arc::new(clock.clone()), Arc::default())
+            .unwrap()
+            .unwrap();
+
+        clock.advance(Duration::from_secs(1));
+        upkeep.run_pending();
+        assert_eq!(1, fast.load(Ordering::SeqCst));
+        clock.advance(Duration::from_secs(1));
+        upkeep.run_pending();
+        assert_eq!(2, fast.load(Ordering::SeqCst));
+        assert_eq!(1, slow.load(Ordering::SeqCst));
+
+        // the slow task is done, stopping runs the fast one only.
+        upkeep.stop();
+        assert_eq!(3, fast.load(Ordering::SeqCst));
+        assert_eq!(1, slow.load(Ordering::SeqCst));
+        assert!(upkeep.next().is_none());
+    }
+
+    #[test]
+    fn jitter_delays_ticks() {
+        let clock = ManualClock::new();
+        let ticks = Arc::new(AtomicUsize::new(0));
+
+        let mut upkeep = Upkeep::with_jitter(Duration::from_secs(5));
+        let count = ticks.clone();
+        upkeep.every(Duration::from_secs(10), move || {
+            count.fetch_add(1, Ordering::SeqCst);
+            true
+        });
+        let upkeep = upkeep
+            .spawn(Arc::new(clock.clone()), Arc::default())
+            .unwrap()
+            .unwrap();
+        let start = clock.now();
+        for _ in 0..3 {
+            let next = upkeep.next().unwrap() - start;
+            let tick = ticks.load(Ordering::SeqCst) as u32 + 1;
+            let due = Duration::from_secs(10) * tick;
+            assert!(
+                next >= due && next <= due + Duration::from_secs(5),
+                "{:?}",
+                next
+            );
+            clock.advance(next - (clock.now() - start));
+            upkeep.run_pending();
+            assert_eq!(tick as usize, ticks.load(Ordering::SeqCst));
+        }
+    }
+
+    #[test]
+    fn isolates_panics() {
+        let clock = ManualClock::new();
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let stats = Arc::new(Stats::default());
+
+        let mut upkeep = Upkeep::default();
+        let count = ticks.clone();
+        upkeep.every(Duration::from_secs(1), move || {
+            count.fetch_add(1, Ordering::SeqCst);
+            panic!("faulty task");
+        });
+        let upkeep = upkeep
+            .spawn(Arc::new(clock.clone()), stats.clone())
+            .unwrap()
+            .unwrap();
+
+        clock.advance(Duration::from_secs(1));
+        upkeep.run_pending();
+        assert_eq!(1, ticks.load(Ordering::SeqCst));
+        clock.advance(Duration::from_secs(1));
+        upkeep.run_pending();
+        assert_eq!(2, ticks.load(Ordering::SeqCst));
+        assert_eq!(2, stats.worker_restarts());
+    }
+}
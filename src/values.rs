@@ -0,0 +1,154 @@
+/// What happens to the values that are out of the bounds given to
+/// [`StatsdBuilder::with_value_bounds`](crate::StatsdBuilder::with_value_bounds), or that are
+/// negative, see [`StatsdBuilder::with_negative_values`](crate::StatsdBuilder::with_negative_values).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ValuePolicy {
+    /// Send the value as it is.
+    PassThrough,
+    /// Send the closest value that is in bounds instead, e.g. zero for negative values. Values that
+    /// have none, i.e. `NaN`, are dropped.
+    Clamp,
+    /// Drop the value, it's counted as a [`DropReason::InvalidValue`](crate::DropReason) and
+    /// logged along with the other dropped metrics, see
+    /// [`StatsdBuilder::with_log`](crate::StatsdBuilder::with_log).
+    Drop,
+}
+
+/// The range of the values that are sent, beyond which numbers get too long to be written in
+/// fixed-point notation, see [`crate::StatsdBuilder::with_value_bounds`].
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ValueBounds {
+    /// The smallest magnitude sent as it is, smaller values are rounded to zero when clamped.
+    pub(crate) min: f64,
+    /// The largest magnitude sent as it is.
+    pub(crate) max: f64,
+    pub(crate) policy: ValuePolicy,
+}
+
+impl ValueBounds {
+    /// `value` as it should be sent, `None` when it's dropped.
+    pub(crate) fn apply(&self, value: f64) -> Option<f64> {
+        let magnitude = value.abs();
+        if value == 0.0 || (magnitude >= self.min && magnitude <= self.max) {
+            return Some(value);
+        }
+        match self.policy {
+            ValuePolicy::PassThrough => Some(value),
+            ValuePolicy::Drop => None,
+            ValuePolicy::Clamp if value.is_nan() => None,
+            ValuePolicy::Clamp if magnitude < self.min => Some(0.0),
+            ValuePolicy::Clamp => Some(self.max.copysign(value)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics::{Key, Label, Recorder};
+
+    use super::*;
+    use crate::testing::METADATA;
+    use crate::{DropReason, StatsdBuilder};
+
+    #[test]
+    fn applies_the_policy_out_of_bounds() {
+        let bounds = |policy| ValueBounds {
+            min: 1e-6,
+            max: 1e12,
+            policy,
+        };
+
+        let clamp = bounds(ValuePolicy::Clamp);
+        assert_eq!(Some(0.0), clamp.apply(0.0));
+        assert_eq!(Some(-2.5), clamp.apply(-2.5));
+        assert_eq!(Some(1e12), clamp.apply(1e18));
+        assert_eq!(Some(-1e12), clamp.apply(f64::NEG_INFINITY));
+        assert_eq!(Some(0.0), clamp.apply(1e-20));
+        assert_eq!(None, clamp.apply(f64::NAN));
+
+        let drop = bounds(ValuePolicy::Drop);
+        assert_eq!(Some(1e12), drop.apply(1e12));
+        assert_eq!(None, drop.apply(1e18));
+        assert_eq!(None, drop.apply(-1e-20));
+
+        let pass = bounds(ValuePolicy::PassThrough);
+        assert_eq!(Some(1e18), pass.apply(1e18));
+    }
+
+    #[test]
+    fn value_bounds() {
+        let sink = crate::testing::FakeSink::new();
+        let recorder = StatsdBuilder::from("", 0)
+            .with_sink(sink.clone())
+            .with_value_bounds(1e-6, 1e12, ValuePolicy::Clamp)
+            .build(None)
+            .unwrap();
+        let handle = recorder.handle();
+
+        let gauge = recorder.register_gauge(&Key::from_name("gauge.name"), &METADATA);
+        gauge.set(1e18);
+        gauge.set(-1e-20);
+        gauge.set(f64::NAN);
+        crate::StatsdExt::record_distribution(&recorder, &Key::from_name("dist.name"), 1e300);
+        assert_eq!(
+            vec![
+                "gauge.name:1000000000000|g",
+                "gauge.name:0|g",
+                "dist.name:1000000000000|d"
+            ],
+            sink.lines()
+        );
+        assert_eq!(1, handle.dropped_metrics().get(DropReason::InvalidValue));
+    }
+
+    #[test]
+    fn negative_values() {
+        let record = |policy: Option<ValuePolicy>| {
+            let sink = crate::testing::FakeSink::new();
+            let mut builder = StatsdBuilder::from("", 0).with_sink(sink.clone());
+            if let Some(policy) = policy {
+                builder = builder.with_negative_values(policy);
+            }
+            let recorder = builder.build(None).unwrap();
+            let key = |name| Key::from((name, vec![Label::new("histogram", name)]));
+            recorder
+                .register_histogram(&key("timer"), &METADATA)
+                .record(-0.0015);
+            recorder
+                .register_histogram(&key("histogram"), &METADATA)
+                .record(-2.0);
+            crate::StatsdExt::record_distribution(&recorder, &Key::from_name("dist"), -3.0);
+            recorder
+                .register_gauge(&Key::from_name("gauge"), &METADATA)
+                .set(-4.0);
+            (
+                sink.lines(),
+                recorder
+                    .handle()
+                    .dropped_metrics()
+                    .get(DropReason::InvalidValue),
+            )
+        };
+
+        let cases: [(Option<ValuePolicy>, &[&str], u64); 4] = [
+            (None, &["histogram:-2|h", "dist:-3|d", "gauge:-4|g"], 0),
+            (
+                Some(ValuePolicy::PassThrough),
+                &["timer:-1.5|ms", "histogram:-2|h", "dist:-3|d", "gauge:-4|g"],
+                0,
+            ),
+            (
+                Some(ValuePolicy::Clamp),
+                &["timer:0|ms", "histogram:0|h", "dist:0|d", "gauge:-4|g"],
+                0,
+            ),
+            (Some(ValuePolicy::Drop), &["gauge:-4|g"], 3),
+        ];
+        for (policy, lines, dropped) in cases {
+            let (sent, invalid) = record(policy);
+            assert_eq!(lines, sent, "{:?}", policy);
+            assert_eq!(dropped, invalid, "{:?}", policy);
+        }
+    }
+}